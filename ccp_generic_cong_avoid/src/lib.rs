@@ -255,24 +255,20 @@ impl<T: Ipc, A: GenericCongAvoidAlg> CongAlg<T> for GenericCongAvoid<T, A> {
     fn init_programs(_cfg: Config<T, Self>) -> Vec<(String, String)> {
         vec![
             (String::from("DatapathIntervalProg"), String::from("
+                (import std)
                 (def
-                (Report
-                    (volatile acked 0)
-                    (volatile sacked 0)
-                    (volatile loss 0)
-                    (volatile timeout false)
-                    (volatile rtt 0)
-                    (volatile inflight 0)
-                )
-                (reportTime 0)
+                    (Report
+                        (volatile acked 0)
+                        (volatile sacked 0)
+                        (volatile loss 0)
+                        (volatile timeout false)
+                        (volatile rtt 0)
+                        (volatile inflight 0)
+                    )
+                    (reportTime 0)
                 )
                 (when true
-                    (:= Report.inflight Flow.packets_in_flight)
-                    (:= Report.rtt Flow.rtt_sample_us)
-                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
-                    (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
-                    (:= Report.loss Ack.lost_pkts_sample)
-                    (:= Report.timeout Flow.was_timeout)
+                    (ack_stats)
                     (fallthrough)
                 )
                 (when (|| Report.timeout (> Report.loss 0))
@@ -285,74 +281,67 @@ impl<T: Ipc, A: GenericCongAvoidAlg> CongAlg<T> for GenericCongAvoid<T, A> {
                 )
             ")),
             (String::from("DatapathIntervalRTTProg"), String::from("
-                (def (Report
-                    (volatile acked 0)
-                    (volatile sacked 0) 
-                    (volatile loss 0)
-                    (volatile timeout false)
-                    (volatile rtt 0)
-                    (volatile inflight 0)
-                ))
+                (import std)
+                (def
+                    (Report
+                        (volatile acked 0)
+                        (volatile sacked 0)
+                        (volatile loss 0)
+                        (volatile timeout false)
+                        (volatile rtt 0)
+                        (volatile inflight 0)
+                    )
+                )
                 (when true
-                    (:= Report.inflight Flow.packets_in_flight)
-                    (:= Report.rtt Flow.rtt_sample_us)
-                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
-                    (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
-                    (:= Report.loss Ack.lost_pkts_sample)
-                    (:= Report.timeout Flow.was_timeout)
+                    (ack_stats)
                     (fallthrough)
                 )
                 (when (|| Report.timeout (> Report.loss 0))
                     (report)
                     (:= Micros 0)
                 )
-                (when (> Micros Flow.rtt_sample_us)
+                (when (rtt_elapsed)
                     (report)
                     (:= Micros 0)
                 )
             ")),
             (String::from("AckUpdateProg"), String::from("
-                (def (Report
-                    (volatile acked 0)
-                    (volatile sacked 0)
-                    (volatile loss 0)
-                    (volatile timeout false)
-                    (volatile rtt 0)
-                    (volatile inflight 0)
-                ))
+                (import std)
+                (def
+                    (Report
+                        (volatile acked 0)
+                        (volatile sacked 0)
+                        (volatile loss 0)
+                        (volatile timeout false)
+                        (volatile rtt 0)
+                        (volatile inflight 0)
+                    )
+                )
                 (when true
-                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
-                    (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
-                    (:= Report.loss Ack.lost_pkts_sample)
-                    (:= Report.timeout Flow.was_timeout)
-                    (:= Report.rtt Flow.rtt_sample_us)
-                    (:= Report.inflight Flow.packets_in_flight)
+                    (ack_stats)
                     (report)
                 )
             ")),
             (String::from("SSUpdateProg"), String::from("
-                (def (Report
-                    (volatile acked 0)
-                    (volatile sacked 0)
-                    (volatile loss 0)
-                    (volatile timeout false)
-                    (volatile rtt 0)
-                    (volatile inflight 0)
-                ))
+                (import std)
+                (def
+                    (Report
+                        (volatile acked 0)
+                        (volatile sacked 0)
+                        (volatile loss 0)
+                        (volatile timeout false)
+                        (volatile rtt 0)
+                        (volatile inflight 0)
+                    )
+                )
                 (when true
-                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
-                    (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
-                    (:= Report.loss Ack.lost_pkts_sample)
-                    (:= Report.timeout Flow.was_timeout)
-                    (:= Report.rtt Flow.rtt_sample_us)
-                    (:= Report.inflight Flow.packets_in_flight)
+                    (ack_stats)
                     (:= Cwnd (+ Cwnd Ack.bytes_acked))
                     (fallthrough)
                 )
                 (when (|| Report.timeout (> Report.loss 0))
                     (report)
                 )
-
             "))]
     }
 
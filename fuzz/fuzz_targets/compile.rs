@@ -0,0 +1,17 @@
+//! Datapath programs, which algorithms (and Python users) pass to `lang::compile`, compiled for
+//! every datapath version and then for each backend.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use portus::lang::{self, Capabilities};
+
+fuzz_target!(|data: &[u8]| {
+    for version in 0..=Capabilities::SIGNED_OPS_VERSION {
+        let capabilities = Capabilities::new(version).with_timers(Capabilities::MAX_TIMERS);
+        if let Ok((bin, sc)) = lang::compile_with_capabilities(data, &[], capabilities) {
+            let _ = bin.serialize();
+            let _ = lang::ebpf::compile(&bin, &sc);
+            let _ = lang::codegen::to_c(&bin, &sc, "fuzz");
+        }
+    }
+});
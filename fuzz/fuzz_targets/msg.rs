@@ -0,0 +1,16 @@
+//! Messages from the datapath, which `ipc::Backend` reads one after another from its buffer.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use portus::serialize::Msg;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data;
+    while let Ok((msg, len)) = Msg::from_buf(buf) {
+        if let Msg::Ms(m) = msg {
+            m.to_msg();
+        }
+
+        buf = &buf[len..];
+    }
+});
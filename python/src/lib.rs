@@ -6,7 +6,7 @@ extern crate slog;
 extern crate time;
 
 extern crate portus;
-use portus::{CongAlg, Config, Datapath, DatapathTrait, Report};
+use portus::{CongAlg, Config, Datapath, DatapathTrait, OwnedReport, Report};
 use portus::ipc;
 use portus::ipc::{BackendBuilder, Ipc};
 use portus::lang::Scope;
@@ -73,7 +73,7 @@ pub struct DatapathInfo {
 // keeps a copy of the scope so the python user doesn't need to manage it 
 #[py::class(gc,weakref,dict)]
 struct PyReport {
-    report : Report,
+    report : OwnedReport,
     sc     : Weak<Scope>,
 }
 
@@ -240,7 +240,7 @@ impl<T: Ipc> CongAlg<T> for PyAlg {
                     return;
                 }
                 let rep = py.init(|_t| PyReport {
-                    report: m,
+                    report: m.into_owned(),
                     sc: Rc::downgrade(s),
                 }).unwrap_or_else(|e| {
                     e.print(py);
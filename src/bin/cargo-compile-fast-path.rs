@@ -8,7 +8,7 @@ use std::fs::File;
 use std::io::Read;
 
 use syn::visit::Visit;
-use syn::{Expr,Item::Impl,Expr::Lit,Expr::MethodCall,Lit::ByteStr,Lit::Str};
+use syn::{Expr,ImplItemMethod,Item::Impl,Expr::Lit,Expr::MethodCall,Lit::ByteStr,Lit::Str};
 use syn::punctuated::{Pair::Punctuated,Pair::End};
 use quote::ToTokens;
 use walkdir::{DirEntry, WalkDir};
@@ -35,20 +35,90 @@ macro_rules! bold {
 struct FastPathProgramFinder {
     total: u32,
     failed: u32,
+    unformatted: u32,
+    check_format: bool,
+    analyze: bool,
     impl_str: String,
     filename: String,
 }
 impl FastPathProgramFinder {
-    fn new(impl_str: String, filename: String) -> Self {
+    fn new(impl_str: String, filename: String, check_format: bool, analyze: bool) -> Self {
         Self {
             total: 0,
             failed: 0,
+            unformatted: 0,
+            check_format,
+            analyze,
             impl_str,
             filename,
         }
     }
+
+    fn check_format(&mut self, src: &str) {
+        match lang::fmt::format_embedded(src) {
+            Ok(ref formatted) if formatted != src => {
+                self.unformatted += 1;
+                eprintln!("{}{}", bold_red!("warning"), bold!(": fast-path program is not formatted, run ccp-fmt"));
+                eprintln!("{} {}", bold_blue!("-->"), self.filename);
+                eprintln!("{} {}\n", bold_blue!("-->"), self.impl_str);
+            }
+            _ => {}
+        }
+    }
+
+    /// Compile a program literal found in the source, reporting whether it compiles.
+    fn check_program(&mut self, l: &syn::Lit) {
+        let compile_result = match *l {
+            ByteStr(ref ls) => {
+                let src = ls.value();
+                if self.check_format {
+                    if let Ok(s) = std::str::from_utf8(&src) {
+                        self.check_format(s);
+                    }
+                }
+                lang::compile(&src, &[])
+            }
+            Str(ref ls)     => {
+                if self.check_format {
+                    self.check_format(&ls.value());
+                }
+                lang::compile(ls.value().as_bytes(), &[])
+            }
+            _           => { panic!("Non-string passed to install(). This shouldn't have compiled in the first place...") }
+        };
+        self.total += 1;
+        match compile_result {
+            Ok((bin, sc)) => if self.analyze {
+                println!("{} {}\n{} {}", bold_blue!("-->"), self.filename, bold_blue!("-->"), self.impl_str);
+                println!("{}", lang::analysis::analyze(&bin, &sc));
+            }
+            Err(e) => { 
+                self.failed += 1;
+                eprintln!("{}{}", bold_red!("error"), bold!(format!(": {:?}", e)));
+                eprintln!("{} {}", bold_blue!("-->"), self.filename);
+                eprintln!("{} {}", bold_blue!("-->"), self.impl_str);
+                let prog_src = l.into_tokens().to_string();
+                eprintln!("{}\n\n", prog_src.split("\n")
+                                .enumerate().map(|(i,l)|format!("{} {}", bold_blue!(format!("{:3} |", i)), l))
+                                .collect::<Vec<String>>()
+                                .join("\n"));
+            }
+        }
+    }
 }
 impl<'v> Visit<'v> for FastPathProgramFinder {
+    fn visit_impl_item_method(&mut self, m: &'v ImplItemMethod) {
+        if m.sig.ident.to_string() == "init_programs" {
+            let mut pf = InitProgramsFinder::default();
+            pf.visit_block(&m.block);
+            for l in pf.programs {
+                self.check_program(&l);
+            }
+        }
+
+        syn::visit::visit_impl_item_method(self, m)
+    }
+
     fn visit_expr(&mut self, e : &Expr) {
         match e {
             &MethodCall(ref emc) => {
@@ -56,26 +126,7 @@ impl<'v> Visit<'v> for FastPathProgramFinder {
                 if method_name == "install" {
                     match emc.args.first() {
                         Some(Punctuated(&Lit(ref l), _)) | Some(End(&Lit(ref l))) => { 
-                            let compile_result = match l.lit {
-                                ByteStr(ref ls) => { lang::compile(&ls.value(), &[]) }
-                                Str(ref ls)     => { lang::compile(ls.value().as_bytes(), &[]) }
-                                _           => { panic!("Non-string passed to install(). This shouldn't have compiled in the first place...") }
-                            };
-                            self.total += 1;
-                            match compile_result {
-                                Ok(_)  => {}
-                                Err(e) => { 
-                                    self.failed += 1;
-                                    eprintln!("{}{}", bold_red!("error"), bold!(format!(": {:?}", e)));
-                                    eprintln!("{} {}", bold_blue!("-->"), self.filename);
-                                    eprintln!("{} {}", bold_blue!("-->"), self.impl_str);
-                                    let prog_src = l.into_tokens().to_string();
-                                    eprintln!("{}\n\n", prog_src.split("\n")
-                                                    .enumerate().map(|(i,l)|format!("{} {}", bold_blue!(format!("{:3} |", i)), l))
-                                                    .collect::<Vec<String>>()
-                                                    .join("\n"));
-                                }
-                            }
+                            self.check_program(&l.lit);
                         }
                         Some(Punctuated(&MethodCall(ref mcmc), _)) | Some(End(&MethodCall(ref mcmc))) => {
                             self.visit_expr(&mcmc.receiver);
@@ -90,14 +141,97 @@ impl<'v> Visit<'v> for FastPathProgramFinder {
     }
 }
 
+/// Collects the program literals of the `(name, program)` pairs returned by
+/// `CongAlg::init_programs`, including those inside `vec![...]`.
+#[derive(Default)]
+struct InitProgramsFinder {
+    programs: Vec<syn::Lit>,
+}
+
+/// The string literal in a program expression such as `"..."`, `String::from("...")` or
+/// `"...".to_string()`.
+fn program_literal(e: &Expr) -> Option<&syn::Lit> {
+    match *e {
+        Lit(ref l) => Some(&l.lit),
+        Expr::Call(ref c) if c.args.len() == 1 => c.args.first().and_then(|a| program_literal(a.value())),
+        MethodCall(ref mc) if mc.args.is_empty() => program_literal(&mc.receiver),
+        Expr::Paren(ref p) => program_literal(&p.expr),
+        Expr::Reference(ref r) => program_literal(&r.expr),
+        _ => None,
+    }
+}
+
+impl<'v> Visit<'v> for InitProgramsFinder {
+    fn visit_expr_tuple(&mut self, t: &'v syn::ExprTuple) {
+        if t.elems.len() == 2 {
+            if let Some(l) = t.elems.last().and_then(|p| program_literal(p.value())) {
+                match *l {
+                    Str(_) | ByteStr(_) => self.programs.push(l.clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        syn::visit::visit_expr_tuple(self, t)
+    }
+
+    fn visit_macro(&mut self, m: &'v syn::Macro) {
+        // macro arguments are not parsed; reparse them as array elements
+        if let Ok(arr) = syn::parse_str::<Expr>(&format!("[{}]", m.tts)) {
+            let mut pf = InitProgramsFinder::default();
+            pf.visit_expr(&arr);
+            self.programs.extend(pf.programs);
+        }
+    }
+}
+
+/// Compile a standalone `.ccp` program file, returning the counts of programs found, failed and
+/// unformatted. Fragments, which have no `(def ...)` block, are only checked when included.
+fn check_ccp_file(filepath: &std::path::Path, src: &str, check_format: bool, analyze: bool) -> (u32, u32, u32) {
+    if lang::is_fragment(src) {
+        return (0, 0, 0);
+    }
+
+    let mut unformatted = 0;
+    if check_format {
+        match lang::fmt::format(src.as_bytes()) {
+            Ok(ref formatted) if formatted != src => {
+                unformatted += 1;
+                eprintln!("{}{}", bold_red!("warning"), bold!(": fast-path program is not formatted, run ccp-fmt"));
+                eprintln!("{} {}\n", bold_blue!("-->"), filepath.display());
+            }
+            _ => {}
+        }
+    }
+
+    match lang::compile_file(filepath, &[]) {
+        Ok((bin, sc)) => {
+            if analyze {
+                println!("{} {}", bold_blue!("-->"), filepath.display());
+                println!("{}", lang::analysis::analyze(&bin, &sc));
+            }
+            (1, 0, unformatted)
+        }
+        Err(e) => {
+            eprintln!("{}{}", bold_red!("error"), bold!(format!(": {:?}", e)));
+            eprintln!("{} {}\n\n", bold_blue!("-->"), filepath.display());
+            (1, 1, unformatted)
+        }
+    }
+}
+
 const HELP_MSG: &str = r#"Tests compilation of fast-path programs
 
 Usage:
-    cargo compile-fast-path [--path PATH]
+    cargo compile-fast-path [--check-format] [--analyze] [--path PATH]
 
 Options:
-    -h, --help    Print this message
-    --path        Root directory of files to check, assumes ./src
+    -h, --help        Print this message
+    --path            Root directory of .rs and .ccp files to check, assumes ./src
+    --check-format    Also check that programs are formatted as ccp-fmt would format them
+    --analyze         Print the instructions each program runs per ack and the registers it uses
+
+Exits with status 1 if any program fails to compile or, with --check-format, is not formatted.
 "#;
 
 fn show_help() {
@@ -110,12 +244,15 @@ fn main() {
         show_help();
         return;
     }
-    let num_args = args().len();
+    let check_format = args().any(|a| a == "--check-format");
+    let analyze = args().any(|a| a == "--analyze");
+    let is_flag = |a: &String| a == "--check-format" || a == "--analyze";
+    let num_args = args().filter(|a| !is_flag(a)).count();
     if num_args != 2 && num_args != 4 {
         show_help();
         return;
     }
-    let mut opts = args().skip(2);
+    let mut opts = args().skip(2).filter(|a| !is_flag(a)).collect::<Vec<String>>().into_iter();
     let path = {
         if opts.len() == 2 {
             if opts.next() != Some("--path".to_string()) {
@@ -135,22 +272,31 @@ fn main() {
     fn is_dir(entry: &DirEntry) -> bool {
         entry.file_type().is_dir()
     }
-    fn is_rs(entry: &DirEntry) -> bool {
-        entry.file_name().to_str().unwrap().to_string().split(".").last().unwrap_or("") == "rs"
+    fn extension(entry: &DirEntry) -> String {
+        entry.file_name().to_str().unwrap().to_string().split(".").last().unwrap_or("").to_string()
     }
 
     let mut total = 0;
     let mut failed = 0;
+    let mut unformatted = 0;
 
     for entry in walker.filter_entry(|e| !is_hidden(&e))
                        .filter(|e| e.is_ok())
                        .map(|e| e.unwrap())
-                       .filter(|e| !is_dir(e) && is_rs(e))
+                       .filter(|e| !is_dir(e) && (extension(e) == "rs" || extension(e) == "ccp"))
     {
         let filepath = &entry.path();
         let mut file = File::open(&filepath).expect("Unable to open file");
         let mut src = String::new();
         file.read_to_string(&mut src).expect("Unable to read file");
+        if extension(&entry) == "ccp" {
+            let (t, f, u) = check_ccp_file(filepath, &src, check_format, analyze);
+            total += t;
+            failed += f;
+            unformatted += u;
+            continue;
+        }
+
         let syntax = syn::parse_file(&src).expect("Unable to parse file");
         for item in syntax.items {
             match item {
@@ -167,12 +313,13 @@ fn main() {
                         Some(tn) => format!("impl {} for {}", tn, struct_name),
                         None => format!("impl {}", struct_name),
                     };
-                    let mut pf = FastPathProgramFinder::new(impl_str, filepath.display().to_string());
+                    let mut pf = FastPathProgramFinder::new(impl_str, filepath.display().to_string(), check_format, analyze);
                     for imp_item in imp.items {
                         pf.visit_impl_item(&imp_item);
                     }
                     total += pf.total;
                     failed += pf.failed;
+                    unformatted += pf.unformatted;
                 },
                 _ => continue,
             }
@@ -189,4 +336,11 @@ fn main() {
     } else {
         println!("       {} 0 fast-path programs in {}", bold_green!("Found"), path);
     }
+    if unformatted > 0 {
+        eprintln!("{}{}", bold_red!("warning"), bold!(format!(": {}/{} fast-path programs are not formatted.", unformatted, total)));
+    }
+
+    if failed > 0 || unformatted > 0 {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,233 @@
+extern crate portus;
+
+use std::env::args;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+
+use portus::lang::{self, fmt};
+
+/// `ccp-fmt` reformats datapath programs in place.
+///
+/// - `.ccp` files are formatted as a whole.
+/// - In `.rs` and `.py` files, every string literal whose contents start with a top-level form
+///   of the language, such as `(def`, `(import` or `(defmacro`, is formatted with
+///   `lang::fmt::format_embedded`. Literals containing escape sequences are left alone.
+///
+/// With `--check`, files are not modified; `ccp-fmt` lists the files that are not canonically
+/// formatted and exits with status 1 if there are any.
+const HELP_MSG: &str = r#"Formats datapath programs
+
+Usage:
+    ccp-fmt [--check] FILE...
+
+Options:
+    -h, --help    Print this message
+    --check       Do not write files; exit with status 1 if any file would change
+"#;
+
+/// A string literal in a host-language source file.
+struct Literal {
+    /// byte offset of the first character after the opening quote
+    start: usize,
+    /// byte offset of the closing quote
+    end: usize,
+}
+
+fn looks_like_program(s: &str) -> bool {
+    lang::starts_with_top_level_form(s.trim_start_matches(|c: char| c.is_whitespace() || c == '\\'))
+}
+
+/// Find the end of a string literal starting at `start`, given its closing delimiter.
+fn find_close(src: &str, start: usize, delim: &str) -> Option<usize> {
+    let bytes = src.as_bytes();
+    let mut i = start;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+
+        if src[i..].starts_with(delim) {
+            return Some(i);
+        }
+
+        i += 1;
+    }
+
+    None
+}
+
+/// String literals of a Rust source file, skipping comments, char literals and raw strings.
+fn rust_literals(src: &str) -> Vec<Literal> {
+    let bytes = src.as_bytes();
+    let mut lits = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if src[i..].starts_with("//") => {
+                i = src[i..].find('\n').map(|n| i + n).unwrap_or_else(|| bytes.len());
+            }
+            b'/' if src[i..].starts_with("/*") => {
+                i = src[i..].find("*/").map(|n| i + n + 2).unwrap_or_else(|| bytes.len());
+            }
+            b'r' if src[i..].starts_with("r\"") || src[i..].starts_with("r#") => {
+                let hashes = src[i + 1..].chars().take_while(|c| *c == '#').count();
+                let close = format!("\"{}", "#".repeat(hashes));
+                let open = i + 2 + hashes;
+                i = find_close(src, open, &close).map(|n| n + close.len()).unwrap_or_else(|| bytes.len());
+            }
+            b'\'' => {
+                // either a char literal or a lifetime
+                if src[i..].starts_with("'\\") {
+                    i = src[i + 2..].find('\'').map(|n| i + n + 3).unwrap_or_else(|| bytes.len());
+                } else if src[i + 1..].chars().nth(1) == Some('\'') {
+                    i += 1 + src[i + 1..].chars().next().map(|c| c.len_utf8()).unwrap_or(0) + 1;
+                } else {
+                    i += 1;
+                }
+            }
+            b'"' => {
+                let start = i + 1;
+                match find_close(src, start, "\"") {
+                    Some(end) => {
+                        lits.push(Literal { start, end });
+                        i = end + 1;
+                    }
+                    None => break,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    lits
+}
+
+/// String literals of a Python source file, skipping comments.
+fn python_literals(src: &str) -> Vec<Literal> {
+    let bytes = src.as_bytes();
+    let mut lits = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                i = src[i..].find('\n').map(|n| i + n).unwrap_or_else(|| bytes.len());
+            }
+            q @ b'"' | q @ b'\'' => {
+                let single = if q == b'"' { "\"" } else { "'" };
+                let triple = single.repeat(3);
+                let delim = if src[i..].starts_with(&triple) { triple } else { String::from(single) };
+                let start = i + delim.len();
+                match find_close(src, start, &delim) {
+                    Some(end) => {
+                        lits.push(Literal { start, end });
+                        i = end + delim.len();
+                    }
+                    None => break,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    lits
+}
+
+/// Format the program in a literal. Python literals may begin with a line continuation
+/// (`"""\`), which is kept.
+fn format_literal(lit: &str) -> portus::lang::Result<String> {
+    if lit.starts_with("\\\n") {
+        return fmt::format_embedded(&lit[1..]).map(|f| format!("\\{}", f));
+    }
+
+    fmt::format_embedded(lit)
+}
+
+/// Returns the formatted contents of the file at `path`, or an error message.
+fn format_file(path: &Path, src: &str) -> Result<String, String> {
+    let literals = match path.extension().and_then(|e| e.to_str()) {
+        Some("ccp") => return fmt::format(src.as_bytes()).map_err(|e| format!("{}", e)),
+        Some("rs") => rust_literals(src),
+        Some("py") => python_literals(src),
+        _ => return Err(String::from("unknown file type (expected .ccp, .rs or .py)")),
+    };
+
+    let mut out = String::with_capacity(src.len());
+    let mut last = 0;
+    for lit in literals {
+        let contents = &src[lit.start..lit.end];
+        // a leading backslash is a line continuation, any other is an escape sequence
+        let rest = contents.char_indices().nth(1).map(|(i, _)| &contents[i..]).unwrap_or("");
+        if !looks_like_program(contents) || rest.contains('\\') {
+            continue;
+        }
+
+        match format_literal(contents) {
+            Ok(formatted) => {
+                out.push_str(&src[last..lit.start]);
+                out.push_str(&formatted);
+                last = lit.end;
+            }
+            Err(e) => {
+                let line = src[..lit.start].lines().count();
+                eprintln!("{}:{}: skipping program: {}", path.display(), line, e);
+            }
+        }
+    }
+
+    out.push_str(&src[last..]);
+    Ok(out)
+}
+
+fn main() {
+    if args().any(|a| a == "--help" || a == "-h") {
+        eprintln!("{}", HELP_MSG);
+        return;
+    }
+
+    let check = args().any(|a| a == "--check");
+    let files: Vec<String> = args().skip(1).filter(|a| a != "--check").collect();
+    if files.is_empty() {
+        eprintln!("{}", HELP_MSG);
+        process::exit(2);
+    }
+
+    let mut unformatted = 0;
+    let mut failed = 0;
+    for f in files {
+        let path = Path::new(&f);
+        let mut src = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut src)) {
+            eprintln!("{}: {}", f, e);
+            failed += 1;
+            continue;
+        }
+
+        let formatted = match format_file(path, &src) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}: {}", f, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        if formatted == src {
+            continue;
+        }
+
+        unformatted += 1;
+        if check {
+            println!("{}", f);
+        } else if let Err(e) = File::create(path).and_then(|mut file| file.write_all(formatted.as_bytes())) {
+            eprintln!("{}: {}", f, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 || (check && unformatted > 0) {
+        process::exit(1);
+    }
+}
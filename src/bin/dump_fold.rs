@@ -9,7 +9,8 @@ use portus::{lang, serialize};
 /// 0. An echo of the input program.
 /// 1. The AST representation of that program
 /// 2. The compiled instructions
-/// 3. The serialized binary which will be sent to the datapath
+/// 3. An analysis of the instructions run per ack and the registers used
+/// 4. The serialized binary which will be sent to the datapath
 ///
 /// On compilation failure, `dump_fold` will panic with the compilation error.
 fn main() {
@@ -20,6 +21,7 @@ fn main() {
     println!("ast:\n{:?}", ast);
     let bin = lang::Bin::compile_prog(&ast, &mut sc).unwrap();
     println!("instructions:\n{:?}", bin);
+    println!("analysis:\n{}", lang::analysis::analyze(&bin, &sc));
     let msg = serialize::install::Msg {
         sid: 1,
         program_uid: 9,
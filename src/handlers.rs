@@ -0,0 +1,55 @@
+//! Handlers for message types beyond those portus defines.
+//!
+//! The datapath may send messages of its own types, defined with `serialize::AsRawMsg`. Register
+//! a handler for each type with `MsgHandlers::register()` and pass them to `run_with_handlers()`
+//! or `spawn_with_handlers()`. A handler is given the message and, if the message's sid names a
+//! flow, that flow's algorithm; it can decode the message with `AsRawMsg::from_raw_msg()`.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use serialize::{RawMsg, RESERVED_MSG_TYPES};
+use super::{Error, Result};
+
+/// A handler for messages of one type.
+pub type MsgHandler<U> = Box<dyn FnMut(RawMsg, Option<&mut U>) -> Result<()> + Send>;
+
+/// Handlers for messages, by type, for an algorithm `U`.
+pub struct MsgHandlers<U> {
+    handlers: HashMap<u8, MsgHandler<U>>,
+}
+
+impl<U> Default for MsgHandlers<U> {
+    fn default() -> Self {
+        MsgHandlers { handlers: HashMap::new() }
+    }
+}
+
+impl<U> MsgHandlers<U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle messages of type `typ` with `handler`. Fails if `typ` is one of the types portus
+    /// defines, or already has a handler.
+    pub fn register<F>(&mut self, typ: u8, handler: F) -> Result<()>
+    where
+        F: FnMut(RawMsg, Option<&mut U>) -> Result<()> + Send + 'static,
+    {
+        if typ < RESERVED_MSG_TYPES {
+            return Err(Error(format!("message type {} is reserved", typ)));
+        }
+
+        match self.handlers.entry(typ) {
+            Entry::Occupied(_) => Err(Error(format!("message type {} already has a handler", typ))),
+            Entry::Vacant(e) => {
+                e.insert(Box::new(handler));
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, typ: u8) -> Option<&mut MsgHandler<U>> {
+        self.handlers.get_mut(&typ)
+    }
+}
@@ -1,5 +1,6 @@
 use std;
 use std::sync::mpsc;
+use std::time::Duration;
 
 use super::Error;
 use super::Result;
@@ -32,6 +33,17 @@ impl<T> Socket<T> {
         Ok(())
     }
     
+    fn __recv_timeout(&self, msg: &mut [u8], timeout: Duration) -> Result<usize> {
+        let r = self.recv.as_ref().ok_or_else(|| Error(String::from("Receive channel side missing")))?;
+        let buf = match r.recv_timeout(timeout) {
+            Ok(buf) => buf,
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok(0),
+            Err(e) => return Err(Error::from(e)),
+        };
+        msg[..buf.len()].copy_from_slice(&buf);
+        Ok(buf.len())
+    }
+
     fn __close(&mut self) -> Result<()> {
         self.send.take();
         self.recv.take();
@@ -56,6 +68,10 @@ impl super::Ipc for Socket<Blocking> {
         Ok(buf.len())
     }
 
+    fn recv_timeout(&self, msg: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.__recv_timeout(msg, timeout)
+    }
+
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
@@ -78,6 +94,10 @@ impl super::Ipc for Socket<Nonblocking> {
         msg[..buf.len()].copy_from_slice(&buf);
         Ok(buf.len())
     }
+
+    fn recv_timeout(&self, msg: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.__recv_timeout(msg, timeout)
+    }
     
     fn close(&mut self) -> Result<()> {
         self.__close()
@@ -7,6 +7,7 @@ use std::fs::File;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use super::Error;
 use super::Result;
@@ -54,6 +55,14 @@ impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
         Ok(len)
     }
 
+    fn recv_timeout(&self, msg: &mut [u8], timeout: Duration) -> Result<usize> {
+        if !super::poll_readable(self.fd.as_raw_fd(), timeout)? {
+            return Ok(0);
+        }
+
+        nix::unistd::read(self.fd.as_raw_fd(), msg).map_err(Error::from)
+    }
+
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
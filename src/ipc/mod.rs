@@ -1,8 +1,15 @@
 //! A library wrapping various IPC mechanisms with a datagram-oriented
 //! messaging layer. This is how CCP communicates with the datapath.
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::{Rc, Weak};
 use std::sync::{Arc, atomic};
+extern crate nix;
+
+use std::cmp;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 use super::Error;
 use super::Result;
@@ -26,8 +33,18 @@ pub trait Ipc: 'static + Send {
     fn send(&self, msg: &[u8]) -> Result<()>;
     /// Blocking listen. Return value is how many bytes were read. Should not allocate.
     fn recv(&self, msg: &mut [u8]) -> Result<usize>;
+    /// Listen for at most about `timeout`, whether or not the socket is blocking. Return value is
+    /// how many bytes were read, 0 if nothing arrived in time. `BackendSender::recv_msg`, and so
+    /// `DatapathTrait::read_fields`, needs this; the default implementation fails.
+    fn recv_timeout(&self, _msg: &mut [u8], _timeout: Duration) -> Result<usize> {
+        Err(Error(format!("{} sockets cannot receive with a timeout", Self::name())))
+    }
     /// Close the underlying sockets
     fn close(&mut self) -> Result<()>;
+    /// The length of the longest message this mechanism carries, at most `serialize::MAX_MSG_LEN`
+    fn max_msg_len() -> u32 {
+        ::serialize::MAX_MSG_LEN
+    }
 }
 
 /// Marker type specifying that the IPC socket should make blocking calls to the underlying socket
@@ -47,8 +64,12 @@ impl<T: Ipc> BackendBuilder<T> {
     }
 }
 
+/// Messages the `Backend` has received but not yet yielded, in order. Each entry holds one or
+/// more messages.
+type Deferred = RefCell<VecDeque<Vec<u8>>>;
+
 /// A send-only handle to the underlying IPC socket.
-pub struct BackendSender<T: Ipc>(Weak<T>);
+pub struct BackendSender<T: Ipc>(Weak<T>, Weak<Deferred>);
 
 impl<T: Ipc> BackendSender<T> {
     /// Blocking send.
@@ -56,11 +77,91 @@ impl<T: Ipc> BackendSender<T> {
         let s = Weak::upgrade(&self.0).ok_or_else(|| Error(String::from("Send on closed IPC socket!")))?;
         s.send(msg).map_err(Error::from)
     }
+
+    /// Wait until `deadline` for a message `wanted` accepts, and return its bytes. Messages the
+    /// `Backend` has received but not yet yielded are searched first. Other messages received in
+    /// the meantime are left for the `Backend` to yield. Fails if the socket does not implement
+    /// `Ipc::recv_timeout`.
+    pub fn recv_msg<F: Fn(&Msg) -> bool>(&self, deadline: Instant, wanted: F) -> Result<Vec<u8>> {
+        let s = Weak::upgrade(&self.0).ok_or_else(|| Error(String::from("Receive on closed IPC socket!")))?;
+        let deferred = Weak::upgrade(&self.1).ok_or_else(|| Error(String::from("Receive on closed IPC socket!")))?;
+        {
+            let mut deferred = deferred.borrow_mut();
+            for i in 0..deferred.len() {
+                if let Some(msg) = take_wanted(&mut deferred[i], &wanted) {
+                    if deferred[i].is_empty() {
+                        deferred.remove(i);
+                    }
+
+                    return Ok(msg);
+                }
+            }
+        }
+
+        let mut buf = vec![0u8; ::serialize::MAX_MSG_LEN as usize];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let read = s.recv_timeout(&mut buf, deadline - now)?;
+
+            let (mut found, mut off) = (None, 0);
+            while off < read {
+                let (is_wanted, len) = match Msg::from_buf(&buf[off..read]) {
+                    Ok((m, len)) if len > 0 => (found.is_none() && wanted(&m), len),
+                    _ => break,
+                };
+
+                let msg = buf[off..off + len].to_vec();
+                off += len;
+                if is_wanted {
+                    found = Some(msg);
+                } else {
+                    deferred.borrow_mut().push_back(msg);
+                }
+            }
+
+            if let Some(msg) = found {
+                return Ok(msg);
+            }
+        }
+
+        Err(Error(String::from("Timed out waiting for a message from the datapath")))
+    }
+}
+
+/// Wait at most `timeout` for `fd` to become readable.
+fn poll_readable(fd: RawFd, timeout: Duration) -> Result<bool> {
+    let ms = timeout.as_secs().saturating_mul(1000) + u64::from(timeout.subsec_nanos() + 999_999) / 1_000_000;
+    let ms = cmp::min(cmp::max(ms, 1), i32::MAX as u64) as i32;
+    let pollfd = nix::poll::PollFd::new(fd, nix::poll::POLLIN);
+    Ok(nix::poll::poll(&mut [pollfd], ms)? > 0)
+}
+
+/// Remove the first message `wanted` accepts from `msgs`, which holds one or more messages.
+fn take_wanted<F: Fn(&Msg) -> bool>(msgs: &mut Vec<u8>, wanted: &F) -> Option<Vec<u8>> {
+    let mut off = 0;
+    while off < msgs.len() {
+        let (is_wanted, len) = match Msg::from_buf(&msgs[off..]) {
+            Ok((m, len)) if len > 0 => (wanted(&m), len),
+            _ => return None,
+        };
+
+        if is_wanted {
+            return Some(msgs.drain(off..off + len).collect());
+        }
+
+        off += len;
+    }
+
+    None
 }
 
 impl<T: Ipc> Clone for BackendSender<T> {
     fn clone(&self) -> Self {
-        BackendSender(self.0.clone())
+        BackendSender(self.0.clone(), self.1.clone())
     }
 }
 
@@ -69,10 +170,11 @@ impl<T: Ipc> Clone for BackendSender<T> {
 /// The atomic bool is a way to stop iterating.
 pub struct Backend<'a, T: Ipc> {
     sock: Rc<T>,
+    deferred: Rc<Deferred>,
+    // the deferred entry `next()` is currently yielding from, which may not fit in `receive_buf`
+    pending: Vec<u8>,
     continue_listening: Arc<atomic::AtomicBool>,
     receive_buf: &'a mut [u8],
-    tot_read: usize,
-    read_until: usize,
 }
 
 use ::serialize::Msg;
@@ -84,15 +186,15 @@ impl<'a, T: Ipc> Backend<'a, T> {
     ) -> Backend<'a, T> {
         Backend{
             sock: Rc::new(sock),
+            deferred: Rc::new(RefCell::new(VecDeque::new())),
+            pending: Vec::new(),
             continue_listening,
             receive_buf,
-            tot_read: 0,
-            read_until: 0,
         }
     }
 
     pub fn sender(&self) -> BackendSender<T> {
-        BackendSender(Rc::downgrade(&self.sock))
+        BackendSender(Rc::downgrade(&self.sock), Rc::downgrade(&self.deferred))
     }
 
     /// Return a copy of the flag variable that indicates that the
@@ -105,19 +207,25 @@ impl<'a, T: Ipc> Backend<'a, T> {
     // This is similar to `impl Iterator`, but the returned value is tied to the lifetime
     // of `self`, so we cannot implement that trait.
     pub fn next<'b>(&'b mut self) -> Option<Msg<'b>> {
-        // if we have leftover buffer from the last read, parse another message.
-        if self.read_until < self.tot_read {
-            let (msg, consumed) = Msg::from_buf(&self.receive_buf[self.read_until..]).ok()?;
-            self.read_until += consumed;
-            return Some(msg);
-        } else {
-            self.tot_read = self.get_next_read().ok()?;
-            self.read_until = 0;
-            let (msg, consumed) = Msg::from_buf(&self.receive_buf[self.read_until..self.tot_read]).ok()?;
-            self.read_until += consumed;
-
-            return Some(msg);
+        let deferred = self.deferred.borrow_mut().pop_front();
+        let buf: &[u8] = match deferred {
+            Some(msgs) => {
+                self.pending = msgs;
+                &self.pending
+            }
+            None => {
+                let read = self.get_next_read().ok()?;
+                &self.receive_buf[..read]
+            }
+        };
+
+        let (msg, consumed) = Msg::from_buf(buf).ok()?;
+        // leave the rest of the read where a `BackendSender` waiting for a message can find it
+        if consumed > 0 && consumed < buf.len() {
+            self.deferred.borrow_mut().push_front(buf[consumed..].to_vec());
         }
+
+        Some(msg)
     }
     
     // calls IPC repeatedly to read one or more messages.
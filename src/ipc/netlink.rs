@@ -1,4 +1,5 @@
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use super::Error;
 use super::Result;
@@ -84,8 +85,16 @@ impl<T> Socket<T> {
         Ok(())
     }
 
+    fn __recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        if !super::poll_readable(self.0, timeout)? {
+            return Ok(0);
+        }
+
+        self.__recv(buf, nix::sys::socket::MSG_DONTWAIT)
+    }
+
     fn __recv(&self, buf: &mut [u8], flags: nix::sys::socket::MsgFlags) -> Result<usize> {
-        let mut nl_buf = [0u8; 1024];
+        let mut nl_buf = [0u8; ::serialize::MAX_MSG_LEN as usize];
         let end = socket::recvmsg::<()>(
             self.0,
             &[nix::sys::uio::IoVec::from_mut_slice(&mut nl_buf[..])],
@@ -93,6 +102,10 @@ impl<T> Socket<T> {
             flags,
         ).map(|r| r.bytes)
             .map_err(Error::from)?;
+        if end < NLMSG_HDRSIZE || end - NLMSG_HDRSIZE > buf.len() {
+            return Err(Error(format!("Cannot receive netlink message of {} bytes", end)));
+        }
+
         buf[..(end - NLMSG_HDRSIZE)].copy_from_slice(&nl_buf[NLMSG_HDRSIZE..end]);
         Ok(end - NLMSG_HDRSIZE)
     }
@@ -152,6 +165,10 @@ impl super::Ipc for Socket<Blocking> {
         self.__recv(buf, nix::sys::socket::MsgFlags::empty())
     }
 
+    fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.__recv_timeout(buf, timeout)
+    }
+
     fn send(&self, buf: &[u8]) -> Result<()> {
         self.__send(buf)
     }
@@ -159,6 +176,11 @@ impl super::Ipc for Socket<Blocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn max_msg_len() -> u32 {
+        // the netlink header and the message fit in one receive buffer
+        ::serialize::MAX_MSG_LEN - NLMSG_HDRSIZE as u32
+    }
 }
 
 use super::Nonblocking;
@@ -171,6 +193,10 @@ impl super::Ipc for Socket<Nonblocking> {
         self.__recv(buf, nix::sys::socket::MSG_DONTWAIT)
     }
 
+    fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.__recv_timeout(buf, timeout)
+    }
+
     fn send(&self, buf: &[u8]) -> Result<()> {
         self.__send(buf)
     }
@@ -178,4 +204,9 @@ impl super::Ipc for Socket<Nonblocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn max_msg_len() -> u32 {
+        // the netlink header and the message fit in one receive buffer
+        ::serialize::MAX_MSG_LEN - NLMSG_HDRSIZE as u32
+    }
 }
@@ -84,6 +84,50 @@ fn test_unix() {
     c2.join().expect("join sender thread");
 }
 
+#[test]
+fn test_unix_recv_timeout() {
+    use std::sync::atomic;
+    use std::time::{Duration, Instant};
+    use super::Blocking;
+
+    // a blocking socket which never receives anything
+    let sk = super::unix::Socket::<Blocking>::new("timeout", "nobody").expect("init socket");
+    let mut buf = [0u8; 1024];
+    let b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let start = Instant::now();
+    assert!(b.sender().recv_msg(start + Duration::from_millis(50), |_| true).is_err());
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn test_deferred_larger_than_receive_buf() {
+    use std::sync::{atomic, mpsc};
+    use std::time::{Duration, Instant};
+    use ::test_helper::TestMsg;
+    use super::Blocking;
+    use ::serialize;
+    use ::serialize::Msg;
+
+    let (s1, r1) = mpsc::channel();
+    let (s2, r2) = mpsc::channel();
+    let sk1 = super::chan::Socket::<Blocking>::new(s1, r2).expect("init socket");
+    let sk2 = super::chan::Socket::<Blocking>::new(s2, r1).expect("init socket");
+
+    let big = "x".repeat(200);
+    let test_msg_buf = serialize::serialize(&TestMsg(big.clone())).expect("serialize test msg");
+    sk2.send(&test_msg_buf[..]).expect("send message");
+
+    let mut buf = [0u8; 64];
+    let mut b = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    // a sender waiting for some other message defers this one, which does not fit in `buf`
+    let deadline = Instant::now() + Duration::from_millis(50);
+    assert!(b.sender().recv_msg(deadline, |_| false).is_err());
+    match b.next().expect("receive message") {
+        Msg::Other(r) => assert_eq!(r.get_bytes().unwrap(), big.as_bytes()),
+        _ => unreachable!(),
+    }
+}
+
 #[test]
 fn test_chan() {
     use std::thread;
@@ -1,5 +1,7 @@
 use std;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
 
 use super::Error;
 use super::Result;
@@ -61,6 +63,14 @@ impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
     fn recv(&self, msg: &mut [u8]) -> Result<usize> {
         self.sk.recv(msg).map_err(Error::from)
     }
+
+    fn recv_timeout(&self, msg: &mut [u8], timeout: Duration) -> Result<usize> {
+        if !super::poll_readable(self.sk.as_raw_fd(), timeout)? {
+            return Ok(0);
+        }
+
+        self.recv(msg)
+    }
     
     fn close(&mut self) -> Result<()> {
         use std::net::Shutdown;
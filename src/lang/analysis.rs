@@ -0,0 +1,246 @@
+//! Static analysis of the work a compiled program does on each ack.
+//!
+//! The datapath evaluates the flag of each event in order, runs the body of each event whose flag
+//! is true, and stops after a body that does not `(fallthrough)`. `analyze()` follows these chains
+//! to find how many instructions an ack runs in the worst case, and, assuming every condition
+//! which is not a constant holds for half of the acks (`ASSUMED_PROBABILITY`), on average. The
+//! same assumption gives the fraction of acks which send a report.
+
+use std::fmt;
+
+use super::{Error, Result};
+use super::ast::Op;
+use super::datapath::{Bin, Event, Instr, Reg, Scope};
+use super::interp::{imm, RegCounts, EVENT_FLAG_REG, SHOULD_CONTINUE_REG, SHOULD_REPORT_REG};
+use super::serialize::{NUM_CONTROL_REGS, NUM_LOCAL_REGS, NUM_REPORT_REGS, NUM_TMP_REGS};
+
+/// The probability with which a condition that is not a constant is assumed to hold.
+pub const ASSUMED_PROBABILITY: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Whether something happens, as far as the analysis can tell.
+pub enum Likelihood {
+    Never,
+    Maybe,
+    Always,
+}
+
+impl Likelihood {
+    fn probability(self) -> f64 {
+        match self {
+            Likelihood::Never => 0.0,
+            Likelihood::Maybe => ASSUMED_PROBABILITY,
+            Likelihood::Always => 1.0,
+        }
+    }
+
+    /// Whether the implicit register `reg` is set by `instrs`, which start by clearing it.
+    fn of_flag(instrs: &[Instr], reg: usize) -> Self {
+        instrs.iter()
+            .filter(|i| match i.res {
+                Reg::Implicit(r, _) => r as usize == reg,
+                _ => false,
+            })
+            .fold(Likelihood::Never, |l, i| match (i.op, imm(&i.right)) {
+                (Op::Bind, Some(0)) => Likelihood::Never,
+                (Op::Bind, Some(_)) => Likelihood::Always,
+                (Op::If, _) | (Op::NotIf, _) if l == Likelihood::Always => l,
+                _ => Likelihood::Maybe,
+            })
+    }
+}
+
+impl fmt::Display for Likelihood {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Likelihood::Never => "never",
+            Likelihood::Maybe => "sometimes",
+            Likelihood::Always => "always",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The cost and control flow of a single event.
+pub struct EventCost {
+    pub flag_instrs: u32,
+    pub body_instrs: u32,
+    /// Whether the event's flag is true.
+    pub runs: Likelihood,
+    /// Whether the event's body continues to the next event.
+    pub falls_through: Likelihood,
+    /// Whether the event's body sends a report.
+    pub reports: Likelihood,
+}
+
+impl EventCost {
+    fn new(ev: &Event, instrs: &[Instr]) -> Self {
+        let flag = &instrs[ev.flag_idx as usize..(ev.flag_idx + ev.num_flag_instrs) as usize];
+        let body = &instrs[ev.body_idx as usize..(ev.body_idx + ev.num_body_instrs) as usize];
+        EventCost {
+            flag_instrs: ev.num_flag_instrs,
+            body_instrs: ev.num_body_instrs,
+            runs: Likelihood::of_flag(flag, EVENT_FLAG_REG),
+            falls_through: Likelihood::of_flag(body, SHOULD_CONTINUE_REG),
+            reports: Likelihood::of_flag(body, SHOULD_REPORT_REG),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// The number of registers of each kind a program uses.
+pub struct RegisterUsage {
+    pub report: usize,
+    pub control: usize,
+    pub local: usize,
+    pub tmp: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The results of `analyze()`.
+pub struct Analysis {
+    pub events: Vec<EventCost>,
+    /// The most instructions an ack can run.
+    pub worst_case_instrs: u32,
+    /// The expected number of instructions an ack runs.
+    pub typical_instrs: f64,
+    /// The expected fraction of acks which send a report.
+    pub report_probability: f64,
+    pub registers: RegisterUsage,
+}
+
+/// Analyze the program `bin`, compiled with `scope`.
+pub fn analyze(bin: &Bin, scope: &Scope) -> Analysis {
+    let events: Vec<EventCost> = bin.events.iter().map(|ev| EventCost::new(ev, &bin.instrs)).collect();
+
+    // work backwards from the last event: the cost of the rest of the events, given that the
+    // datapath evaluates the current one.
+    let (mut worst, mut typical, mut report) = (0u32, 0f64, 0f64);
+    for e in events.iter().rev() {
+        let (p_run, p_cont, p_report) = (e.runs.probability(), e.falls_through.probability(), e.reports.probability());
+        let worst_if_run = e.body_instrs + if e.falls_through == Likelihood::Never { 0 } else { worst };
+        let worst_if_not = if e.runs == Likelihood::Always { 0 } else { worst };
+        worst = e.flag_instrs + match e.runs {
+            Likelihood::Never => worst_if_not,
+            _ => ::std::cmp::max(worst_if_run, worst_if_not),
+        };
+
+        typical = f64::from(e.flag_instrs)
+            + p_run * (f64::from(e.body_instrs) + p_cont * typical)
+            + (1.0 - p_run) * typical;
+        report = p_run * (1.0 - (1.0 - p_report) * (1.0 - p_cont * report))
+            + (1.0 - p_run) * report;
+    }
+
+    let counts = RegCounts::of(bin, scope);
+    Analysis {
+        events,
+        worst_case_instrs: worst,
+        typical_instrs: typical,
+        report_probability: report,
+        registers: RegisterUsage {
+            report: counts.report,
+            control: counts.control,
+            local: counts.local,
+            tmp: counts.tmp,
+        },
+    }
+}
+
+impl Analysis {
+    /// Fail if an ack may run more than `max_instrs` instructions.
+    pub fn check_budget(&self, max_instrs: u32) -> Result<()> {
+        if self.worst_case_instrs > max_instrs {
+            return Err(Error::from(format!(
+                "program runs up to {} instructions per ack, more than the budget of {}",
+                self.worst_case_instrs, max_instrs,
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "instructions per ack: {} worst case, {:.1} typical",
+            self.worst_case_instrs, self.typical_instrs,
+        )?;
+        writeln!(f, "reports: {:.1}% of acks", 100.0 * self.report_probability)?;
+        writeln!(
+            f,
+            "registers: {}/{} report, {}/{} control, {}/{} local, {}/{} tmp",
+            self.registers.report, NUM_REPORT_REGS,
+            self.registers.control, NUM_CONTROL_REGS,
+            self.registers.local, NUM_LOCAL_REGS,
+            self.registers.tmp, NUM_TMP_REGS,
+        )?;
+        for (i, e) in self.events.iter().enumerate() {
+            writeln!(
+                f,
+                "event {}: {} flag + {} body instructions, {} runs, {} falls through, {} reports",
+                i, e.flag_instrs, e.body_instrs, e.runs, e.falls_through, e.reports,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang;
+    use super::Likelihood::*;
+
+    #[test]
+    fn fallthrough_chains() {
+        let foo = b"
+        (def (Report (volatile acked 0)))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (fallthrough)
+        )
+        (when (> Micros 1000)
+            (:= Micros 0)
+            (report)
+        )
+        (when (> Ack.lost_pkts_sample 0)
+            (:= Cwnd (/ Cwnd 2))
+            (cond ((> Cwnd 10000) (fallthrough)))
+        )
+        (when false
+            (report)
+        )
+        ";
+        let (bin, sc) = lang::compile(foo, &[]).unwrap();
+        let a = super::analyze(&bin, &sc);
+        let flow: Vec<_> = a.events.iter().map(|e| (e.runs, e.falls_through, e.reports)).collect();
+        assert_eq!(flow, vec![
+            (Always, Always, Never),
+            (Maybe, Never, Always),
+            (Maybe, Maybe, Never),
+            (Never, Never, Always),
+        ]);
+
+        // worst case: the first event, then the second is false and the third falls through
+        let e = &a.events;
+        let worst = e[0].flag_instrs + e[0].body_instrs
+            + e[1].flag_instrs
+            + e[2].flag_instrs + e[2].body_instrs
+            + e[3].flag_instrs;
+        assert_eq!(a.worst_case_instrs, worst);
+        assert!(a.typical_instrs < f64::from(worst));
+        assert_eq!(a.report_probability, 0.5);
+        assert_eq!(a.registers.report, 1);
+
+        assert!(a.check_budget(worst).is_ok());
+        assert!(a.check_budget(worst - 1).is_err());
+
+        // compiling for a datapath with a budget enforces it
+        let caps = |budget| lang::Capabilities::default().with_instruction_budget(budget);
+        assert!(lang::compile_with_capabilities(foo, &[], caps(worst)).is_ok());
+        assert!(lang::compile_with_capabilities(foo, &[], caps(worst - 1)).is_err());
+    }
+}
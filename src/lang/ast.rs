@@ -1,29 +1,63 @@
 use nom;
 use super::{Error, Result};
 
+/// Fixed-point values have this many fractional bits: a value `x` is stored as the integer
+/// `x * 2^FIXED_FRAC_BITS`, rounded to the nearest integer.
+pub const FIXED_FRAC_BITS: u32 = 16;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Prim {
     Bool(bool),
+    /// A fixed-point literal such as `0.7`, scaled by `2^FIXED_FRAC_BITS`.
+    Fixed(u64),
+    /// A signed literal such as `-5` or `+5`.
+    Int(i64),
     Name(String),
     Num(u64),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Op {
+    AbsDiff, // (absdiff a b) return |a - b|
     Add, // (add a b) return a+b
     And, // (and a b) return a && b
     Bind, // (bind a b) assign variable a to value b
+    BitAnd, // (& a b) return a & b (bitwise)
+    BitOr, // (| a b) return a | b (bitwise)
+    CheckedSub, // (checked_sub a b) return a - b, failing on overflow
     Div, // (div a b) return a/b (integer division)
     Equiv, // (eq a b) return a == b
     Gt, // (> a b) return a > b
+    Gte, // (>= a b) return a >= b
     Lt, // (< a b) return a < b
+    Lte, // (<= a b) return a <= b
     Max, // (max a b) return max(a,b)
     MaxWrap, // (max a b) return max(a,b) with integer wraparound
     Min, // (min a b) return min(a,b)
+    Mod, // (% a b) return a mod b
     Mul, // (mul a b) return a * b
+    Neq, // (!= a b) return a != b
     Or,  // (or a b) return a || b
+    SatSub, // (sat_sub a b) return a - b, clamped to the range of the type
+    Shl, // (<< a b) return a << b
+    Shr, // (>> a b) return a >> b
     Sub, // (sub a b) return a - b
 
+    // SPECIAL: unary, the right operand is always Expr::None
+    Not, // (! a) return !a
+    ToInt, // (int a) return a as an Int
+    ToNum, // (num a) return a as a Num, or 0 if a is negative
+
+    // SPECIAL: cannot be called by user, generated for Int operands
+    CheckedSubInt,
+    GtInt,
+    GteInt,
+    LtInt,
+    LteInt,
+    MaxInt,
+    MinInt,
+    SatSubInt,
+
     // SPECIAL: cannot be called by user, only generated
     Def, // top of prog: (def (Foo 0) (Bar 100000000) ...)
 
@@ -34,6 +68,9 @@ pub enum Op {
     // SPECIAL: reads return register
     Ewma, // (ewma a b) ret * a/10 + b * (10-a)/10.
 
+    // SPECIAL: a statement, lowered to arithmetic on the buckets of histogram a
+    Observe, // (observe a b) count b in the bucket of histogram a it falls in
+
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -47,29 +84,56 @@ pub enum Expr {
     Atom(Prim),
     Cmd(Command),
     Sexp(Op, Box<Expr>, Box<Expr>),
+    Ite(Box<Expr>, Box<Expr>, Box<Expr>), // (if c a b) return a if c is true, otherwise b
+    Cond(Vec<(Expr, Vec<Expr>)>), // (cond (c1 body...) (c2 body...) (else body...)) evaluate the body of the first true condition
+    Let(String, Box<Expr>, Vec<Expr>), // (let (x e) body...) evaluate body with local x bound to e
+    Call(String, Vec<Expr>), // (name args...) use of a macro, expanded before compilation
     None,
 }
 
 use std::str;
+use nom::multispace;
+
+// An operator must be followed by whitespace or a subexpression, so that e.g. `(maxrtt 1 2)` is
+// not read as `(max rtt ...)` and `(>= a b)` is not read as `(> = ...)`.
+named_complete!(
+    delimiter<()>,
+    map!(peek!(alt!(multispace | tag!("("))), |_| ())
+);
+
 named_complete!(
     op<Result<Op>>,
     alt!(
-        alt!(tag!("+") | tag!("add"))   => { |_| Ok(Op::Add) }     |
-        alt!(tag!("&&") | tag!("and"))  => { |_| Ok(Op::And) }     |
-        alt!(tag!(":=") | tag!("bind")) => { |_| Ok(Op::Bind) }    |
-        tag!("if")                      => { |_| Ok(Op::If) }      |
-        alt!(tag!("/") | tag!("div"))   => { |_| Ok(Op::Div) }     |
-        alt!(tag!("==") | tag!("eq"))   => { |_| Ok(Op::Equiv) }   |
-        tag!("ewma")                    => { |_| Ok(Op::Ewma) }    |
-        alt!(tag!(">") | tag!("gt"))    => { |_| Ok(Op::Gt) }      |
-        alt!(tag!("<") | tag!("lt"))    => { |_| Ok(Op::Lt) }      |
-        tag!("wrapped_max")             => { |_| Ok(Op::MaxWrap) } |
-        tag!("max")                     => { |_| Ok(Op::Max) }     |
-        tag!("min")                     => { |_| Ok(Op::Min) }     |
-        alt!(tag!("*") | tag!("mul"))   => { |_| Ok(Op::Mul) }     |
-        alt!(tag!("||") | tag!("or"))   => { |_| Ok(Op::Or) }      |
-        tag!("!if")                     => { |_| Ok(Op::NotIf) }   |
-        alt!(tag!("-") | tag!("sub"))   => { |_| Ok(Op::Sub) }     |
+        terminated!(alt!(
+            alt!(tag!("+") | tag!("add"))      => { |_| Ok(Op::Add) }     |
+            alt!(tag!("&&") | tag!("and"))     => { |_| Ok(Op::And) }     |
+            alt!(tag!("&") | tag!("bitand"))   => { |_| Ok(Op::BitAnd) }  |
+            tag!("checked_sub")                => { |_| Ok(Op::CheckedSub) } |
+            tag!("absdiff")                    => { |_| Ok(Op::AbsDiff) } |
+            alt!(tag!(":=") | tag!("bind"))    => { |_| Ok(Op::Bind) }    |
+            alt!(tag!("||") | tag!("or"))      => { |_| Ok(Op::Or) }      |
+            alt!(tag!("|") | tag!("bitor"))    => { |_| Ok(Op::BitOr) }   |
+            tag!("if")                         => { |_| Ok(Op::If) }      |
+            alt!(tag!("/") | tag!("div"))      => { |_| Ok(Op::Div) }     |
+            alt!(tag!("==") | tag!("eq"))      => { |_| Ok(Op::Equiv) }   |
+            tag!("ewma")                       => { |_| Ok(Op::Ewma) }    |
+            alt!(tag!(">=") | tag!("gte"))     => { |_| Ok(Op::Gte) }     |
+            alt!(tag!(">>") | tag!("shr"))     => { |_| Ok(Op::Shr) }     |
+            alt!(tag!(">") | tag!("gt"))       => { |_| Ok(Op::Gt) }      |
+            alt!(tag!("<=") | tag!("lte"))     => { |_| Ok(Op::Lte) }     |
+            alt!(tag!("<<") | tag!("shl"))     => { |_| Ok(Op::Shl) }     |
+            alt!(tag!("<") | tag!("lt"))       => { |_| Ok(Op::Lt) }      |
+            tag!("wrapped_max")                => { |_| Ok(Op::MaxWrap) } |
+            tag!("max")                        => { |_| Ok(Op::Max) }     |
+            tag!("min")                        => { |_| Ok(Op::Min) }     |
+            tag!("observe")                    => { |_| Ok(Op::Observe) } |
+            alt!(tag!("%") | tag!("mod"))      => { |_| Ok(Op::Mod) }     |
+            alt!(tag!("*") | tag!("mul"))      => { |_| Ok(Op::Mul) }     |
+            alt!(tag!("!=") | tag!("neq"))     => { |_| Ok(Op::Neq) }     |
+            tag!("!if")                        => { |_| Ok(Op::NotIf) }   |
+            tag!("sat_sub")                    => { |_| Ok(Op::SatSub) }  |
+            alt!(tag!("-") | tag!("sub"))      => { |_| Ok(Op::Sub) }
+        ), delimiter) |
         atom => { |f: Result<Expr>| Err(Error::from(format!("unexpected token {:?}", f))) }
     )
 );
@@ -89,7 +153,174 @@ fn check_expr(op: Op, left: Expr, right: Expr) -> Result<Expr> {
     }
 }
 
-use nom::multispace;
+named_complete!(
+    unary_op<Op>,
+    terminated!(alt!(
+        alt!(tag!("!") | tag!("not")) => { |_| Op::Not }   |
+        tag!("int")                   => { |_| Op::ToInt } |
+        tag!("num")                   => { |_| Op::ToNum }
+    ), delimiter)
+);
+
+named_complete!(
+    unary_sexp<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            o: unary_op >>
+            arg: expr >>
+            (arg.and_then(|a| match a {
+                Expr::Sexp(Op::If, _, _) | Expr::Sexp(Op::NotIf, _, _) => Err(Error::from(
+                    format!("Conditional cannot be bound to temp register: {:?}", a),
+                )),
+                _ => Ok(Expr::Sexp(o, Box::new(a), Box::new(Expr::None))),
+            }))
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    if_kw<()>,
+    terminated!(map!(tag!("if"), |_| ()), delimiter)
+);
+
+named_complete!(
+    ite<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            if_kw >>
+            c: expr >>
+            a: expr >>
+            b: expr >>
+            (c.and_then(
+                |c| a.and_then(
+                |a| b.and_then(
+                |b| Ok(Expr::Ite(Box::new(c), Box::new(a), Box::new(b)))
+            ))))
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    else_kw<Result<Expr>>,
+    terminated!(map!(tag!("else"), |_| Ok(Expr::Atom(Prim::Bool(true)))), delimiter)
+);
+
+// (condition body...) or (else body...)
+named_complete!(
+    cond_clause<Result<(Expr, Vec<Expr>)>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            c: alt!(else_kw | expr) >>
+            body: exprs >>
+            (c.and_then(|c| {
+                let body: Result<Vec<Expr>> = body.into_iter().filter(|e| match e {
+                    Ok(Expr::None) => false,
+                    _ => true,
+                }).collect();
+                Ok((c, body?))
+            }))
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    cond_kw<()>,
+    terminated!(map!(tag!("cond"), |_| ()), delimiter)
+);
+
+named_complete!(
+    cond<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            cond_kw >>
+            clauses: many1!(cond_clause) >>
+            (clauses.into_iter().collect::<Result<Vec<_>>>().map(Expr::Cond))
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    let_kw<()>,
+    terminated!(map!(tag!("let"), |_| ()), delimiter)
+);
+
+named_complete!(
+    let_binding<(String, Result<Expr>)>,
+    ws!(delimited!(
+        tag!("("),
+        pair!(name, expr),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    let_expr<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            let_kw >>
+            binding: let_binding >>
+            body: exprs >>
+            ({
+                let (var, val) = binding;
+                val.and_then(|val| {
+                    let body: Result<Vec<Expr>> = body.into_iter().filter(|e| match e {
+                        Ok(Expr::None) => false,
+                        _ => true,
+                    }).collect();
+                    Ok(Expr::Let(var, Box::new(val), body?))
+                })
+            })
+        ),
+        tag!(")")
+    ))
+);
+
+/// Words which begin built-in forms, and so cannot name a macro.
+const RESERVED: &[&str] = &[
+    "absdiff", "add", "and", "bind", "bitand", "bitor", "checked_sub", "cond", "def", "defconst",
+    "defmacro", "div", "else", "eq", "ewma", "fallthrough", "false", "gt", "gte", "hist", "if",
+    "import", "include", "int", "let", "lt", "lte", "max", "min", "mod", "mul", "neq", "not",
+    "num", "observe", "or", "otherwise", "report", "sat_sub", "shl", "shr", "sub", "true", "when",
+    "wrapped_max",
+];
+
+named_complete!(
+    macro_name<String>,
+    map_res!(name, |n: String| if RESERVED.contains(&n.as_str()) {
+        Err(Error::from(format!("{:?} is reserved", n)))
+    } else {
+        Ok(n)
+    })
+);
+
+named_complete!(
+    call<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            n: macro_name >>
+            args: many0!(expr) >>
+            ({
+                let args: Result<Vec<Expr>> = args.into_iter().filter(|e| match e {
+                    Ok(Expr::None) => false,
+                    _ => true,
+                }).collect();
+                args.map(|a| Expr::Call(n, a))
+            })
+        ),
+        tag!(")")
+    ))
+);
+
 named_complete!(
     sexp<Result<Expr>>,
     ws!(delimited!(
@@ -124,6 +355,36 @@ named_complete!(
     )
 );
 
+// a literal with a sign, such as -5 or +5, as an Int
+named_complete!(
+    pub int<i64>,
+    map_res!(
+        recognize!(tuple!(alt!(tag!("-") | tag!("+")), digit)),
+        |d: CompleteByteSlice| -> Result<i64> {
+            let st = str::from_utf8(d.0)?;
+            i64::from_str(st).map_err(|_| Error::from(format!("Int literal too big: {}", st)))
+        }
+    )
+);
+
+// a decimal literal, such as 0.7, as a fixed-point value
+named_complete!(
+    pub fixed<u64>,
+    map_res!(
+        recognize!(tuple!(digit, tag!("."), digit)),
+        |d: CompleteByteSlice| -> Result<u64> {
+            let st = str::from_utf8(d.0)?;
+            let point = st.find('.').unwrap();
+            let whole: u64 = FromStr::from_str(&st[..point])?;
+            let frac = &st[point + 1..];
+            let frac = f64::from_str(&format!("0.{}", frac)).map_err(|e| Error::from(format!("{}", e)))?;
+            whole.checked_mul(1 << FIXED_FRAC_BITS)
+                .and_then(|w| w.checked_add((frac * f64::from(1u32 << FIXED_FRAC_BITS)).round() as u64))
+                .ok_or_else(|| Error::from(format!("fixed-point literal too big: {}", st)))
+        }
+    )
+);
+
 use nom::is_alphanumeric;
 named_complete!(
     pub name<String>,
@@ -148,6 +409,8 @@ named_complete!(
             tag!("true")  => { |_| Ok(Prim::Bool(true)) }  |
             tag!("false") => { |_| Ok(Prim::Bool(false)) } |
             tag!("+infinity") => { |_| Ok(Prim::Num(u64::max_value())) } |
+            int => { |i: i64| Ok(Prim::Int(i)) } |
+            fixed => { |f: u64| Ok(Prim::Fixed(f)) } |
             num => { |n: u64| Ok(Prim::Num(n)) } |
             name => { |n: String| Ok(Prim::Name(n)) }
         ) >>
@@ -181,7 +444,7 @@ named_complete!(
 
 named_complete!(
     pub expr<Result<Expr>>,
-    alt_complete!(comment | sexp | command | atom)
+    alt_complete!(comment | unary_sexp | ite | cond | let_expr | call | sexp | command | atom)
 );
 
 named_complete!(
@@ -229,10 +492,140 @@ impl Expr {
                 left.desugar();
                 right.desugar();
             }
+            Expr::Ite(box ref mut c, box ref mut a, box ref mut b) => {
+                c.desugar();
+                a.desugar();
+                b.desugar();
+            }
+            Expr::Cond(ref mut clauses) => {
+                for &mut (ref mut c, ref mut body) in clauses.iter_mut() {
+                    c.desugar();
+                    body.iter_mut().for_each(|e| e.desugar());
+                }
+            }
+            Expr::Let(_, box ref mut val, ref mut body) => {
+                val.desugar();
+                body.iter_mut().for_each(|e| e.desugar());
+            }
+            Expr::Call(_, ref mut args) => args.iter_mut().for_each(|e| e.desugar()),
+        }
+    }
+
+    /// Replace each occurrence of the name `var` with `with`.
+    /// A nested `let` that binds `var` again shadows it in its body.
+    pub fn substitute(&mut self, var: &str, with: &Expr) {
+        self.substitute_all(&[(var, with)])
+    }
+
+    /// Replace each occurrence of each name in `subs` with its expression. The names are
+    /// replaced at once, so names inside a replacement are not replaced again.
+    /// A nested `let` that binds one of the names again shadows it in its body.
+    pub fn substitute_all(&mut self, subs: &[(&str, &Expr)]) {
+        let with = match *self {
+            Expr::Atom(Prim::Name(ref n)) => match subs.iter().find(|&&(var, _)| var == n) {
+                Some(&(_, with)) => with.clone(),
+                None => return,
+            },
+            Expr::Atom(_) | Expr::Cmd(_) | Expr::None => return,
+            Expr::Sexp(_, box ref mut left, box ref mut right) => {
+                left.substitute_all(subs);
+                right.substitute_all(subs);
+                return;
+            }
+            Expr::Ite(box ref mut c, box ref mut a, box ref mut b) => {
+                c.substitute_all(subs);
+                a.substitute_all(subs);
+                b.substitute_all(subs);
+                return;
+            }
+            Expr::Cond(ref mut clauses) => {
+                for &mut (ref mut c, ref mut body) in clauses.iter_mut() {
+                    c.substitute_all(subs);
+                    body.iter_mut().for_each(|e| e.substitute_all(subs));
+                }
+
+                return;
+            }
+            Expr::Let(ref bound, box ref mut val, ref mut body) => {
+                val.substitute_all(subs);
+                let unshadowed: Vec<(&str, &Expr)> = subs.iter()
+                    .filter(|&&(var, _)| var != bound)
+                    .cloned()
+                    .collect();
+                body.iter_mut().for_each(|e| e.substitute_all(&unshadowed));
+                return;
+            }
+            Expr::Call(_, ref mut args) => {
+                args.iter_mut().for_each(|e| e.substitute_all(subs));
+                return;
+            }
+        };
+
+        *self = with;
+    }
+}
+
+impl Expr {
+    /// Every name that appears in this expression.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names(&self, names: &mut Vec<String>) {
+        match *self {
+            Expr::Atom(Prim::Name(ref n)) => if !names.contains(n) {
+                names.push(n.clone());
+            },
+            Expr::Atom(_) | Expr::Cmd(_) | Expr::None => (),
+            Expr::Sexp(_, box ref left, box ref right) => {
+                left.collect_names(names);
+                right.collect_names(names);
+            }
+            Expr::Ite(box ref c, box ref a, box ref b) => {
+                c.collect_names(names);
+                a.collect_names(names);
+                b.collect_names(names);
+            }
+            Expr::Cond(ref clauses) => for &(ref c, ref body) in clauses {
+                c.collect_names(names);
+                body.iter().for_each(|e| e.collect_names(names));
+            },
+            Expr::Let(_, box ref val, ref body) => {
+                val.collect_names(names);
+                body.iter().for_each(|e| e.collect_names(names));
+            }
+            Expr::Call(_, ref args) => args.iter().for_each(|e| e.collect_names(names)),
         }
     }
 }
 
+/// Flatten the `let` statements in `body`: `(let (x e) stmts...)` becomes `(:= __letN_x e)`
+/// followed by `stmts`, where `x` is replaced by the hidden local `__letN_x`.
+/// `count` numbers the `let`s of a program so that their locals do not collide.
+pub fn flatten_lets(body: Vec<Expr>, count: &mut usize) -> Vec<Expr> {
+    let mut flat = vec![];
+    for e in body {
+        match e {
+            Expr::Let(var, val, stmts) => {
+                let local = Expr::Atom(Prim::Name(format!("__let{}_{}", *count, var)));
+                *count += 1;
+                flat.push(Expr::Sexp(Op::Bind, Box::new(local.clone()), val));
+                let mut stmts = stmts;
+                stmts.iter_mut().for_each(|s| s.substitute(&var, &local));
+                flat.extend(flatten_lets(stmts, count));
+            }
+            Expr::Cond(clauses) => flat.push(Expr::Cond(
+                clauses.into_iter().map(|(c, b)| (c, flatten_lets(b, count))).collect(),
+            )),
+            e => flat.push(e),
+        }
+    }
+
+    flat
+}
+
 #[cfg(test)]
 mod tests {
     use nom::types::CompleteByteSlice;
@@ -319,6 +712,36 @@ mod tests {
         );
     }
     
+    #[test]
+    fn atom_fixed() {
+        let foo = b"0.7 1.5 0.0";
+        let e = Expr::new(foo).unwrap();
+        assert_eq!(
+            e,
+            vec![
+                Expr::Atom(Prim::Fixed(45875)),
+                Expr::Atom(Prim::Fixed(3 << 15)),
+                Expr::Atom(Prim::Fixed(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn atom_int() {
+        let foo = b"-5 +5 -0 (- 5 3) (int 4)";
+        let e = Expr::new(foo).unwrap();
+        assert_eq!(
+            e,
+            vec![
+                Expr::Atom(Prim::Int(-5)),
+                Expr::Atom(Prim::Int(5)),
+                Expr::Atom(Prim::Int(0)),
+                Expr::Sexp(Op::Sub, Box::new(Expr::Atom(Prim::Num(5))), Box::new(Expr::Atom(Prim::Num(3)))),
+                Expr::Sexp(Op::ToInt, Box::new(Expr::Atom(Prim::Num(4))), Box::new(Expr::None)),
+            ]
+        );
+    }
+
     #[test]
     fn simple_exprs() {
         let foo = b"(+ 10 20)";
@@ -335,12 +758,14 @@ mod tests {
             ]
         );
 
+        // unknown operators are macro calls, which fail to expand
         let foo = b"(blah 10 20)";
         let er = Expr::new(foo);
-        match er {
-            Ok(e) => panic!("false ok: {:?}", e),
-            Err(_) => (),
-        }
+        assert_eq!(
+            er.unwrap(),
+            vec![Expr::Call(String::from("blah"), vec![Expr::Atom(Prim::Num(10)), Expr::Atom(Prim::Num(20))])],
+        );
+        assert!(::lang::compile(b"(def (foo 0)) (when true (:= foo (blah 10 20)))", &[]).is_err());
         
         let foo = b"(blah 10 20";
         let er = Expr::new(foo);
@@ -381,6 +806,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn extended_ops() {
+        let foo = b"(>= 10 20) (<< 1 2) (!= 3 (% 4 5)) (! true) (not (& 6 7))";
+        let er = Expr::new(foo);
+        let e = er.unwrap();
+        let num = |n| Box::new(Expr::Atom(Prim::Num(n)));
+        assert_eq!(
+            e,
+            vec![
+                Expr::Sexp(Op::Gte, num(10), num(20)),
+                Expr::Sexp(Op::Shl, num(1), num(2)),
+                Expr::Sexp(Op::Neq, num(3), Box::new(Expr::Sexp(Op::Mod, num(4), num(5)))),
+                Expr::Sexp(Op::Not, Box::new(Expr::Atom(Prim::Bool(true))), Box::new(Expr::None)),
+                Expr::Sexp(Op::Not, Box::new(Expr::Sexp(Op::BitAnd, num(6), num(7))), Box::new(Expr::None)),
+            ]
+        );
+
+        // operators must be followed by a delimiter
+        assert_eq!(
+            Expr::new(b"(maximum 10 20)").unwrap(),
+            vec![Expr::Call(String::from("maximum"), vec![*num(10), *num(20)])],
+        );
+        assert_eq!(Expr::new(b"(nothing)").unwrap(), vec![Expr::Call(String::from("nothing"), vec![])]);
+    }
+
+    #[test]
+    fn ite() {
+        let foo = b"(if (> Ack.lost_pkts_sample 0) (/ Cwnd 2) (+ Cwnd 1448))";
+        let er = Expr::new(foo);
+        let e = er.unwrap();
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        let num = |n| Box::new(Expr::Atom(Prim::Num(n)));
+        assert_eq!(
+            e,
+            vec![
+                Expr::Ite(
+                    Box::new(Expr::Sexp(Op::Gt, name("Ack.lost_pkts_sample"), num(0))),
+                    Box::new(Expr::Sexp(Op::Div, name("Cwnd"), num(2))),
+                    Box::new(Expr::Sexp(Op::Add, name("Cwnd"), num(1448))),
+                ),
+            ]
+        );
+
+        // the two-argument form is still a conditional bind
+        let foo = b"(if true 4)";
+        let er = Expr::new(foo);
+        assert_eq!(er.unwrap(), vec![Expr::Sexp(Op::If, Box::new(Expr::Atom(Prim::Bool(true))), num(4))]);
+    }
+
+    #[test]
+    fn cond() {
+        let foo = b"
+            (cond
+                ((> foo 0) # lost
+                    (:= bar 1)
+                    (report))
+                (else (:= bar 2)))
+        ";
+        let er = Expr::new(foo);
+        let e = er.unwrap();
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        let num = |n| Box::new(Expr::Atom(Prim::Num(n)));
+        assert_eq!(
+            e,
+            vec![
+                Expr::Cond(vec![
+                    (
+                        Expr::Sexp(Op::Gt, name("foo"), num(0)),
+                        vec![Expr::Sexp(Op::Bind, name("bar"), num(1)), Expr::Cmd(Command::Report)],
+                    ),
+                    (
+                        Expr::Atom(Prim::Bool(true)),
+                        vec![Expr::Sexp(Op::Bind, name("bar"), num(2))],
+                    ),
+                ]),
+            ]
+        );
+    }
+
     #[test]
     fn expr_leftover() {
         use nom;
@@ -561,9 +1065,7 @@ mod tests {
     fn old_syntax() {
         let foo = b"(reset)";
         let er = Expr::new(foo);
-        match er {
-            Ok(e) => panic!("false ok: {:?}", e),
-            Err(_) => (),
-        }
+        assert_eq!(er.unwrap(), vec![Expr::Call(String::from("reset"), vec![])]);
+        assert!(::lang::compile(b"(def (foo 0)) (when true (reset))", &[]).is_err());
     }
 }
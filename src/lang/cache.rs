@@ -0,0 +1,79 @@
+//! Reusing the results of earlier compilations.
+//!
+//! Compiling is deterministic: the same source, updates and `Capabilities` always give the same
+//! `Bin`, and so the same `program_uid`. A `CompileCache` remembers the programs it has compiled
+//! for a datapath, so compiling an identical source again only costs a lookup.
+
+use std::collections::HashMap;
+
+use super::{compile_with_capabilities, Bin, Capabilities, Result, Scope};
+
+/// A program's source and the updates applied to it.
+type Key = (Vec<u8>, Vec<(String, u32)>);
+
+/// The programs compiled for a datapath with the given `Capabilities`, by source and updates.
+pub struct CompileCache {
+    capabilities: Capabilities,
+    programs: HashMap<Key, (Bin, Scope)>,
+}
+
+impl CompileCache {
+    pub fn new(capabilities: Capabilities) -> Self {
+        CompileCache { capabilities, programs: HashMap::new() }
+    }
+
+    /// Like `lang::compile_with_capabilities()`, but only compiles each source and set of updates
+    /// once. Programs which fail to compile are not remembered.
+    pub fn compile(&mut self, src: &[u8], updates: &[(&str, u32)]) -> Result<(Bin, Scope)> {
+        let key: Key = (
+            src.to_vec(),
+            updates.iter().map(|&(name, v)| (String::from(name), v)).collect(),
+        );
+        if let Some(compiled) = self.programs.get(&key) {
+            return Ok(compiled.clone());
+        }
+
+        let compiled = compile_with_capabilities(src, updates, self.capabilities.clone())?;
+        self.programs.insert(key, compiled.clone());
+        Ok(compiled)
+    }
+
+    /// The number of programs compiled so far.
+    pub fn len(&self) -> usize {
+        self.programs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::Capabilities;
+    use super::CompileCache;
+
+    #[test]
+    fn program_identity() {
+        let foo = b"(def (Report (volatile acked 0)) (step 2)) (when true (:= Report.acked (+ Report.acked step)) (report))";
+        let mut cache = CompileCache::new(Capabilities::default());
+        let (b1, s1) = cache.compile(foo, &[]).unwrap();
+        let (b2, s2) = cache.compile(foo, &[]).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(b1, b2);
+
+        // the uid depends only on the compiled program, so it survives restarts
+        let (_, s3) = ::lang::compile(foo, &[]).unwrap();
+        assert_ne!(s1.program_uid, 0);
+        assert_eq!(s1.program_uid, s2.program_uid);
+        assert_eq!(s1.program_uid, s3.program_uid);
+
+        // different initial values are different programs
+        let (_, s4) = cache.compile(foo, &[("step", 3)]).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_ne!(s1.program_uid, s4.program_uid);
+
+        assert!(cache.compile(b"(def (Report.x 0)) (when true", &[]).is_err());
+        assert_eq!(cache.len(), 2);
+    }
+}
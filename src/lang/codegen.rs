@@ -0,0 +1,391 @@
+//! Compile datapath programs to C.
+//!
+//! `to_c()` turns a compiled program into a self-contained C translation unit, for datapaths that
+//! cannot embed libccp's interpreter or want to avoid its overhead. For a program named `prog`, it
+//! defines:
+//!
+//! ```c
+//! struct prog_state;   // the program's registers: report[], control[], local[], implicit[]
+//! struct prog_actions; // what to do after an ack: report (with fields[]), set_cwnd, set_rate
+//! void prog_init(struct prog_state *s, uint64_t now);
+//! int prog_on_ack(struct prog_state *s, const uint64_t *primitives, uint64_t now, struct prog_actions *a);
+//! ```
+//!
+//! `prog_on_ack` returns 0, or -1 if the program divided by zero or a `checked_sub` overflowed. Its semantics are those of
+//! `lang::interp::Machine`: `primitives` holds the values of the measurement primitives in register
+//! order, `now` is in microseconds, and the datapath's current congestion window and rate may be
+//! written to `s->implicit[PROG_CWND_REG]` and `s->implicit[PROG_RATE_REG]` before each ack.
+
+use std::fmt::Write;
+
+use super::{Error, Result};
+use super::ast::Op;
+use super::datapath::{Bin, Instr, Reg, Scope};
+use super::interp::{
+    self, RegCounts, CWND_REG, EVENT_FLAG_REG, RATE_REG, SHOULD_CONTINUE_REG, SHOULD_REPORT_REG,
+};
+
+/// An expression reading `r`.
+fn rvalue(r: &Reg) -> String {
+    match interp::imm(r) {
+        Some(v) => format!("{}ULL", v),
+        None => lvalue(r),
+    }
+}
+
+/// An expression naming the storage of `r`.
+fn lvalue(r: &Reg) -> String {
+    match *r {
+        Reg::Report(i, _, _) => format!("s->report[{}]", i),
+        Reg::Control(i, _) => format!("s->control[{}]", i),
+        Reg::Local(i, _) => format!("s->local[{}]", i),
+        Reg::Implicit(i, _) => format!("s->implicit[{}]", i),
+        Reg::Primitive(i, _) => format!("primitives[{}]", i),
+        Reg::Tmp(i, _) => format!("tmp[{}]", i),
+        _ => String::from("0"),
+    }
+}
+
+/// A C condition which holds if `l - r` overflows as a signed subtraction: for `l >= 0` if
+/// `r < l - INT64_MAX`, and otherwise if `r > l - INT64_MIN`, neither of which overflows.
+fn int_sub_overflows(l: &str, r: &str) -> String {
+    format!(
+        "((int64_t){0} >= 0 ? (int64_t){1} < (int64_t){0} - INT64_MAX : (int64_t){1} > (int64_t){0} - INT64_MIN)",
+        l, r,
+    )
+}
+
+/// The C statements, one per line, for an instruction in an event.
+fn statement(i: &Instr) -> String {
+    let (res, l, r) = (lvalue(&i.res), rvalue(&i.left), rvalue(&i.right));
+    let expr = match i.op {
+        Op::Def => return String::new(),
+        Op::Bind => r,
+        Op::If => return format!("if ({}) {} = {};", l, res, r),
+        Op::NotIf => return format!("if (!{}) {} = {};", l, res, r),
+        Op::Div | Op::Mod => {
+            let op = if i.op == Op::Div { "/" } else { "%" };
+            let check = match interp::imm(&i.right) {
+                Some(0) | None => format!("if ({} == 0) return -1;\n", r),
+                Some(_) => String::new(),
+            };
+            return format!("{}{} = {} {} {};", check, res, l, op, r);
+        }
+        Op::CheckedSub => return format!("if ({} > {}) return -1;\n{} = {} - {};", r, l, res, l, r),
+        Op::CheckedSubInt => return format!("if ({}) return -1;\n{} = {} - {};", int_sub_overflows(&l, &r), res, l, r),
+        Op::SatSub => format!("{0} > {1} ? {0} - {1} : 0", l, r),
+        Op::SatSubInt => format!(
+            "{} ? ((int64_t){} < 0 ? (uint64_t)INT64_MIN : (uint64_t)INT64_MAX) : {} - {}",
+            int_sub_overflows(&l, &r), l, l, r,
+        ),
+        Op::Ewma => format!("({} * {} + {} * (10 - {})) / 10", res, l, r, l),
+        Op::Add => format!("{} + {}", l, r),
+        Op::Sub => format!("{} - {}", l, r),
+        Op::Mul => format!("{} * {}", l, r),
+        Op::Shl => format!("{} >= 64 ? 0 : {} << {}", r, l, r),
+        Op::Shr => format!("{} >= 64 ? 0 : {} >> {}", r, l, r),
+        Op::BitAnd => format!("{} & {}", l, r),
+        Op::BitOr => format!("{} | {}", l, r),
+        Op::AbsDiff => format!("{0} > {1} ? {0} - {1} : {1} - {0}", l, r),
+        Op::Max => format!("{0} > {1} ? {0} : {1}", l, r),
+        Op::Min => format!("{0} < {1} ? {0} : {1}", l, r),
+        Op::MaxWrap => format!("(int64_t)({0} - {1}) < 0 ? {1} : {0}", l, r),
+        Op::Equiv => format!("{} == {}", l, r),
+        Op::Neq => format!("{} != {}", l, r),
+        Op::Gt => format!("{} > {}", l, r),
+        Op::Gte => format!("{} >= {}", l, r),
+        Op::Lt => format!("{} < {}", l, r),
+        Op::Lte => format!("{} <= {}", l, r),
+        Op::GtInt => format!("(int64_t){} > (int64_t){}", l, r),
+        Op::GteInt => format!("(int64_t){} >= (int64_t){}", l, r),
+        Op::LtInt => format!("(int64_t){} < (int64_t){}", l, r),
+        Op::LteInt => format!("(int64_t){} <= (int64_t){}", l, r),
+        Op::MaxInt => format!("(int64_t){0} > (int64_t){1} ? {0} : {1}", l, r),
+        Op::MinInt => format!("(int64_t){0} < (int64_t){1} ? {0} : {1}", l, r),
+        Op::And => format!("{} && {}", l, r),
+        Op::Or => format!("{} || {}", l, r),
+        Op::Not => format!("!{}", l),
+        Op::Observe | Op::ToInt | Op::ToNum => unreachable!(),
+    };
+
+    format!("{} = {};", res, expr)
+}
+
+/// Generate C source for the program `bin`, compiled with `scope`. `name` prefixes every
+/// identifier the source defines, and must be a valid C identifier.
+pub fn to_c(bin: &Bin, scope: &Scope, name: &str) -> Result<String> {
+    if name.is_empty()
+        || name.starts_with(|c: char| c.is_ascii_digit())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Error::from(format!("{:?} is not a valid C identifier", name)));
+    }
+
+    let counts = RegCounts::of(bin, scope);
+    let timers = counts.timers();
+    let upper = name.to_uppercase();
+    // C does not allow empty arrays
+    let len = |n: usize| ::std::cmp::max(n, 1);
+
+    let mut c = String::new();
+    writeln!(c, "/* {}: generated from a CCP datapath program. */", name)?;
+    writeln!(c, "#include <stdint.h>\n")?;
+    writeln!(c, "#define {}_NUM_REPORT_FIELDS {}", upper, counts.report)?;
+    writeln!(c, "#define {}_NUM_PRIMITIVES {}", upper, counts.primitive)?;
+    writeln!(c, "#define {}_CWND_REG {}", upper, CWND_REG)?;
+    writeln!(c, "#define {}_RATE_REG {}\n", upper, RATE_REG)?;
+
+    writeln!(c, "struct {}_state {{", name)?;
+    writeln!(c, "    uint64_t report[{}];", len(counts.report))?;
+    writeln!(c, "    uint64_t control[{}];", len(counts.control))?;
+    writeln!(c, "    uint64_t local[{}];", len(counts.local))?;
+    writeln!(c, "    uint64_t implicit[{}];", counts.implicit)?;
+    writeln!(c, "    uint64_t time_zero[{}];", timers.len())?;
+    writeln!(c, "}};\n")?;
+
+    writeln!(c, "struct {}_actions {{", name)?;
+    writeln!(c, "    int report;")?;
+    writeln!(c, "    uint64_t fields[{}];", len(counts.report))?;
+    writeln!(c, "    int set_cwnd;\n    uint64_t cwnd;")?;
+    writeln!(c, "    int set_rate;\n    uint64_t rate;")?;
+    writeln!(c, "}};\n")?;
+
+    let defs = |volatile_only: bool| -> String {
+        bin.instrs.iter()
+            .filter(|i| i.op == Op::Def)
+            .filter(|i| !volatile_only || match i.left {
+                Reg::Report(_, _, is_volatile) => is_volatile,
+                _ => false,
+            })
+            .map(|i| format!("    {} = {};\n", lvalue(&i.left), rvalue(&i.right)))
+            .collect()
+    };
+
+    writeln!(c, "void {}_init(struct {}_state *s, uint64_t now) {{", name, name)?;
+    writeln!(c, "    int i;")?;
+    writeln!(c, "    for (i = 0; i < {}; i++) s->report[i] = 0;", len(counts.report))?;
+    writeln!(c, "    for (i = 0; i < {}; i++) s->control[i] = 0;", len(counts.control))?;
+    writeln!(c, "    for (i = 0; i < {}; i++) s->local[i] = 0;", len(counts.local))?;
+    writeln!(c, "    for (i = 0; i < {}; i++) s->implicit[i] = 0;", counts.implicit)?;
+    writeln!(c, "    for (i = 0; i < {}; i++) s->time_zero[i] = now;", timers.len())?;
+    write!(c, "{}", defs(false))?;
+    writeln!(c, "}}\n")?;
+
+    writeln!(
+        c,
+        "int {}_on_ack(struct {}_state *s, const uint64_t *primitives, uint64_t now, struct {}_actions *a) {{",
+        name, name, name,
+    )?;
+    writeln!(c, "    uint64_t tmp[{}] = {{0}};", len(counts.tmp))?;
+    writeln!(c, "    uint64_t cwnd = s->implicit[{}], rate = s->implicit[{}];", CWND_REG, RATE_REG)?;
+    writeln!(c, "    (void)primitives;")?;
+    writeln!(c, "    (void)tmp;")?;
+    for (k, t) in timers.iter().enumerate() {
+        writeln!(c, "    s->implicit[{}] = now - s->time_zero[{}];", t, k)?;
+    }
+
+    for (n, ev) in bin.events.iter().enumerate() {
+        writeln!(c, "\n    /* event {} */", n)?;
+        writeln!(c, "    s->implicit[{}] = 0;", EVENT_FLAG_REG)?;
+        let flag = ev.flag_idx as usize..(ev.flag_idx + ev.num_flag_instrs) as usize;
+        for l in bin.instrs[flag].iter().flat_map(|i| statement(i).lines().map(String::from).collect::<Vec<_>>()) {
+            writeln!(c, "    {}", l)?;
+        }
+
+        writeln!(c, "    if (s->implicit[{}]) {{", EVENT_FLAG_REG)?;
+        writeln!(c, "        s->implicit[{}] = 0;", SHOULD_CONTINUE_REG)?;
+        let body = ev.body_idx as usize..(ev.body_idx + ev.num_body_instrs) as usize;
+        for l in bin.instrs[body].iter().flat_map(|i| statement(i).lines().map(String::from).collect::<Vec<_>>()) {
+            writeln!(c, "        {}", l)?;
+        }
+
+        writeln!(c, "        if (!s->implicit[{}]) goto done;", SHOULD_CONTINUE_REG)?;
+        writeln!(c, "    }}")?;
+    }
+
+    writeln!(c, "\ndone:")?;
+    for (k, t) in timers.iter().enumerate() {
+        writeln!(c, "    s->time_zero[{}] = now - s->implicit[{}];", k, t)?;
+    }
+
+    writeln!(c, "    a->report = 0;")?;
+    writeln!(c, "    if (s->implicit[{}]) {{", SHOULD_REPORT_REG)?;
+    writeln!(c, "        int i;")?;
+    writeln!(c, "        a->report = 1;")?;
+    writeln!(c, "        for (i = 0; i < {}; i++) a->fields[i] = s->report[i];", counts.report)?;
+    writeln!(c, "        s->implicit[{}] = 0;", SHOULD_REPORT_REG)?;
+    for l in defs(true).lines() {
+        writeln!(c, "    {}", l)?;
+    }
+
+    writeln!(c, "    }}")?;
+    writeln!(c, "    a->set_cwnd = s->implicit[{}] != cwnd;", CWND_REG)?;
+    writeln!(c, "    a->cwnd = s->implicit[{}];", CWND_REG)?;
+    writeln!(c, "    a->set_rate = s->implicit[{}] != rate;", RATE_REG)?;
+    writeln!(c, "    a->rate = s->implicit[{}];", RATE_REG)?;
+    writeln!(c, "    return 0;")?;
+    writeln!(c, "}}")?;
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    use lang::{self, Capabilities, Reg};
+    use lang::interp::tests::{expected, random_acks, EXTENDED_OPS, GENERIC_CONG_AVOID, SIGNED_OPS, START};
+
+    const HARNESS: &str = r#"
+#include <inttypes.h>
+#include <stdio.h>
+int main(void) {
+    struct prog_state s;
+    struct prog_actions a;
+    uint64_t now, prims[PROG_NUM_PRIMITIVES + 16];
+    int i;
+    prog_init(&s, 1000000);
+    while (scanf("%" SCNu64, &now) == 1) {
+        for (i = 0; i < NUM_INPUTS; i++) if (scanf("%" SCNu64, &prims[i]) != 1) return 1;
+        if (prog_on_ack(&s, prims, now, &a) != 0) { printf("err\n"); continue; }
+        if (a.report) {
+            printf("r [");
+            for (i = 0; i < PROG_NUM_REPORT_FIELDS; i++) printf(i ? ", %" PRIu64 : "%" PRIu64, a.fields[i]);
+            printf("] ");
+        }
+        if (a.set_cwnd) printf("c %" PRIu64 " ", a.cwnd);
+        if (a.set_rate) printf("t %" PRIu64 " ", a.rate);
+        printf("\n");
+    }
+    return 0;
+}
+"#;
+
+    /// Set to skip these tests on machines without a C compiler.
+    const SKIP_VAR: &str = "PORTUS_SKIP_C_TESTS";
+
+    /// A program from the libccp integration tests (`scenarios/volatile.rs`), with the values of
+    /// `Report.foo` and `Report.bar` libccp reports for it on 20 acks.
+    const LIBCCP_VOLATILE: &[u8] = b"
+        (def (Report (volatile foo 0) (bar 0)))
+        (when true
+            (:= Report.foo (+ Report.foo 1))
+            (:= Report.bar (+ Report.bar 1))
+            (fallthrough)
+        )
+        (when (== Report.foo 10)
+            (report)
+        )
+    ";
+    const LIBCCP_VOLATILE_REPORTS: [(u64, u64, u64); 2] = [(10, 10, 10), (20, 10, 20)];
+
+    /// Compile `prog` to C and build it with the system C compiler, returning the executable's
+    /// directory. Returns `None` if there is no C compiler and `PORTUS_SKIP_C_TESTS` is set.
+    fn build(test: &str, prog: &[u8], caps: Capabilities, num_primitives: usize) -> Option<PathBuf> {
+        let (bin, sc) = lang::compile_with_capabilities(prog, &[], caps).unwrap();
+        let src = super::to_c(&bin, &sc, "prog").unwrap()
+            + &format!("#define NUM_INPUTS {}\n", num_primitives)
+            + HARNESS;
+
+        let dir = env::temp_dir().join(format!("portus-codegen-{}-{}", test, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("prog.c")).unwrap().write_all(src.as_bytes()).unwrap();
+        let cc = Command::new("cc")
+            .args(&["-std=c99", "-Wall", "-Werror", "-o"])
+            .arg(dir.join("prog"))
+            .arg(dir.join("prog.c"))
+            .status();
+        match cc {
+            Ok(ref s) if s.success() => Some(dir),
+            Ok(_) => panic!("generated C failed to compile:\n{}", src),
+            Err(_) if env::var_os(SKIP_VAR).is_some() => {
+                fs::remove_dir_all(&dir).unwrap();
+                None
+            }
+            Err(e) => panic!("cannot run the C compiler ({}); set {} to skip this test", e, SKIP_VAR),
+        }
+    }
+
+    /// Run the program built in `dir` on `inputs`, returning its output.
+    fn run(dir: &Path, inputs: &[(u64, Vec<u64>)]) -> String {
+        let mut child = Command::new(dir.join("prog"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        {
+            let stdin = child.stdin.as_mut().unwrap();
+            for &(now, ref prims) in inputs {
+                let line: Vec<String> = Some(now).into_iter().chain(prims.iter().cloned()).map(|v| v.to_string()).collect();
+                writeln!(stdin, "{}", line.join(" ")).unwrap();
+            }
+        }
+        let out = child.wait_with_output().unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Compile `prog` to C with the system C compiler, run it on random acks, and compare its
+    /// output with the interpreter's.
+    fn check(test: &str, prog: &[u8], caps: Capabilities) {
+        let num_primitives = caps.primitives.iter().count();
+        let dir = match build(test, prog, caps.clone(), num_primitives) {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        for seed in 1..4u64 {
+            let inputs = random_acks(seed, num_primitives);
+            assert_eq!(run(&dir, &inputs), expected(prog, caps.clone(), &inputs));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn libccp_fixture() {
+        let caps = Capabilities::default();
+        let num_primitives = caps.primitives.iter().count();
+        let (_, sc) = lang::compile(LIBCCP_VOLATILE, &[]).unwrap();
+        let idx = |n| match *sc.get(n).unwrap() {
+            Reg::Report(i, _, _) => i as usize,
+            ref r => panic!("{} is not a Report field: {:?}", n, r),
+        };
+
+        let inputs: Vec<(u64, Vec<u64>)> = (1..21).map(|i| (START + i * 1000, vec![5; num_primitives])).collect();
+        let want: String = (1..21)
+            .map(|i| match LIBCCP_VOLATILE_REPORTS.iter().find(|r| r.0 == i) {
+                Some(&(_, foo, bar)) => {
+                    let mut fields = vec![0; 2];
+                    fields[idx("Report.foo")] = foo;
+                    fields[idx("Report.bar")] = bar;
+                    format!("r {:?} \n", fields)
+                }
+                None => String::from("\n"),
+            })
+            .collect();
+
+        assert_eq!(expected(LIBCCP_VOLATILE, caps.clone(), &inputs), want);
+        if let Some(dir) = build("libccp", LIBCCP_VOLATILE, caps, num_primitives) {
+            assert_eq!(run(&dir, &inputs), want);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn generic_cong_avoid() {
+        check("gca", GENERIC_CONG_AVOID, Capabilities::default());
+    }
+
+    #[test]
+    fn extended_ops() {
+        check("ext", EXTENDED_OPS, Capabilities::new(1).with_timers(1));
+    }
+
+    #[test]
+    fn signed_ops() {
+        check("signed", SIGNED_OPS, Capabilities::new(2));
+    }
+}
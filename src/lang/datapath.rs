@@ -1,12 +1,20 @@
 use super::{Error, Result};
-use super::ast::{Expr, Op, Prim};
+use super::ast::{Expr, FIXED_FRAC_BITS, Op, Prim};
 use super::prog::Prog;
+use super::serialize::{
+    MAX_TIMER_REGS, NUM_CONTROL_REGS, NUM_IMPLICIT_REGS, NUM_LOCAL_REGS, NUM_PRIMITIVE_REGS, NUM_REPORT_REGS,
+    NUM_TMP_REGS,
+};
 
 #[derive(Clone)]
 #[derive(Debug)]
 #[derive(PartialEq, Eq, Hash)]
 pub enum Type {
     Bool(Option<bool>),
+    /// A fixed-point number, scaled by `2^FIXED_FRAC_BITS`.
+    Fixed(Option<u64>),
+    /// A signed integer, stored in two's complement.
+    Int(Option<i64>),
     Name(String),
     Num(Option<u64>),
     None,
@@ -17,6 +25,8 @@ pub(crate) fn check_atom_type(e: &Expr) -> Result<Type> {
         Expr::Atom(ref t) => {
             match *t {
                 Prim::Bool(t) => Ok(Type::Bool(Some(t))),
+                Prim::Fixed(f) => Ok(Type::Fixed(Some(f))),
+                Prim::Int(i) => Ok(Type::Int(Some(i))),
                 Prim::Name(ref name) => Ok(Type::Name(name.clone())),
                 Prim::Num(n) => Ok(Type::Num(Some(n))),
             }
@@ -31,6 +41,9 @@ pub enum Reg {
     Control(u8, Type),
     ImmNum(u64),
     ImmBool(bool),
+    /// A fixed-point immediate, scaled by `2^FIXED_FRAC_BITS`.
+    ImmFixed(u64),
+    ImmInt(i64),
     Implicit(u8, Type),
     Local(u8, Type),
     Primitive(u8, Type),
@@ -39,11 +52,28 @@ pub enum Reg {
     None,
 }
 
+/// The `Implicit` register of `Cwnd`.
+const CWND_REG: u8 = 4;
+/// The `Implicit` register of `Rate`.
+const RATE_REG: u8 = 5;
+
 impl Reg {
+    /// Whether CCP can set this register when it switches or updates a flow's program: control
+    /// variables, `Cwnd` and `Rate` can be set.
+    pub fn is_updatable(&self) -> bool {
+        match *self {
+            Reg::Control(..) => true,
+            Reg::Implicit(idx, _) => idx == CWND_REG || idx == RATE_REG,
+            _ => false,
+        }
+    }
+
     fn get_type(&self) -> Result<Type> {
         match *self {
             Reg::ImmNum(n)           => Ok(Type::Num(Some(n))),
             Reg::ImmBool(b)          => Ok(Type::Bool(Some(b))),
+            Reg::ImmFixed(f)         => Ok(Type::Fixed(Some(f))),
+            Reg::ImmInt(i)           => Ok(Type::Int(Some(i))),
             Reg::Control(_, ref t)   |
             Reg::Implicit(_, ref t)  |
             Reg::Local(_, ref t)     |
@@ -93,8 +123,15 @@ impl Bin {
     /// Take a `Prog`, which is a `Vec<portus::lang::prog::Event>`, and turn it into
     /// a `Bin`, which is a `Vec<portus::lang::datapath::Event>` and a `Vec<Instr>`.
     pub fn compile_prog(p: &Prog, mut scope: &mut Scope) -> Result<Self> {
+        let used: Vec<String> = p.0.iter()
+            .flat_map(|ev| Some(&ev.flag).into_iter().chain(ev.body.iter()))
+            .flat_map(|e| e.names())
+            .collect();
+        scope.pack_reports(&used);
+
         let def_instrs = scope.clone().into_iter().collect::<Vec<Instr>>();
         let mut curr_idx = def_instrs.len() as u32;
+        let mut num_tmps = 0;
 
         // this is ugly
         // there might be some way to do this without all the intermediate `.collect()`
@@ -108,6 +145,19 @@ impl Bin {
                     // assign the flag value to the EventFlag reg.
                     let flag_reg = scope.get("__eventFlag").unwrap();
                     match res {
+                        Reg::Tmp(_, Type::Bool(_)) if instrs.last().map_or(false, |l| l.op == Op::If || l.op == Op::NotIf) => {
+                            // the result of (if c a b) is written by two instructions
+                            instrs.push(
+                                Instr{
+                                    res: flag_reg.clone(),
+                                    op: Op::Bind,
+                                    left: flag_reg.clone(),
+                                    right: res,
+                                }
+                            );
+
+                            Ok(instrs)
+                        }
                         Reg::Tmp(_, Type::Bool(_)) => {
                             if let Some(last) = instrs.last_mut() {
                                 (*last).res = flag_reg.clone();
@@ -129,17 +179,22 @@ impl Bin {
 
                             Ok(instrs)
                         }
-                        Reg::Report(_, _, _) => unreachable!(),
                         x => {
                             Err(Error::from(format!("Flag expression must result in bool: {:?}", x)))
                         }
                     }
                 })?;
+                let mut flag_instrs = flag_instrs;
+                num_tmps = ::std::cmp::max(num_tmps, reuse_tmps(&mut flag_instrs));
                 let num_flag_instrs = flag_instrs.len() as u32;
 
                 let body_instrs_nested: Result<Vec<Vec<Instr>>> = ev.body.iter().map(|expr| {
                     scope.clear_tmps();
-                    compile_expr(expr, &mut scope).map(|t| t.0) // Result<Vec<Instr>>
+                    compile_expr(expr, &mut scope).map(|t| {
+                        let mut instrs = t.0;
+                        num_tmps = ::std::cmp::max(num_tmps, reuse_tmps(&mut instrs));
+                        instrs
+                    }) // Result<Vec<Instr>>
                 }).collect(); // do this intermediate collect to go from Vec<Result<Vec<Instr>>> -> Result<Vec<Vec<Instr>>>
 
                 // flatten the Vec<Vec<Instr>>
@@ -163,13 +218,134 @@ impl Bin {
             }).collect();
 
         let (evs, instrs): (Vec<_>, Vec<_>) = ls?.into_iter().unzip();
-        Ok(Bin{
+        check_register_limits(scope, num_tmps)?;
+
+        let bin = Bin{
             events: evs,
             instrs: def_instrs.into_iter().chain(
                 instrs.into_iter().flat_map(|x| x.into_iter())
             ).collect(),
-        })
+        };
+        scope.program_uid = bin.program_uid();
+        Ok(bin)
+    }
+}
+
+/// Fail if the program needs more registers of some kind than the datapath provides, naming the
+/// variables that use them and suggesting how to use fewer.
+fn check_register_limits(scope: &Scope, num_tmps: u8) -> Result<()> {
+    if scope.num_perm > NUM_REPORT_REGS {
+        return Err(Error::from(format!(
+            "too many Report fields: the datapath supports {}, but the program uses {}: {}; \
+             fields the program never reads or writes are not stored in the datapath, so consider \
+             computing derived values in CCP instead",
+            NUM_REPORT_REGS,
+            scope.num_perm,
+            scope.reg_names(|r| match *r { Reg::Report(i, _, _) => Some(i), _ => None }).join(", "),
+        )));
+    }
+
+    if scope.num_control > NUM_CONTROL_REGS {
+        return Err(Error::from(format!(
+            "too many control variables: the datapath supports {}, but the program uses {}: {}; \
+             consider declaring values that never change with defconst",
+            NUM_CONTROL_REGS,
+            scope.num_control,
+            scope.reg_names(|r| match *r { Reg::Control(i, _) => Some(i), _ => None }).join(", "),
+        )));
+    }
+
+    if scope.num_local > NUM_LOCAL_REGS {
+        return Err(Error::from(format!(
+            "too many local variables: the datapath supports {}, but the program uses {}: {}; \
+             consider reusing variables for values that are not needed at the same time",
+            NUM_LOCAL_REGS,
+            scope.num_local,
+            scope.local_names().join(", "),
+        )));
+    }
+
+    if num_tmps > NUM_TMP_REGS {
+        return Err(Error::from(format!(
+            "expression too complex: it needs {} temporary registers, but the datapath supports {}; \
+             consider splitting it into parts bound with let",
+            num_tmps,
+            NUM_TMP_REGS,
+        )));
+    }
+
+    Ok(())
+}
+
+/// Renumber the temporary registers in the instructions of one statement, so that a register
+/// is reused once the value in it has been read for the last time. Returns the number of
+/// registers used.
+fn reuse_tmps(instrs: &mut [Instr]) -> u8 {
+    // the index of the last instruction using each tmp
+    let mut last_use: Vec<(u8, usize)> = vec![];
+    for (i, instr) in instrs.iter().enumerate() {
+        for r in &[&instr.left, &instr.right, &instr.res] {
+            if let Reg::Tmp(t, _) = **r {
+                match last_use.iter_mut().find(|u| u.0 == t) {
+                    Some(u) => u.1 = i,
+                    None => last_use.push((t, i)),
+                }
+            }
+        }
+    }
+
+    let last = |t: u8| last_use.iter().find(|u| u.0 == t).map_or(0, |u| u.1);
+    let mut assigned: Vec<(u8, u8)> = vec![];
+    let mut free: Vec<u8> = vec![];
+    let mut num_regs = 0;
+    let mut assign = |reg: &Reg, assigned: &mut Vec<(u8, u8)>, free: &mut Vec<u8>| match *reg {
+        Reg::Tmp(t, ref typ) => {
+            let idx = match assigned.iter().find(|a| a.0 == t) {
+                Some(a) => a.1,
+                None => {
+                    free.sort_by(|a, b| b.cmp(a));
+                    let idx = free.pop().unwrap_or_else(|| {
+                        num_regs += 1;
+                        num_regs - 1
+                    });
+                    assigned.push((t, idx));
+                    idx
+                }
+            };
+
+            Reg::Tmp(idx, typ.clone())
+        }
+        ref r => r.clone(),
+    };
+    let release = |reg: &Reg, assigned: &mut Vec<(u8, u8)>, free: &mut Vec<u8>| if let Reg::Tmp(t, _) = *reg {
+        if let Some(pos) = assigned.iter().position(|a| a.0 == t) {
+            free.push(assigned.remove(pos).1);
+        }
+    };
+
+    for (i, instr) in instrs.iter_mut().enumerate() {
+        // the result may reuse the register of an operand read for the last time
+        let left = assign(&instr.left, &mut assigned, &mut free);
+        let right = assign(&instr.right, &mut assigned, &mut free);
+        for operand in &[&instr.left, &instr.right] {
+            match **operand {
+                Reg::Tmp(t, _) if last(t) == i && **operand != instr.res => release(operand, &mut assigned, &mut free),
+                _ => (),
+            }
+        }
+
+        let res = assign(&instr.res, &mut assigned, &mut free);
+        match instr.res {
+            Reg::Tmp(t, _) if last(t) == i => release(&instr.res, &mut assigned, &mut free),
+            _ => (),
+        }
+
+        instr.left = left;
+        instr.right = right;
+        instr.res = res;
     }
+
+    num_regs
 }
 
 // TODO make iterative instead of recursive, and return impl Iterator<Instr>
@@ -188,6 +364,14 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                     if scope.has(name) {
                         let reg = scope.get(name).unwrap();
                         Ok((vec![], reg.clone()))
+                    } else if scope.histogram(name).is_some() {
+                        Err(Error::from(format!("histogram {:?} can only be used with observe", name)))
+                    } else if name.starts_with("Ack.") || name.starts_with("Flow.") {
+                        let primitives = &scope.capabilities.primitives;
+                        Err(Error::from(match primitives.suggest(name) {
+                            Some(s) => format!("unknown primitive {:?}, did you mean {:?}?", name, s),
+                            None => format!("unknown primitive {:?}: the datapath does not provide it", name),
+                        }))
                     } else {
                         Ok((
                             vec![],
@@ -196,13 +380,141 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                     }
                 }
                 Prim::Num(n) => Ok((vec![], Reg::ImmNum(n as u64))),
+                Prim::Fixed(f) => Ok((vec![], Reg::ImmFixed(f))),
+                Prim::Int(i) => {
+                    require_signed_ops(scope, "Int literals")?;
+                    Ok((vec![], Reg::ImmInt(i)))
+                }
+            }
+        }
+        Expr::Cmd(_) | Expr::None => Err(Error::from(format!("expected an expression: {:?}", e))),
+        Expr::Ite(box ref cond_expr, box ref then_expr, box ref else_expr) => {
+            let (mut instrs, cond) = compile_expr(cond_expr, &mut scope)?;
+            match cond.get_type() {
+                Ok(Type::Bool(_)) => (),
+                x => return Err(Error::from(format!("If expected Bool condition, got {:?}", x))),
             }
+
+            let (mut then_instrs, then_val) = compile_expr(then_expr, &mut scope)?;
+            let (mut else_instrs, else_val) = compile_expr(else_expr, &mut scope)?;
+            let typ = match (then_val.get_type(), else_val.get_type()) {
+                (Ok(Type::Num(_)), Ok(Type::Num(_))) => Type::Num(None),
+                (Ok(Type::Fixed(_)), Ok(Type::Fixed(_))) => Type::Fixed(None),
+                (Ok(Type::Int(_)), Ok(Type::Int(_))) => Type::Int(None),
+                (Ok(Type::Bool(_)), Ok(Type::Bool(_))) => Type::Bool(None),
+                (x, y) => return Err(Error::from(
+                    format!("If branches must both be Num, both be Fixed, both be Int or both be Bool, got {:?} and {:?}", x, y),
+                )),
+            };
+
+            // the If and NotIf together always write the result register
+            let res = scope.new_tmp(typ);
+            instrs.append(&mut then_instrs);
+            instrs.push(Instr {
+                res: res.clone(),
+                op: Op::If,
+                left: cond.clone(),
+                right: then_val,
+            });
+            instrs.append(&mut else_instrs);
+            instrs.push(Instr {
+                res: res.clone(),
+                op: Op::NotIf,
+                left: cond,
+                right: else_val,
+            });
+
+            Ok((instrs, res))
+        }
+        Expr::Cond(ref clauses) => Ok((compile_cond(clauses, None, &mut scope)?, Reg::None)),
+        Expr::Call(ref name, _) => Err(Error::from(format!("unknown macro {:?}", name))),
+        Expr::Let(_, _, _) => Err(Error::from(format!("let is a statement and cannot be used as a value: {:?}", e))),
+        Expr::Sexp(Op::Not, box ref arg_expr, _) => {
+            let (mut instrs, arg) = compile_expr(arg_expr, &mut scope)?;
+            match arg.get_type() {
+                Ok(Type::Bool(_)) => (),
+                x => return Err(Error::from(format!("Not expected Bool, got {:?}", x))),
+            }
+
+            // on older datapaths, (! a) is (== a false)
+            let res = scope.new_tmp(Type::Bool(None));
+            instrs.push(Instr {
+                res: res.clone(),
+                op: if scope.capabilities.supports_extended_ops() { Op::Not } else { Op::Equiv },
+                left: arg,
+                right: Reg::ImmBool(false),
+            });
+
+            Ok((instrs, res))
+        }
+        Expr::Sexp(o, box ref arg_expr, _) if o == Op::ToInt || o == Op::ToNum => {
+            require_signed_ops(scope, "(int ...) and (num ...)")?;
+            let (mut instrs, arg) = compile_expr(arg_expr, &mut scope)?;
+            let res = match (o, arg.get_type()) {
+                (Op::ToInt, Ok(Type::Int(_))) | (Op::ToNum, Ok(Type::Num(_))) => arg,
+                // the bits are unchanged, so Nums of 2^63 and more become negative
+                (Op::ToInt, Ok(Type::Num(_))) => match arg {
+                    Reg::ImmNum(n) => Reg::ImmInt(n as i64),
+                    arg => {
+                        let res = scope.new_tmp(Type::Int(None));
+                        instrs.push(Instr { res: res.clone(), op: Op::Add, left: arg, right: Reg::ImmNum(0) });
+                        res
+                    }
+                },
+                // negative values become 0
+                (Op::ToNum, Ok(Type::Int(_))) => match arg {
+                    Reg::ImmInt(i) => Reg::ImmNum(::std::cmp::max(i, 0) as u64),
+                    arg => {
+                        let res = scope.new_tmp(Type::Num(None));
+                        instrs.push(Instr { res: res.clone(), op: Op::MaxInt, left: arg, right: Reg::ImmInt(0) });
+                        res
+                    }
+                },
+                (_, x) => return Err(Error::from(format!(
+                    "{} expected {}, got {:?}",
+                    if o == Op::ToInt { "int" } else { "num" },
+                    if o == Op::ToInt { "Num" } else { "Int" },
+                    x,
+                ))),
+            };
+
+            Ok((instrs, res))
+        }
+        Expr::Sexp(Op::Observe, box ref hist_expr, box ref val_expr) => {
+            let name = match *hist_expr {
+                Expr::Atom(Prim::Name(ref n)) if scope.histogram(n).is_some() => n.clone(),
+                _ => return Err(Error::from(format!("observe expected a histogram, got {:?}", hist_expr))),
+            };
+
+            let (mut instrs, val) = compile_expr(val_expr, &mut scope)?;
+            match val.get_type() {
+                Ok(Type::Num(_)) => (),
+                x => return Err(Error::from(format!("observe expected Num, got {:?}", x))),
+            }
+
+            let mut observe = compile_observe(&name, val, &mut scope);
+            instrs.append(&mut observe);
+            Ok((instrs, Reg::None))
         }
-        Expr::Cmd(_) | Expr::None => unreachable!(),
         Expr::Sexp(ref o, box ref left_expr, box ref right_expr) => {
             let (mut instrs, mut left) = compile_expr(left_expr, &mut scope)?;
-            let (mut right_instrs, right) = compile_expr(right_expr, &mut scope)?;
+            let (mut right_instrs, mut right) = match (*o, right_expr) {
+                // a quotient assigned to a fixed-point variable is computed in fixed point, so
+                // that a quotient of Nums is not first truncated to an integer
+                (Op::Bind, &Expr::Sexp(Op::Div, box ref a, box ref b)) if is_fixed(&left) => {
+                    compile_fixed_quotient(a, b, &mut scope)?
+                }
+                _ => compile_expr(right_expr, &mut scope)?,
+            };
             instrs.append(&mut right_instrs);
+            if *o != Op::Bind && (is_fixed(&left) || is_fixed(&right)) {
+                return compile_fixed_op(*o, left, right, instrs, &mut scope);
+            }
+
+            if *o != Op::Bind && (is_int(&left) || is_int(&right)) {
+                return compile_int_op(*o, left, right, instrs, &mut scope);
+            }
+
             match *o {
                 Op::Add | Op::Div | Op::Max | Op::MaxWrap | Op::Min | Op::Mul | Op::Sub => {
                     // left and right should have type num
@@ -229,6 +541,31 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, res))
                 }
+                Op::CheckedSub | Op::SatSub => {
+                    for r in &[&left, &right] {
+                        match r.get_type() {
+                            Ok(Type::Num(_)) => (),
+                            x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
+                        }
+                    }
+
+                    let res = scope.new_tmp(Type::Num(None));
+                    if scope.capabilities.supports_signed_ops() {
+                        instrs.push(Instr { res: res.clone(), op: *o, left, right });
+                    } else if *o == Op::SatSub {
+                        // a - b, or 0 if b > a, is max(a, b) - b
+                        let hi = scope.new_tmp(Type::Num(None));
+                        instrs.push(Instr { res: hi.clone(), op: Op::Max, left, right: right.clone() });
+                        instrs.push(Instr { res: res.clone(), op: Op::Sub, left: hi, right });
+                    } else {
+                        return Err(Error::from(format!(
+                            "{:?} requires datapath version {}",
+                            o, Capabilities::SIGNED_OPS_VERSION,
+                        )));
+                    }
+
+                    Ok((instrs, res))
+                }
                 Op::And | Op::Or => {
                     // left and right should have type num
                     match left.get_type() {
@@ -275,8 +612,46 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, res))
                 }
+                Op::AbsDiff | Op::BitAnd | Op::BitOr | Op::Mod | Op::Shl | Op::Shr |
+                Op::Gte | Op::Lte | Op::Neq => {
+                    // left and right should have type num
+                    match left.get_type() {
+                        Ok(Type::Num(_)) => (),
+                        x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
+                    }
+                    match right.get_type() {
+                        Ok(Type::Num(_)) => (),
+                        x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
+                    }
+
+                    let res = match *o {
+                        Op::Gte | Op::Lte | Op::Neq => scope.new_tmp(Type::Bool(None)),
+                        _ => scope.new_tmp(Type::Num(None)),
+                    };
+
+                    if scope.capabilities.supports_extended_ops() {
+                        instrs.push(Instr {
+                            res: res.clone(),
+                            op: *o,
+                            left,
+                            right,
+                        });
+                    } else {
+                        let mut lowered = lower_extended_op(*o, left, right, res.clone(), &mut scope)?;
+                        instrs.append(&mut lowered);
+                    }
+
+                    Ok((instrs, res))
+                }
                 Op::Bind => {
                     // (bind a b) assign variable a to value b
+                    match *right_expr {
+                        Expr::Cond(_) => return Err(Error::from("cond is a statement and cannot be bound to a variable")),
+                        Expr::Sexp(Op::Observe, _, _) => return Err(Error::from(
+                            "observe is a statement and cannot be bound to a variable",
+                        )),
+                        _ => (),
+                    }
 
                     // if type(left) is None, give it type of right
                     if let Ok(Type::Name(s)) = left.get_type() {
@@ -284,10 +659,29 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                         left = scope.update_type(&s, &right_type)?;
                     }
 
+                    // fixed-point values are rounded when assigned to Num variables, and Num
+                    // values scaled when assigned to Fixed variables
+                    match (left.get_type(), right.get_type()) {
+                        (Ok(Type::Num(_)), Ok(Type::Fixed(_))) => right = fixed_to_num(right, &mut instrs, &mut scope),
+                        (Ok(Type::Fixed(_)), Ok(Type::Num(_))) => right = num_to_fixed(right, &mut instrs, &mut scope),
+                        // Ints are only converted explicitly
+                        (Ok(Type::Int(_)), Ok(Type::Num(_))) | (Ok(Type::Int(_)), Ok(Type::Fixed(_))) |
+                        (Ok(Type::Num(_)), Ok(Type::Int(_))) | (Ok(Type::Fixed(_)), Ok(Type::Int(_))) => {
+                            return Err(Error::from(format!(
+                                "cannot assign {:?} to {:?}, convert it with (int ...) or (num ...)",
+                                right_expr, left_expr,
+                            )));
+                        }
+                        _ => (),
+                    }
+
                     // left must be a mutable register
                     // and if right is a Reg::None, we have to replace it
                     match (&left, &right) {
-                        (&Reg::Report(_, _, _), &Reg::None) | (&Reg::Control(_,_), &Reg::None) => {
+                        (&Reg::Report(_, _, _), &Reg::None) |
+                        (&Reg::Control(_,_), &Reg::None)     |
+                        (&Reg::Implicit(_, _), &Reg::None)   |
+                        (&Reg::Local(_, _), &Reg::None) => {
                             let last_instr = instrs.last_mut().map(|last| {
                                 // Double-check that the instruction being replaced
                                 // actually is a Reg::None before we go replace it
@@ -346,12 +740,369 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, Reg::None))
                 }
-                Op::Def => unreachable!(),
+                Op::Def | Op::Not | Op::Observe | Op::ToInt | Op::ToNum => unreachable!(),
+                Op::CheckedSubInt | Op::GtInt | Op::GteInt | Op::LtInt | Op::LteInt |
+                Op::MaxInt | Op::MinInt | Op::SatSubInt => unreachable!(),
             }
         }
     }
 }
 
+const FIXED_ONE: u64 = 1 << FIXED_FRAC_BITS;
+
+fn is_fixed(r: &Reg) -> bool {
+    match r.get_type() {
+        Ok(Type::Fixed(_)) => true,
+        _ => false,
+    }
+}
+
+fn is_int(r: &Reg) -> bool {
+    match r.get_type() {
+        Ok(Type::Int(_)) => true,
+        _ => false,
+    }
+}
+
+fn require_signed_ops(scope: &Scope, what: &str) -> Result<()> {
+    if scope.capabilities.supports_signed_ops() {
+        Ok(())
+    } else {
+        Err(Error::from(format!("{} require datapath version {}", what, Capabilities::SIGNED_OPS_VERSION)))
+    }
+}
+
+/// Compile `(o left right)` where at least one operand is an Int. Both must be: Ints and Nums are
+/// only converted explicitly. Operators whose result depends on the sign use their signed forms.
+fn compile_int_op(o: Op, left: Reg, right: Reg, mut instrs: Vec<Instr>, scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
+    for r in &[&left, &right] {
+        match r.get_type() {
+            Ok(Type::Int(_)) => (),
+            x => return Err(Error::from(format!(
+                "{:?} expected Int, got {:?}; convert it with (int ...) or (num ...)", o, x,
+            ))),
+        }
+    }
+
+    let (op, typ) = match o {
+        Op::Add | Op::Sub | Op::Mul => (o, Type::Int(None)),
+        Op::Equiv | Op::Neq => (o, Type::Bool(None)),
+        Op::Gt => (Op::GtInt, Type::Bool(None)),
+        Op::Gte => (Op::GteInt, Type::Bool(None)),
+        Op::Lt => (Op::LtInt, Type::Bool(None)),
+        Op::Lte => (Op::LteInt, Type::Bool(None)),
+        Op::Max => (Op::MaxInt, Type::Int(None)),
+        Op::Min => (Op::MinInt, Type::Int(None)),
+        Op::SatSub => (Op::SatSubInt, Type::Int(None)),
+        Op::CheckedSub => (Op::CheckedSubInt, Type::Int(None)),
+        _ => return Err(Error::from(format!("{:?} does not support Int operands", o))),
+    };
+
+    let res = scope.new_tmp(typ);
+    instrs.push(Instr { res: res.clone(), op, left, right });
+    Ok((instrs, res))
+}
+
+/// `r`, a Num, as a fixed-point value.
+fn num_to_fixed(r: Reg, instrs: &mut Vec<Instr>, scope: &mut Scope) -> Reg {
+    match r {
+        Reg::ImmNum(n) if n.checked_mul(FIXED_ONE).is_some() => Reg::ImmFixed(n * FIXED_ONE),
+        r => {
+            let res = scope.new_tmp(Type::Fixed(None));
+            instrs.push(Instr { res: res.clone(), op: Op::Mul, left: r, right: Reg::ImmNum(FIXED_ONE) });
+            res
+        }
+    }
+}
+
+/// `r`, a fixed-point value, rounded to the nearest Num.
+fn fixed_to_num(r: Reg, instrs: &mut Vec<Instr>, scope: &mut Scope) -> Reg {
+    // rounded without adding half first, which would overflow for the largest literals
+    if let Reg::ImmFixed(f) = r {
+        return Reg::ImmNum(f / FIXED_ONE + u64::from(f % FIXED_ONE >= FIXED_ONE / 2));
+    }
+
+    let half = scope.new_tmp(Type::Num(None));
+    let res = scope.new_tmp(Type::Num(None));
+    instrs.push(Instr { res: half.clone(), op: Op::Add, left: r, right: Reg::ImmNum(FIXED_ONE / 2) });
+    instrs.push(Instr { res: res.clone(), op: Op::Div, left: half, right: Reg::ImmNum(FIXED_ONE) });
+    res
+}
+
+/// Compile `(/ a b)` to a fixed-point quotient, even if both operands are Nums.
+fn compile_fixed_quotient(a: &Expr, b: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
+    let (mut instrs, left) = compile_expr(a, &mut scope)?;
+    let (mut right_instrs, right) = compile_expr(b, &mut scope)?;
+    instrs.append(&mut right_instrs);
+    if !is_fixed(&left) && !is_fixed(&right) && (is_int(&left) || is_int(&right)) {
+        return compile_int_op(Op::Div, left, right, instrs, &mut scope);
+    }
+
+    compile_fixed_op(Op::Div, left, right, instrs, &mut scope)
+}
+
+/// Compile `(o left right)` where at least one operand is fixed-point, or a division whose
+/// quotient should be fixed-point. Num operands are
+/// converted to fixed-point; products and quotients are rounded to the nearest fixed-point value.
+fn compile_fixed_op(o: Op, left: Reg, right: Reg, mut instrs: Vec<Instr>, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
+    for r in &[&left, &right] {
+        match r.get_type() {
+            Ok(Type::Num(_)) | Ok(Type::Fixed(_)) => (),
+            x => return Err(Error::from(format!("{:?} expected Num or Fixed, got {:?}", o, x))),
+        }
+    }
+
+    let instr = |res: &Reg, op: Op, left: &Reg, right: &Reg| Instr {
+        res: res.clone(),
+        op,
+        left: left.clone(),
+        right: right.clone(),
+    };
+    match o {
+        Op::Mul => {
+            let res = scope.new_tmp(Type::Fixed(None));
+            if is_fixed(&left) && is_fixed(&right) {
+                // the product has twice the fractional bits
+                let prod = scope.new_tmp(Type::Num(None));
+                instrs.push(instr(&prod, Op::Mul, &left, &right));
+                instrs.push(instr(&prod, Op::Add, &prod, &Reg::ImmNum(FIXED_ONE / 2)));
+                instrs.push(instr(&res, Op::Div, &prod, &Reg::ImmNum(FIXED_ONE)));
+            } else {
+                // a Num times a fixed-point value is exact
+                instrs.push(instr(&res, Op::Mul, &left, &right));
+            }
+
+            Ok((instrs, res))
+        }
+        Op::Div => {
+            // (a + b / 2) / b, with the dividend a scaled so the quotient is fixed-point
+            let dividend = match (is_fixed(&left), is_fixed(&right)) {
+                (true, false) => left,
+                (true, true) | (false, false) => {
+                    let scaled = scope.new_tmp(Type::Num(None));
+                    instrs.push(instr(&scaled, Op::Mul, &left, &Reg::ImmNum(FIXED_ONE)));
+                    scaled
+                }
+                (false, true) => {
+                    let scaled = scope.new_tmp(Type::Num(None));
+                    instrs.push(instr(&scaled, Op::Mul, &left, &Reg::ImmNum(FIXED_ONE)));
+                    instrs.push(instr(&scaled, Op::Mul, &scaled, &Reg::ImmNum(FIXED_ONE)));
+                    scaled
+                }
+            };
+
+            let half = scope.new_tmp(Type::Num(None));
+            let sum = scope.new_tmp(Type::Num(None));
+            let res = scope.new_tmp(Type::Fixed(None));
+            instrs.push(instr(&half, Op::Div, &right, &Reg::ImmNum(2)));
+            instrs.push(instr(&sum, Op::Add, &dividend, &half));
+            instrs.push(instr(&res, Op::Div, &sum, &right));
+            Ok((instrs, res))
+        }
+        Op::Add | Op::Sub | Op::Max | Op::Min | Op::AbsDiff |
+        Op::Equiv | Op::Gt | Op::Lt | Op::Gte | Op::Lte | Op::Neq => {
+            let left = if is_fixed(&left) { left } else { num_to_fixed(left, &mut instrs, &mut scope) };
+            let right = if is_fixed(&right) { right } else { num_to_fixed(right, &mut instrs, &mut scope) };
+            let res = match o {
+                Op::Add | Op::Sub | Op::Max | Op::Min | Op::AbsDiff => scope.new_tmp(Type::Fixed(None)),
+                _ => scope.new_tmp(Type::Bool(None)),
+            };
+
+            match o {
+                Op::AbsDiff | Op::Gte | Op::Lte | Op::Neq if !scope.capabilities.supports_extended_ops() => {
+                    let mut lowered = lower_extended_op(o, left, right, res.clone(), &mut scope)?;
+                    instrs.append(&mut lowered);
+                }
+                _ => instrs.push(instr(&res, o, &left, &right)),
+            }
+
+            Ok((instrs, res))
+        }
+        _ => Err(Error::from(format!("{:?} does not support fixed-point operands", o))),
+    }
+}
+
+/// Lower `(observe hist val)` to instructions which add one to the bucket of `hist` that `val`
+/// falls in. With `below[i] = val < bounds[i]`, which is 0 or 1, bucket `i` gets
+/// `below[i] - below[i-1]`; the first bucket gets `below[0]` and the last `1 - below[n-1]`.
+fn compile_observe(hist: &str, val: Reg, scope: &mut Scope) -> Vec<Instr> {
+    let bounds = scope.histogram(hist).unwrap().to_vec();
+    let mut instrs = vec![];
+    let mut below_prev = Reg::ImmNum(0);
+    for i in 0..=bounds.len() {
+        let below = match bounds.get(i) {
+            Some(&b) => {
+                let below = scope.new_tmp(Type::Num(None));
+                instrs.push(Instr { res: below.clone(), op: Op::Lt, left: val.clone(), right: Reg::ImmNum(b) });
+                below
+            }
+            None => Reg::ImmNum(1),
+        };
+
+        let inc = match below_prev {
+            Reg::ImmNum(0) => below.clone(),
+            _ => {
+                let inc = scope.new_tmp(Type::Num(None));
+                instrs.push(Instr { res: inc.clone(), op: Op::Sub, left: below.clone(), right: below_prev });
+                inc
+            }
+        };
+
+        let bucket = scope.get(&bucket_name(hist, i)).unwrap().clone();
+        instrs.push(Instr { res: bucket.clone(), op: Op::Add, left: bucket, right: inc });
+        below_prev = below;
+    }
+
+    instrs
+}
+
+/// Lower `(cond (c1 body...) (c2 body...) ...)` to instructions.
+///
+/// The guard of each clause is true if its condition holds and no earlier clause's did. All the
+/// guards are computed before any body runs, so a body cannot change which clause is taken. Each
+/// statement `(:= x e)` in a body then becomes `x <- (if guard e)`. `outer` is the guard of the
+/// enclosing clause when `cond`s are nested.
+fn compile_cond(clauses: &[(Expr, Vec<Expr>)], outer: Option<Reg>, mut scope: &mut Scope) -> Result<Vec<Instr>> {
+    let mut instrs = vec![];
+    let mut guards = vec![];
+    // whether an earlier clause's condition holds
+    let mut taken: Option<Reg> = None;
+    for (i, &(ref cond_expr, _)) in clauses.iter().enumerate() {
+        let (mut cond_instrs, cond) = compile_expr(cond_expr, &mut scope)?;
+        match cond.get_type() {
+            Ok(Type::Bool(_)) => (),
+            x => return Err(Error::from(format!("cond expected Bool condition, got {:?}", x))),
+        }
+
+        instrs.append(&mut cond_instrs);
+        let guard = match taken {
+            None => cond,
+            Some(ref t) => {
+                let not_taken = scope.new_tmp(Type::Bool(None));
+                let guard = scope.new_tmp(Type::Bool(None));
+                instrs.push(Instr { res: not_taken.clone(), op: Op::Equiv, left: t.clone(), right: Reg::ImmBool(false) });
+                instrs.push(Instr { res: guard.clone(), op: Op::Mul, left: cond, right: not_taken });
+                guard
+            }
+        };
+
+        if i + 1 < clauses.len() {
+            taken = Some(match taken {
+                None => guard.clone(),
+                Some(t) => {
+                    let any = scope.new_tmp(Type::Bool(None));
+                    instrs.push(Instr { res: any.clone(), op: Op::Add, left: t, right: guard.clone() });
+                    any
+                }
+            });
+        }
+
+        guards.push(guard);
+    }
+
+    for (&(_, ref body), guard) in clauses.iter().zip(guards) {
+        let guard = match outer {
+            None => guard,
+            Some(ref o) => {
+                let both = scope.new_tmp(Type::Bool(None));
+                instrs.push(Instr { res: both.clone(), op: Op::Mul, left: o.clone(), right: guard });
+                both
+            }
+        };
+
+        for stmt in body {
+            match *stmt {
+                Expr::Cond(ref nested) => {
+                    let mut nested_instrs = compile_cond(nested, Some(guard.clone()), &mut scope)?;
+                    instrs.append(&mut nested_instrs);
+                }
+                Expr::Sexp(Op::Bind, _, _) => {
+                    let (mut stmt_instrs, _) = compile_expr(stmt, &mut scope)?;
+                    match stmt_instrs.last_mut() {
+                        Some(ref mut last) if last.op == Op::Bind => {
+                            last.op = Op::If;
+                            last.left = guard.clone();
+                        }
+                        _ => return Err(Error::from(format!(
+                            "conditional bind inside cond, use a nested cond instead: {:?}",
+                            stmt,
+                        ))),
+                    }
+
+                    instrs.append(&mut stmt_instrs);
+                }
+                _ => return Err(Error::from(format!("expected statement in cond clause, found {:?}", stmt))),
+            }
+        }
+    }
+
+    Ok(instrs)
+}
+
+/// Express an operator added in datapath version 1 with instructions older datapaths understand.
+fn lower_extended_op(o: Op, left: Reg, right: Reg, res: Reg, scope: &mut Scope) -> Result<Vec<Instr>> {
+    let instr = |res: &Reg, op: Op, left: &Reg, right: &Reg| Instr {
+        res: res.clone(),
+        op,
+        left: left.clone(),
+        right: right.clone(),
+    };
+
+    match o {
+        // |a - b| = max(a, b) - min(a, b)
+        Op::AbsDiff => {
+            let hi = scope.new_tmp(Type::Num(None));
+            let lo = scope.new_tmp(Type::Num(None));
+            Ok(vec![
+                instr(&hi, Op::Max, &left, &right),
+                instr(&lo, Op::Min, &left, &right),
+                instr(&res, Op::Sub, &hi, &lo),
+            ])
+        }
+        // a mod b = a - (a / b) * b
+        Op::Mod => {
+            let quot = scope.new_tmp(Type::Num(None));
+            let prod = scope.new_tmp(Type::Num(None));
+            Ok(vec![
+                instr(&quot, Op::Div, &left, &right),
+                instr(&prod, Op::Mul, &quot, &right),
+                instr(&res, Op::Sub, &left, &prod),
+            ])
+        }
+        // shifting by a constant is a multiplication or division by a power of two
+        Op::Shl | Op::Shr => match right {
+            Reg::ImmNum(k) if k < 31 => Ok(vec![
+                instr(&res, if o == Op::Shl { Op::Mul } else { Op::Div }, &left, &Reg::ImmNum(1 << k)),
+            ]),
+            _ => Err(Error::from(format!(
+                "{:?} by a non-constant amount requires datapath version {}",
+                o, Capabilities::EXTENDED_OPS_VERSION,
+            ))),
+        },
+        // comparisons are 0 or 1, and a > b and a == b are exclusive, so their sum is their or
+        Op::Gte | Op::Lte => {
+            let cmp = scope.new_tmp(Type::Bool(None));
+            let eq = scope.new_tmp(Type::Bool(None));
+            Ok(vec![
+                instr(&cmp, if o == Op::Gte { Op::Gt } else { Op::Lt }, &left, &right),
+                instr(&eq, Op::Equiv, &left, &right),
+                instr(&res, Op::Add, &cmp, &eq),
+            ])
+        }
+        Op::Neq => {
+            let eq = scope.new_tmp(Type::Bool(None));
+            Ok(vec![
+                instr(&eq, Op::Equiv, &left, &right),
+                instr(&res, Op::Equiv, &eq, &Reg::ImmBool(false)),
+            ])
+        }
+        _ => Err(Error::from(format!(
+            "{:?} requires datapath version {}",
+            o, Capabilities::EXTENDED_OPS_VERSION,
+        ))),
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub(crate) struct RegFile(pub(crate) Vec<(String, Reg)>);
 
@@ -380,18 +1131,245 @@ impl RegFile {
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Features of the datapath a program is compiled for.
+///
+/// Programs that use operators the datapath does not support are rewritten in terms of
+/// operators it does support where possible, and fail to compile otherwise.
+pub struct Capabilities {
+    /// Version of the datapath's instruction set. Version 0 is the original instruction set.
+    /// Version 1 adds `%`, `<<`, `>>`, `&`, `|`, `>=`, `<=`, `!=`, `!` and `absdiff`. Version 2
+    /// adds the `Int` type, signed comparisons, `sat_sub` and `checked_sub`.
+    pub version: u32,
+    /// Number of timers, besides `Micros`, the datapath advances for each program. Datapaths
+    /// report this when a flow is created (`DatapathInfo::num_timers`).
+    pub num_timers: u8,
+    /// The `Ack.*` and `Flow.*` measurements the datapath provides.
+    pub primitives: Primitives,
+    /// The most instructions a program may run on each ack, as estimated by `lang::analysis`.
+    /// Programs which may exceed it fail to compile.
+    pub instruction_budget: Option<u32>,
+}
+
+impl Capabilities {
+    pub const EXTENDED_OPS_VERSION: u32 = 1;
+    pub const SIGNED_OPS_VERSION: u32 = 2;
+    /// The most timers, besides `Micros`, a datapath can provide.
+    pub const MAX_TIMERS: u8 = MAX_TIMER_REGS;
+
+    pub fn new(version: u32) -> Self {
+        Capabilities { version, num_timers: 0, primitives: Primitives::default(), instruction_budget: None }
+    }
+
+    /// These capabilities, for a datapath that also provides `n` timers.
+    ///
+    /// # Panics
+    /// If `n` is more than `Capabilities::MAX_TIMERS`.
+    pub fn with_timers(self, n: u8) -> Self {
+        assert!(n <= MAX_TIMER_REGS, "datapaths support at most {} timers, not {}", MAX_TIMER_REGS, n);
+        Capabilities { num_timers: n, ..self }
+    }
+
+    /// These capabilities, for a datapath that runs at most `max_instrs` instructions per ack.
+    pub fn with_instruction_budget(self, max_instrs: u32) -> Self {
+        Capabilities { instruction_budget: Some(max_instrs), ..self }
+    }
+
+    pub fn supports_signed_ops(&self) -> bool {
+        self.version >= Capabilities::SIGNED_OPS_VERSION
+    }
+
+    pub fn supports_extended_ops(&self) -> bool {
+        self.version >= Self::EXTENDED_OPS_VERSION
+    }
+}
+
+/// The primitives every datapath provides, in order of their registers.
+const STANDARD_PRIMITIVES: &[(&str, bool, &str)] = &[
+    ("Ack.bytes_acked", false, "Bytes newly acknowledged by this ack."),
+    ("Ack.bytes_misordered", false, "Bytes this ack reports as received out of order (e.g. SACKed)."),
+    ("Ack.ecn_bytes", false, "Bytes acknowledged with an ECN echo."),
+    ("Ack.ecn_packets", false, "Packets acknowledged with an ECN echo."),
+    ("Ack.lost_pkts_sample", false, "Packets detected as lost since the last ack."),
+    ("Ack.now", false, "Datapath time at which the ack was processed, in microseconds."),
+    ("Ack.packets_acked", false, "Packets newly acknowledged by this ack."),
+    ("Ack.packets_misordered", false, "Packets this ack reports as received out of order."),
+    ("Flow.bytes_in_flight", false, "Bytes sent but not yet acknowledged."),
+    ("Flow.bytes_pending", false, "Bytes queued by the application but not yet sent."),
+    ("Flow.packets_in_flight", false, "Packets sent but not yet acknowledged."),
+    ("Flow.rate_incoming", false, "Receive rate estimate, in bytes per second."),
+    ("Flow.rate_outgoing", false, "Send rate estimate, in bytes per second."),
+    ("Flow.rtt_sample_us", false, "Most recent RTT sample, in microseconds."),
+    ("Flow.was_timeout", true, "Whether the flow had a retransmission timeout since the last ack."),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A measurement the datapath provides to programs, e.g. `Ack.bytes_acked`.
+pub struct Primitive {
+    pub name: String,
+    /// `Type::Num(None)` or `Type::Bool(None)`.
+    pub typ: Type,
+    pub doc: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// The registry of measurement primitives a datapath provides. A primitive is stored in the
+/// primitive register numbered by its position in the registry.
+///
+/// `Primitives::default()` holds the primitives every datapath provides. A datapath measuring
+/// other signals, such as delivery rate samples or app-limited flags, registers them in the
+/// `Capabilities` it compiles programs for. Programs using a primitive the registry does not hold
+/// fail to compile.
+pub struct Primitives(Vec<Primitive>);
+
+impl Default for Primitives {
+    fn default() -> Self {
+        Primitives(STANDARD_PRIMITIVES.iter().map(|&(name, is_bool, doc)| Primitive {
+            name: String::from(name),
+            typ: if is_bool { Type::Bool(None) } else { Type::Num(None) },
+            doc: String::from(doc),
+        }).collect())
+    }
+}
+
+impl Primitives {
+    /// A registry without any primitives, for datapaths that do not provide the standard ones.
+    pub fn empty() -> Self {
+        Primitives(vec![])
+    }
+
+    /// Add the primitive `name` of type `typ` (`Num` or `Bool`), returning its register index.
+    /// The name must start with `Ack.` or `Flow.`.
+    pub fn register(&mut self, name: &str, typ: Type, doc: &str) -> Result<u8> {
+        let field = match name.find('.').map(|i| name.split_at(i + 1)) {
+            Some(("Ack.", f)) | Some(("Flow.", f)) => f,
+            _ => return Err(Error::from(format!("primitive {:?} must be named Ack.<name> or Flow.<name>", name))),
+        };
+        if field.is_empty() || !field.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(Error::from(format!("invalid primitive name {:?}", name)));
+        }
+
+        let typ = match typ {
+            Type::Num(_) => Type::Num(None),
+            Type::Bool(_) => Type::Bool(None),
+            t => return Err(Error::from(format!("primitive {:?} must be Num or Bool, got {:?}", name, t))),
+        };
+
+        if self.get(name).is_some() {
+            return Err(Error::from(format!("primitive {:?} is already registered", name)));
+        }
+
+        if self.0.len() >= NUM_PRIMITIVE_REGS as usize {
+            return Err(Error::from(format!(
+                "cannot register primitive {:?}: the datapath has {} primitive registers",
+                name, NUM_PRIMITIVE_REGS,
+            )));
+        }
+
+        self.0.push(Primitive { name: String::from(name), typ, doc: String::from(doc) });
+        Ok(self.0.len() as u8 - 1)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Primitive> {
+        self.0.iter().find(|p| p.name == name)
+    }
+
+    /// The primitives, in order of their registers.
+    pub fn iter<'a>(&'a self) -> ::std::slice::Iter<'a, Primitive> {
+        self.0.iter()
+    }
+
+    /// The registered primitive whose name is closest to `name`, if any is close enough to be a
+    /// likely typo.
+    pub fn suggest(&self, name: &str) -> Option<&str> {
+        self.0.iter()
+            .map(|p| (edit_distance(&p.name, name), p.name.as_str()))
+            .filter(|&(d, _)| d <= ::std::cmp::max(2, name.len() / 4))
+            .min_by_key(|&(d, _)| d)
+            .map(|(_, n)| n)
+    }
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr.push(::std::cmp::min(sub, ::std::cmp::min(prev[j + 1], curr[j]) + 1));
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A parameter of a program, declared in its `(def ...)` block with `(param name type [default])`.
+///
+/// `typ` is `Type::Num` or `Type::Bool`, holding the default value. A parameter without a default
+/// is required: it must be given a value whenever the program is installed with `set_program`.
+pub struct Param {
+    pub name: String,
+    pub typ: Type,
+}
+
+impl Param {
+    pub fn is_required(&self) -> bool {
+        match self.typ {
+            Type::Num(None) | Type::Bool(None) => true,
+            _ => false,
+        }
+    }
+
+    /// The value `v` as this parameter's type. Boolean parameters take 0 (false) or 1 (true).
+    pub fn value(&self, v: u32) -> Result<Type> {
+        match self.typ {
+            Type::Bool(_) if v <= 1 => Ok(Type::Bool(Some(v == 1))),
+            Type::Bool(_) => Err(Error::from(format!(
+                "parameter {:?} is a bool, expected 0 or 1, got {}",
+                self.name, v,
+            ))),
+            _ => Ok(Type::Num(Some(u64::from(v)))),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 /// A mapping from variable names defined in the datapath program to their
 /// datapath register representations.
 pub struct Scope {
+    /// The `Bin::program_uid()` of the program compiled with this scope, or 0 before it is compiled.
     pub        program_uid: u32,
+    pub(crate) capabilities: Capabilities,
     pub(crate) named: RegFile,
     pub(crate) num_control: u8,
     pub(crate) num_local: u8,
     pub(crate) num_perm: u8,
+    pub(crate) num_timers: u8,
+    params: Vec<Param>,
+    histograms: Vec<(String, Vec<u64>)>,
+    persistent: Vec<String>,
     tmp: Vec<Reg>,
 }
 
+/// The name of the Report field holding bucket `i` of the histogram `hist`. Names cannot contain
+/// `[`, so bucket names cannot collide with the names of other variables. The buckets are
+/// consecutive Report registers because `Scope::new_histogram` allocates them in sequence.
+pub(crate) fn bucket_name(hist: &str, i: usize) -> String {
+    format!("{}[{:02}]", hist, i)
+}
+
+/// Whether the datapath keeps `r` from one ack to the next: unused Report fields are not stored.
+fn is_stored(r: &Reg) -> bool {
+    match *r {
+        Reg::Report(..) | Reg::Control(..) => true,
+        _ => false,
+    }
+}
+
 macro_rules! add_reg {
     ($scope:ident, $name:expr, $rtyp:ident, $idx:expr, $typ:expr) => ({
         $scope.named.insert(
@@ -431,46 +1409,37 @@ macro_rules! expand_reg {
     });
 }
 
-
-use std::sync::atomic::{AtomicU32, Ordering};
-static ID_COUNTER : AtomicU32 = AtomicU32::new(0);
-macro_rules! get_next_uid {
-    () => (ID_COUNTER.fetch_add(1, Ordering::SeqCst) + 1)
-}
-
 impl Scope {
     /// Define variables always accessible in the datapath,
     /// in the context of the most recent packet.
     /// All datapaths shall recognize these Names.
     pub fn new() -> Self {
+        Scope::with_capabilities(Capabilities::default())
+    }
+
+    /// Like `new()`, for a datapath with the given `Capabilities`.
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
         let mut sc = Scope {
-            program_uid: get_next_uid!(),
+            program_uid: 0,
+            capabilities,
             named: RegFile::new(),
             num_control: 0,
             num_local: 0,
             num_perm: 0,
+            num_timers: 0,
+            params: vec![],
+            histograms: vec![],
+            persistent: vec![],
             tmp: vec![],
         };
 
-        // available measurement primitives (alphabetical order)
-        expand_reg!(
-            sc; Primitive;
-            "Ack.bytes_acked"         =>  Type::Num(None),
-            "Ack.bytes_misordered"    =>  Type::Num(None),
-            "Ack.ecn_bytes"           =>  Type::Num(None),
-            "Ack.ecn_packets"         =>  Type::Num(None),
-            "Ack.lost_pkts_sample"    =>  Type::Num(None),
-            "Ack.now"                 =>  Type::Num(None),
-            "Ack.packets_acked"       =>  Type::Num(None),
-            "Ack.packets_misordered"  =>  Type::Num(None),
-            "Flow.bytes_in_flight"    =>  Type::Num(None),
-            "Flow.bytes_pending"      =>  Type::Num(None),
-            "Flow.packets_in_flight"  =>  Type::Num(None),
-            "Flow.rate_incoming"      =>  Type::Num(None),
-            "Flow.rate_outgoing"      =>  Type::Num(None),
-            "Flow.rtt_sample_us"      =>  Type::Num(None),
-            "Flow.was_timeout"        =>  Type::Bool(None)
-        );
+        // measurement primitives, numbered by their position in the registry
+        let primitives: Vec<(String, Type)> = sc.capabilities.primitives.iter()
+            .map(|p| (p.name.clone(), p.typ.clone()))
+            .collect();
+        for (i, (name, typ)) in primitives.into_iter().enumerate() {
+            add_reg!(sc, name, Primitive, i as u8, typ);
+        }
 
         // implicit return registers
 
@@ -490,6 +1459,10 @@ impl Scope {
         sc
     }
 
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     pub fn has(&self, name: &str) -> bool {
         self.named.get(name).is_some()
     }
@@ -498,6 +1471,93 @@ impl Scope {
         self.named.get(name)
     }
 
+    /// The parameters of the program, in order of declaration.
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    /// The bucket boundaries of the histogram `name`. Bucket `i` counts the observed values `v`
+    /// with `bounds[i-1] <= v < bounds[i]`, so there is one more bucket than boundary.
+    pub fn histogram(&self, name: &str) -> Option<&[u64]> {
+        self.histograms.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref b)| b.as_slice())
+    }
+
+    /// The registers whose values carry over when a flow switches from the program of `old` to
+    /// this one, as `(old register, new register)` pairs: those of this program's persistent
+    /// variables which `old` also has, with the same type. The datapath copies their values
+    /// before running the new program.
+    pub fn register_mapping(&self, old: &Scope) -> Vec<(Reg, Reg)> {
+        self.persistent.iter()
+            .filter_map(|name| match (old.get(name), self.get(name)) {
+                (Some(from), Some(to)) if is_stored(from) && is_stored(to) => Some((from.clone(), to.clone())),
+                _ => None,
+            })
+            .filter(|&(ref from, ref to)| match (from.get_type(), to.get_type()) {
+                (Ok(f), Ok(t)) => ::std::mem::discriminant(&f) == ::std::mem::discriminant(&t),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Check that `updates` only name fields that can be updated, that is control variables,
+    /// `Cwnd` and `Rate`, and that their values suit the types of the parameters they name. If
+    /// `install` is true, every required parameter must be given a value.
+    ///
+    /// `set_program` and `update_field` run this check, but it needs only the program's `Scope`,
+    /// so updates can also be validated before a flow runs the program.
+    pub fn check_updates(&self, updates: &[(&str, u32)], install: bool) -> Result<()> {
+        for &(name, v) in updates {
+            self.check_update_name(name)?;
+            if let Some(p) = self.param(name) {
+                p.value(v)?;
+            }
+        }
+
+        if install {
+            let missing: Vec<&str> = self.params.iter()
+                .filter(|p| p.is_required() && !updates.iter().any(|&(n, _)| n == p.name))
+                .map(|p| p.name.as_str())
+                .collect();
+            if !missing.is_empty() {
+                return Err(Error::from(format!("missing required parameters: {}", missing.join(", "))));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_update_name(&self, name: &str) -> Result<()> {
+        if name.starts_with("__") {
+            return Err(Error::from(format!("cannot update reserved field {:?}", name)));
+        }
+
+        match self.get(name) {
+            Some(r) if r.is_updatable() => Ok(()),
+            Some(_) => Err(Error::from(format!(
+                "cannot update field {:?}: only control variables, Cwnd and Rate can be updated",
+                name,
+            ))),
+            None => {
+                let closest = self.named.0.iter()
+                    .filter(|&&(ref n, ref r)| !n.starts_with("__") && match *r {
+                        Reg::Control(..) => true,
+                        _ => false,
+                    })
+                    .map(|&(ref n, _)| (edit_distance(n, name), n.as_str()))
+                    .filter(|&(d, _)| d <= ::std::cmp::max(2, name.len() / 4))
+                    .min_by_key(|&(d, _)| d);
+                Err(Error::from(match closest {
+                    Some((_, n)) => format!("unknown field {:?}, did you mean {:?}?", name, n),
+                    None => format!("unknown field {:?}", name),
+                }))
+            }
+        }
+    }
+
     pub(crate) fn new_tmp(&mut self, t: Type) -> Reg {
         let id = self.tmp.len() as u8;
         let r = Reg::Tmp(id, t);
@@ -513,6 +1573,20 @@ impl Scope {
         r
     }
 
+    /// A histogram is a run of Report fields, one per bucket.
+    pub(crate) fn new_histogram(&mut self, is_volatile: bool, name: String, bounds: Vec<u64>) {
+        for i in 0..=bounds.len() {
+            self.new_report(is_volatile, bucket_name(&name, i), Type::Num(Some(0)));
+        }
+
+        self.histograms.push((name, bounds));
+    }
+
+    /// Mark the variable `name` as keeping its value across program switches.
+    pub(crate) fn new_persistent(&mut self, name: String) {
+        self.persistent.push(name);
+    }
+
     pub(crate) fn new_control(&mut self, name: String, t: Type) -> Reg {
         let id = self.num_control;
         self.num_control += 1;
@@ -520,7 +1594,27 @@ impl Scope {
         self.named.insert(name, r.clone());
         r
     }
-    
+
+    pub(crate) fn new_param(&mut self, name: String, t: Type) -> Reg {
+        self.params.push(Param { name: name.clone(), typ: t.clone() });
+        self.new_control(name, t)
+    }
+
+    /// A timer is an implicit register that the datapath advances, like `Micros`.
+    pub(crate) fn new_timer(&mut self, name: String) -> Result<Reg> {
+        if self.num_timers >= self.capabilities.num_timers {
+            return Err(Error::from(format!(
+                "cannot declare timer {:?}: the datapath supports {} timers besides Micros",
+                name, self.capabilities.num_timers,
+            )));
+        }
+
+        let r = Reg::Implicit(NUM_IMPLICIT_REGS + self.num_timers, Type::Num(None));
+        self.num_timers += 1;
+        self.named.insert(name, r.clone());
+        Ok(r)
+    }
+
     pub(crate) fn new_local(&mut self, name: String, t: Type) -> Reg {
         let id = self.num_local;
         self.num_local += 1;
@@ -529,6 +1623,69 @@ impl Scope {
         r
     }
 
+    /// Stop storing Report fields that the program never reads or writes: such a field always
+    /// holds its initial value, so its name now refers to an immediate. The remaining fields are
+    /// renumbered in order. The buckets of histograms are always stored, so that a `Report`
+    /// holds each histogram as a slice.
+    pub(crate) fn pack_reports(&mut self, used: &[String]) {
+        let mut num_perm = 0;
+        let histograms = &self.histograms;
+        for &mut (ref name, ref mut reg) in self.named.0.iter_mut() {
+            let is_bucket = histograms.iter().any(|&(ref h, ref b)| (0..=b.len()).any(|i| bucket_name(h, i) == *name));
+            *reg = match (reg.clone(), used.contains(name) || is_bucket) {
+                (Reg::Report(_, Type::Num(Some(n)), _), false) => Reg::ImmNum(n),
+                (Reg::Report(_, Type::Bool(Some(b)), _), false) => Reg::ImmBool(b),
+                (Reg::Report(_, Type::Fixed(Some(f)), _), false) => Reg::ImmFixed(f),
+                (Reg::Report(_, Type::Int(Some(i)), _), false) => Reg::ImmInt(i),
+                (Reg::Report(_, t, is_volatile), _) => {
+                    num_perm += 1;
+                    Reg::Report(num_perm - 1, t, is_volatile)
+                }
+                (r, _) => r,
+            };
+        }
+
+        self.num_perm = num_perm;
+    }
+
+    /// Names of the variables in the registers selected by `index`, in register order.
+    pub(crate) fn reg_names<F: Fn(&Reg) -> Option<u8>>(&self, index: F) -> Vec<String> {
+        let mut regs: Vec<(u8, String)> = self.named.0.iter()
+            .filter_map(|&(ref name, ref reg)| index(reg).map(|i| (i, name.clone())))
+            .collect();
+        regs.sort();
+        regs.into_iter().map(|(_, n)| n).collect()
+    }
+
+    /// Names of the local variables, in register order. Hidden locals introduced by a `let`
+    /// or a macro expansion are shown as `x (let)` or `x (macro)`.
+    pub(crate) fn local_names(&self) -> Vec<String> {
+        // strip the __let{n}_ and __m{n}_ prefixes
+        fn source_name(name: &str) -> &str {
+            for prefix in &["__let", "__m"] {
+                if name.starts_with(prefix) {
+                    let rest = name[prefix.len()..].trim_start_matches(|c: char| c.is_digit(10));
+                    if rest.starts_with('_') {
+                        return source_name(&rest[1..]);
+                    }
+                }
+            }
+
+            name
+        }
+
+        self.reg_names(|r| match *r { Reg::Local(i, _) => Some(i), _ => None })
+            .into_iter()
+            .map(|name| if name.starts_with("__let") {
+                format!("{} (let)", source_name(&name))
+            } else if name.starts_with("__m") {
+                format!("{} (macro)", source_name(&name))
+            } else {
+                name
+            })
+            .collect()
+    }
+
     // if the Type was initially None, update it now that we know what it is.
     // When updating values in scope before installation in datapath, this is used
     pub(crate) fn update_type(&mut self, name: &str, t: &Type) -> Result<Reg> {
@@ -579,7 +1736,9 @@ impl Iterator for ScopeDefInstrIter {
             let (_, reg) = self.v.next()?;
             match reg {
                 Reg::Report(_, Type::Num(Some(n)), _) |
-                Reg::Control(_, Type::Num(Some(n))) => {
+                Reg::Control(_, Type::Num(Some(n))) |
+                Reg::Report(_, Type::Fixed(Some(n)), _) |
+                Reg::Control(_, Type::Fixed(Some(n))) => {
                     return Some(Instr {
                         res: reg.clone(),
                         op: Op::Def,
@@ -587,6 +1746,15 @@ impl Iterator for ScopeDefInstrIter {
                         right: Reg::ImmNum(n),
                     })
                 }
+                Reg::Report(_, Type::Int(Some(i)), _) |
+                Reg::Control(_, Type::Int(Some(i))) => {
+                    return Some(Instr {
+                        res: reg.clone(),
+                        op: Op::Def,
+                        left: reg.clone(),
+                        right: Reg::ImmInt(i),
+                    })
+                }
                 Reg::Report(_, Type::Bool(Some(b)), _) |
                 Reg::Control(_, Type::Bool(Some(b))) => {
                     return Some(Instr {
@@ -615,7 +1783,7 @@ impl IntoIterator for Scope {
 mod tests {
     use lang::ast::Op;
     use lang::prog::Prog;
-    use super::{Bin, Event, Instr, Reg, Type};
+    use super::{Bin, Capabilities, Event, Instr, Reg, Type};
     #[test]
     fn primitives() {
         let foo = b"
@@ -650,6 +1818,8 @@ mod tests {
         assert_eq!(sc.get("Micros"          ).unwrap().clone(), Reg::Implicit(3, Type::Num(None)));
         assert_eq!(sc.get("Cwnd"            ).unwrap().clone(), Reg::Implicit(4, Type::Num(None)));
         assert_eq!(sc.get("Rate"            ).unwrap().clone(), Reg::Implicit(5, Type::Num(None)));
+        assert!(sc.get("Cwnd").unwrap().is_updatable() && sc.get("Rate").unwrap().is_updatable());
+        assert!(!sc.get("Micros").unwrap().is_updatable());
 
         // state
         assert_eq!(sc.get("Report.foo").unwrap().clone(), Reg::Report(0, Type::Num(Some(0)), false));
@@ -1042,7 +2212,7 @@ mod tests {
                         right: Reg::ImmNum(2),
                     },
                     Instr {
-                        res: Reg::Tmp(1, Type::Num(None)),
+                        res: Reg::Tmp(0, Type::Num(None)),
                         op: Op::Add,
                         left: Reg::Tmp(0, Type::Num(None)),
                         right: Reg::ImmNum(3),
@@ -1051,7 +2221,7 @@ mod tests {
                         res: foo_reg.clone(),
                         op: Op::Bind,
                         left: foo_reg.clone(),
-                        right: Reg::Tmp(1, Type::Num(None)),
+                        right: Reg::Tmp(0, Type::Num(None)),
                     },
                     Instr {
                         res: Reg::Tmp(0, Type::Num(None)),
@@ -1060,7 +2230,7 @@ mod tests {
                         right: Reg::ImmNum(5),
                     },
                     Instr {
-                        res: Reg::Tmp(1, Type::Num(None)),
+                        res: Reg::Tmp(0, Type::Num(None)),
                         op: Op::Add,
                         left: Reg::Tmp(0, Type::Num(None)),
                         right: Reg::ImmNum(6),
@@ -1069,7 +2239,7 @@ mod tests {
                         res: foo_reg.clone(),
                         op: Op::Bind,
                         left: foo_reg.clone(),
-                        right: Reg::Tmp(1, Type::Num(None)),
+                        right: Reg::Tmp(0, Type::Num(None)),
                     },
                 ]
             }
@@ -1333,4 +2503,577 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn extended_ops() {
+        let foo = b"
+        (def (Report.diff 0) (Report.ok false))
+        (when (>= Flow.rtt_sample_us 100)
+            (:= Report.diff (absdiff (% Ack.bytes_acked 1448) (<< Ack.packets_acked 2)))
+            (:= Report.ok (! (!= Report.diff 0)))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_capabilities(foo, Capabilities::new(1)).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let diff_reg = sc.get("Report.diff").unwrap().clone();
+        let ok_reg = sc.get("Report.ok").unwrap().clone();
+        let num_tmp = |i| Reg::Tmp(i, Type::Num(None));
+        let bool_tmp = |i| Reg::Tmp(i, Type::Bool(None));
+
+        assert_eq!(b.events, vec![Event { flag_idx: 2, num_flag_instrs: 1, body_idx: 3, num_body_instrs: 7 }]);
+        assert_eq!(
+            b.instrs[2..].to_vec(),
+            vec![
+                Instr {
+                    res: sc.get("__eventFlag").unwrap().clone(),
+                    op: Op::Gte,
+                    left: sc.get("Flow.rtt_sample_us").unwrap().clone(),
+                    right: Reg::ImmNum(100),
+                },
+                Instr {
+                    res: num_tmp(0),
+                    op: Op::Mod,
+                    left: sc.get("Ack.bytes_acked").unwrap().clone(),
+                    right: Reg::ImmNum(1448),
+                },
+                Instr {
+                    res: num_tmp(1),
+                    op: Op::Shl,
+                    left: sc.get("Ack.packets_acked").unwrap().clone(),
+                    right: Reg::ImmNum(2),
+                },
+                // the result of each operation reuses the register of its operand
+                Instr { res: num_tmp(0), op: Op::AbsDiff, left: num_tmp(0), right: num_tmp(1) },
+                Instr { res: diff_reg.clone(), op: Op::Bind, left: diff_reg.clone(), right: num_tmp(0) },
+                Instr { res: bool_tmp(0), op: Op::Neq, left: diff_reg.clone(), right: Reg::ImmNum(0) },
+                Instr { res: bool_tmp(0), op: Op::Not, left: bool_tmp(0), right: Reg::ImmBool(false) },
+                Instr { res: ok_reg.clone(), op: Op::Bind, left: ok_reg.clone(), right: bool_tmp(0) },
+            ],
+        );
+    }
+
+    #[test]
+    fn extended_ops_lowered() {
+        let foo = b"
+        (def (Report.diff 0) (Report.ok false))
+        (when (>= Flow.rtt_sample_us 100)
+            (:= Report.diff (absdiff (% Ack.bytes_acked 1448) (<< Ack.packets_acked 2)))
+            (:= Report.ok (! (!= Report.diff 0)))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let rtt_reg = sc.get("Flow.rtt_sample_us").unwrap().clone();
+        let acked_reg = sc.get("Ack.bytes_acked").unwrap().clone();
+        let diff_reg = sc.get("Report.diff").unwrap().clone();
+        let ok_reg = sc.get("Report.ok").unwrap().clone();
+        let num_tmp = |i| Reg::Tmp(i, Type::Num(None));
+        let bool_tmp = |i| Reg::Tmp(i, Type::Bool(None));
+
+        assert_eq!(b.events, vec![Event { flag_idx: 2, num_flag_instrs: 3, body_idx: 5, num_body_instrs: 12 }]);
+        assert_eq!(
+            b.instrs[2..].to_vec(),
+            vec![
+                // (>= rtt 100) => (+ (> rtt 100) (== rtt 100))
+                Instr { res: bool_tmp(0), op: Op::Gt, left: rtt_reg.clone(), right: Reg::ImmNum(100) },
+                Instr { res: bool_tmp(1), op: Op::Equiv, left: rtt_reg.clone(), right: Reg::ImmNum(100) },
+                Instr {
+                    res: sc.get("__eventFlag").unwrap().clone(),
+                    op: Op::Add,
+                    left: bool_tmp(0),
+                    right: bool_tmp(1),
+                },
+                // (% acked 1448) => (- acked (* (/ acked 1448) 1448))
+                Instr { res: num_tmp(0), op: Op::Div, left: acked_reg.clone(), right: Reg::ImmNum(1448) },
+                Instr { res: num_tmp(0), op: Op::Mul, left: num_tmp(0), right: Reg::ImmNum(1448) },
+                Instr { res: num_tmp(0), op: Op::Sub, left: acked_reg.clone(), right: num_tmp(0) },
+                // (<< pkts 2) => (* pkts 4)
+                Instr {
+                    res: num_tmp(1),
+                    op: Op::Mul,
+                    left: sc.get("Ack.packets_acked").unwrap().clone(),
+                    right: Reg::ImmNum(4),
+                },
+                // (absdiff a b) => (- (max a b) (min a b))
+                Instr { res: num_tmp(2), op: Op::Max, left: num_tmp(0), right: num_tmp(1) },
+                Instr { res: num_tmp(0), op: Op::Min, left: num_tmp(0), right: num_tmp(1) },
+                Instr { res: num_tmp(0), op: Op::Sub, left: num_tmp(2), right: num_tmp(0) },
+                Instr { res: diff_reg.clone(), op: Op::Bind, left: diff_reg.clone(), right: num_tmp(0) },
+                // (!= diff 0) => (== (== diff 0) false)
+                Instr { res: bool_tmp(0), op: Op::Equiv, left: diff_reg.clone(), right: Reg::ImmNum(0) },
+                Instr { res: bool_tmp(0), op: Op::Equiv, left: bool_tmp(0), right: Reg::ImmBool(false) },
+                // (! a) => (== a false)
+                Instr { res: bool_tmp(0), op: Op::Equiv, left: bool_tmp(0), right: Reg::ImmBool(false) },
+                Instr { res: ok_reg.clone(), op: Op::Bind, left: ok_reg.clone(), right: bool_tmp(0) },
+            ],
+        );
+    }
+
+    #[test]
+    fn extended_ops_unsupported() {
+        let foo = b"
+        (def (Report.flags 0))
+        (when true
+            (:= Report.flags (| Report.flags (& Ack.ecn_packets 1)))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+
+        let (p, mut sc) = Prog::new_with_capabilities(foo, Capabilities::new(1)).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_ok());
+
+        let shift = b"(def (Report.x 0)) (when true (:= Report.x (>> Report.x Ack.packets_acked)))";
+        let (p, mut sc) = Prog::new_with_scope(shift).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+    }
+
+    #[test]
+    fn ite() {
+        let foo = b"
+        (def (Report.loss false))
+        (when true
+            (:= Cwnd (if (> Ack.lost_pkts_sample 0) (/ Cwnd 2) (+ Cwnd 1448)))
+        )
+        (when (if Report.loss false true)
+            (report)
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let cwnd_reg = sc.get("Cwnd").unwrap().clone();
+        let evflag_reg = sc.get("__eventFlag").unwrap().clone();
+        let loss_reg = sc.get("Report.loss").unwrap().clone();
+
+        assert_eq!(
+            b.events,
+            vec![
+                Event { flag_idx: 1, num_flag_instrs: 1, body_idx: 2, num_body_instrs: 6 },
+                Event { flag_idx: 8, num_flag_instrs: 3, body_idx: 11, num_body_instrs: 1 },
+            ],
+        );
+        assert_eq!(
+            b.instrs[2..11].to_vec(),
+            vec![
+                Instr {
+                    res: Reg::Tmp(0, Type::Bool(None)),
+                    op: Op::Gt,
+                    left: sc.get("Ack.lost_pkts_sample").unwrap().clone(),
+                    right: Reg::ImmNum(0),
+                },
+                Instr { res: Reg::Tmp(1, Type::Num(None)), op: Op::Div, left: cwnd_reg.clone(), right: Reg::ImmNum(2) },
+                // the result reuses the register of the then branch
+                Instr {
+                    res: Reg::Tmp(1, Type::Num(None)),
+                    op: Op::If,
+                    left: Reg::Tmp(0, Type::Bool(None)),
+                    right: Reg::Tmp(1, Type::Num(None)),
+                },
+                Instr { res: Reg::Tmp(2, Type::Num(None)), op: Op::Add, left: cwnd_reg.clone(), right: Reg::ImmNum(1448) },
+                Instr {
+                    res: Reg::Tmp(1, Type::Num(None)),
+                    op: Op::NotIf,
+                    left: Reg::Tmp(0, Type::Bool(None)),
+                    right: Reg::Tmp(2, Type::Num(None)),
+                },
+                Instr { res: cwnd_reg.clone(), op: Op::Bind, left: cwnd_reg.clone(), right: Reg::Tmp(1, Type::Num(None)) },
+                // the flag is written by both halves of the if, then copied to the event flag
+                Instr { res: Reg::Tmp(0, Type::Bool(None)), op: Op::If, left: loss_reg.clone(), right: Reg::ImmBool(false) },
+                Instr { res: Reg::Tmp(0, Type::Bool(None)), op: Op::NotIf, left: loss_reg.clone(), right: Reg::ImmBool(true) },
+                Instr { res: evflag_reg.clone(), op: Op::Bind, left: evflag_reg.clone(), right: Reg::Tmp(0, Type::Bool(None)) },
+            ],
+        );
+    }
+
+    #[test]
+    fn cond() {
+        let foo = b"
+        (def (Report.loss 0) (Report.acked 0))
+        (when true
+            (cond
+                ((> Ack.lost_pkts_sample 0)
+                    (:= Report.loss Ack.lost_pkts_sample)
+                    (report)
+                )
+                ((> Cwnd 10000)
+                    (:= Report.acked Ack.bytes_acked)
+                )
+                (else
+                    (:= Cwnd (+ Cwnd 1448))
+                )
+            )
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let acked_reg = sc.get("Report.acked").unwrap().clone();
+        let loss_reg = sc.get("Report.loss").unwrap().clone();
+        let report_reg = sc.get("__shouldReport").unwrap().clone();
+        let cwnd_reg = sc.get("Cwnd").unwrap().clone();
+        let t = |i| Reg::Tmp(i, Type::Bool(None));
+
+        assert_eq!(
+            b.instrs[3..].to_vec(),
+            vec![
+                // guards
+                Instr { res: t(0), op: Op::Gt, left: sc.get("Ack.lost_pkts_sample").unwrap().clone(), right: Reg::ImmNum(0) },
+                Instr { res: t(1), op: Op::Gt, left: cwnd_reg.clone(), right: Reg::ImmNum(10000) },
+                Instr { res: t(2), op: Op::Equiv, left: t(0), right: Reg::ImmBool(false) },
+                Instr { res: t(1), op: Op::Mul, left: t(1), right: t(2) },
+                Instr { res: t(2), op: Op::Add, left: t(0), right: t(1) },
+                Instr { res: t(2), op: Op::Equiv, left: t(2), right: Reg::ImmBool(false) },
+                Instr { res: t(2), op: Op::Mul, left: Reg::ImmBool(true), right: t(2) },
+                // bodies
+                Instr { res: loss_reg.clone(), op: Op::If, left: t(0), right: sc.get("Ack.lost_pkts_sample").unwrap().clone() },
+                Instr { res: report_reg.clone(), op: Op::If, left: t(0), right: Reg::ImmBool(true) },
+                Instr { res: acked_reg.clone(), op: Op::If, left: t(1), right: sc.get("Ack.bytes_acked").unwrap().clone() },
+                Instr { res: Reg::Tmp(0, Type::Num(None)), op: Op::Add, left: cwnd_reg.clone(), right: Reg::ImmNum(1448) },
+                Instr { res: cwnd_reg.clone(), op: Op::If, left: t(2), right: Reg::Tmp(0, Type::Num(None)) },
+            ],
+        );
+
+        // cond is a statement, not a value
+        let foo = b"(def (Report.x 0)) (when true (:= Report.x (cond (true (:= Report.x 1)))))";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let foo = b"
+        (def (Report.foo 0))
+        (when true
+            (:= a 1) (:= b 2) (:= c 3) (:= d 4)
+            (let (e 5)
+                (let (f 6)
+                    (let (g 7)
+                        (:= Report.foo (+ e (+ f g)))
+                    )
+                )
+            )
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        match Bin::compile_prog(&p, &mut sc) {
+            Ok(_) => panic!("compiled a program with 7 locals"),
+            Err(e) => assert_eq!(
+                e.0,
+                "too many local variables: the datapath supports 6, but the program uses 7: a, b, c, d, e (let), f (let), g (let); \
+                 consider reusing variables for values that are not needed at the same time",
+            ),
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for foo in &[
+            &b"(def (Report.a 0)) (when Report.a (report))"[..],
+            &b"(def (Report.a 0)) (when (report) (report))"[..],
+            &b"(def (Report.a 0) (Report.b false)) (when true (:= Report.a.(+ Report.a 1)) bind Report.b (!if Report.b true)))"[..],
+        ] {
+            let res = ::lang::compile(foo, &[]).and_then(|(bin, _)| bin.serialize());
+            assert!(res.is_err(), "{}", String::from_utf8_lossy(foo));
+        }
+    }
+
+    #[test]
+    fn timers() {
+        let foo = b"
+        (def (Report (volatile minrtt +infinity)) (timer probe))
+        (when true
+            (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (fallthrough)
+        )
+        (when (> probe 10000000)
+            (:= probe 0)
+            (report)
+        )
+        (when (> Micros Flow.rtt_sample_us)
+            (:= Micros 0)
+            (report)
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_capabilities(foo, Capabilities::new(0).with_timers(2)).unwrap();
+        let probe = sc.get("probe").unwrap().clone();
+        assert_eq!(probe, Reg::Implicit(6, Type::Num(None)));
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert!(b.instrs.contains(&Instr { res: probe.clone(), op: Op::Bind, left: probe.clone(), right: Reg::ImmNum(0) }));
+        b.serialize().unwrap();
+
+        // the datapath provides no timers by default
+        match Prog::new_with_scope(foo) {
+            Ok(_) => panic!("declared a timer the datapath does not provide"),
+            Err(e) => assert_eq!(e.0, "cannot declare timer \"probe\": the datapath supports 0 timers besides Micros"),
+        }
+    }
+
+    #[test]
+    fn register_limits() {
+        // only fields the program uses are stored in the datapath
+        let foo = b"
+        (def (Report (volatile acked 0) (constant 7) (flag true) (volatile rtt 0)))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.rtt Flow.rtt_sample_us)
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(sc.get("Report.acked").unwrap().clone(), Reg::Report(0, Type::Num(Some(0)), true));
+        assert_eq!(sc.get("Report.constant").unwrap().clone(), Reg::ImmNum(7));
+        assert_eq!(sc.get("Report.flag").unwrap().clone(), Reg::ImmBool(true));
+        assert_eq!(sc.get("Report.rtt").unwrap().clone(), Reg::Report(1, Type::Num(Some(0)), true));
+
+        let fields: Vec<String> = (0..17).map(|i| format!("(volatile f{} 0)", i)).collect();
+        let uses: Vec<String> = (0..17).map(|i| format!("(:= Report.f{} Ack.now)", i)).collect();
+        let foo = format!("(def (Report {})) (when true {})", fields.join(" "), uses.join(" "));
+        let (p, mut sc) = Prog::new_with_scope(foo.as_bytes()).unwrap();
+        match Bin::compile_prog(&p, &mut sc) {
+            Ok(_) => panic!("compiled a program with 17 Report fields"),
+            Err(e) => assert!(e.0.starts_with(
+                "too many Report fields: the datapath supports 16, but the program uses 17: Report.f0, Report.f1,"
+            ), "{}", e),
+        }
+
+        // evaluating (+ (* Ack.now 2) e) holds the product in a register while e is evaluated,
+        // so each level of nesting needs another register
+        let nested = |n| (0..n).fold(String::from("Ack.now"), |e, _| format!("(+ (* Ack.now 2) {})", e));
+        let foo = format!("(def (Report.x 0)) (when true (:= Report.x {}) (:= Report.x {}))", nested(16), nested(16));
+        let (p, mut sc) = Prog::new_with_scope(foo.as_bytes()).unwrap();
+        Bin::compile_prog(&p, &mut sc).unwrap().serialize().unwrap();
+
+        let foo = format!("(def (Report.x 0)) (when true (:= Report.x {}))", nested(17));
+        let (p, mut sc) = Prog::new_with_scope(foo.as_bytes()).unwrap();
+        match Bin::compile_prog(&p, &mut sc) {
+            Ok(_) => panic!("compiled an expression needing 17 temporaries"),
+            Err(e) => assert!(e.0.starts_with("expression too complex: it needs 17 temporary registers"), "{}", e),
+        }
+    }
+
+    #[test]
+    fn fixed_literal_limits() {
+        // the largest literal the parser accepts, just under 2^48, rounds up to 2^48
+        let foo = b"(def (Report.x 0)) (when true (:= Report.x 281474976710655.9) (report))";
+        let (bin, _) = ::lang::compile(foo, &[]).unwrap();
+        assert!(bin.instrs.iter().any(|i| i.right == Reg::ImmNum(1 << 48)));
+
+        for foo in &[
+            &b"(def (Report.x 0.5)) (when true (:= Report.x (* Report.x 281474976710655.9)) (report))"[..],
+            &b"(def (Report.x 0.5)) (when true (:= Report.x (/ Report.x 281474976710655.9)) (report))"[..],
+            &b"(def (Report.x 0)) (when true (:= Report.x (+ Report.x 281474976710655.9)) (report))"[..],
+            &b"(def (Report.x 281474976710655.9)) (when true (:= Cwnd Report.x) (report))"[..],
+            &b"(def (Report.x 0)) (when true (:= Report.x 281474976710656.0) (report))"[..],
+        ] {
+            let _ = ::lang::compile(foo, &[]);
+        }
+    }
+
+    #[test]
+    fn fixed_point() {
+        let foo = b"
+        (def (Report (volatile ratio 0.0)) (beta 0.7))
+        (when true
+            (:= Cwnd (* Cwnd beta))
+            (:= Report.ratio (/ Ack.bytes_misordered Ack.bytes_acked))
+            (:= Report.ratio (* Report.ratio 0.5))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let cwnd_reg = sc.get("Cwnd").unwrap().clone();
+        let beta_reg = sc.get("beta").unwrap().clone();
+        let ratio_reg = sc.get("Report.ratio").unwrap().clone();
+        let misordered_reg = sc.get("Ack.bytes_misordered").unwrap().clone();
+        let acked_reg = sc.get("Ack.bytes_acked").unwrap().clone();
+        let num_tmp = |i| Reg::Tmp(i, Type::Num(None));
+        let fixed_tmp = |i| Reg::Tmp(i, Type::Fixed(None));
+        assert_eq!(beta_reg, Reg::Control(0, Type::Fixed(Some(45875))));
+        assert_eq!(ratio_reg, Reg::Report(0, Type::Fixed(Some(0)), true));
+
+        assert_eq!(
+            b.instrs,
+            vec![
+                Instr { res: ratio_reg.clone(), op: Op::Def, left: ratio_reg.clone(), right: Reg::ImmNum(0) },
+                Instr { res: beta_reg.clone(), op: Op::Def, left: beta_reg.clone(), right: Reg::ImmNum(45875) },
+                Instr { res: sc.get("__eventFlag").unwrap().clone(), op: Op::Bind, left: sc.get("__eventFlag").unwrap().clone(), right: Reg::ImmBool(true) },
+                // Num * Fixed is exact, then rounded to a Num: (x + 2^15) / 2^16
+                Instr { res: fixed_tmp(0), op: Op::Mul, left: cwnd_reg.clone(), right: beta_reg.clone() },
+                Instr { res: num_tmp(0), op: Op::Add, left: fixed_tmp(0), right: Reg::ImmNum(1 << 15) },
+                Instr { res: num_tmp(0), op: Op::Div, left: num_tmp(0), right: Reg::ImmNum(1 << 16) },
+                Instr { res: cwnd_reg.clone(), op: Op::Bind, left: cwnd_reg.clone(), right: num_tmp(0) },
+                // Num / Num assigned to a Fixed is rounded: (a * 2^16 + b / 2) / b
+                Instr { res: num_tmp(0), op: Op::Mul, left: misordered_reg.clone(), right: Reg::ImmNum(1 << 16) },
+                Instr { res: num_tmp(1), op: Op::Div, left: acked_reg.clone(), right: Reg::ImmNum(2) },
+                Instr { res: num_tmp(0), op: Op::Add, left: num_tmp(0), right: num_tmp(1) },
+                Instr { res: fixed_tmp(0), op: Op::Div, left: num_tmp(0), right: acked_reg.clone() },
+                Instr { res: ratio_reg.clone(), op: Op::Bind, left: ratio_reg.clone(), right: fixed_tmp(0) },
+                // Fixed * Fixed drops the extra fractional bits, rounding
+                Instr { res: num_tmp(0), op: Op::Mul, left: ratio_reg.clone(), right: Reg::ImmFixed(1 << 15) },
+                Instr { res: num_tmp(0), op: Op::Add, left: num_tmp(0), right: Reg::ImmNum(1 << 15) },
+                Instr { res: fixed_tmp(0), op: Op::Div, left: num_tmp(0), right: Reg::ImmNum(1 << 16) },
+                Instr { res: ratio_reg.clone(), op: Op::Bind, left: ratio_reg.clone(), right: fixed_tmp(0) },
+            ],
+        );
+        b.serialize().unwrap();
+
+        // fixed-point values are not booleans, and do not support bitwise operators
+        let foo = b"(def (Report.x 0.5)) (when true (:= Report.x (% Report.x 0.25)))";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+        let foo = b"(def (Report.x 0.5)) (when (&& Report.x true) (report))";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+    }
+
+    #[test]
+    fn histogram() {
+        let foo = b"
+        (def (Report (volatile acked 0) (volatile rtts (hist 100 200 400))))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (observe Report.rtts Flow.rtt_sample_us)
+        )
+        ";
+        let (bin, sc) = ::lang::compile(foo, &[]).unwrap();
+        assert_eq!(sc.histogram("Report.rtts"), Some(&[100, 200, 400][..]));
+        bin.serialize().unwrap();
+
+        let mut m = ::lang::interp::Machine::new(&bin, &sc, 0);
+        let mut prims = vec![0; 15];
+        for (now, &rtt) in [50, 99, 100, 250, 399, 400, 1000].iter().enumerate() {
+            prims[13] = rtt;
+            m.on_ack(&prims, now as u64).unwrap();
+        }
+
+        // the buckets are a run of Report fields, after or before the other fields
+        let fields: Vec<u64> = (0..sc.num_perm).map(|i| m.get(&Reg::Report(i, Type::Num(None), true)).unwrap()).collect();
+        let m = ::serialize::measure::Msg { sid: 1, program_uid: sc.program_uid, num_fields: fields.len() as u8, fields };
+        let r = ::Report { program_uid: sc.program_uid, fields: m.into() };
+        assert_eq!(r.get_histogram("Report.rtts", &sc).unwrap().collect::<Vec<_>>(), vec![2, 1, 2, 2]);
+        assert!(r.get_histogram("Report.acked", &sc).is_err());
+
+        for foo in &[
+            &b"(def (Report (h (hist 200 100)))) (when true (observe Report.h 1))"[..],
+            &b"(def (Report (h (hist 100)) (x 0))) (when true (:= Report.x (observe Report.h 1)))"[..],
+            &b"(def (Report (h (hist 100)) (x 0))) (when true (:= Report.x Report.h))"[..],
+            &b"(def (Report (h (hist 100)) (x 0))) (when true (observe Report.x 1))"[..],
+            &b"(def (Report (h (hist 100)))) (when true (observe Report.h true))"[..],
+        ] {
+            assert!(::lang::compile(foo, &[]).is_err(), "{}", String::from_utf8_lossy(foo));
+        }
+    }
+
+    #[test]
+    fn register_mapping() {
+        let slow_start = b"
+            (def (Report (persistent minrtt +infinity) (volatile acked 0)) (persistent rounds 0) (persistent gain true))
+            (when true (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us)) (:= rounds (+ rounds 1)) (:= gain false))
+        ";
+        let cong_avoid = b"
+            (def (Report (acked 0) (persistent minrtt +infinity)) (persistent gain 1) (persistent rounds 0) (persistent unused 0))
+            (when true (:= Report.acked Report.minrtt) (:= rounds (+ rounds gain)))
+        ";
+        let (_, ss) = ::lang::compile(slow_start, &[]).unwrap();
+        let (_, ca) = ::lang::compile(cong_avoid, &[]).unwrap();
+
+        // gain changes type, and unused is neither used nor declared by slow_start
+        let m = ca.register_mapping(&ss);
+        assert_eq!(m, vec![
+            (ss.get("Report.minrtt").unwrap().clone(), ca.get("Report.minrtt").unwrap().clone()),
+            (ss.get("rounds").unwrap().clone(), ca.get("rounds").unwrap().clone()),
+        ]);
+
+        // only the new program's persistent variables carry over
+        assert_eq!(ss.register_mapping(&ca).len(), 2);
+        assert!(::lang::Scope::new().register_mapping(&ss).is_empty());
+    }
+
+    #[test]
+    fn signed_ints() {
+        use lang::compile_with_capabilities;
+        let v2 = Capabilities::new(2);
+
+        // Ints need datapath version 2, and so does checked_sub; sat_sub on Nums is lowered
+        assert!(compile_with_capabilities(b"(def (Report.x -1)) (when true (report))", &[], Capabilities::new(1)).is_err());
+        assert!(compile_with_capabilities(b"(def (Report.x 0)) (when true (:= Report.x (num -1)))", &[], Capabilities::new(1)).is_err());
+        assert!(compile_with_capabilities(b"(def (Report.x 0)) (when true (:= Report.x (checked_sub Report.x 1)))", &[], Capabilities::new(1)).is_err());
+        let (b, _) = compile_with_capabilities(b"(def (Report.x 0)) (when true (:= Report.x (sat_sub Report.x 1)))", &[], Capabilities::default()).unwrap();
+        assert!(b.instrs.iter().all(|i| i.op != Op::SatSub));
+
+        // signed comparisons, and conversions in both directions
+        let foo = b"(def (Report (x -5) (y 0))) (when (< Report.x (int Report.y)) (:= Report.y (num Report.x)))";
+        let (b, sc) = compile_with_capabilities(foo, &[], v2.clone()).unwrap();
+        let x = sc.get("Report.x").unwrap().clone();
+        assert_eq!(x, Reg::Report(0, Type::Int(Some(-5)), false));
+        assert_eq!(b.instrs[0], Instr { res: x.clone(), op: Op::Def, left: x.clone(), right: Reg::ImmInt(-5) });
+        assert!(b.instrs.iter().any(|i| i.op == Op::LtInt));
+        assert!(b.instrs.iter().any(|i| i.op == Op::MaxInt && i.right == Reg::ImmInt(0)));
+
+        // negative immediates are sign-extended from 32 bits
+        let bytes = b.serialize().unwrap();
+        assert_eq!(&bytes[16..16 + 16], &[2, 6, 0, 0, 0, 0, 6, 0, 0, 0, 0, 8, 0xfb, 0xff, 0xff, 0xff]);
+        let (b, _) = compile_with_capabilities(b"(def (Report.x -4294967296)) (when true (:= Report.x (- Report.x +1)))", &[], v2.clone()).unwrap();
+        assert!(b.serialize().is_err());
+
+        for foo in &[
+            // Ints and Nums do not mix without a conversion
+            &b"(def (Report (x -5) (y 0))) (when true (:= Report.y Report.x))"[..],
+            &b"(def (Report (x -5) (y 0))) (when true (:= Report.x (+ Report.x Report.y)))"[..],
+            &b"(def (Report (x -5) (y 0))) (when (< Report.x 3) (report))"[..],
+            // nor do operators whose unsigned forms would give the wrong answer
+            &b"(def (Report (x -5))) (when true (:= Report.x (/ Report.x +2)))"[..],
+            &b"(def (Report (x -5))) (when true (:= Report.x (>> Report.x +1)))"[..],
+            &b"(def (Report (x 0.5))) (when true (:= Report.x (int Report.x)))"[..],
+        ] {
+            assert!(compile_with_capabilities(foo, &[], v2.clone()).is_err(), "{}", String::from_utf8_lossy(foo));
+        }
+    }
+
+    #[test]
+    fn custom_primitives() {
+        use super::Primitives;
+
+        let mut primitives = Primitives::default();
+        assert_eq!(primitives.register("Ack.delivery_rate", Type::Num(Some(3)), "Delivery rate sample.").unwrap(), 15);
+        assert!(primitives.register("Ack.delivery_rate", Type::Num(None), "").is_err());
+        assert!(primitives.register("Report.foo", Type::Num(None), "").is_err());
+        assert!(primitives.register("Flow.pacing_rate", Type::Num(None), "").is_err());
+
+        let foo = b"
+        (def (Report (volatile rate 0)))
+        (when true
+            (:= Report.rate Ack.delivery_rate)
+        )
+        ";
+        let caps = Capabilities { primitives, ..Capabilities::new(0) };
+        let (p, mut sc) = Prog::new_with_capabilities(foo, caps).unwrap();
+        assert_eq!(sc.get("Ack.delivery_rate").unwrap().clone(), Reg::Primitive(15, Type::Num(None)));
+        Bin::compile_prog(&p, &mut sc).unwrap().serialize().unwrap();
+
+        // datapaths may provide a different set of primitives altogether
+        let mut primitives = Primitives::empty();
+        primitives.register("Flow.pacing_rate", Type::Num(None), "Pacing rate, in bytes per second.").unwrap();
+        let caps = Capabilities { primitives, ..Capabilities::new(0) };
+        let (_, sc) = Prog::new_with_capabilities(foo, caps).unwrap();
+        assert_eq!(sc.get("Flow.pacing_rate").unwrap().clone(), Reg::Primitive(0, Type::Num(None)));
+        assert!(sc.get("Ack.bytes_acked").is_none());
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let err = Bin::compile_prog(&p, &mut sc).unwrap_err();
+        assert_eq!(err.0, "unknown primitive \"Ack.delivery_rate\": the datapath does not provide it");
+
+        let foo = b"(def (Report.acked 0)) (when true (:= Report.acked Ack.bytes_ackd))";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let err = Bin::compile_prog(&p, &mut sc).unwrap_err();
+        assert_eq!(err.0, "unknown primitive \"Ack.bytes_ackd\", did you mean \"Ack.bytes_acked\"?");
+    }
 }
+
@@ -0,0 +1,819 @@
+//! Compile datapath programs to eBPF bytecode.
+//!
+//! `compile()` turns a compiled program into a BPF program to run on each ack, e.g. from a
+//! `struct_ops` congestion control. It uses two maps, which the loader creates and patches into
+//! the instructions listed in `Program::map_relocs`:
+//!
+//! - `Map::State`, a hash map from a `u64` flow id to the flow's registers, laid out as described
+//!   by `Program::layout`. The loader inserts `Program::initial_state()` when a flow starts, and
+//!   writes control registers to update fields.
+//! - `Map::Reports`, a ring buffer. Each report is a record of `u64`s: the flow id, then the
+//!   `Report` fields in register order.
+//!
+//! The program's context is a struct of `u64`s: the flow id, the time in microseconds, then the
+//! measurement primitives in register order (see `CTX_FLOW_ID`, `CTX_NOW`, `CTX_PRIMITIVES`). It
+//! returns -1 if the program divided by zero or the flow has no state, and otherwise a bitmask of
+//! `ACTION_REPORT`, `ACTION_CWND` and `ACTION_RATE`; the new congestion window and rate are in the
+//! flow's implicit registers. Its semantics are those of `lang::interp::Machine`.
+//!
+//! The output is raw bytecode for `BPF_PROG_LOAD`, not an ELF object: map definitions and BTF are
+//! up to the loader.
+
+use super::{Error, Result};
+use super::ast::Op;
+use super::datapath::{Bin, Instr, Reg, Scope};
+use super::interp::{
+    self, RegCounts, CWND_REG, EVENT_FLAG_REG, RATE_REG, SHOULD_CONTINUE_REG, SHOULD_REPORT_REG,
+};
+
+/// Byte offsets of the fields of the program's context.
+pub const CTX_FLOW_ID: i16 = 0;
+pub const CTX_NOW: i16 = 8;
+pub const CTX_PRIMITIVES: i16 = 16;
+
+/// Bits of the program's return value.
+pub const ACTION_REPORT: u64 = 1;
+pub const ACTION_CWND: u64 = 2;
+pub const ACTION_RATE: u64 = 4;
+
+/// BPF helper function ids.
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_RINGBUF_OUTPUT: i32 = 130;
+
+/// Instruction classes and fields, from `linux/bpf.h`.
+pub(crate) mod opcode {
+    pub const LD_IMM64: u8 = 0x18;
+    pub const LDX_DW: u8 = 0x79;
+    pub const ST_DW: u8 = 0x7a;
+    pub const STX_DW: u8 = 0x7b;
+
+    pub const ALU64: u8 = 0x07;
+    pub const JMP: u8 = 0x05;
+    /// Source operand is a register rather than the immediate.
+    pub const X: u8 = 0x08;
+
+    pub const ADD: u8 = 0x00;
+    pub const SUB: u8 = 0x10;
+    pub const MUL: u8 = 0x20;
+    pub const DIV: u8 = 0x30;
+    pub const OR: u8 = 0x40;
+    pub const AND: u8 = 0x50;
+    pub const LSH: u8 = 0x60;
+    pub const RSH: u8 = 0x70;
+    pub const MOD: u8 = 0x90;
+    pub const XOR: u8 = 0xa0;
+    pub const MOV: u8 = 0xb0;
+
+    pub const JA: u8 = 0x00;
+    pub const JEQ: u8 = 0x10;
+    pub const JGT: u8 = 0x20;
+    pub const JGE: u8 = 0x30;
+    pub const JNE: u8 = 0x50;
+    pub const JSGT: u8 = 0x60;
+    pub const JSGE: u8 = 0x70;
+    pub const CALL: u8 = 0x80;
+    pub const EXIT: u8 = 0x90;
+    pub const JLT: u8 = 0xa0;
+    pub const JLE: u8 = 0xb0;
+    pub const JSLT: u8 = 0xc0;
+    pub const JSLE: u8 = 0xd0;
+
+    /// `src` of an `LD_IMM64` whose immediate is a map file descriptor.
+    pub const PSEUDO_MAP_FD: u8 = 1;
+}
+
+use self::opcode::*;
+
+// register use: r0-r5 are scratch, r6 holds the context, r7 the flow's registers and r8 the time.
+const CTX: u8 = 6;
+const STATE: u8 = 7;
+const NOW: u8 = 8;
+const FP: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A single eBPF instruction.
+pub struct Insn {
+    pub code: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    /// The instruction's encoding, as the kernel expects it on a little-endian machine.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let off = self.off.to_le_bytes();
+        let imm = self.imm.to_le_bytes();
+        [self.code, (self.src << 4) | self.dst, off[0], off[1], imm[0], imm[1], imm[2], imm[3]]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The maps a program uses.
+pub enum Map {
+    State,
+    Reports,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Where each kind of register is in a flow's state, as indices of `u64`s.
+pub struct Layout {
+    pub report: usize,
+    pub control: usize,
+    pub local: usize,
+    pub implicit: usize,
+    pub time_zero: usize,
+    /// The number of `u64`s in a flow's state.
+    pub len: usize,
+    pub num_report: usize,
+}
+
+impl Layout {
+    fn new(counts: &RegCounts, num_timers: usize) -> Self {
+        let report = 0;
+        let control = report + counts.report;
+        let local = control + counts.control;
+        let implicit = local + counts.local;
+        let time_zero = implicit + counts.implicit;
+        Layout { report, control, local, implicit, time_zero, len: time_zero + num_timers, num_report: counts.report }
+    }
+
+    /// The index of register `r` in a flow's state.
+    pub fn index(&self, r: &Reg) -> Option<usize> {
+        match *r {
+            Reg::Report(i, _, _) => Some(self.report + i as usize),
+            Reg::Control(i, _) => Some(self.control + i as usize),
+            Reg::Local(i, _) => Some(self.local + i as usize),
+            Reg::Implicit(i, _) => Some(self.implicit + i as usize),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// A datapath program compiled to eBPF.
+pub struct Program {
+    pub insns: Vec<Insn>,
+    /// Indices of the `LD_IMM64` instructions whose immediate must be set to a map's file
+    /// descriptor before loading.
+    pub map_relocs: Vec<(usize, Map)>,
+    pub layout: Layout,
+    defs: Vec<(usize, u64)>,
+}
+
+impl Program {
+    /// The program as raw bytecode.
+    pub fn bytecode(&self) -> Vec<u8> {
+        self.insns.iter().flat_map(|i| i.to_bytes().to_vec()).collect()
+    }
+
+    /// The registers of a flow whose program is installed at time `now`.
+    pub fn initial_state(&self, now: u64) -> Vec<u64> {
+        let mut state = vec![0; self.layout.len];
+        for &(idx, v) in &self.defs {
+            state[idx] = v;
+        }
+
+        for z in &mut state[self.layout.time_zero..] {
+            *z = now;
+        }
+
+        state
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Label(usize);
+
+/// Emits instructions, resolving jumps to labels once every label is placed.
+struct Asm {
+    insns: Vec<Insn>,
+    labels: Vec<Option<usize>>,
+    jumps: Vec<(usize, Label)>,
+    map_relocs: Vec<(usize, Map)>,
+}
+
+impl Asm {
+    fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.insns.push(Insn { code, dst, src, off, imm });
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn place(&mut self, l: Label) {
+        self.labels[l.0] = Some(self.insns.len());
+    }
+
+    fn jump(&mut self, l: Label) {
+        self.jumps.push((self.insns.len(), l));
+        self.emit(JMP | JA, 0, 0, 0, 0);
+    }
+
+    /// `if dst <op> src goto l`.
+    fn jump_reg(&mut self, op: u8, dst: u8, src: u8, l: Label) {
+        self.jumps.push((self.insns.len(), l));
+        self.emit(JMP | X | op, dst, src, 0, 0);
+    }
+
+    /// `if dst <op> imm goto l`.
+    fn jump_imm(&mut self, op: u8, dst: u8, imm: i32, l: Label) {
+        self.jumps.push((self.insns.len(), l));
+        self.emit(JMP | op, dst, 0, 0, imm);
+    }
+
+    fn alu_reg(&mut self, op: u8, dst: u8, src: u8) {
+        self.emit(ALU64 | X | op, dst, src, 0, 0);
+    }
+
+    fn alu_imm(&mut self, op: u8, dst: u8, imm: i32) {
+        self.emit(ALU64 | op, dst, 0, 0, imm);
+    }
+
+    fn mov_imm64(&mut self, dst: u8, v: u64) {
+        if v <= i32::max_value() as u64 {
+            self.alu_imm(MOV, dst, v as i32);
+        } else {
+            self.emit(LD_IMM64, dst, 0, 0, v as u32 as i32);
+            self.emit(0, 0, 0, 0, (v >> 32) as u32 as i32);
+        }
+    }
+
+    fn load_map(&mut self, dst: u8, map: Map) {
+        self.map_relocs.push((self.insns.len(), map));
+        self.emit(LD_IMM64, dst, PSEUDO_MAP_FD, 0, 0);
+        self.emit(0, 0, 0, 0, 0);
+    }
+
+    fn load(&mut self, dst: u8, base: u8, off: i16) {
+        self.emit(LDX_DW, dst, base, off, 0);
+    }
+
+    fn store(&mut self, base: u8, off: i16, src: u8) {
+        self.emit(STX_DW, base, src, off, 0);
+    }
+
+    fn store_imm(&mut self, base: u8, off: i16, imm: i32) {
+        self.emit(ST_DW, base, 0, off, imm);
+    }
+
+    fn call(&mut self, helper: i32) {
+        self.emit(JMP | CALL, 0, 0, 0, helper);
+    }
+
+    fn finish(mut self) -> Result<(Vec<Insn>, Vec<(usize, Map)>)> {
+        for &(at, l) in &self.jumps {
+            let target = self.labels[l.0].ok_or_else(|| Error::from("jump to unplaced label"))?;
+            let off = target as i64 - at as i64 - 1;
+            if off < i64::from(i16::min_value()) || off > i64::from(i16::max_value()) {
+                return Err(Error::from("program too long for eBPF jumps"));
+            }
+
+            self.insns[at].off = off as i16;
+        }
+
+        Ok((self.insns, self.map_relocs))
+    }
+}
+
+/// Stack slots, as offsets from the frame pointer.
+const STACK_KEY: i16 = -8;
+const STACK_CWND: i16 = -16;
+const STACK_RATE: i16 = -24;
+fn stack_tmp(i: u8) -> i16 {
+    -32 - 8 * i16::from(i)
+}
+
+struct Compiler {
+    asm: Asm,
+    layout: Layout,
+    err: Label,
+}
+
+impl Compiler {
+    fn state_off(&self, r: &Reg) -> Result<i16> {
+        self.layout.index(r)
+            .map(|i| 8 * i as i16)
+            .ok_or_else(|| Error::from(format!("not a state register: {:?}", r)))
+    }
+
+    fn implicit_off(&self, i: usize) -> i16 {
+        8 * (self.layout.implicit + i) as i16
+    }
+
+    /// Load the value of `r` into the BPF register `dst`.
+    fn read(&mut self, dst: u8, r: &Reg) -> Result<()> {
+        match *r {
+            Reg::Primitive(i, _) => self.asm.load(dst, CTX, CTX_PRIMITIVES + 8 * i16::from(i)),
+            Reg::Tmp(i, _) => self.asm.load(dst, FP, stack_tmp(i)),
+            Reg::None => self.asm.mov_imm64(dst, 0),
+            ref r => match interp::imm(r) {
+                Some(v) => self.asm.mov_imm64(dst, v),
+                None => {
+                    let off = self.state_off(r)?;
+                    self.asm.load(dst, STATE, off);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Store the BPF register `src` in `r`.
+    fn write(&mut self, r: &Reg, src: u8) -> Result<()> {
+        match *r {
+            Reg::Tmp(i, _) => self.asm.store(FP, stack_tmp(i), src),
+            ref r => {
+                let off = self.state_off(r)?;
+                self.asm.store(STATE, off, src);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `r2 = r2 <op> r3` for a comparison `op`, as 0 or 1.
+    fn compare(&mut self, op: u8) {
+        let (t, end) = (self.asm.label(), self.asm.label());
+        self.asm.jump_reg(op, 2, 3, t);
+        self.asm.alu_imm(MOV, 2, 0);
+        self.asm.jump(end);
+        self.asm.place(t);
+        self.asm.alu_imm(MOV, 2, 1);
+        self.asm.place(end);
+    }
+
+    /// `r2 = r2 <op> r3` for a shift `op`, which yields 0 for shifts by 64 or more.
+    fn shift(&mut self, op: u8) {
+        let (zero, end) = (self.asm.label(), self.asm.label());
+        self.asm.jump_imm(JGE, 3, 64, zero);
+        self.asm.alu_reg(op, 2, 3);
+        self.asm.jump(end);
+        self.asm.place(zero);
+        self.asm.alu_imm(MOV, 2, 0);
+        self.asm.place(end);
+    }
+
+    /// `r2 = r3` unless `r2 <op> r3`.
+    fn select(&mut self, op: u8) {
+        let keep = self.asm.label();
+        self.asm.jump_reg(op, 2, 3, keep);
+        self.asm.alu_reg(MOV, 2, 3);
+        self.asm.place(keep);
+    }
+
+    /// `r4 = r2 - r3`, jumping to `l` unless the subtraction overflows as a signed one, which it
+    /// does if the operands have different signs and the result's differs from `r2`'s.
+    fn int_sub(&mut self, l: Label) {
+        self.asm.alu_reg(MOV, 4, 2);
+        self.asm.alu_reg(SUB, 4, 3);
+        self.asm.alu_reg(MOV, 0, 2);
+        self.asm.alu_reg(XOR, 0, 3);
+        self.asm.alu_reg(MOV, 5, 2);
+        self.asm.alu_reg(XOR, 5, 4);
+        self.asm.alu_reg(AND, 0, 5);
+        self.asm.jump_imm(JSGE, 0, 0, l);
+    }
+
+    fn instr(&mut self, i: &Instr) -> Result<()> {
+        if i.op == Op::Def {
+            return Ok(());
+        }
+
+        self.read(2, &i.left)?;
+        self.read(3, &i.right)?;
+        match i.op {
+            Op::Bind => return self.write(&i.res, 3),
+            Op::If | Op::NotIf => {
+                let skip = self.asm.label();
+                self.asm.jump_imm(if i.op == Op::If { JEQ } else { JNE }, 2, 0, skip);
+                self.write(&i.res, 3)?;
+                self.asm.place(skip);
+                return Ok(());
+            }
+            Op::Ewma => {
+                // (old * l + r * (10 - l)) / 10
+                self.read(4, &i.res)?;
+                self.asm.alu_reg(MUL, 4, 2);
+                self.asm.alu_imm(MOV, 0, 10);
+                self.asm.alu_reg(SUB, 0, 2);
+                self.asm.alu_reg(MUL, 0, 3);
+                self.asm.alu_reg(ADD, 4, 0);
+                self.asm.alu_imm(DIV, 4, 10);
+                return self.write(&i.res, 4);
+            }
+            Op::Add => self.asm.alu_reg(ADD, 2, 3),
+            Op::Sub => self.asm.alu_reg(SUB, 2, 3),
+            Op::Mul => self.asm.alu_reg(MUL, 2, 3),
+            Op::Div | Op::Mod => {
+                // BPF defines division by zero, but datapath programs do not
+                let err = self.err;
+                self.asm.jump_imm(JEQ, 3, 0, err);
+                self.asm.alu_reg(if i.op == Op::Div { DIV } else { MOD }, 2, 3);
+            }
+            Op::Shl => self.shift(LSH),
+            Op::Shr => self.shift(RSH),
+            Op::BitAnd => self.asm.alu_reg(AND, 2, 3),
+            Op::BitOr => self.asm.alu_reg(OR, 2, 3),
+            Op::AbsDiff => {
+                let (gt, end) = (self.asm.label(), self.asm.label());
+                self.asm.jump_reg(JGT, 2, 3, gt);
+                self.asm.alu_reg(SUB, 3, 2);
+                self.asm.alu_reg(MOV, 2, 3);
+                self.asm.jump(end);
+                self.asm.place(gt);
+                self.asm.alu_reg(SUB, 2, 3);
+                self.asm.place(end);
+            }
+            Op::Max => self.select(JGE),
+            Op::Min => self.select(JLE),
+            Op::MaxInt => self.select(JSGE),
+            Op::MinInt => self.select(JSLE),
+            // max(a, b) - b
+            Op::SatSub => {
+                self.select(JGE);
+                self.asm.alu_reg(SUB, 2, 3);
+            }
+            Op::CheckedSub => {
+                let err = self.err;
+                self.asm.jump_reg(JGT, 3, 2, err);
+                self.asm.alu_reg(SUB, 2, 3);
+            }
+            Op::SatSubInt => {
+                // on overflow, the result is the minimum if a is negative and the maximum if not
+                let done = self.asm.label();
+                self.int_sub(done);
+                self.asm.alu_imm(MOV, 4, 1);
+                self.asm.alu_imm(LSH, 4, 63);
+                self.asm.jump_imm(JSLT, 2, 0, done);
+                self.asm.alu_imm(SUB, 4, 1);
+                self.asm.place(done);
+                self.asm.alu_reg(MOV, 2, 4);
+            }
+            Op::CheckedSubInt => {
+                let (done, err) = (self.asm.label(), self.err);
+                self.int_sub(done);
+                self.asm.jump(err);
+                self.asm.place(done);
+                self.asm.alu_reg(MOV, 2, 4);
+            }
+            Op::MaxWrap => {
+                let keep = self.asm.label();
+                self.asm.alu_reg(MOV, 4, 2);
+                self.asm.alu_reg(SUB, 4, 3);
+                self.asm.jump_imm(JSGE, 4, 0, keep);
+                self.asm.alu_reg(MOV, 2, 3);
+                self.asm.place(keep);
+            }
+            Op::Equiv => self.compare(JEQ),
+            Op::Neq => self.compare(JNE),
+            Op::Gt => self.compare(JGT),
+            Op::Gte => self.compare(JGE),
+            Op::Lt => self.compare(JLT),
+            Op::Lte => self.compare(JLE),
+            Op::GtInt => self.compare(JSGT),
+            Op::GteInt => self.compare(JSGE),
+            Op::LtInt => self.compare(JSLT),
+            Op::LteInt => self.compare(JSLE),
+            Op::And | Op::Or => {
+                // And: r0 = 0, and 1 if neither is 0. Or: r0 = 1, and 0 if both are 0.
+                let (start, test, end) = if i.op == Op::And { (0, JEQ, 1) } else { (1, JNE, 0) };
+                let done = self.asm.label();
+                self.asm.alu_imm(MOV, 0, start);
+                self.asm.jump_imm(test, 2, 0, done);
+                self.asm.jump_imm(test, 3, 0, done);
+                self.asm.alu_imm(MOV, 0, end);
+                self.asm.place(done);
+                self.asm.alu_reg(MOV, 2, 0);
+            }
+            Op::Not => {
+                self.asm.alu_imm(MOV, 3, 0);
+                self.compare(JEQ);
+            }
+            Op::Def | Op::Observe | Op::ToInt | Op::ToNum => unreachable!(),
+        }
+
+        self.write(&i.res, 2)
+    }
+}
+
+/// Compile the program `bin`, compiled with `scope`, to eBPF.
+pub fn compile(bin: &Bin, scope: &Scope) -> Result<Program> {
+    let counts = RegCounts::of(bin, scope);
+    let timers = counts.timers();
+    let layout = Layout::new(&counts, timers.len());
+    let record = stack_tmp(counts.tmp as u8) - 8 * counts.report as i16;
+    if record < -512 {
+        return Err(Error::from("program needs more than the 512 bytes of eBPF stack"));
+    }
+
+    let mut asm = Asm { insns: vec![], labels: vec![], jumps: vec![], map_relocs: vec![] };
+    let err = asm.label();
+    let mut c = Compiler { asm, layout, err };
+
+    // look up the flow's registers
+    c.asm.alu_reg(MOV, CTX, 1);
+    c.asm.load(1, CTX, CTX_FLOW_ID);
+    c.asm.store(FP, STACK_KEY, 1);
+    c.asm.load_map(1, Map::State);
+    c.asm.alu_reg(MOV, 2, FP);
+    c.asm.alu_imm(ADD, 2, i32::from(STACK_KEY));
+    c.asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    c.asm.jump_imm(JEQ, 0, 0, err);
+    c.asm.alu_reg(MOV, STATE, 0);
+
+    let (cwnd, rate) = (c.implicit_off(CWND_REG), c.implicit_off(RATE_REG));
+    c.asm.load(1, STATE, cwnd);
+    c.asm.store(FP, STACK_CWND, 1);
+    c.asm.load(1, STATE, rate);
+    c.asm.store(FP, STACK_RATE, 1);
+
+    c.asm.load(NOW, CTX, CTX_NOW);
+    for (k, &t) in timers.iter().enumerate() {
+        c.asm.alu_reg(MOV, 1, NOW);
+        c.asm.load(2, STATE, 8 * (layout.time_zero + k) as i16);
+        c.asm.alu_reg(SUB, 1, 2);
+        let off = c.implicit_off(t);
+        c.asm.store(STATE, off, 1);
+    }
+
+    let done = c.asm.label();
+    let (flag, cont) = (c.implicit_off(EVENT_FLAG_REG), c.implicit_off(SHOULD_CONTINUE_REG));
+    for ev in &bin.events {
+        let next = c.asm.label();
+        c.asm.store_imm(STATE, flag, 0);
+        for i in &bin.instrs[ev.flag_idx as usize..(ev.flag_idx + ev.num_flag_instrs) as usize] {
+            c.instr(i)?;
+        }
+
+        c.asm.load(1, STATE, flag);
+        c.asm.jump_imm(JEQ, 1, 0, next);
+        c.asm.store_imm(STATE, cont, 0);
+        for i in &bin.instrs[ev.body_idx as usize..(ev.body_idx + ev.num_body_instrs) as usize] {
+            c.instr(i)?;
+        }
+
+        c.asm.load(1, STATE, cont);
+        c.asm.jump_imm(JEQ, 1, 0, done);
+        c.asm.place(next);
+    }
+
+    c.asm.place(done);
+    for (k, &t) in timers.iter().enumerate() {
+        c.asm.alu_reg(MOV, 1, NOW);
+        let off = c.implicit_off(t);
+        c.asm.load(2, STATE, off);
+        c.asm.alu_reg(SUB, 1, 2);
+        c.asm.store(STATE, 8 * (layout.time_zero + k) as i16, 1);
+    }
+
+    // r9 holds the actions
+    c.asm.alu_imm(MOV, 9, 0);
+    let no_report = c.asm.label();
+    let should_report = c.implicit_off(SHOULD_REPORT_REG);
+    c.asm.load(1, STATE, should_report);
+    c.asm.jump_imm(JEQ, 1, 0, no_report);
+    c.asm.alu_imm(OR, 9, ACTION_REPORT as i32);
+    c.asm.store_imm(STATE, should_report, 0);
+    c.asm.load(1, CTX, CTX_FLOW_ID);
+    c.asm.store(FP, record, 1);
+    for i in 0..layout.num_report {
+        c.asm.load(1, STATE, 8 * (layout.report + i) as i16);
+        c.asm.store(FP, record + 8 * (i as i16 + 1), 1);
+    }
+
+    c.asm.load_map(1, Map::Reports);
+    c.asm.alu_reg(MOV, 2, FP);
+    c.asm.alu_imm(ADD, 2, i32::from(record));
+    c.asm.alu_imm(MOV, 3, 8 * (layout.num_report as i32 + 1));
+    c.asm.alu_imm(MOV, 4, 0);
+    c.asm.call(BPF_FUNC_RINGBUF_OUTPUT);
+    let volatile_defs = bin.instrs.iter().filter(|i| i.op == Op::Def).filter(|i| match i.left {
+        Reg::Report(_, _, is_volatile) => is_volatile,
+        _ => false,
+    });
+    for i in volatile_defs {
+        c.read(1, &i.right)?;
+        c.write(&i.left, 1)?;
+    }
+
+    c.asm.place(no_report);
+    for &(reg, saved, action) in &[(cwnd, STACK_CWND, ACTION_CWND), (rate, STACK_RATE, ACTION_RATE)] {
+        let same = c.asm.label();
+        c.asm.load(1, STATE, reg);
+        c.asm.load(2, FP, saved);
+        c.asm.jump_reg(JEQ, 1, 2, same);
+        c.asm.alu_imm(OR, 9, action as i32);
+        c.asm.place(same);
+    }
+
+    c.asm.alu_reg(MOV, 0, 9);
+    c.asm.emit(JMP | EXIT, 0, 0, 0, 0);
+    c.asm.place(err);
+    c.asm.alu_imm(MOV, 0, -1);
+    c.asm.emit(JMP | EXIT, 0, 0, 0, 0);
+
+    let defs = bin.instrs.iter()
+        .filter(|i| i.op == Op::Def)
+        .filter_map(|i| layout.index(&i.left).map(|idx| (idx, interp::imm(&i.right).unwrap_or(0))))
+        .collect();
+    let (insns, map_relocs) = c.asm.finish()?;
+    Ok(Program { insns, map_relocs, layout, defs })
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::{self, Capabilities};
+    use lang::interp::{Actions, CWND_REG, RATE_REG};
+    use lang::interp::tests::{describe, expected, random_acks, EXTENDED_OPS, GENERIC_CONG_AVOID, SIGNED_OPS, START};
+    use super::opcode::*;
+    use super::{Map, Program, ACTION_CWND, ACTION_RATE, ACTION_REPORT};
+
+    const CTX_BASE: u64 = 1 << 40;
+    const STACK_TOP: u64 = 2 << 40;
+    const STATE_BASE: u64 = 3 << 40;
+    const FLOW_ID: u64 = 42;
+
+    /// A user-space eBPF interpreter, for the subset of eBPF the compiler emits.
+    struct Vm<'a> {
+        prog: &'a Program,
+        ctx: Vec<u64>,
+        stack: Vec<u64>,
+        state: Vec<u64>,
+        records: Vec<Vec<u64>>,
+    }
+
+    impl<'a> Vm<'a> {
+        fn slot(&mut self, addr: u64) -> &mut u64 {
+            assert_eq!(addr % 8, 0, "unaligned access");
+            if addr >= STATE_BASE {
+                &mut self.state[((addr - STATE_BASE) / 8) as usize]
+            } else if addr >= STACK_TOP - 512 && addr < STACK_TOP {
+                let len = self.stack.len();
+                &mut self.stack[len - ((STACK_TOP - addr) / 8) as usize]
+            } else if addr >= CTX_BASE && addr < STACK_TOP - 512 {
+                &mut self.ctx[((addr - CTX_BASE) / 8) as usize]
+            } else {
+                panic!("invalid access at {:#x}", addr)
+            }
+        }
+
+        fn run(&mut self) -> u64 {
+            let insns = &self.prog.insns;
+            let mut r = [0u64; 11];
+            r[1] = CTX_BASE;
+            r[10] = STACK_TOP;
+            let mut pc = 0;
+            for _ in 0..100_000 {
+                let i = insns[pc];
+                let (dst, src) = (i.dst as usize, i.src as usize);
+                let imm = i64::from(i.imm) as u64;
+                pc += 1;
+                match i.code {
+                    LD_IMM64 => {
+                        r[dst] = u64::from(i.imm as u32) | u64::from(insns[pc].imm as u32) << 32;
+                        pc += 1;
+                    }
+                    LDX_DW => r[dst] = *self.slot(r[src].wrapping_add(i.off as u64)),
+                    STX_DW => *self.slot(r[dst].wrapping_add(i.off as u64)) = r[src],
+                    ST_DW => *self.slot(r[dst].wrapping_add(i.off as u64)) = imm,
+                    c if c & 0x07 == ALU64 => {
+                        let v = if c & X != 0 { r[src] } else { imm };
+                        let d = r[dst];
+                        r[dst] = match c & 0xf0 {
+                            ADD => d.wrapping_add(v),
+                            SUB => d.wrapping_sub(v),
+                            MUL => d.wrapping_mul(v),
+                            DIV => if v == 0 { 0 } else { d / v },
+                            MOD => if v == 0 { d } else { d % v },
+                            OR => d | v,
+                            AND => d & v,
+                            XOR => d ^ v,
+                            LSH => d << (v & 63),
+                            RSH => d >> (v & 63),
+                            MOV => v,
+                            op => panic!("unsupported alu op {:#x}", op),
+                        };
+                    }
+                    c if c == JMP | CALL => {
+                        r[0] = match i.imm {
+                            1 => {
+                                assert_eq!(r[1], 1, "lookup in the wrong map");
+                                if *self.slot(r[2]) == FLOW_ID { STATE_BASE } else { 0 }
+                            }
+                            130 => {
+                                assert_eq!(r[1], 2, "output to the wrong map");
+                                let rec = (0..r[3] / 8).map(|k| *self.slot(r[2] + 8 * k)).collect();
+                                self.records.push(rec);
+                                0
+                            }
+                            h => panic!("unsupported helper {}", h),
+                        };
+                    }
+                    c if c == JMP | EXIT => return r[0],
+                    c if c & 0x07 == JMP => {
+                        let (a, b) = (r[dst], if c & X != 0 { r[src] } else { imm });
+                        let taken = match c & 0xf0 {
+                            JA => true,
+                            JEQ => a == b,
+                            JNE => a != b,
+                            JGT => a > b,
+                            JGE => a >= b,
+                            JLT => a < b,
+                            JLE => a <= b,
+                            JSGT => a as i64 > b as i64,
+                            JSGE => a as i64 >= b as i64,
+                            JSLT => (a as i64) < b as i64,
+                            JSLE => a as i64 <= b as i64,
+                            op => panic!("unsupported jump {:#x}", op),
+                        };
+                        if taken {
+                            pc = (pc as i64 + i64::from(i.off)) as usize;
+                        }
+                    }
+                    c => panic!("unsupported instruction {:#x}", c),
+                }
+            }
+
+            panic!("program did not terminate")
+        }
+    }
+
+    fn check(prog: &[u8], caps: Capabilities) {
+        let (bin, sc) = lang::compile_with_capabilities(prog, &[], caps.clone()).unwrap();
+        let mut p = super::compile(&bin, &sc).unwrap();
+        // patch in map "file descriptors"
+        for &(at, map) in &p.map_relocs.clone() {
+            p.insns[at].imm = if map == Map::State { 1 } else { 2 };
+        }
+
+        let num_primitives = caps.primitives.iter().count();
+        for seed in 1..4u64 {
+            let inputs = random_acks(seed, num_primitives);
+            let mut vm = Vm { prog: &p, ctx: vec![], stack: vec![0; 64], state: p.initial_state(START), records: vec![] };
+            let mut out = String::new();
+            for &(now, ref prims) in &inputs {
+                vm.ctx = vec![FLOW_ID, now].into_iter().chain(prims.iter().cloned()).collect();
+                let ret = vm.run();
+                if ret == u64::max_value() {
+                    out.push_str(&describe(&Err(lang::Error::from("err"))));
+                    continue;
+                }
+
+                let implicit = p.layout.implicit;
+                let a = Actions {
+                    report: if ret & ACTION_REPORT != 0 {
+                        let rec = vm.records.pop().unwrap();
+                        assert_eq!(rec[0], FLOW_ID);
+                        Some(rec[1..].to_vec())
+                    } else {
+                        None
+                    },
+                    cwnd: if ret & ACTION_CWND != 0 { Some(vm.state[implicit + CWND_REG]) } else { None },
+                    rate: if ret & ACTION_RATE != 0 { Some(vm.state[implicit + RATE_REG]) } else { None },
+                };
+                out.push_str(&describe(&Ok(a)));
+            }
+
+            assert_eq!(out, expected(prog, caps.clone(), &inputs));
+        }
+    }
+
+    #[test]
+    fn generic_cong_avoid() {
+        check(GENERIC_CONG_AVOID, Capabilities::default());
+    }
+
+    #[test]
+    fn extended_ops() {
+        check(EXTENDED_OPS, Capabilities::new(1).with_timers(1));
+    }
+
+    #[test]
+    fn signed_ops() {
+        check(SIGNED_OPS, Capabilities::new(2));
+    }
+
+    #[test]
+    fn encoding() {
+        let (bin, sc) = lang::compile(GENERIC_CONG_AVOID, &[]).unwrap();
+        let p = super::compile(&bin, &sc).unwrap();
+        let bytes = p.bytecode();
+        assert_eq!(bytes.len(), 8 * p.insns.len());
+        // mov r6, r1
+        assert_eq!(&bytes[..8], &[0xbf, 0x16, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(p.map_relocs.iter().map(|&(_, m)| m).collect::<Vec<_>>(), vec![Map::State, Map::Reports]);
+        for &(at, _) in &p.map_relocs {
+            assert_eq!((p.insns[at].code, p.insns[at].src), (LD_IMM64, PSEUDO_MAP_FD));
+        }
+
+        let state = p.initial_state(START);
+        assert_eq!(state.len(), p.layout.len);
+        // beta is 0.7 in fixed point
+        assert_eq!(state[p.layout.index(sc.get("beta").unwrap()).unwrap()], 45875);
+        assert_eq!(state[p.layout.time_zero], START);
+    }
+}
@@ -0,0 +1,509 @@
+//! Canonical formatting of datapath program source.
+//!
+//! The parser in `ast` and `prog` discards comments and layout, so the formatter works on a
+//! lossless s-expression tree instead. Before returning, `format()` parses both its input and its
+//! output and checks that they describe the same `Prog` and `def` block, so formatting never
+//! changes what a program means.
+//!
+//! Layout rules:
+//! 1. Top-level forms start at column 0, one per line. A single blank line between forms is kept.
+//! 2. `def`, `Report`, `cond`, `let`, `defmacro` and the event forms are block forms: the head
+//!    (and the condition of an event, the binding of a `let`, or the name and parameters of a
+//!    `defmacro`) stays on the first line, every other element goes on its own line indented by four spaces,
+//!    and the closing paren gets its own line.
+//! 3. Any other list is printed on one line, unless it contains a comment.
+//! 4. Comments stay where they were: a comment that followed other tokens on the same line stays
+//!    at the end of that line, other comments get their own line.
+//!
+//! ### Example
+//! ```
+//! extern crate portus;
+//! use portus::lang::fmt;
+//!
+//! fn main() {
+//!     let src = b"(def (Report (volatile acked 0))) (when true (:= Report.acked (+ Report.acked Ack.bytes_acked)))";
+//!     assert_eq!(
+//!         fmt::format(src).unwrap(),
+//!         "(def\n    (Report\n        (volatile acked 0)\n    )\n)\n(when true\n    (:= Report.acked (+ Report.acked Ack.bytes_acked))\n)\n",
+//!     );
+//! }
+//! ```
+
+use std::str;
+
+use super::{Error, Result};
+use super::datapath::Instr;
+use super::prog::Prog;
+
+const INDENT: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Node {
+    Atom(String),
+    List(Vec<Item>),
+    Comment(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Item {
+    pub(crate) node: Node,
+    /// A comment on the same line as the preceding token.
+    trailing: bool,
+    /// At least one empty line separates this item from the preceding token.
+    blank_before: bool,
+}
+
+impl Item {
+    fn new(node: Node, newlines: usize) -> Self {
+        Item {
+            node,
+            trailing: newlines == 0,
+            blank_before: newlines > 1,
+        }
+    }
+}
+
+enum Token {
+    Open,
+    Close,
+    Atom(String),
+    Comment(String),
+}
+
+/// Split `src` into tokens, each paired with the number of newlines preceding it.
+fn tokenize(src: &str) -> Result<Vec<(Token, usize)>> {
+    let mut toks = vec![];
+    let mut chars = src.chars().peekable();
+    let mut newlines = 0;
+    while let Some(&c) = chars.peek() {
+        let tok = match c {
+            '\n' => {
+                newlines += 1;
+                chars.next();
+                continue;
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                Token::Open
+            }
+            ')' => {
+                chars.next();
+                Token::Close
+            }
+            '#' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+
+                    s.push(c);
+                    chars.next();
+                }
+
+                Token::Comment(String::from(s.trim_end()))
+            }
+            '"' => {
+                let mut s = String::new();
+                s.push(c);
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err(Error::from("unterminated string")),
+                    }
+                }
+
+                s.push('"');
+                Token::Atom(s)
+            }
+            _ => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '#' {
+                        break;
+                    }
+
+                    s.push(c);
+                    chars.next();
+                }
+
+                Token::Atom(s)
+            }
+        };
+
+        toks.push((tok, newlines));
+        newlines = 0;
+    }
+
+    Ok(toks)
+}
+
+/// Read `src` into a list of top-level items, keeping comments.
+pub(crate) fn parse(src: &str) -> Result<Vec<Item>> {
+    // each open list, with the number of newlines preceding its opening paren
+    let mut stack: Vec<(Vec<Item>, usize)> = vec![(vec![], 0)];
+    for (tok, newlines) in tokenize(src)? {
+        match tok {
+            Token::Open => stack.push((vec![], newlines)),
+            Token::Close => {
+                if stack.len() < 2 {
+                    return Err(Error::from("unexpected \")\""));
+                }
+
+                let (list, open_newlines) = stack.pop().unwrap();
+                stack.last_mut().unwrap().0.push(Item::new(Node::List(list), open_newlines));
+            }
+            Token::Atom(a) => stack.last_mut().unwrap().0.push(Item::new(Node::Atom(a), newlines)),
+            Token::Comment(c) => stack.last_mut().unwrap().0.push(Item::new(Node::Comment(c), newlines)),
+        }
+    }
+
+    if stack.len() != 1 {
+        return Err(Error::from("unbalanced parentheses: missing \")\""));
+    }
+
+    Ok(stack.pop().unwrap().0)
+}
+
+/// Number of elements after the head that stay on the first line of a block form,
+/// or `None` if the list headed by `head` is not a block form.
+fn block_header_len(head: &str) -> Option<usize> {
+    match head {
+        "def" | "Report" | "cond" | "otherwise" => Some(0),
+        "when" | "on-rise" | "on-fall" | "let" => Some(1),
+        "defmacro" => Some(2),
+        _ => None,
+    }
+}
+
+fn head(items: &[Item]) -> Option<&str> {
+    match items.first() {
+        Some(&Item { node: Node::Atom(ref a), .. }) => Some(a.as_str()),
+        _ => None,
+    }
+}
+
+fn is_inline(items: &[Item]) -> bool {
+    if head(items).and_then(block_header_len).is_some() {
+        return false;
+    }
+
+    items.iter().all(|i| match i.node {
+        Node::Atom(_) => true,
+        Node::Comment(_) => false,
+        Node::List(ref l) => is_inline(l),
+    })
+}
+
+struct Printer {
+    out: String,
+}
+
+impl Printer {
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.out.extend(::std::iter::repeat(' ').take(indent));
+    }
+
+    fn inline(&mut self, n: &Node) {
+        match *n {
+            Node::Atom(ref a) | Node::Comment(ref a) => self.out.push_str(a),
+            Node::List(ref l) => {
+                self.out.push('(');
+                for (i, it) in l.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push(' ');
+                    }
+
+                    self.inline(&it.node);
+                }
+
+                self.out.push(')');
+            }
+        }
+    }
+
+    fn node(&mut self, n: &Node, indent: usize) {
+        match *n {
+            Node::List(ref l) if !is_inline(l) => self.block(l, indent),
+            _ => self.inline(n),
+        }
+    }
+
+    fn block(&mut self, items: &[Item], indent: usize) {
+        let header_len = head(items).and_then(block_header_len).unwrap_or(0);
+        self.out.push('(');
+        let mut rest = items.iter();
+        if head(items).is_some() {
+            self.inline(&rest.next().unwrap().node);
+        }
+
+        let mut on_first_line = 0;
+        let mut header_done = false;
+        let mut first_body_item = true;
+        for it in rest {
+            match it.node {
+                Node::Comment(ref c) if it.trailing => {
+                    self.out.push(' ');
+                    self.out.push_str(c);
+                    header_done = true;
+                    continue;
+                }
+                Node::Comment(_) => header_done = true,
+                _ if !header_done && on_first_line < header_len => {
+                    self.out.push(' ');
+                    self.node(&it.node, indent + INDENT);
+                    on_first_line += 1;
+                    continue;
+                }
+                _ => header_done = true,
+            }
+
+            if it.blank_before && !first_body_item {
+                self.out.push('\n');
+            }
+
+            first_body_item = false;
+            self.newline(indent + INDENT);
+            self.node(&it.node, indent + INDENT);
+        }
+
+        self.newline(indent);
+        self.out.push(')');
+    }
+
+    fn top_level(&mut self, items: &[Item]) {
+        for (i, it) in items.iter().enumerate() {
+            match it.node {
+                Node::Comment(ref c) if it.trailing && i > 0 => {
+                    self.out.push(' ');
+                    self.out.push_str(c);
+                    continue;
+                }
+                _ => {
+                    if i > 0 {
+                        self.out.push('\n');
+                        if it.blank_before {
+                            self.out.push('\n');
+                        }
+                    }
+
+                    self.node(&it.node, 0);
+                }
+            }
+        }
+
+        if !items.is_empty() {
+            self.out.push('\n');
+        }
+    }
+}
+
+fn semantics(src: &[u8]) -> Result<(Prog, Vec<Instr>)> {
+    let (p, sc) = Prog::new_with_scope(src)?;
+    Ok((p, sc.into_iter().collect()))
+}
+
+/// `items` without comments or layout.
+fn shape(items: &[Item]) -> Vec<Node> {
+    items.iter()
+        .filter_map(|it| match it.node {
+            Node::Comment(_) => None,
+            Node::Atom(ref a) => Some(Node::Atom(a.clone())),
+            Node::List(ref l) => Some(Node::List(
+                shape(l).into_iter().map(|n| Item::new(n, 1)).collect(),
+            )),
+        })
+        .collect()
+}
+
+/// Whether `items` can be compiled by itself: it has a `(def ...)` block and includes no other
+/// files.
+fn is_standalone(items: &[Item]) -> bool {
+    let heads: Vec<&str> = items.iter()
+        .filter_map(|it| match it.node {
+            Node::List(ref l) => head(l),
+            _ => None,
+        })
+        .collect();
+    heads.contains(&"def") && !heads.contains(&"include")
+}
+
+/// Print `items` in canonical style.
+pub(crate) fn print(items: &[Item]) -> String {
+    let mut p = Printer { out: String::new() };
+    p.top_level(items);
+    p.out
+}
+
+/// Format a datapath program in canonical style. The output always ends with a newline.
+///
+/// Fails if `src` is not a valid datapath program. Fragments meant to be included in other
+/// programs only need to be well-formed.
+pub fn format(src: &[u8]) -> Result<String> {
+    let items = parse(str::from_utf8(src)?)?;
+    let out = print(&items);
+
+    let unchanged = if is_standalone(&items) {
+        semantics(src)? == semantics(out.as_bytes())?
+    } else {
+        shape(&items) == shape(&parse(&out)?)
+    };
+
+    if !unchanged {
+        return Err(Error::from("formatting changed the meaning of the program"));
+    }
+
+    Ok(out)
+}
+
+/// Format a program that is embedded in a string literal of a host language (Rust or Python).
+///
+/// The indentation of the closing quote is taken from the whitespace after the last newline in
+/// `src`. The result starts with a newline, indents each line of the program one level deeper
+/// than the closing quote, and ends with a newline followed by the closing quote's indentation.
+/// Formatting an already-formatted literal returns it unchanged.
+pub fn format_embedded(src: &str) -> Result<String> {
+    let base = match src.rfind('\n') {
+        Some(i) if src[i + 1..].chars().all(|c| c == ' ') => src.len() - i - 1,
+        _ => 0,
+    };
+
+    let body = format(src.as_bytes())?;
+    let mut out = String::from("\n");
+    for line in body.lines() {
+        if !line.is_empty() {
+            out.extend(::std::iter::repeat(' ').take(base + INDENT));
+            out.push_str(line);
+        }
+
+        out.push('\n');
+    }
+
+    out.extend(::std::iter::repeat(' ').take(base));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn canonical() {
+        let src = b"
+          (def (Report
+            (volatile acked 0)   (volatile rtt 0))
+            (reportTime 0))
+        (when true
+                (:= Report.acked (+ Report.acked   Ack.bytes_acked))
+          (fallthrough))
+        (when (> Micros reportTime) (report) (:= Micros 0))
+        ";
+
+        assert_eq!(
+            super::format(src).unwrap(),
+            "(def
+    (Report
+        (volatile acked 0)
+        (volatile rtt 0)
+    )
+    (reportTime 0)
+)
+(when true
+    (:= Report.acked (+ Report.acked Ack.bytes_acked))
+    (fallthrough)
+)
+(when (> Micros reportTime)
+    (report)
+    (:= Micros 0)
+)
+",
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let src = b"
+            (def (foo 0) (bar 0)) # trailing comment
+
+            (when (> foo 0) # trailing after condition
+                # own line
+                (:= bar (+ bar 1)) # trailing after statement
+                (:= foo (* foo 2))
+            )
+        ";
+
+        assert_eq!(
+            super::format(src).unwrap(),
+            "(def
+    (foo 0)
+    (bar 0)
+) # trailing comment
+
+(when (> foo 0) # trailing after condition
+    # own line
+    (:= bar (+ bar 1)) # trailing after statement
+    (:= foo (* foo 2))
+)
+",
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let src = b"(def (Report.foo 0)) (when true (bind Report.foo 4) # set foo\n (report))";
+        let once = super::format(src).unwrap();
+        let twice = super::format(once.as_bytes()).unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn embedded() {
+        let src = "
+                (def (Report (volatile acked 0)))
+                (when true
+                    (:= Report.acked Ack.bytes_acked)
+                    (report)
+                )
+            ";
+
+        let formatted = super::format_embedded(src).unwrap();
+        assert_eq!(
+            formatted,
+            "
+                (def
+                    (Report
+                        (volatile acked 0)
+                    )
+                )
+                (when true
+                    (:= Report.acked Ack.bytes_acked)
+                    (report)
+                )
+            ",
+        );
+
+        assert_eq!(super::format_embedded(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(super::format(b"(def (foo 0)) (when true (:= foo 1)").is_err());
+        assert!(super::format(b"(def (foo 0))) (when true (:= foo 1))").is_err());
+        assert!(super::format(b"(def (foo 0)) (when true (blah foo 1))").is_err());
+        assert!(super::format(b"(defconst MSS 1448").is_err());
+    }
+
+    #[test]
+    fn fragment() {
+        let src = b"(include \"consts.ccp\") # shared\n(defmacro add_acked (v) (:= v (+ v MSS)))";
+        assert_eq!(
+            super::format(src).unwrap(),
+            "(include \"consts.ccp\") # shared\n(defmacro add_acked (v)\n    (:= v (+ v MSS))\n)\n",
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Loading datapath programs from `.ccp` files.
+//!
+//! A top-level `(include "path")` form is replaced by the contents of the named file, which is
+//! resolved relative to the directory of the including file. Included files, or fragments, usually
+//! hold shared constants and macros; they may include other fragments, but not themselves.
+//! Each fragment is included at most once per program, so two fragments may both include a third.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{Error, Result};
+use super::fmt::{self, Item, Node};
+use super::prog::TOP_LEVEL_FORMS;
+
+/// If `item` is an `(include "path")` form, the path.
+fn include_path(item: &Item) -> Option<Result<&str>> {
+    let list = match item.node {
+        Node::List(ref l) => l,
+        _ => return None,
+    };
+
+    match list.first() {
+        Some(&Item { node: Node::Atom(ref a), .. }) if a == "include" => (),
+        _ => return None,
+    }
+
+    let args: Vec<&Item> = list[1..].iter().filter(|i| match i.node {
+        Node::Comment(_) => false,
+        _ => true,
+    }).collect();
+    Some(match args.as_slice() {
+        [&Item { node: Node::Atom(ref p), .. }] if p.len() >= 2 && p.starts_with('"') && p.ends_with('"') => {
+            Ok(&p[1..p.len() - 1])
+        }
+        _ => Err(Error::from("expected (include \"path\")")),
+    })
+}
+
+/// The files being read, outermost first, and every file already read.
+#[derive(Default)]
+struct Includes {
+    stack: Vec<PathBuf>,
+    seen: Vec<PathBuf>,
+}
+
+fn read_items(path: &Path, includes: &mut Includes) -> Result<Vec<Item>> {
+    let canonical = path.canonicalize()
+        .map_err(|e| Error::from(format!("{}: {}", path.display(), e)))?;
+    let stack = &includes.stack;
+    if stack.contains(&canonical) {
+        let chain: Vec<String> = stack.iter()
+            .chain(Some(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(Error::from(format!("include cycle: {}", chain.join(" -> "))));
+    }
+
+    if includes.seen.contains(&canonical) {
+        return Ok(vec![]);
+    }
+
+    let mut src = String::new();
+    File::open(&canonical)
+        .and_then(|mut f| f.read_to_string(&mut src))
+        .map_err(|e| Error::from(format!("{}: {}", path.display(), e)))?;
+    let items = fmt::parse(&src)
+        .map_err(|e| Error::from(format!("{}: {}", path.display(), e)))?;
+
+    let dir = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+    includes.seen.push(canonical.clone());
+    includes.stack.push(canonical);
+    let mut resolved = vec![];
+    for item in items {
+        match include_path(&item) {
+            Some(included) => {
+                let included = included.map_err(|e| Error::from(format!("{}: {}", path.display(), e)))?;
+                resolved.extend(read_items(&dir.join(included), includes)?);
+            }
+            None => resolved.push(item),
+        }
+    }
+
+    includes.stack.pop();
+    Ok(resolved)
+}
+
+/// Read the datapath program in the file at `path`, with its includes resolved.
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let items = read_items(path.as_ref(), &mut Includes::default())?;
+    Ok(fmt::print(&items))
+}
+
+/// Whether `src` is a fragment to be included in programs rather than a program itself,
+/// i.e. whether it lacks a `(def ...)` block.
+pub fn is_fragment(src: &str) -> bool {
+    fmt::parse(src)
+        .map(|items| !items.iter().any(|i| match i.node {
+            Node::List(ref l) => match l.first() {
+                Some(&Item { node: Node::Atom(ref a), .. }) => a == "def",
+                _ => false,
+            },
+            _ => false,
+        }))
+        .unwrap_or(false)
+}
+
+/// Whether `src` begins with a top-level form of a program or fragment, e.g. `(def ...)`,
+/// `(import std)` or `(defmacro ...)`. Leading comments are skipped.
+pub fn starts_with_top_level_form(src: &str) -> bool {
+    let items = match fmt::parse(src) {
+        Ok(items) => items,
+        Err(_) => return false,
+    };
+
+    for i in items {
+        match i.node {
+            Node::Comment(_) => continue,
+            Node::List(ref l) => return match l.first() {
+                Some(&Item { node: Node::Atom(ref a), .. }) => TOP_LEVEL_FORMS.contains(&a.as_str()),
+                _ => false,
+            },
+            Node::Atom(_) => return false,
+        }
+    }
+
+    false
+}
+
+/// Load every program in the directory `dir`: each `.ccp` file directly inside `dir` is a
+/// program, named after the file without its extension. Fragments shared between programs should
+/// be kept in subdirectories. Programs are returned in order of their names.
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, String)>> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| Error::from(format!("{}: {}", dir.display(), e)))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().map_or(false, |e| e == "ccp"))
+        .collect();
+    paths.sort();
+
+    paths.into_iter()
+        .map(|p| {
+            let name = p.file_stem().unwrap().to_string_lossy().into_owned();
+            load_file(&p).map(|src| (name, src))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("portus-include-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    fn write(path: PathBuf, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn include() {
+        let dir = tmp_dir("include");
+        write(dir.join("lib").join("consts.ccp"), "(defconst MSS 1448)\n");
+        write(dir.join("lib").join("acked.ccp"), "(include \"consts.ccp\")\n(defmacro add_acked (v) (:= v (+ v MSS)))\n");
+        write(
+            dir.join("prog.ccp"),
+            "(include \"lib/acked.ccp\")\n(def (Report (volatile acked 0)))\n(when true\n    (add_acked Report.acked)\n)\n",
+        );
+
+        let src = super::load_file(dir.join("prog.ccp")).unwrap();
+        assert!(!super::is_fragment(&src));
+        assert!(super::is_fragment("(defconst MSS 1448)"));
+        for form in &["(import std) (def (a 0))", "(defmacro f (v) (:= v 1))", "(include \"a.ccp\")", "# c\n(when true)"] {
+            assert!(super::starts_with_top_level_form(form), "{}", form);
+        }
+        assert!(!super::starts_with_top_level_form("(foo 1)"));
+        assert!(!super::starts_with_top_level_form("hello"));
+        let (_, sc) = ::lang::compile(src.as_bytes(), &[]).unwrap();
+        assert!(sc.get("Report.acked").is_some());
+
+        let progs = super::load_dir(&dir).unwrap();
+        assert_eq!(progs.len(), 1);
+        assert_eq!(progs[0], (String::from("prog"), src));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_once() {
+        // both a.ccp and b.ccp include consts.ccp
+        let dir = tmp_dir("once");
+        write(dir.join("lib").join("consts.ccp"), "(defconst MSS 1448)\n");
+        write(dir.join("lib").join("a.ccp"), "(include \"consts.ccp\")\n(defmacro add_a (v) (:= v (+ v MSS)))\n");
+        write(dir.join("lib").join("b.ccp"), "(include \"consts.ccp\")\n(defmacro add_b (v) (:= v (* v MSS)))\n");
+        write(
+            dir.join("prog.ccp"),
+            "(include \"lib/a.ccp\")\n(include \"lib/b.ccp\")\n(def (Report (volatile acked 0)))\n(when true\n    (add_a Report.acked)\n    (add_b Report.acked)\n)\n",
+        );
+
+        let src = super::load_file(dir.join("prog.ccp")).unwrap();
+        assert_eq!(src.matches("defconst").count(), 1);
+        ::lang::compile_file(dir.join("prog.ccp"), &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = tmp_dir("cycle");
+        write(dir.join("lib").join("a.ccp"), "(include \"b.ccp\")\n");
+        write(dir.join("lib").join("b.ccp"), "(include \"a.ccp\")\n");
+        write(dir.join("prog.ccp"), "(include \"lib/a.ccp\")\n(def (foo 0))\n(when true (:= foo 1))\n");
+
+        let err = super::load_file(dir.join("prog.ccp")).unwrap_err();
+        assert!(err.0.starts_with("include cycle: "), "{}", err);
+        assert!(super::load_file(dir.join("missing.ccp")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
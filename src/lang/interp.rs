@@ -0,0 +1,514 @@
+//! A reference interpreter for compiled datapath programs.
+//!
+//! `Machine` executes a `Bin` the way libccp does: on each ack, it evaluates the events in order,
+//! running the body of each event whose flag is true, and stops after the first body that does not
+//! `(fallthrough)`. It is the specification the other backends, such as `lang::codegen`, are tested
+//! against.
+//!
+//! All values are `u64`s; booleans are 0 or 1. Arithmetic wraps on overflow, shifts by 64 or more
+//! yield 0, and division or remainder by zero is an error which aborts the ack.
+
+use super::{Error, Result};
+use super::ast::Op;
+use super::datapath::{Bin, Event, Instr, Reg, Scope};
+use super::serialize::NUM_IMPLICIT_REGS;
+
+/// Implicit register indices, as assigned by `Scope::new()`.
+pub(crate) const EVENT_FLAG_REG: usize = 0;
+pub(crate) const SHOULD_CONTINUE_REG: usize = 1;
+pub(crate) const SHOULD_REPORT_REG: usize = 2;
+pub(crate) const MICROS_REG: usize = 3;
+pub(crate) const CWND_REG: usize = 4;
+pub(crate) const RATE_REG: usize = 5;
+
+/// Register counts of each kind used by a program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RegCounts {
+    pub(crate) report: usize,
+    pub(crate) control: usize,
+    pub(crate) local: usize,
+    pub(crate) primitive: usize,
+    pub(crate) tmp: usize,
+    pub(crate) implicit: usize,
+}
+
+impl RegCounts {
+    pub(crate) fn of(bin: &Bin, scope: &Scope) -> Self {
+        let mut c = RegCounts {
+            implicit: (NUM_IMPLICIT_REGS + scope.num_timers) as usize,
+            ..Default::default()
+        };
+        for r in bin.instrs.iter().flat_map(|i| vec![&i.res, &i.left, &i.right]) {
+            let (count, i) = match *r {
+                Reg::Report(i, _, _) => (&mut c.report, i),
+                Reg::Control(i, _) => (&mut c.control, i),
+                Reg::Local(i, _) => (&mut c.local, i),
+                Reg::Primitive(i, _) => (&mut c.primitive, i),
+                Reg::Tmp(i, _) => (&mut c.tmp, i),
+                Reg::Implicit(i, _) => (&mut c.implicit, i),
+                _ => continue,
+            };
+            *count = ::std::cmp::max(*count, i as usize + 1);
+        }
+
+        c
+    }
+
+    /// Indices of the implicit registers that count microseconds: `Micros` and the timers.
+    pub(crate) fn timers(&self) -> Vec<usize> {
+        Some(MICROS_REG).into_iter().chain(NUM_IMPLICIT_REGS as usize..self.implicit).collect()
+    }
+}
+
+/// The result of evaluating a binary operator, other than `Bind`, `Def`, `If`, `NotIf` and `Ewma`.
+pub(crate) fn eval_op(op: Op, a: u64, b: u64) -> Result<u64> {
+    Ok(match op {
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Mul => a.wrapping_mul(b),
+        Op::Div | Op::Mod if b == 0 => return Err(Error::from("division by zero")),
+        Op::Div => a / b,
+        Op::Mod => a % b,
+        Op::Shl => if b >= 64 { 0 } else { a << b },
+        Op::Shr => if b >= 64 { 0 } else { a >> b },
+        Op::BitAnd => a & b,
+        Op::BitOr => a | b,
+        Op::AbsDiff => if a > b { a - b } else { b - a },
+        Op::Max => ::std::cmp::max(a, b),
+        Op::Min => ::std::cmp::min(a, b),
+        Op::MaxWrap => if (a.wrapping_sub(b) as i64) < 0 { b } else { a },
+        Op::Equiv => (a == b) as u64,
+        Op::Neq => (a != b) as u64,
+        Op::Gt => (a > b) as u64,
+        Op::Gte => (a >= b) as u64,
+        Op::Lt => (a < b) as u64,
+        Op::Lte => (a <= b) as u64,
+        Op::And => (a != 0 && b != 0) as u64,
+        Op::Or => (a != 0 || b != 0) as u64,
+        Op::Not => (a == 0) as u64,
+        Op::SatSub => a.saturating_sub(b),
+        Op::CheckedSub => a.checked_sub(b).ok_or_else(|| Error::from("subtraction overflow"))?,
+        Op::SatSubInt => (a as i64).saturating_sub(b as i64) as u64,
+        Op::CheckedSubInt => (a as i64).checked_sub(b as i64).ok_or_else(|| Error::from("subtraction overflow"))? as u64,
+        Op::GtInt => ((a as i64) > (b as i64)) as u64,
+        Op::GteInt => ((a as i64) >= (b as i64)) as u64,
+        Op::LtInt => ((a as i64) < (b as i64)) as u64,
+        Op::LteInt => ((a as i64) <= (b as i64)) as u64,
+        Op::MaxInt => ::std::cmp::max(a as i64, b as i64) as u64,
+        Op::MinInt => ::std::cmp::min(a as i64, b as i64) as u64,
+        Op::Bind | Op::Def | Op::If | Op::NotIf | Op::Ewma | Op::Observe | Op::ToInt | Op::ToNum => unreachable!(),
+    })
+}
+
+/// What the datapath should do after an ack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Actions {
+    /// The `Report` fields to send to CCP, in register order, if the program reported.
+    pub report: Option<Vec<u64>>,
+    /// The new congestion window, if the program changed it.
+    pub cwnd: Option<u64>,
+    /// The new sending rate, if the program changed it.
+    pub rate: Option<u64>,
+}
+
+/// The state of an installed datapath program.
+#[derive(Clone, Debug)]
+pub struct Machine {
+    events: Vec<Event>,
+    instrs: Vec<Instr>,
+    timers: Vec<usize>,
+    report: Vec<u64>,
+    control: Vec<u64>,
+    local: Vec<u64>,
+    implicit: Vec<u64>,
+    tmp: Vec<u64>,
+    time_zero: Vec<u64>,
+}
+
+impl Machine {
+    /// Install `bin` at time `now`, in microseconds, initializing its variables.
+    pub fn new(bin: &Bin, scope: &Scope, now: u64) -> Self {
+        let counts = RegCounts::of(bin, scope);
+        let timers = counts.timers();
+        let mut m = Machine {
+            events: bin.events.clone(),
+            instrs: bin.instrs.clone(),
+            time_zero: vec![now; timers.len()],
+            timers,
+            report: vec![0; counts.report],
+            control: vec![0; counts.control],
+            local: vec![0; counts.local],
+            implicit: vec![0; counts.implicit],
+            tmp: vec![0; counts.tmp],
+        };
+
+        m.run_defs(|_| true);
+        m
+    }
+
+    /// The current value of a `Report`, control, local or implicit register.
+    pub fn get(&self, r: &Reg) -> Option<u64> {
+        match *r {
+            Reg::Report(i, _, _) => self.report.get(i as usize).cloned(),
+            Reg::Control(i, _) => self.control.get(i as usize).cloned(),
+            Reg::Local(i, _) => self.local.get(i as usize).cloned(),
+            Reg::Implicit(i, _) => self.implicit.get(i as usize).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Set a control register, as `update_field` does.
+    pub fn set_control(&mut self, idx: u8, v: u64) {
+        if let Some(c) = self.control.get_mut(idx as usize) {
+            *c = v;
+        }
+    }
+
+    /// Set the congestion window and rate the datapath is currently using.
+    pub fn set_cwnd_rate(&mut self, cwnd: u64, rate: u64) {
+        self.implicit[CWND_REG] = cwnd;
+        self.implicit[RATE_REG] = rate;
+    }
+
+    /// Process an ack received at time `now` with the measurement primitives `primitives`, in
+    /// register order.
+    pub fn on_ack(&mut self, primitives: &[u64], now: u64) -> Result<Actions> {
+        let (cwnd, rate) = (self.implicit[CWND_REG], self.implicit[RATE_REG]);
+        for (&t, &z) in self.timers.iter().zip(self.time_zero.iter()) {
+            self.implicit[t] = now.wrapping_sub(z);
+        }
+
+        for ev in self.events.clone() {
+            self.implicit[EVENT_FLAG_REG] = 0;
+            self.run(ev.flag_idx, ev.num_flag_instrs, primitives)?;
+            if self.implicit[EVENT_FLAG_REG] == 0 {
+                continue;
+            }
+
+            self.implicit[SHOULD_CONTINUE_REG] = 0;
+            self.run(ev.body_idx, ev.num_body_instrs, primitives)?;
+            if self.implicit[SHOULD_CONTINUE_REG] == 0 {
+                break;
+            }
+        }
+
+        for (&t, z) in self.timers.iter().zip(self.time_zero.iter_mut()) {
+            *z = now.wrapping_sub(self.implicit[t]);
+        }
+
+        let mut actions = Actions::default();
+        if self.implicit[SHOULD_REPORT_REG] != 0 {
+            actions.report = Some(self.report.clone());
+            self.implicit[SHOULD_REPORT_REG] = 0;
+            self.run_defs(|r| match *r {
+                Reg::Report(_, _, is_volatile) => is_volatile,
+                _ => false,
+            });
+        }
+
+        if self.implicit[CWND_REG] != cwnd {
+            actions.cwnd = Some(self.implicit[CWND_REG]);
+        }
+
+        if self.implicit[RATE_REG] != rate {
+            actions.rate = Some(self.implicit[RATE_REG]);
+        }
+
+        Ok(actions)
+    }
+
+    fn run_defs<F: Fn(&Reg) -> bool>(&mut self, which: F) {
+        let defs: Vec<(Reg, u64)> = self.instrs.iter()
+            .filter(|i| i.op == Op::Def && which(&i.left))
+            .map(|i| (i.left.clone(), imm(&i.right).unwrap_or(0)))
+            .collect();
+        for (r, v) in defs {
+            self.write(&r, v);
+        }
+    }
+
+    fn run(&mut self, start: u32, len: u32, primitives: &[u64]) -> Result<()> {
+        for idx in start as usize..(start + len) as usize {
+            let i = self.instrs[idx].clone();
+            let l = self.read(&i.left, primitives)?;
+            let r = self.read(&i.right, primitives)?;
+            match i.op {
+                Op::Def => (),
+                Op::Bind => self.write(&i.res, r),
+                Op::If if l != 0 => self.write(&i.res, r),
+                Op::NotIf if l == 0 => self.write(&i.res, r),
+                Op::If | Op::NotIf => (),
+                Op::Ewma => {
+                    let old = self.read(&i.res, primitives)?;
+                    let v = old.wrapping_mul(l).wrapping_add(r.wrapping_mul(10u64.wrapping_sub(l))) / 10;
+                    self.write(&i.res, v);
+                }
+                op => {
+                    let v = eval_op(op, l, r)?;
+                    self.write(&i.res, v);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, r: &Reg, primitives: &[u64]) -> Result<u64> {
+        match *r {
+            Reg::Primitive(i, _) => primitives.get(i as usize).cloned()
+                .ok_or_else(|| Error::from(format!("missing primitive {}", i))),
+            Reg::Tmp(i, _) => Ok(self.tmp[i as usize]),
+            Reg::None => Ok(0),
+            ref r => imm(r).or_else(|| self.get(r))
+                .ok_or_else(|| Error::from(format!("cannot read {:?}", r))),
+        }
+    }
+
+    fn write(&mut self, r: &Reg, v: u64) {
+        let slot = match *r {
+            Reg::Report(i, _, _) => &mut self.report[i as usize],
+            Reg::Control(i, _) => &mut self.control[i as usize],
+            Reg::Local(i, _) => &mut self.local[i as usize],
+            Reg::Implicit(i, _) => &mut self.implicit[i as usize],
+            Reg::Tmp(i, _) => &mut self.tmp[i as usize],
+            _ => return,
+        };
+        *slot = v;
+    }
+}
+
+/// The value of an immediate register.
+pub(crate) fn imm(r: &Reg) -> Option<u64> {
+    match *r {
+        Reg::ImmNum(n) | Reg::ImmFixed(n) => Some(n),
+        Reg::ImmInt(i) => Some(i as u64),
+        Reg::ImmBool(b) => Some(b as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use lang::{self, Capabilities, Result};
+    use super::{Actions, Machine};
+
+    /// Exercises reports, fixed-point arithmetic, `ewma`, conditionals and `Micros`.
+    pub(crate) const GENERIC_CONG_AVOID: &[u8] = b"
+        (def
+            (Report
+                (volatile acked 0)
+                (volatile sacked 0)
+                (volatile loss 0)
+                (volatile timeout false)
+                (volatile rtt 0)
+                (volatile inflight 0)
+            )
+            (beta 0.7)
+        )
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
+            (:= Report.loss Ack.lost_pkts_sample)
+            (:= Report.timeout Flow.was_timeout)
+            (:= Report.rtt (ewma 2 Flow.rtt_sample_us))
+            (:= Report.inflight Flow.packets_in_flight)
+            (fallthrough)
+        )
+        (when (|| Report.timeout (> Report.loss 50000))
+            (:= Cwnd (max 2896 (* Cwnd beta)))
+            (report)
+        )
+        (when (> Micros (/ Report.rtt 2))
+            (:= Cwnd (+ Cwnd (if (> Report.acked 10000) 1448 0)))
+            (:= Micros 0)
+            (report)
+        )
+    ";
+
+    /// Exercises the version 1 operators, timers and division by zero.
+    pub(crate) const EXTENDED_OPS: &[u8] = b"
+        (def (Report (volatile diff 0) (volatile bits 0) (volatile q 0)) (timer probe))
+        (when (>= Flow.rtt_sample_us 100)
+            (:= Report.diff (absdiff (% Ack.bytes_acked 1448) (<< Ack.packets_acked 2)))
+            (:= Report.bits (| (& Ack.ecn_bytes 255) (>> Ack.ecn_packets (% Ack.now 70))))
+            (:= Report.q (/ Flow.rate_incoming (% Flow.bytes_pending 7)))
+            (:= Rate (wrapped_max Rate Flow.rate_outgoing))
+            (fallthrough)
+        )
+        (when (&& (! (<= probe 30000)) (!= Report.q 3))
+            (:= probe 0)
+            (report)
+        )
+    ";
+
+    /// Signed arithmetic, for datapath version 2.
+    pub(crate) const SIGNED_OPS: &[u8] = b"
+        (def (Report (volatile grad +0) (volatile fast 0) (volatile clamped 0) (acc -1)) (prev +0))
+        (when true
+            (:= Report.grad (- (int Flow.rtt_sample_us) prev))
+            (:= prev (int Flow.rtt_sample_us))
+            (:= Report.fast (sat_sub Ack.bytes_acked Ack.packets_acked))
+            (:= Report.acc (sat_sub (* Report.acc +3) (int Ack.packets_acked)))
+            (:= Report.clamped (num (max (min Report.grad +5000) -5000)))
+            (fallthrough)
+        )
+        (when (|| (< Report.grad -20000) (> (checked_sub Report.acc -7) +1000000))
+            (report)
+        )
+    ";
+
+    /// The time at which test programs are installed.
+    pub(crate) const START: u64 = 1_000_000;
+
+    /// 500 acks with pseudo-random primitives, reproducible from `seed`.
+    pub(crate) fn random_acks(seed: u64, num_primitives: usize) -> Vec<(u64, Vec<u64>)> {
+        // xorshift
+        let mut x = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let mut next = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let mut now = START;
+        (0..500).map(|_| {
+            now += next() % 20_000;
+            let prims = (0..num_primitives).map(|i| match i {
+                14 => next() % 2,
+                _ => next() % 100_000,
+            }).collect();
+            (now, prims)
+        }).collect()
+    }
+
+    /// One line describing the outcome of an ack, e.g. `r [1, 2] c 1448 `.
+    pub(crate) fn describe(a: &Result<Actions>) -> String {
+        let mut out = String::new();
+        match *a {
+            Ok(ref a) => {
+                if let Some(ref fields) = a.report {
+                    out.push_str(&format!("r {:?} ", fields));
+                }
+                if let Some(cwnd) = a.cwnd {
+                    out.push_str(&format!("c {} ", cwnd));
+                }
+                if let Some(rate) = a.rate {
+                    out.push_str(&format!("t {} ", rate));
+                }
+            }
+            Err(_) => out.push_str("err"),
+        }
+
+        out.push('\n');
+        out
+    }
+
+    /// The outcomes of `inputs` according to the interpreter, one line per ack.
+    pub(crate) fn expected(prog: &[u8], caps: Capabilities, inputs: &[(u64, Vec<u64>)]) -> String {
+        let (bin, sc) = lang::compile_with_capabilities(prog, &[], caps).unwrap();
+        let mut m = Machine::new(&bin, &sc, START);
+        let out: String = inputs.iter().map(|&(now, ref prims)| describe(&m.on_ack(prims, now))).collect();
+        assert!(out.contains("r ["), "program never reported");
+        out
+    }
+
+    #[test]
+    fn report_and_reset() {
+        let foo = b"
+        (def (Report (volatile acked 0) (minrtt +infinity)) (beta 0.5))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (fallthrough)
+        )
+        (when (> Micros 100)
+            (:= Cwnd (* Report.acked beta))
+            (:= Micros 0)
+            (report)
+        )
+        ";
+        let (bin, sc) = ::lang::compile(foo, &[]).unwrap();
+        let mut m = Machine::new(&bin, &sc, 1000);
+        let mut prims = vec![0; 15];
+        prims[0] = 1448;
+        prims[13] = 50;
+        assert_eq!(m.on_ack(&prims, 1050).unwrap(), Actions::default());
+        prims[13] = 40;
+        assert_eq!(
+            m.on_ack(&prims, 1101).unwrap(),
+            Actions { report: Some(vec![2896, 40]), cwnd: Some(1448), rate: None },
+        );
+        assert_eq!(m.get(sc.get("Report.acked").unwrap()), Some(0));
+        assert_eq!(m.get(sc.get("Report.minrtt").unwrap()), Some(40));
+        assert_eq!(m.get(sc.get("Micros").unwrap()), Some(0));
+        assert_eq!(m.on_ack(&prims, 1150).unwrap(), Actions::default());
+        assert_eq!(m.get(sc.get("Micros").unwrap()), Some(49));
+    }
+
+    #[test]
+    fn signed_ops() {
+        let foo = b"
+        (def (Report (a 0) (b +0) (c +0) (d 0) (neg false)))
+        (when true
+            (:= Report.a (sat_sub Ack.packets_acked Ack.bytes_acked))
+            (:= Report.b (sat_sub (int Ack.packets_acked) (int Ack.bytes_acked)))
+            (:= Report.c (max (int Ack.bytes_acked) -3))
+            (:= Report.d (num (- (int Ack.packets_acked) (int Ack.bytes_acked))))
+            (:= Report.neg (< (int Ack.bytes_acked) +0))
+        )
+        ";
+        let (bin, sc) = ::lang::compile_with_capabilities(foo, &[], Capabilities::new(2)).unwrap();
+        let mut m = Machine::new(&bin, &sc, 0);
+        let get = |m: &Machine, n| m.get(sc.get(n).unwrap()).unwrap();
+        let mut prims = vec![0; 15];
+        prims[0] = 5;
+        prims[6] = 3;
+        m.on_ack(&prims, 1).unwrap();
+        assert_eq!(get(&m, "Report.a"), 0);
+        assert_eq!(get(&m, "Report.b") as i64, -2);
+        assert_eq!(get(&m, "Report.c"), 5);
+        assert_eq!(get(&m, "Report.d"), 0);
+        assert_eq!(get(&m, "Report.neg"), 0);
+
+        // 2^63 is the most negative Int
+        prims[0] = 1 << 63;
+        prims[6] = 1;
+        m.on_ack(&prims, 2).unwrap();
+        assert_eq!(get(&m, "Report.a"), 0);
+        assert_eq!(get(&m, "Report.b") as i64, i64::max_value());
+        assert_eq!(get(&m, "Report.c") as i64, -3);
+        assert_eq!(get(&m, "Report.d"), 0);
+        assert_eq!(get(&m, "Report.neg"), 1);
+
+        let foo = b"(def (Report.a 0)) (when true (:= Report.a (checked_sub Ack.packets_acked Ack.bytes_acked)))";
+        let (bin, sc) = ::lang::compile_with_capabilities(foo, &[], Capabilities::new(2)).unwrap();
+        let mut m = Machine::new(&bin, &sc, 0);
+        prims[0] = 2;
+        prims[6] = 3;
+        m.on_ack(&prims, 1).unwrap();
+        assert_eq!(m.get(sc.get("Report.a").unwrap()), Some(1));
+        prims[0] = 4;
+        assert!(m.on_ack(&prims, 2).is_err());
+    }
+
+    #[test]
+    fn fixed_division() {
+        let foo = b"(def (Report (ratio 0.0))) (when true (:= Report.ratio (/ Ack.bytes_misordered Ack.bytes_acked)))";
+        let (bin, sc) = ::lang::compile(foo, &[]).unwrap();
+        let mut m = Machine::new(&bin, &sc, 0);
+        let mut prims = vec![0; 15];
+        prims[0] = 3;
+        prims[1] = 2;
+        m.on_ack(&prims, 1).unwrap();
+        // 2/3 * 2^16 = 43690.67, rounded
+        assert_eq!(m.get(sc.get("Report.ratio").unwrap()), Some(43691));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let foo = b"(def (Report.q 0)) (when true (:= Report.q (/ Ack.bytes_acked Ack.packets_acked)))";
+        let (bin, sc) = ::lang::compile(foo, &[]).unwrap();
+        let mut m = Machine::new(&bin, &sc, 0);
+        assert!(m.on_ack(&[0; 15], 1).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Compile-time macros.
+//!
+//! `(defmacro name (params...) body...)` declares a macro, and `(name args...)` expands to its
+//! body with each parameter replaced by the corresponding argument. A macro used where a value is
+//! expected must have a single-expression body; a macro used as a statement may expand to several
+//! statements.
+//!
+//! Expansion is hygienic: a name in a macro body that is not a parameter, a variable of the
+//! program, or a constant becomes a hidden local, so it cannot collide with a name at the call
+//! site. Expansions that do not nest share these locals, so using a macro many times does not use
+//! up the datapath's local registers.
+
+use nom;
+
+use super::{Error, Result};
+use super::ast::{Expr, exprs, name};
+use super::datapath::Scope;
+
+/// Macros may expand to other macros up to this depth.
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+/// Macros available to programs that `(import std)`.
+///
+/// `ack_stats` expects the `Report` fields `acked`, `sacked`, `loss`, `timeout`, `rtt` and
+/// `inflight`. `track_min_rtt` computes the minimum RTT over a window if `v` is a volatile
+/// `Report` field initialized to `+infinity`: it is reset after every report.
+pub const STD: &str = "
+    (defmacro ack_stats ()
+        (:= Report.acked (+ Report.acked Ack.bytes_acked))
+        (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
+        (:= Report.loss Ack.lost_pkts_sample)
+        (:= Report.timeout Flow.was_timeout)
+        (:= Report.rtt Flow.rtt_sample_us)
+        (:= Report.inflight Flow.packets_in_flight)
+    )
+    (defmacro loss_detected ()
+        (|| Flow.was_timeout (> Ack.lost_pkts_sample 0))
+    )
+    (defmacro rtt_elapsed ()
+        (> Micros Flow.rtt_sample_us)
+    )
+    (defmacro track_min_rtt (v)
+        (:= v (min v Flow.rtt_sample_us))
+    )
+";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    params: Vec<String>,
+    body: Vec<Expr>,
+}
+
+named_complete!(
+    pub defmacro<Result<Macro>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("defmacro") >>
+            n: name >>
+            params: delimited!(tag!("("), many0!(name), tag!(")")) >>
+            body: exprs >>
+            ({
+                let body: Result<Vec<Expr>> = body.into_iter().filter(|e| match e {
+                    Ok(Expr::None) => false,
+                    _ => true,
+                }).collect();
+                body.map(|body| Macro { name: n, params, body })
+            })
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    pub import<String>,
+    ws!(delimited!(
+        tag!("("),
+        preceded!(tag!("import"), name),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    defmacros<Vec<Result<Macro>>>,
+    many1!(defmacro)
+);
+
+/// Macros provided by the library `lib`.
+pub fn import_lib(lib: &str) -> Result<Vec<Macro>> {
+    use nom::types::CompleteByteSlice;
+    match lib {
+        "std" => match defmacros(CompleteByteSlice(STD.as_bytes())) {
+            Ok((_, ms)) => ms.into_iter().collect(),
+            Err(e) => Err(Error::from(e)),
+        },
+        _ => Err(Error::from(format!("unknown library {:?}", lib))),
+    }
+}
+
+/// Expands uses of macros in the events of a program.
+pub struct Expander<'a> {
+    macros: &'a [Macro],
+    scope: &'a Scope,
+    consts: &'a [(String, Expr)],
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(macros: &'a [Macro], scope: &'a Scope, consts: &'a [(String, Expr)]) -> Self {
+        Expander {
+            macros,
+            scope,
+            consts,
+        }
+    }
+
+    /// Expand a list of statements. A statement macro expands to its whole body.
+    pub fn stmts(&mut self, body: Vec<Expr>) -> Result<Vec<Expr>> {
+        self.stmts_at(body, 0)
+    }
+
+    /// Expand an expression in value position.
+    pub fn expr(&mut self, e: Expr) -> Result<Expr> {
+        self.expr_at(e, 0)
+    }
+
+    fn stmts_at(&mut self, body: Vec<Expr>, depth: usize) -> Result<Vec<Expr>> {
+        let mut expanded = vec![];
+        for e in body {
+            match e {
+                Expr::Call(n, args) => expanded.extend(self.instantiate(&n, args, depth)?),
+                e => expanded.push(self.expr_at(e, depth)?),
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    fn expr_at(&mut self, e: Expr, depth: usize) -> Result<Expr> {
+        Ok(match e {
+            Expr::Call(n, args) => {
+                let mut body = self.instantiate(&n, args, depth)?;
+                if body.len() != 1 {
+                    return Err(Error::from(format!(
+                        "macro {:?} expands to {} statements, but is used as a value",
+                        n, body.len(),
+                    )));
+                }
+
+                body.pop().unwrap()
+            }
+            Expr::Sexp(o, box l, box r) => Expr::Sexp(
+                o,
+                Box::new(self.expr_at(l, depth)?),
+                Box::new(self.expr_at(r, depth)?),
+            ),
+            Expr::Ite(box c, box a, box b) => Expr::Ite(
+                Box::new(self.expr_at(c, depth)?),
+                Box::new(self.expr_at(a, depth)?),
+                Box::new(self.expr_at(b, depth)?),
+            ),
+            Expr::Cond(clauses) => Expr::Cond(clauses.into_iter()
+                .map(|(c, body)| Ok((self.expr_at(c, depth)?, self.stmts_at(body, depth)?)))
+                .collect::<Result<_>>()?),
+            Expr::Let(v, box val, body) => {
+                let val = self.expr_at(val, depth)?;
+                Expr::Let(v, Box::new(val), self.stmts_at(body, depth)?)
+            }
+            e => e,
+        })
+    }
+
+    fn instantiate(&mut self, name: &str, args: Vec<Expr>, depth: usize) -> Result<Vec<Expr>> {
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(Error::from(format!("macro {:?} expands too deeply (max {})", name, MAX_EXPANSION_DEPTH)));
+        }
+
+        let m = self.macros.iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| Error::from(format!("unknown macro {:?}", name)))?;
+        if args.len() != m.params.len() {
+            return Err(Error::from(format!(
+                "macro {:?} takes {} arguments, got {}",
+                name, m.params.len(), args.len(),
+            )));
+        }
+
+        // arguments are expanded one level down, so their locals are distinct from the body's
+        let args = args.into_iter()
+            .map(|a| self.expr_at(a, depth + 1))
+            .collect::<Result<Vec<_>>>()?;
+
+        // rename the macro's own variables, so they are distinct from the caller's. They are named
+        // after the depth of the expansion: an expansion's locals are only live until it ends, so
+        // the next expansion at the same depth can reuse them.
+        let mut body = m.body.clone();
+        let own_vars: Vec<String> = body.iter()
+            .flat_map(|e| e.names())
+            .filter(|n| !m.params.contains(n))
+            .filter(|n| !self.scope.has(n))
+            .filter(|n| !self.consts.iter().any(|&(ref c, _)| c == n))
+            .collect();
+        for v in own_vars {
+            let fresh = Expr::Atom(::lang::ast::Prim::Name(format!("__m{}_{}", depth, v)));
+            body.iter_mut().for_each(|e| e.substitute(&v, &fresh));
+        }
+
+        // substitute every parameter at once, so a parameter's name in another argument is kept
+        let subs: Vec<(&str, &Expr)> = m.params.iter().map(|p| p.as_str()).zip(args.iter()).collect();
+        body.iter_mut().for_each(|e| e.substitute_all(&subs));
+
+        self.stmts_at(body, depth + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::ast::{Expr, Op, Prim};
+    use lang::prog::Prog;
+
+    #[test]
+    fn std_parses() {
+        let std = super::import_lib("std").unwrap();
+        assert_eq!(
+            std.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(),
+            vec!["ack_stats", "loss_detected", "rtt_elapsed", "track_min_rtt"],
+        );
+    }
+
+    #[test]
+    fn expand() {
+        let foo = b"
+            (defmacro add_to (v x) (:= v (+ v x)))
+            (defmacro twice (x) (* x 2))
+            (def (Report.foo 0) (Report.bar 0))
+            (when true
+                (add_to Report.foo (twice Ack.bytes_acked))
+                (add_to Report.bar 1)
+            )
+        ";
+
+        let (p, _) = Prog::new_with_scope(foo).unwrap();
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        let num = |n| Box::new(Expr::Atom(Prim::Num(n)));
+        assert_eq!(
+            p.0[0].body,
+            vec![
+                Expr::Sexp(
+                    Op::Bind,
+                    name("Report.foo"),
+                    Box::new(Expr::Sexp(
+                        Op::Add,
+                        name("Report.foo"),
+                        Box::new(Expr::Sexp(Op::Mul, name("Ack.bytes_acked"), num(2))),
+                    )),
+                ),
+                Expr::Sexp(Op::Bind, name("Report.bar"), Box::new(Expr::Sexp(Op::Add, name("Report.bar"), num(1)))),
+            ],
+        );
+    }
+
+    #[test]
+    fn hygiene() {
+        let foo = b"
+            (defmacro swap_with_acked (v)
+                (:= tmp v)
+                (:= v Ack.bytes_acked)
+                (:= Report.old tmp)
+            )
+            (def (Report.old 0) (Report.cur 0))
+            (when true
+                (:= tmp 5)
+                (swap_with_acked Report.cur)
+            )
+        ";
+
+        let (p, _) = Prog::new_with_scope(foo).unwrap();
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        assert_eq!(
+            p.0[0].body,
+            vec![
+                Expr::Sexp(Op::Bind, name("tmp"), Box::new(Expr::Atom(Prim::Num(5)))),
+                Expr::Sexp(Op::Bind, name("__m0_tmp"), name("Report.cur")),
+                Expr::Sexp(Op::Bind, name("Report.cur"), name("Ack.bytes_acked")),
+                Expr::Sexp(Op::Bind, name("Report.old"), name("__m0_tmp")),
+            ],
+        );
+
+        // an argument naming another parameter refers to the caller's variable
+        let foo = b"
+            (defmacro f (x y) (:= Report.a x) (:= Report.b y))
+            (def (y 3) (Report (volatile a 0) (volatile b 0)))
+            (when true (f y 7) (report))
+        ";
+
+        let (p, _) = Prog::new_with_scope(foo).unwrap();
+        assert_eq!(
+            p.0[0].body[..2],
+            [
+                Expr::Sexp(Op::Bind, name("Report.a"), name("y")),
+                Expr::Sexp(Op::Bind, name("Report.b"), Box::new(Expr::Atom(Prim::Num(7)))),
+            ],
+        );
+    }
+
+    #[test]
+    fn reuse_locals() {
+        // each expansion needs a local, but there are only 6 local registers
+        let foo = b"
+            (defmacro bump (v) (:= tmp (+ v 1)) (:= v (* tmp 2)))
+            (def (Report.a 0) (Report.b 0) (Report.c 0) (Report.d 0))
+            (when true
+                (bump Report.a) (bump Report.b) (bump Report.c) (bump Report.d)
+                (bump Report.a) (bump Report.b) (bump Report.c) (bump Report.d)
+            )
+        ";
+
+        let (_, sc) = ::lang::compile(foo, &[]).unwrap();
+        assert_eq!(sc.local_names(), vec!["tmp (macro)"]);
+    }
+
+    #[test]
+    fn errors() {
+        // wrong number of arguments
+        assert!(Prog::new_with_scope(b"(defmacro f (x) (+ x 1)) (def (A 0)) (when true (:= A (f 1 2)))").is_err());
+        // multi-statement macro used as a value
+        assert!(Prog::new_with_scope(b"(defmacro f () (:= A 1) (:= A 2)) (def (A 0)) (when true (:= A (f)))").is_err());
+        // unbounded recursion
+        assert!(Prog::new_with_scope(b"(defmacro f () (f)) (def (A 0)) (when true (f))").is_err());
+        // unknown macro
+        assert!(Prog::new_with_scope(b"(def (A 0)) (when true (g))").is_err());
+    }
+
+    #[test]
+    fn std_programs() {
+        let foo = b"
+            (import std)
+            (def (Report
+                (volatile acked 0)
+                (volatile sacked 0)
+                (volatile loss 0)
+                (volatile timeout false)
+                (volatile rtt 0)
+                (volatile inflight 0)
+                (volatile minrtt +infinity)
+            ))
+            (when true
+                (ack_stats)
+                (track_min_rtt Report.minrtt)
+                (fallthrough)
+            )
+            (when (loss_detected)
+                (report)
+            )
+            (when (rtt_elapsed)
+                (report)
+                (:= Micros 0)
+            )
+        ";
+
+        ::lang::compile(foo, &[]).unwrap();
+    }
+}
@@ -26,6 +26,97 @@
 //! )
 //! ```
 //!
+//! A variable declared `persistent` keeps its value when a flow switches to this program with
+//! `set_program`, if the previous program also had a variable of that name and type. Otherwise
+//! it starts from its default value, like any other variable. For example, slow start and
+//! congestion avoidance programs could both declare `(persistent minrtt +infinity)` so that
+//! the minimum RTT carries over.
+//!
+//! Parameters
+//! ----------
+//!
+//! `(param name type [default])` in the `def` clause declares a parameter of the program, which
+//! is set when the program is installed with `set_program` and may be changed with
+//! `update_field`. The type is `num` or `bool`; boolean parameters are set with 0 or 1. A
+//! parameter without a default is required, and `set_program` fails if it is not given a value.
+//! `Scope::params()` lists the parameters of a compiled program.
+//!
+//! ### Example
+//! ```no-run
+//! (def
+//!     (Report (volatile acked 0))
+//!     (param gain num)
+//!     (param use_ecn bool false)
+//! )
+//! ```
+//!
+//! Fixed-Point Numbers
+//! -------------------
+//!
+//! A literal with a decimal point, such as `0.7`, is a fixed-point number with
+//! `lang::FIXED_FRAC_BITS` (16) fractional bits; variables initialized with one are fixed-point
+//! too. Arithmetic and comparisons mixing fixed-point numbers and integers convert the integers,
+//! and products and quotients of fixed-point numbers are rounded to the nearest representable
+//! value. A quotient of integers assigned to a fixed-point variable is computed in fixed point
+//! and rounded, rather than truncated to an integer. Assigning a fixed-point value to an integer
+//! variable, such as `Cwnd`, rounds it to the nearest integer. `Report::get_field_f64()` reads a fixed-point `Report` field as an `f64`.
+//!
+//! Fixed-point values are stored as 64-bit integers scaled by 2^16, so a product of two
+//! fixed-point values overflows if it exceeds 2^32, and the whole part of a literal must be
+//! smaller than 2^48; larger literals fail to compile.
+//!
+//! ### Example
+//! ```no-run
+//! (def (Report (volatile loss 0)) (beta 0.7))
+//! (when (> Ack.lost_pkts_sample 0)
+//!     (:= Cwnd (* Cwnd beta))
+//!     (report)
+//! )
+//! ```
+//!
+//! Timers
+//! ------
+//!
+//! Besides `Micros`, the microseconds since the program was installed or `Micros` was last reset,
+//! a program may declare timers with `(timer name)` in the `def` clause. The datapath advances
+//! each timer like `Micros`, and `(:= name 0)` resets it independently of the others. Timers use
+//! extra implicit registers, so they are only available if the datapath's `Capabilities` include
+//! them (see `Capabilities::with_timers()`). When portus runs an algorithm, programs may declare
+//! up to `Capabilities::MAX_TIMERS` timers, and a flow can only switch to a program if its
+//! datapath reported at least as many timers when the flow was created.
+//!
+//! ### Example
+//! ```no-run
+//! (def (Report (volatile minrtt +infinity)) (timer probe))
+//! (when (> probe 10000000)
+//!     (:= probe 0)
+//!     (report)
+//! )
+//! ```
+//!
+//! Histograms
+//! ----------
+//!
+//! `(name (hist b1 b2 ...))` in the `Report` struct declares a histogram with increasing bucket
+//! boundaries `b1 b2 ...`, and `(observe Report.name v)` counts `v` in the bucket it falls in:
+//! values below `b1` in the first, values from `b1` up to `b2` in the second, and so on, with
+//! values of at least the last boundary in the last bucket. Each bucket takes a Report field, so a
+//! report carries the distribution of a measurement over an interval instead of one sample.
+//! `Report::get_histogram()` returns the bucket counts, and `Scope::histogram()` the boundaries.
+//!
+//! ### Example
+//! ```no-run
+//! (def (Report (volatile rtts (hist 10000 20000 50000))))
+//! (when true
+//!     (observe Report.rtts Flow.rtt_sample_us)
+//!     (fallthrough)
+//! )
+//! (when (> Micros 100000)
+//!     (:= Micros 0)
+//!     (report)
+//! )
+//! ```
+//!
 //! Event Definitions
 //! -----------------
 //!
@@ -46,12 +137,101 @@
 //! )
 //! ```
 //!
+//! `(on-rise cond body...)` runs its body only on the acks where `cond` holds but did not the last
+//! time the event was evaluated, and `(on-fall cond body...)` only where it stopped holding. A
+//! condition is taken to be false before the first ack. A last `(otherwise body...)` event runs
+//! its body if no earlier event's body ran. These forms are lowered to `when` events and hidden
+//! control variables, one per edge-triggered event and one for `otherwise`.
+//!
+//! ### Example
+//! ```no-run
+//! (on-rise (> Ack.lost_pkts_sample 0)
+//!     (:= Cwnd (/ Cwnd 2))
+//!     (report)
+//! )
+//! (otherwise
+//!     (:= Cwnd (+ Cwnd Ack.bytes_acked))
+//! )
+//! ```
+//!
+//! Constants and Local Variables
+//! -----------------------------
+//!
+//! `(defconst NAME value)` declares a named constant, before or after the `def` clause. Constants
+//! are substituted at compile time, including in the initial values of variables.
+//! `(let (x expr) body...)` binds the local variable `x` to `expr` within `body`. Locals use the
+//! datapath's local registers, of which there are 6; programs that need more fail to compile.
+//!
+//! ### Example
+//! ```no-run
+//! (defconst MSS 1448)
+//! (def (Report (volatile acked 0)))
+//! (when true
+//!     (let (pkts (/ Ack.bytes_acked MSS))
+//!         (:= Report.acked (+ Report.acked pkts))
+//!     )
+//! )
+//! ```
+//!
+//! Macros
+//! ------
+//!
+//! `(defmacro name (params...) body...)` declares a macro, which is expanded at compile time
+//! wherever `(name args...)` appears. Like constants, macros are declared before or after the
+//! `def` clause. `(import std)` makes the macros in `lang::macros::STD` available, which
+//! accumulate the usual ACK statistics (`ack_stats`), detect loss (`loss_detected`), check whether
+//! an RTT has passed since `Micros` was reset (`rtt_elapsed`), and track the minimum RTT
+//! (`track_min_rtt`). See the `lang::macros` module for details.
+//!
+//! ### Example
+//! ```no-run
+//! (import std)
+//! (defmacro add_acked (v) (:= v (+ v Ack.bytes_acked)))
+//! (def (Report (volatile acked 0) (volatile minrtt +infinity)))
+//! (when true
+//!     (add_acked Report.acked)
+//!     (track_min_rtt Report.minrtt)
+//!     (fallthrough)
+//! )
+//! (when (rtt_elapsed)
+//!     (report)
+//!     (:= Micros 0)
+//! )
+//! ```
+//!
+//! Conditionals
+//! ------------
+//!
+//! `(if c a b)` evaluates to `a` if `c` is true and to `b` otherwise. `(cond ...)` runs the body
+//! of the first clause whose condition is true; a final `else` clause runs if none is. All of
+//! the conditions are evaluated before any body runs.
+//!
+//! ### Example
+//! ```no-run
+//! (when true
+//!     (:= Cwnd (if (> Ack.lost_pkts_sample 0) (/ Cwnd 2) (+ Cwnd Ack.bytes_acked)))
+//!     (cond
+//!         ((> Ack.lost_pkts_sample 0)
+//!             (:= Report.loss Ack.lost_pkts_sample)
+//!             (report)
+//!         )
+//!         (else
+//!             (:= Report.acked (+ Report.acked Ack.bytes_acked))
+//!         )
+//!     )
+//! )
+//! ```
+//!
 //! Compiling
 //! ---------
 //!
 //! `lang::compile()` will take a byte array with datapath program source and produce a `Bin`,
 //! which contains a series of instructions and can be serialized into a format libccp-compliant
-//! datapaths understand.
+//! datapaths understand. The `Scope`'s `program_uid`, which identifies the program to the
+//! datapath, is a hash of the `Bin`, so identical programs share it. Uids are sparse 32-bit
+//! values, so datapaths must look programs up by uid rather than index a table with it, and two
+//! different programs can, rarely, share a uid; portus refuses to install both. A `CompileCache`
+//! compiles each distinct source only once.
 //!
 //! ### Example
 //!
@@ -70,13 +250,131 @@
 //!         )
 //!         (when (> Micros 1000)
 //!             (report)
-//!             (reset)
+//!             (:= Micros 0)
 //!         )
 //!     ";
 //!     let (bin, scope) = lang::compile(my_cool_program, &[]).unwrap();
 //! }
 //! ```
 //!
+//! Datapath Versions
+//! -----------------
+//!
+//! Besides the arithmetic and comparison operators of the original instruction set, datapaths
+//! implementing version 1 support `%`, `<<`, `>>`, `&` (bitwise and), `|` (bitwise or), `>=`,
+//! `<=`, `!=`, `!` (logical not) and `absdiff`. Pass a `Capabilities` to
+//! `lang::compile_with_capabilities()` to target a datapath's version. When compiling for
+//! version 0, `>=`, `<=`, `!=`, `!`, `absdiff`, `%` and shifts by a constant are rewritten in terms
+//! of older operators; the bitwise operators and shifts by a variable amount are rejected.
+//!
+//! Integers and Overflow
+//! ---------------------
+//!
+//! Numbers are unsigned 64-bit integers. Datapaths implementing version 2 also support `Int`s,
+//! signed 64-bit integers: a variable is an `Int` if its default value is a signed literal such as
+//! `-5` or `+5`. `Int`s and numbers do not mix; `(int x)` converts a number to an `Int`, and
+//! `(num x)` converts an `Int` to a number, clamping negative values to `0`.
+//!
+//! | Operator                  | Numbers                   | `Int`s                    |
+//! |---------------------------|---------------------------|---------------------------|
+//! | `+`, `-`, `*`             | wrap around modulo 2^64   | wrap around modulo 2^64   |
+//! | `sat_sub`                 | stops at `0`              | stops at the `Int` limits |
+//! | `checked_sub`             | fails the ack if negative | fails the ack on overflow |
+//! | `<`, `>`, `<=`, `>=`      | unsigned                  | signed                    |
+//! | `max`, `min`              | unsigned                  | signed                    |
+//! | `==`, `!=`                | equality                  | equality                  |
+//!
+//! An ack whose `checked_sub` fails stops running the program, leaving the variables it has
+//! already assigned. `/`, `%`, `<<`, `>>`, `&`, `|`, `absdiff`, `ewma` and `wrapped_max` only
+//! take numbers. When compiling for older datapaths, `sat_sub` is rewritten in terms of `max`,
+//! and `checked_sub` and `Int`s are rejected.
+//!
+//! ```
+//! use portus::lang::{self, Capabilities};
+//! let src = b"
+//!     (def (Report (volatile gap 0)) (target 100))
+//!     (when true
+//!         (:= Report.gap (num (- (int target) (int Ack.bytes_acked))))
+//!         (:= target (sat_sub target Ack.bytes_acked))
+//!     )
+//! ";
+//! assert!(lang::compile_with_capabilities(src, &[], Capabilities::new(2)).is_ok());
+//! assert!(lang::compile_with_capabilities(src, &[], Capabilities::new(1)).is_err());
+//! ```
+//!
+//! Primitives
+//! ----------
+//!
+//! `Ack.*` and `Flow.*` names are measurements the datapath provides, such as
+//! `Ack.bytes_acked` or `Flow.rtt_sample_us`. `Primitives::default()` lists those every datapath
+//! provides, with their types and documentation. A datapath measuring other signals registers
+//! them in its `Capabilities`, which assign each primitive its register:
+//!
+//! ```
+//! use portus::lang::{self, Capabilities, Primitives, Type};
+//! let mut primitives = Primitives::default();
+//! primitives.register("Ack.app_limited", Type::Bool(None), "Whether the sender was app-limited.").unwrap();
+//! let capabilities = Capabilities { primitives, ..Capabilities::new(1) };
+//! let src = b"(def (Report (volatile limited false))) (when true (:= Report.limited Ack.app_limited))";
+//! assert!(lang::compile_with_capabilities(src, &[], capabilities.clone()).is_ok());
+//! // a misspelled primitive is a compile error, not a new variable
+//! let src = b"(def (Report (volatile limited false))) (when true (:= Report.limited Ack.app_limitd))";
+//! assert!(lang::compile_with_capabilities(src, &[], capabilities).is_err());
+//! ```
+//!
+//! Registers
+//! ---------
+//!
+//! The datapath provides each program with 16 registers for `Report` fields, 16 for control
+//! variables and parameters, 6 for local variables and 16 for the intermediate values of an
+//! expression. Intermediate registers are reused once their values have been read, and `Report`
+//! fields the program never reads or writes are not stored in the datapath at all. A program that
+//! still needs more registers than the datapath provides fails to compile with an error naming
+//! the variables involved.
+//!
+//! Formatting
+//! ----------
+//!
+//! `lang::fmt::format()` prints a program in canonical layout, keeping its comments. The `ccp-fmt`
+//! binary applies it to `.ccp` files and to programs embedded in Rust or Python string literals.
+//!
+//! Cost Analysis
+//! -------------
+//!
+//! `lang::analysis::analyze()` estimates the instructions a program runs on each ack, in the
+//! worst case and on average, how often it reports, and how many registers it uses. `dump_fold`
+//! and `cargo compile-fast-path --analyze` print it. Programs compiled for `Capabilities` with an
+//! instruction budget (see `Capabilities::with_instruction_budget()`, which portus sets from
+//! `CongAlg::instruction_budget()`) fail to compile if they may run too many instructions.
+//!
+//! Native Code
+//! -----------
+//!
+//! `lang::codegen::to_c()` compiles a program to a C function, for datapaths that do not embed
+//! libccp's interpreter. `lang::interp::Machine` executes compiled programs in Rust, and defines
+//! the semantics the generated code follows. `lang::ebpf::compile()` compiles a program to eBPF
+//! bytecode, for datapaths implementing congestion control in BPF.
+//!
+//! Program Files
+//! -------------
+//!
+//! Programs may be kept in `.ccp` files and read with `lang::load_file()` or compiled with
+//! `lang::compile_file()` (or `lang::compile_file_with_capabilities()`). A top-level
+//! `(include "path")` in a file is replaced by the contents of the named file, resolved relative
+//! to the directory of the including file; this is how programs share constants and macros. A
+//! file included more than once is only expanded the first time. Include cycles are an error.
+//!
+//! `lang::load_dir()` reads every `.ccp` file in a directory as a program named after the file.
+//! Included fragments should live in a subdirectory.
+//!
+//! ```text
+//! (include "lib/common.ccp")
+//! (def (Report (volatile acked 0)))
+//! (when true
+//!     (add_acked Report.acked)
+//! )
+//! ```
+//!
 //! Available Primitives
 //! --------------------
 //!
@@ -155,6 +453,11 @@ impl From<std::num::ParseIntError> for Error {
         Error(format!("int err {}", e))
     }
 }
+impl From<std::fmt::Error> for Error {
+    fn from(e: std::fmt::Error) -> Error {
+        Error(format!("fmt err {}", e))
+    }
+}
 
 /// Define this helper macro to replace the named! macro provided by nom
 /// to address https://github.com/Geal/nom/issues/790 with CompleteByteSlice
@@ -171,15 +474,31 @@ macro_rules! named_complete {
     )
 }
 
+pub mod analysis;
 mod ast;
+mod cache;
+pub mod codegen;
 mod datapath;
+pub mod ebpf;
+pub mod fmt;
+mod include;
+pub mod interp;
+pub mod macros;
 mod prog;
 mod serialize;
 
+pub use self::ast::FIXED_FRAC_BITS;
+pub(crate) use self::datapath::bucket_name;
+pub use self::cache::CompileCache;
 pub use self::datapath::Bin;
+pub use self::datapath::Capabilities;
+pub use self::datapath::Param;
+pub use self::datapath::Primitive;
+pub use self::datapath::Primitives;
 pub use self::datapath::Type;
 pub use self::datapath::Reg;
 pub use self::datapath::Scope;
+pub use self::include::{is_fragment, load_dir, load_file, starts_with_top_level_form};
 pub use self::prog::Prog;
 
 /// `compile()` uses 5 passes to yield Instrs.
@@ -191,19 +510,45 @@ pub use self::prog::Prog;
 /// 4. The list of runtime updates (from `updates`) for values is applied to the Scope.
 /// 5. `Bin::compile_prog()` turns a `Prog` into a `Bin`, which is a `Vec` of datapath `Instr`
 pub fn compile(src: &[u8], updates: &[(&str, u32)]) -> Result<(Bin, Scope)> {
-    Prog::new_with_scope(src)
+    compile_with_capabilities(src, updates, Capabilities::default())
+}
+
+/// Like `compile()`, for a datapath with the given `Capabilities`.
+pub fn compile_with_capabilities(src: &[u8], updates: &[(&str, u32)], capabilities: Capabilities) -> Result<(Bin, Scope)> {
+    Prog::new_with_capabilities(src, capabilities)
         .and_then(|(p, mut s)| {
             for &(name, new_val) in updates {
-                match s.update_type(name, &Type::Num(Some(new_val as u64))) {
+                let t = match s.param(name) {
+                    Some(p) => p.value(new_val)?,
+                    None => Type::Num(Some(new_val as u64)),
+                };
+
+                match s.update_type(name, &t) {
                     Ok(_) => {},
                     Err(e) => println!("err: {}", e)
                 }
             }
 
-            Ok((Bin::compile_prog(&p, &mut s)?, s))
+            let bin = Bin::compile_prog(&p, &mut s)?;
+            if let Some(budget) = s.capabilities.instruction_budget {
+                analysis::analyze(&bin, &s).check_budget(budget)?;
+            }
+
+            Ok((bin, s))
         })
 }
 
+/// Like `compile()`, for the program in the file at `path`. See `load_file()`.
+pub fn compile_file<P: AsRef<std::path::Path>>(path: P, updates: &[(&str, u32)]) -> Result<(Bin, Scope)> {
+    compile_file_with_capabilities(path, updates, Capabilities::default())
+}
+
+/// Like `compile_file()`, for a datapath with the given `Capabilities`.
+pub fn compile_file_with_capabilities<P: AsRef<std::path::Path>>(path: P, updates: &[(&str, u32)], capabilities: Capabilities) -> Result<(Bin, Scope)> {
+    let src = load_file(path)?;
+    compile_with_capabilities(src.as_bytes(), updates, capabilities)
+}
+
 /// `compile_and_serialize()` adds a fourth pass.
 /// The resulting bytes can be passed to the datapath.
 ///
@@ -287,4 +632,26 @@ mod tests {
         ".as_bytes();
         b.iter(|| super::compile_and_serialize(fold, &[]).unwrap())
     }
+
+    #[bench]
+    fn bench_report_get_field(b: &mut Bencher) {
+        use serialize::{self, measure};
+        let fold = "
+            (def (Report.foo 0) (Report.bar 0))
+            (when true
+                (:= Report.foo (+ Report.foo Ack.bytes_acked))
+                (:= Report.bar (+ Report.bar Ack.bytes_misordered))
+            )
+        ".as_bytes();
+        let (_, sc) = super::compile(fold, &[]).unwrap();
+        let m = measure::Msg { sid: 1, program_uid: sc.program_uid, num_fields: 2, fields: vec![42, 7] };
+        let buf = serialize::serialize(&m).unwrap();
+        b.iter(|| match serialize::Msg::from_buf(&buf[..]).unwrap().0 {
+            serialize::Msg::Ms(m) => {
+                let r = ::Report { program_uid: m.program_uid, fields: m };
+                r.get_field("Report.foo", &sc).unwrap() + r.get_field("Report.bar", &sc).unwrap()
+            }
+            _ => unreachable!(),
+        })
+    }
 }
@@ -1,8 +1,9 @@
 use nom;
 
 use super::{Error, Result};
-use super::ast::{atom, comment, expr, Expr, exprs, name};
-use super::datapath::{Scope, Type, check_atom_type};
+use super::ast::{atom, comment, expr, Command, Expr, exprs, flatten_lets, name, Op, Prim};
+use super::datapath::{Capabilities, Scope, Type, check_atom_type};
+use super::macros::{defmacro, import, import_lib, Expander, Macro};
 
 /// An `Event` is a condition expression and a sequence of execution expressions.
 /// If the condition expression evaluates to `true`, the execution expressions are
@@ -19,77 +20,286 @@ pub struct Event {
 #[derive(Debug, PartialEq)]
 pub struct Prog(pub Vec<Event>);
 
+/// How an event's condition decides whether its body runs. `Prog::desugar` turns every other kind
+/// of event into plain `when` events.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trigger {
+    /// `(when cond ...)`: whenever `cond` holds.
+    When,
+    /// `(on-rise cond ...)`: when `cond` holds, but did not the last time it was evaluated.
+    Rise,
+    /// `(on-fall cond ...)`: when `cond` does not hold, but did the last time it was evaluated.
+    Fall,
+    /// `(otherwise ...)`: when no earlier event's body ran.
+    Otherwise,
+}
+
 // ------------------------------------------
 // (def (decl)...) grammar
 // ------------------------------------------
 
+/// How long the value of a variable lasts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lifetime {
+    /// Until the program is replaced.
+    Program,
+    /// Until the next `(report)`.
+    Volatile,
+    /// Across switches to programs which also declare the variable.
+    Persistent,
+}
+
+named_complete!(
+    lifetime<Lifetime>,
+    map!(opt!(alt!(
+        tag!("volatile")   => { |_| Lifetime::Volatile } |
+        tag!("persistent") => { |_| Lifetime::Persistent }
+    )), |l: Option<Lifetime>| l.unwrap_or(Lifetime::Program))
+);
+
 // Declare a state variable and provide an initial value
-// Optionally declare the variable "volatile", meaning it gets reset on "(report)"
+// Optionally declare the variable "volatile", meaning it gets reset on "(report)", or
+// "persistent", meaning it keeps its value when the flow switches to another program
+// declaring a variable of the same name and type
 named_complete!(
-    decl<(bool, Type, Type)>,
+    decl<(Lifetime, Type, Type)>,
     ws!(delimited!(
         tag!("("),
         tuple!(
-            map!(opt!(tag!("volatile")), |v: Option<nom::types::CompleteByteSlice>| v.is_some()),
+            lifetime,
             map!(name, |n: String| Type::Name(n)),
             map_res!(atom, |a: Result<Expr>| a.and_then(|i| check_atom_type(&i)))
         ),
         tag!(")")
     ))
 );
+
+// Declare a histogram in the Report struct, with the given bucket boundaries:
+// (rtts (hist 1000 5000 20000)), optionally "volatile"
 named_complete!(
-    report_struct<Vec<(bool, Type, Type)>>,
+    hist_decl<(bool, String, Vec<Result<Expr>>)>,
+    ws!(delimited!(
+        tag!("("),
+        tuple!(
+            map!(opt!(tag!("volatile")), |v: Option<nom::types::CompleteByteSlice>| v.is_some()),
+            name,
+            delimited!(tag!("("), preceded!(tag!("hist"), many1!(atom)), tag!(")"))
+        ),
+        tag!(")")
+    ))
+);
+named_complete!(
+    report_struct<Vec<Def>>,
     ws!(delimited!(
         tag!("("),
         do_parse!(
             tag!("Report") >>
-            d: many1!(decl) >> 
+            d: many1!(alt!(
+                hist_decl => { |(v, n, b)| Def::Hist(v, n, b) } |
+                decl      => { |(v, n, t)| Def::Var(v, n, t) }
+            )) >> 
             (d)
         ),
         tag!(")")
     ))
 );
 
+// Declare a parameter of the program, set when it is installed:
+// (param name num|bool [default])
+named_complete!(
+    param<(String, String, Option<Result<Expr>>)>,
+    ws!(delimited!(
+        tag!("("),
+        tuple!(
+            preceded!(tag!("param"), name),
+            name,
+            opt!(atom)
+        ),
+        tag!(")")
+    ))
+);
+
+/// The type of the parameter `n`, declared as `t`, holding its default value, if any.
+/// The default may name one of `consts`.
+fn param_type(n: &str, t: &str, default: Option<Result<Expr>>, consts: &[(String, Expr)]) -> Result<Type> {
+    let default = match default.map_or(Ok(None), |d| d.map(Some))? {
+        Some(Expr::Atom(Prim::Name(c))) => Some(consts.iter()
+            .find(|&&(ref name, _)| *name == c)
+            .map(|&(_, ref v)| v.clone())
+            .ok_or_else(|| Error::from(format!("default value of parameter {:?} is unknown: {:?}", n, c)))?),
+        d => d,
+    };
+
+    match (t, default.map_or(Ok(None), |d| check_atom_type(&d).map(Some))?) {
+        ("num", None) => Ok(Type::Num(None)),
+        ("bool", None) => Ok(Type::Bool(None)),
+        ("num", Some(d @ Type::Num(_))) | ("bool", Some(d @ Type::Bool(_))) => Ok(d),
+        ("num", Some(_)) | ("bool", Some(_)) => Err(Error::from(format!(
+            "default value of parameter {:?} is not a {}", n, t,
+        ))),
+        _ => Err(Error::from(format!("parameter {:?} has unknown type {:?}, expected num or bool", n, t))),
+    }
+}
+
+/// The bucket boundaries of the histogram `n`, which may name one of `consts`. They must be
+/// increasing.
+fn hist_bounds(n: &str, bounds: Vec<Result<Expr>>, consts: &[(String, Expr)]) -> Result<Vec<u64>> {
+    let mut vals: Vec<u64> = vec![];
+    for b in bounds {
+        let b = match b? {
+            Expr::Atom(Prim::Name(c)) => consts.iter()
+                .find(|&&(ref name, _)| *name == c)
+                .map(|&(_, ref v)| v.clone())
+                .ok_or_else(|| Error::from(format!("bucket boundary of histogram {:?} is unknown: {:?}", n, c)))?,
+            b => b,
+        };
+
+        match b {
+            Expr::Atom(Prim::Num(v)) if vals.last().map_or(true, |&l| l < v) => vals.push(v),
+            Expr::Atom(Prim::Num(_)) => return Err(Error::from(format!(
+                "bucket boundaries of histogram {:?} must be increasing", n,
+            ))),
+            b => return Err(Error::from(format!(
+                "bucket boundary of histogram {:?} is not a number: {:?}", n, b,
+            ))),
+        }
+    }
+
+    Ok(vals)
+}
+
+// Declare a timer, which the datapath advances like Micros: (timer name)
+named_complete!(
+    timer<String>,
+    ws!(delimited!(
+        tag!("("),
+        preceded!(tag!("timer"), name),
+        tag!(")")
+    ))
+);
+
+/// A declaration in the `(def ...)` block.
+enum Def {
+    Var(Lifetime, Type, Type),
+    Param(String, String, Option<Result<Expr>>),
+    Timer(String),
+    Hist(bool, String, Vec<Result<Expr>>),
+}
+
+/// A histogram declared in the `Report` struct: whether it is volatile, its name, and its
+/// bucket boundaries.
+type HistDecl = (bool, String, Vec<Result<Expr>>);
+
+named_complete!(
+    def_item<Def>,
+    alt!(
+        param => { |(n, t, d)| Def::Param(n, t, d) } |
+        timer => { |n| Def::Timer(n) }               |
+        decl  => { |(v, n, t)| Def::Var(v, n, t) }
+    )
+);
+
 // a Prog has special syntax *at the beginning* to declare variables.
 // (def (decl) ...)
+// Returns the variables, the parameters, the timers, and the histograms.
 named_complete!(
-    defs<Vec<(bool, Type, Type)>>,
+    defs<(Vec<(Lifetime, Type, Type)>, Vec<(String, String, Option<Result<Expr>>)>, Vec<String>, Vec<HistDecl>)>,
     ws!(delimited!(
         tag!("("),
         do_parse!(
             tag!("def") >> 
-            defs1 : many0!(decl) >>
+            defs1 : many0!(def_item) >>
             reports : opt!(report_struct) >>
-            defs2 :  many0!(decl) >>
-            (
-                reports.into_iter().flat_map(|v| v.into_iter())
-                    .filter_map(|(is_volatile, name, init_val)| {
+            defs2 :  many0!(def_item) >>
+            ({
+                let (mut vars, mut params, mut timers) = (vec![], vec![], vec![]);
+                for d in defs1.into_iter().chain(defs2) {
+                    match d {
+                        Def::Var(lifetime, name, init_val) => vars.push((lifetime, name, init_val)),
+                        Def::Param(n, t, d) => params.push((n, t, d)),
+                        Def::Timer(n) => timers.push(n),
+                        Def::Hist(..) => unreachable!(),
+                    }
+                }
+
+                let (mut report_vars, mut hists) = (vec![], vec![]);
+                for d in reports.into_iter().flat_map(|v| v.into_iter()) {
+                    match d {
+                        Def::Var(lifetime, name, init_val) => report_vars.push((lifetime, name, init_val)),
+                        Def::Hist(is_volatile, n, bounds) => hists.push((is_volatile, format!("Report.{}", n), bounds)),
+                        _ => unreachable!(),
+                    }
+                }
+
+                let vars = report_vars.into_iter()
+                    .filter_map(|(lifetime, name, init_val)| {
                         match name {
                             Type::Name(name) => Some(Type::Name(format!("Report.{}", name))),
                             _ => None
                         }.map(|full_name| 
                             match init_val {
-                                x@ Type::Num(_) | x@ Type::Bool(_) => (is_volatile, full_name, x),
-                                _ => (is_volatile, full_name, Type::None)
+                                x@ Type::Num(_) | x@ Type::Bool(_) | x@ Type::Fixed(_) | x@ Type::Int(_) | x@ Type::Name(_) => (lifetime, full_name, x),
+                                _ => (lifetime, full_name, Type::None)
                             }
                         )
                     })
                     .chain(
-                        defs1.into_iter()
-                            .chain(defs2)
-                            .map(|(is_volatile, name, init_val)| {
+                        vars.into_iter()
+                            .map(|(lifetime, name, init_val)| {
                             match init_val {
-                                x@ Type::Num(_) | x@ Type::Bool(_) => (is_volatile, name, x),
-                                _ => (is_volatile, name, Type::None)
+                                x@ Type::Num(_) | x@ Type::Bool(_) | x@ Type::Fixed(_) | x@ Type::Int(_) | x@ Type::Name(_) => (lifetime, name, x),
+                                _ => (lifetime, name, Type::None)
                             } 
                         })
-                    ).collect()
-            )
+                    ).collect();
+                (vars, params, timers, hists)
+            })
         ),
         tag!(")")
     ))
 );
 
+// ------------------------------------------
+// (defconst NAME value) grammar
+// ------------------------------------------
+
+named_complete!(
+    defconst<(String, Result<Expr>)>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("defconst") >>
+            n: name >>
+            v: atom >>
+            ((n, v))
+        ),
+        tag!(")")
+    ))
+);
+
+/// The keywords which begin the top-level forms of a program or fragment, including
+/// `(include ...)`, which is resolved before parsing.
+pub(crate) const TOP_LEVEL_FORMS: &[&str] = &[
+    "def", "defconst", "defmacro", "import", "include", "when", "otherwise", "on-rise", "on-fall",
+];
+
+/// A declaration outside the `(def ...)` block.
+enum Decl {
+    Const(String, Result<Expr>),
+    Macro(Result<Macro>),
+    Import(String),
+}
+
+// Constants, macros and imports may be declared before or after the (def ...) block.
+named_complete!(
+    decls<Vec<Decl>>,
+    many0!(alt!(
+        defconst => { |(n, v)| Decl::Const(n, v) } |
+        defmacro => { |m| Decl::Macro(m) }         |
+        import   => { |l| Decl::Import(l) }
+    ))
+);
+
 // ------------------------------------------
 // (when (bool expr) (body)...) grammar
 // ------------------------------------------
@@ -116,11 +326,60 @@ named_complete!(
         tag!(")")
     ))
 );
+
+fn new_event(flag: Result<Expr>, body: Vec<Result<Expr>>) -> Result<Event> {
+    Ok(Event{
+        flag: flag?,
+        body: body.into_iter().collect::<Result<_>>()?,
+    })
+}
+
+named_complete!(
+    edge<Trigger>,
+    alt!(
+        tag!("on-rise") => { |_| Trigger::Rise } |
+        tag!("on-fall") => { |_| Trigger::Fall }
+    )
+);
+
+// (on-rise (single expr) (expr)...) or (on-fall (single expr) (expr)...)
 named_complete!(
-    events<Vec<Result<Event>>>,
+    edge_event<Result<(Trigger, Event)>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            t : edge >>
+            c : expr >>
+            body : exprs >>
+            (new_event(c, body).map(|ev| (t, ev)))
+        ),
+        tag!(")")
+    ))
+);
+
+// (otherwise (expr)...)
+named_complete!(
+    otherwise<Result<(Trigger, Event)>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("otherwise") >>
+            body : exprs >>
+            (new_event(Ok(Expr::Atom(Prim::Bool(true))), body).map(|ev| (Trigger::Otherwise, ev)))
+        ),
+        tag!(")")
+    ))
+);
+
+named_complete!(
+    events<Vec<Result<(Trigger, Event)>>>,
     many1!(do_parse!(
         opt!(comment) >>
-        e: event >>
+        e: alt!(
+            event => { |e: Result<Event>| e.map(|ev| (Trigger::When, ev)) } |
+            edge_event |
+            otherwise
+        ) >>
         (e)
     ))
 );
@@ -129,28 +388,99 @@ impl Prog {
     /// Turn raw bytes into an AST representation, including implementing syntactic sugar features
     /// such as `(report)` and `(fallthrough)`. 
     pub fn new_with_scope(source: &[u8]) -> Result<(Self, Scope)> {
-        let mut scope = Scope::new();
+        Prog::new_with_capabilities(source, Capabilities::default())
+    }
+
+    /// Like `new_with_scope()`, for a datapath with the given `Capabilities`.
+    pub fn new_with_capabilities(source: &[u8], capabilities: Capabilities) -> Result<(Self, Scope)> {
+        check_reserved_names(source)?;
+        let mut scope = Scope::with_capabilities(capabilities);
         use nom::Needed;
         use nom::types::CompleteByteSlice;
-        let body = match defs(CompleteByteSlice(source)) {
-            Ok((rest, flow_state)) => {
-                let (reports, controls): (Vec<(bool, String, Type)>, Vec<(bool, String, Type)>) = flow_state
+        let (body, mut declared) = decls(CompleteByteSlice(source)).map_err(Error::from)?;
+        let body = match defs(body) {
+            Ok((rest, (flow_state, params, timers, hists))) => {
+                let (rest, more_decls) = decls(rest).map_err(Error::from)?;
+                declared.extend(more_decls);
+                let mut consts = vec![];
+                let mut macros = vec![];
+                for d in declared {
+                    match d {
+                        Decl::Const(n, v) => consts.push((n, v)),
+                        Decl::Macro(m) => macros.push(m?),
+                        Decl::Import(lib) => macros.extend(import_lib(&lib)?),
+                    }
+                }
+
+                let consts = resolve_consts(consts)?;
+                let (reports, controls): (Vec<(Lifetime, String, Type)>, Vec<(Lifetime, String, Type)>) = flow_state
                     .into_iter()
-                    .map(|(is_volatile, var, typ)| match var {
-                        Type::Name(v) => (is_volatile, v, typ),
+                    .map(|(lifetime, var, typ)| match var {
+                        Type::Name(v) => (lifetime, v, typ),
                         _ => unreachable!(),
                     })
+                    .map(|(lifetime, var, typ)| match typ {
+                        Type::Name(n) => match consts.iter().find(|&&(ref c, _)| *c == n) {
+                            Some(&(_, ref val)) => (lifetime, var, check_atom_type(val).unwrap()),
+                            None => (lifetime, var, Type::None),
+                        },
+                        t => (lifetime, var, t),
+                    })
                     .partition(|&(_, ref var, _)| var.starts_with("Report."));
 
-                for (is_volatile, var, typ) in reports {
-                    scope.new_report(is_volatile, var, typ); 
+                if let Some(&(_, ref var, _)) = reports.iter().chain(controls.iter())
+                    .find(|&&(_, _, ref typ)| match *typ { Type::Int(_) => true, _ => false }) {
+                    if !scope.capabilities().supports_signed_ops() {
+                        return Err(Error::from(format!(
+                            "Int variable {:?} requires datapath version {}",
+                            var, Capabilities::SIGNED_OPS_VERSION,
+                        )));
+                    }
+                }
+
+                for (lifetime, var, typ) in reports.into_iter().chain(controls) {
+                    if lifetime == Lifetime::Persistent {
+                        scope.new_persistent(var.clone());
+                    }
+
+                    if var.starts_with("Report.") {
+                        scope.new_report(lifetime == Lifetime::Volatile, var, typ);
+                    } else {
+                        scope.new_control(var, typ);
+                    }
+                }
+
+                for (n, t, default) in params {
+                    let typ = param_type(&n, &t, default, &consts)?;
+                    if scope.has(&n) {
+                        return Err(Error::from(format!("parameter {:?} is already defined", n)));
+                    }
+
+                    scope.new_param(n, typ);
+                }
+
+                for n in timers {
+                    if scope.has(&n) {
+                        return Err(Error::from(format!("timer {:?} is already defined", n)));
+                    }
+
+                    scope.new_timer(n)?;
+                }
+
+                for (is_volatile, n, bounds) in hists {
+                    let bounds = hist_bounds(&n, bounds, &consts)?;
+                    if scope.has(&n) || scope.histogram(&n).is_some() {
+                        return Err(Error::from(format!("histogram {:?} is already defined", n)));
+                    }
+
+                    scope.new_histogram(is_volatile, n, bounds);
                 }
 
-                for (_is_volatile, var, typ) in controls {
-                    scope.new_control(var, typ);
+                if let Some(&(ref c, _)) = consts.iter().find(|&&(ref c, _)| scope.has(c)) {
+                    return Err(Error::from(format!("constant {:?} shadows a variable", c)));
                 }
 
-                Ok(rest)
+                Ok((rest, consts, macros))
             }
             Err(nom::Err::Error(e)) |
             Err(nom::Err::Failure(e)) => Err(Error::from(e)),
@@ -160,7 +490,8 @@ impl Prog {
             ),
         }?;
 
-        let evs = match events(body) {
+        let (body, consts, macros) = body;
+        let evs: Vec<(Trigger, Event)> = match events(body) {
             Ok((_, me)) => me.into_iter().collect(),
             Err(nom::Err::Error(e)) |
             Err(nom::Err::Failure(e)) => Err(Error::from(e)),
@@ -170,20 +501,157 @@ impl Prog {
             ),
         }?;
 
-        let mut p = Prog(evs);
-        p.desugar();
+        let (triggers, evs): (Vec<Trigger>, Vec<Event>) = evs.into_iter().unzip();
+        let mut p = {
+            let mut expander = Expander::new(&macros, &scope, &consts);
+            Prog(evs.into_iter().map(|ev| Ok(Event{
+                flag: expander.expr(ev.flag)?,
+                body: expander.stmts(ev.body)?,
+            })).collect::<Result<_>>()?)
+        };
+
+        for &(ref c, ref val) in &consts {
+            p.0.iter_mut().for_each(|ev| {
+                ev.flag.substitute(c, val);
+                ev.body.iter_mut().for_each(|e| e.substitute(c, val));
+            });
+        }
+
+        p.desugar(&triggers, &mut scope)?;
 
         // TODO make Expr::new return Iter, make self wrap an iter also
         Ok((p, scope))
     }
     
-    fn desugar(&mut self) {
+    /// Lower `on-rise`, `on-fall` and `otherwise` events, `let` bindings, `(report)` and
+    /// `(fallthrough)` to plain `when` events which only assign variables.
+    ///
+    /// An edge-triggered event keeps the last value of its condition in a hidden Control register,
+    /// and becomes two events: the first clears the latch and falls through when the condition
+    /// stops holding, and the second sets it and runs the body when the condition starts to.
+    /// `otherwise` gets a hidden Control register which a first event clears on every ack and the
+    /// body of every other event sets.
+    fn desugar(&mut self, triggers: &[Trigger], scope: &mut Scope) -> Result<()> {
+        if let Some(i) = triggers.iter().position(|&t| t == Trigger::Otherwise) {
+            if i != triggers.len() - 1 {
+                return Err(Error::from("(otherwise ...) must be the last event"));
+            }
+        }
+
+        let has_otherwise = triggers.last() == Some(&Trigger::Otherwise);
+        let matched = String::from("__matched");
+        let mut evs = vec![];
+        if has_otherwise {
+            scope.new_control(matched.clone(), Type::Bool(Some(false)));
+            evs.push(Event{
+                flag: Expr::Atom(Prim::Bool(true)),
+                body: vec![bind(&matched, false), Expr::Cmd(Command::Fallthrough)],
+            });
+        }
+
+        let mut num_edges = 0;
+        for (Event{flag, mut body}, &t) in self.0.drain(..).zip(triggers) {
+            if t == Trigger::Otherwise {
+                evs.push(Event{flag: not(var(&matched)), body});
+                continue;
+            }
+
+            if has_otherwise {
+                body.insert(0, bind(&matched, true));
+            }
+
+            // a fall is a rise of the negated condition, which does not hold before the first ack.
+            let (cond, initial) = match t {
+                Trigger::When => {
+                    evs.push(Event{flag, body});
+                    continue;
+                }
+                Trigger::Rise => (flag, false),
+                Trigger::Fall => (not(flag), true),
+                Trigger::Otherwise => unreachable!(),
+            };
+
+            let latch = format!("__edge{}", num_edges);
+            num_edges += 1;
+            scope.new_control(latch.clone(), Type::Bool(Some(initial)));
+            evs.push(Event{
+                flag: and(var(&latch), not(cond.clone())),
+                body: vec![bind(&latch, false), Expr::Cmd(Command::Fallthrough)],
+            });
+            body.insert(0, bind(&latch, true));
+            evs.push(Event{flag: and(cond, not(var(&latch))), body});
+        }
+
+        self.0 = evs;
+        let mut num_lets = 0;
         self.0.iter_mut()
-            .for_each(|v| v.body.iter_mut()
-            .for_each(|e| e.desugar()));
+            .for_each(|v| {
+                let body = ::std::mem::replace(&mut v.body, vec![]);
+                v.body = flatten_lets(body, &mut num_lets);
+                v.body.iter_mut().for_each(|e| e.desugar());
+            });
+        Ok(())
     }
 }
 
+fn var(name: &str) -> Expr {
+    Expr::Atom(Prim::Name(String::from(name)))
+}
+
+fn bind(name: &str, val: bool) -> Expr {
+    Expr::Sexp(Op::Bind, Box::new(var(name)), Box::new(Expr::Atom(Prim::Bool(val))))
+}
+
+fn not(e: Expr) -> Expr {
+    Expr::Sexp(Op::Not, Box::new(e), Box::new(Expr::None))
+}
+
+fn and(a: Expr, b: Expr) -> Expr {
+    Expr::Sexp(Op::And, Box::new(a), Box::new(b))
+}
+
+/// Names beginning with `__` are reserved for the variables the compiler adds, such as the latches
+/// of edge-triggered events. The parser fails on them without saying why, so look for them first.
+fn check_reserved_names(source: &[u8]) -> Result<()> {
+    let src = String::from_utf8_lossy(source);
+    for line in src.lines() {
+        let code = line.split('#').next().unwrap_or("");
+        let reserved = code
+            .split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '_'))
+            .find(|w| w.starts_with("__"));
+        if let Some(name) = reserved {
+            return Err(Error::from(
+                format!("Names beginning with \"__\" are reserved for internal use: {:?}", name),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluate `(defconst NAME value)` declarations in order. A constant's value may name an
+/// earlier constant.
+fn resolve_consts(decls: Vec<(String, Result<Expr>)>) -> Result<Vec<(String, Expr)>> {
+    let mut consts: Vec<(String, Expr)> = vec![];
+    for (name, val) in decls {
+        if consts.iter().any(|&(ref c, _)| *c == name) {
+            return Err(Error::from(format!("constant {:?} defined twice", name)));
+        }
+
+        let val = match val? {
+            Expr::Atom(Prim::Name(ref n)) => consts.iter()
+                .find(|&&(ref c, _)| c == n)
+                .map(|&(_, ref v)| v.clone())
+                .ok_or_else(|| Error::from(format!("constant {:?} refers to unknown constant {:?}", name, n)))?,
+            v => v,
+        };
+
+        consts.push((name, val));
+    }
+
+    Ok(consts)
+}
+
 #[cfg(test)]
 mod tests {
     use nom;
@@ -191,22 +659,24 @@ mod tests {
 
     use lang::ast::{Expr, Op, Prim};
     use lang::prog::{Event,Prog};
+    use lang::prog::Lifetime::*;
     use lang::datapath::{Scope, Type};
 
     #[test]
     fn defs() {
-        let foo = b"(def (Bar 0) (Report (Foo 0) (volatile Baz 0)) (Qux 0))";
+        let foo = b"(def (Bar 0) (Report (Foo 0) (volatile Baz 0)) (persistent Qux 0))";
         use nom::Needed;
         match super::defs(CompleteByteSlice(foo)) {
-            Ok((r, me)) => {
+            Ok((r, (me, params, _, _))) => {
                 assert_eq!(r, CompleteByteSlice(&[]));
+                assert!(params.is_empty());
                 assert_eq!(
                 me,
                 vec![
-                    (false, Type::Name(String::from("Report.Foo")), Type::Num(Some(0))),
-                    (true, Type::Name(String::from("Report.Baz")), Type::Num(Some(0))),
-                    (false, Type::Name(String::from("Bar")), Type::Num(Some(0))),
-                    (false, Type::Name(String::from("Qux")), Type::Num(Some(0))),
+                    (Program, Type::Name(String::from("Report.Foo")), Type::Num(Some(0))),
+                    (Volatile, Type::Name(String::from("Report.Baz")), Type::Num(Some(0))),
+                    (Program, Type::Name(String::from("Bar")), Type::Num(Some(0))),
+                    (Persistent, Type::Name(String::from("Qux")), Type::Num(Some(0))),
                 ]
             );
             }
@@ -222,12 +692,12 @@ mod tests {
         let foo = b"(def (Report (Foo +infinity)))";
         use nom::Needed;
         match super::defs(CompleteByteSlice(foo)) {
-            Ok((r, me)) => {
+            Ok((r, (me, _, _, _))) => {
                 assert_eq!(r, CompleteByteSlice(&[]));
                 assert_eq!(
                     me,
                     vec![
-                        (false, Type::Name(String::from("Report.Foo")), Type::Num(Some(u64::max_value()))),
+                        (Program, Type::Name(String::from("Report.Foo")), Type::Num(Some(u64::max_value()))),
                     ]
                 );
             }
@@ -238,6 +708,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn edge_triggers() {
+        let foo = b"
+            (def (Report (rises 0) (falls 0) (others 0)))
+            (on-rise (> Ack.lost_pkts_sample 0)
+                (:= Report.rises (+ Report.rises 1))
+                (fallthrough)
+            )
+            (on-fall (> Ack.lost_pkts_sample 0)
+                (:= Report.falls (+ Report.falls 1))
+                (fallthrough)
+            )
+            (otherwise
+                (:= Report.others (+ Report.others 1))
+            )
+        ";
+        let (bin, sc) = ::lang::compile(foo, &[]).unwrap();
+        let mut m = ::lang::interp::Machine::new(&bin, &sc, 0);
+        let mut prims = vec![0; 15];
+        for (now, &lost) in [0, 1, 1, 0, 0, 1].iter().enumerate() {
+            prims[4] = lost;
+            m.on_ack(&prims, now as u64).unwrap();
+        }
+
+        let get = |n| m.get(sc.get(n).unwrap()).unwrap();
+        assert_eq!(get("Report.rises"), 2);
+        assert_eq!(get("Report.falls"), 1);
+        assert_eq!(get("Report.others"), 3);
+
+        let foo = b"(def (Report.foo 0)) (otherwise (:= Report.foo 1)) (when true (:= Report.foo 2))";
+        assert!(Prog::new_with_scope(foo).is_err());
+    }
+
     #[test]
     fn reserved_names() {
         use nom::Needed;
@@ -249,6 +752,16 @@ mod tests {
             Err(nom::Err::Incomplete(Needed::Unknown)) => panic!("incomplete"),
             Err(nom::Err::Incomplete(Needed::Size(s))) => panic!("need {} more bytes", s),
         }
+
+        // the compiler's hidden variables cannot be declared or used
+        for foo in &[
+            &b"(def (__matched false)) (when true (otherwise (report)))"[..],
+            &b"(def (Report.foo 0)) (on-rise true (:= __edge0 false))"[..],
+            &b"(def (Report (__x 0))) (when true (report))"[..],
+        ] {
+            let err = Prog::new_with_scope(foo).unwrap_err();
+            assert!(err.0.starts_with("Names beginning with \"__\" are reserved"), "{}", err);
+        }
     }
 
     #[test]
@@ -344,7 +857,7 @@ mod tests {
         match super::events(CompleteByteSlice(foo)) {
             Ok((r, me)) => {
                 assert_eq!(r, CompleteByteSlice(&[]));
-                let res_me: Vec<Event> = me.into_iter().collect::<Result<Vec<Event>>>().unwrap();
+                let res_me: Vec<Event> = me.into_iter().map(|e| e.map(|(_, ev)| ev)).collect::<Result<Vec<Event>>>().unwrap();
                 assert_eq!(
                     res_me,
                     vec![
@@ -485,4 +998,116 @@ mod tests {
             ]),
         );
     }
+
+    #[test]
+    fn defconst() {
+        let foo = b"
+            (defconst MSS 1448)
+            (def (Report (volatile acked MSS)) (Control.rate INIT_RATE))
+            (defconst INIT_RATE MSS)
+            (when (> Ack.bytes_acked MSS)
+                (:= Report.acked (+ Report.acked MSS))
+            )
+        ";
+
+        let (p, sc) = Prog::new_with_scope(foo).unwrap();
+        assert_eq!(sc.get("Report.acked").unwrap().clone(), ::lang::Reg::Report(0, Type::Num(Some(1448)), true));
+        assert_eq!(sc.get("Control.rate").unwrap().clone(), ::lang::Reg::Control(0, Type::Num(Some(1448))));
+        assert_eq!(
+            p,
+            Prog(vec![
+                Event{
+                    flag: Expr::Sexp(
+                        Op::Gt,
+                        Box::new(Expr::Atom(Prim::Name(String::from("Ack.bytes_acked")))),
+                        Box::new(Expr::Atom(Prim::Num(1448))),
+                    ),
+                    body: vec![
+                        Expr::Sexp(
+                            Op::Bind,
+                            Box::new(Expr::Atom(Prim::Name(String::from("Report.acked")))),
+                            Box::new(Expr::Sexp(
+                                Op::Add,
+                                Box::new(Expr::Atom(Prim::Name(String::from("Report.acked")))),
+                                Box::new(Expr::Atom(Prim::Num(1448))),
+                            )),
+                        ),
+                    ],
+                },
+            ]),
+        );
+
+        assert!(Prog::new_with_scope(b"(defconst A 1) (defconst A 2) (def (B 0)) (when true (:= B A))").is_err());
+        assert!(Prog::new_with_scope(b"(defconst A C) (def (B 0)) (when true (:= B A))").is_err());
+        assert!(Prog::new_with_scope(b"(defconst B 1) (def (B 0)) (when true (:= B 2))").is_err());
+    }
+
+    #[test]
+    fn params() {
+        let foo = b"
+            (defconst MSS 1448)
+            (def
+                (Report (volatile acked 0))
+                (param cwnd_gain num)
+                (param init_cwnd num MSS)
+                (param use_ecn bool false)
+            )
+            (when use_ecn
+                (:= Cwnd (* init_cwnd cwnd_gain))
+            )
+        ";
+
+        let (_, sc) = Prog::new_with_scope(foo).unwrap();
+        let params: Vec<(&str, bool)> = sc.params().iter().map(|p| (p.name.as_str(), p.is_required())).collect();
+        assert_eq!(params, vec![("cwnd_gain", true), ("init_cwnd", false), ("use_ecn", false)]);
+        assert_eq!(sc.get("init_cwnd").unwrap().clone(), ::lang::Reg::Control(1, Type::Num(Some(1448))));
+        assert_eq!(sc.param("use_ecn").unwrap().typ, Type::Bool(Some(false)));
+
+        assert!(sc.check_updates(&[("cwnd_gain", 2), ("use_ecn", 1)], true).is_ok());
+        assert!(sc.check_updates(&[("use_ecn", 1)], false).is_ok());
+        assert_eq!(
+            sc.check_updates(&[("use_ecn", 1)], true).unwrap_err().0,
+            "missing required parameters: cwnd_gain",
+        );
+        assert!(sc.check_updates(&[("cwnd_gain", 2), ("use_ecn", 2)], true).is_err());
+        assert!(sc.check_updates(&[("Cwnd", 10)], false).is_ok());
+        assert_eq!(
+            sc.check_updates(&[("cwnd_gian", 2)], false).unwrap_err().0,
+            "unknown field \"cwnd_gian\", did you mean \"cwnd_gain\"?",
+        );
+        assert!(sc.check_updates(&[("Report.acked", 2)], false).is_err());
+
+        // bad types and defaults
+        assert!(Prog::new_with_scope(b"(def (param a float)) (when true (:= Cwnd a))").is_err());
+        assert!(Prog::new_with_scope(b"(def (param a num true)) (when true (:= Cwnd a))").is_err());
+        assert!(Prog::new_with_scope(b"(def (param a bool B)) (when true (:= Cwnd a))").is_err());
+        assert!(Prog::new_with_scope(b"(def (a 0) (param a num)) (when true (:= Cwnd a))").is_err());
+    }
+
+    #[test]
+    fn let_locals() {
+        let foo = b"
+            (def (Report.foo 0))
+            (when true
+                (let (x (* Ack.bytes_acked 2))
+                    (let (x (+ x 1))
+                        (:= Report.foo x)
+                    )
+                    (:= Report.foo (+ Report.foo x))
+                )
+            )
+        ";
+
+        let (p, _) = Prog::new_with_scope(foo).unwrap();
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        assert_eq!(
+            p.0[0].body,
+            vec![
+                Expr::Sexp(Op::Bind, name("__let0_x"), Box::new(Expr::Sexp(Op::Mul, name("Ack.bytes_acked"), Box::new(Expr::Atom(Prim::Num(2)))))),
+                Expr::Sexp(Op::Bind, name("__let1_x"), Box::new(Expr::Sexp(Op::Add, name("__let0_x"), Box::new(Expr::Atom(Prim::Num(1)))))),
+                Expr::Sexp(Op::Bind, name("Report.foo"), name("__let1_x")),
+                Expr::Sexp(Op::Bind, name("Report.foo"), Box::new(Expr::Sexp(Op::Add, name("Report.foo"), name("__let0_x")))),
+            ],
+        );
+    }
 }
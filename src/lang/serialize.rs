@@ -3,6 +3,18 @@ use super::ast::Op;
 use super::datapath::{Bin, Event, Instr, Reg};
 use ::serialize::u32_to_u8s;
 
+/// Number of registers of each kind a datapath provides to each program.
+pub(crate) const NUM_CONTROL_REGS: u8 = 16;
+pub(crate) const NUM_LOCAL_REGS: u8 = 6;
+pub(crate) const NUM_PRIMITIVE_REGS: u8 = 16;
+pub(crate) const NUM_REPORT_REGS: u8 = 16;
+pub(crate) const NUM_TMP_REGS: u8 = 16;
+
+/// Number of implicit registers every datapath provides. Timers declared by a program use
+/// further implicit registers, up to `MAX_TIMER_REGS`, if the datapath supports them.
+pub(crate) const NUM_IMPLICIT_REGS: u8 = 6;
+pub(crate) const MAX_TIMER_REGS: u8 = 8;
+
 /// Serialize a Bin to bytes for transfer to the datapath
 impl Bin {
     pub fn serialize(&self) -> Result<Vec<u8>> {
@@ -16,6 +28,23 @@ impl Bin {
             .chain(ists)
             .collect()
     }
+
+    /// The identity of this program: the 32-bit FNV-1a hash of its serialization, so identical
+    /// programs share a uid across restarts and datapaths. A `Bin` which cannot be serialized,
+    /// and so could never be installed, has uid 0; no other `Bin` does.
+    pub fn program_uid(&self) -> u32 {
+        match self.serialize() {
+            Ok(buf) => ::std::cmp::max(fnv1a(&buf), 1),
+            Err(_) => 0,
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+fn fnv1a(buf: &[u8]) -> u32 {
+    buf.iter().fold(FNV_OFFSET_BASIS, |h, b| (h ^ u32::from(*b)).wrapping_mul(FNV_PRIME))
 }
 /// pub struct Event {
 ///     flag_idx: u32,
@@ -68,7 +97,7 @@ impl IntoIterator for Instr {
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let op = vec![Ok(serialize_op(&self.op))];
+        let op = vec![serialize_op(&self.op)];
         op.into_iter()
             .chain(self.res)
             .chain(self.left)
@@ -78,10 +107,12 @@ impl IntoIterator for Instr {
     }
 }
 
-fn serialize_op(o: &Op) -> u8 {
-    match *o {
+fn serialize_op(o: &Op) -> Result<u8> {
+    Ok(match *o {
         Op::Add      => 0,
-        Op::And      => unreachable!(),
+        Op::And | Op::Or | Op::Observe | Op::ToInt | Op::ToNum => {
+            return Err(Error::from(format!("no datapath instruction for {:?}", o)));
+        }
         Op::Bind     => 1,
         Op::Def      => 2,
         Op::Div      => 3,
@@ -95,9 +126,30 @@ fn serialize_op(o: &Op) -> u8 {
         Op::Min      => 11,
         Op::Mul      => 12,
         Op::NotIf    => 13,
-        Op::Or       => unreachable!(),
         Op::Sub      => 14,
-    }
+        // datapath version 1
+        Op::Mod      => 15,
+        Op::Shl      => 16,
+        Op::Shr      => 17,
+        Op::BitAnd   => 18,
+        Op::BitOr    => 19,
+        Op::Gte      => 20,
+        Op::Lte      => 21,
+        Op::Neq      => 22,
+        Op::Not      => 23,
+        Op::AbsDiff  => 24,
+        // datapath version 2
+        Op::SatSub   => 25,
+        Op::CheckedSub => 26,
+        Op::SatSubInt => 27,
+        Op::CheckedSubInt => 28,
+        Op::LtInt    => 29,
+        Op::GtInt    => 30,
+        Op::LteInt   => 31,
+        Op::GteInt   => 32,
+        Op::MaxInt   => 33,
+        Op::MinInt   => 34,
+    })
 }
 
 impl IntoIterator for Reg {
@@ -107,16 +159,16 @@ impl IntoIterator for Reg {
     fn into_iter(self) -> Self::IntoIter {
         let reg = match self {
             Reg::Control(i, _) => {
-                if i > 15 {
+                if i >= NUM_CONTROL_REGS {
                     Err(Error::from(
-                        format!("Control Register index too big (max 15): {:?}", i),
+                        format!("Control Register index too big (max {}): {:?}", NUM_CONTROL_REGS - 1, i),
                     ))
                 } else {
                     Ok((0u8, u32::from(i)))
                 }
             }
             Reg::ImmBool(bl) => Ok((1u8, bl as u32)),
-            Reg::ImmNum(num) => {
+            Reg::ImmNum(num) | Reg::ImmFixed(num) => {
                 if num == u64::max_value() || num < (1 << 31) {
                     Ok((1u8, num as u32))
                 } else {
@@ -125,37 +177,50 @@ impl IntoIterator for Reg {
                     ))
                 }
             }
+            // non-negative Ints are ordinary immediates, negative ones are sign-extended from 32
+            // bits by datapaths of version 2, which support Ints.
+            Reg::ImmInt(i) => {
+                if i >= 0 && i < (1 << 31) {
+                    Ok((1u8, i as u32))
+                } else if i < 0 && i >= -(1 << 31) {
+                    Ok((8u8, i as i32 as u32))
+                } else {
+                    Err(Error::from(
+                        format!("ImmInt out of range (32 bits): {:?}", i),
+                    ))
+                }
+            }
             Reg::Implicit(i, _) => {
-                if i > 5 {
+                if i >= NUM_IMPLICIT_REGS + MAX_TIMER_REGS {
                     Err(Error::from(
-                        format!("Implicit Register index too big (max 5): {:?}", i),
+                        format!("Implicit Register index too big (max {}): {:?}", NUM_IMPLICIT_REGS + MAX_TIMER_REGS - 1, i),
                     ))
                 } else {
                     Ok((2u8, u32::from(i)))
                 }
             }
             Reg::Local(i, _) => {
-                if i > 5 {
+                if i >= NUM_LOCAL_REGS {
                     Err(Error::from(
-                        format!("Local Register index too big (max 5): {:?}", i),
+                        format!("Local Register index too big (max {}): {:?}", NUM_LOCAL_REGS - 1, i),
                     ))
                 } else {
                     Ok((3u8, u32::from(i)))
                 }
             }
             Reg::Primitive(i, _) => {
-                if i > 15 {
+                if i >= NUM_PRIMITIVE_REGS {
                     Err(Error::from(
-                        format!("Primitive Register index too big (max 15): {:?}", i),
+                        format!("Primitive Register index too big (max {}): {:?}", NUM_PRIMITIVE_REGS - 1, i),
                     ))
                 } else {
                     Ok((4u8, u32::from(i)))
                 }
             }
             Reg::Report(i, _, is_volatile) => {
-                if i > 15 {
+                if i >= NUM_REPORT_REGS {
                     Err(Error::from(
-                        format!("Report Register index too big (max 15): {:?}", i),
+                        format!("Report Register index too big (max {}): {:?}", NUM_REPORT_REGS - 1, i),
                     ))
                 } else {
                     // in libccp:
@@ -166,15 +231,15 @@ impl IntoIterator for Reg {
                 }
             }
             Reg::Tmp(i, _) => {
-                if i > 15 {
+                if i >= NUM_TMP_REGS {
                     Err(Error::from(
-                        format!("Tmp Register index too big (max 15): {:?}", i),
+                        format!("Tmp Register index too big (max {}): {:?}", NUM_TMP_REGS - 1, i),
                     ))
                 } else {
                     Ok((7u8, u32::from(i)))
                 }
             }
-            Reg::None => unreachable!(),
+            Reg::None => Err(Error::from("cannot serialize Reg::None")),
         };
 
         reg
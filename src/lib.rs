@@ -60,7 +60,6 @@
 #![feature(box_patterns)]
 #![feature(test)]
 #![feature(never_type)]
-#![feature(integer_atomics)]
 
 extern crate bytes;
 extern crate clap;
@@ -83,14 +82,19 @@ pub mod test_helper;
 pub mod algs;
 mod errors;
 pub use errors::*;
+mod handlers;
+pub use handlers::{MsgHandler, MsgHandlers};
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::rc::Rc;
 use ipc::Ipc;
 use ipc::{BackendSender, BackendBuilder};
 use serialize::Msg;
 use std::sync::{Arc, atomic};
 use std::thread;
+use std::time::{Duration, Instant};
 use lang::{Reg, Scope, Bin};
 
 /// CCP custom `Result` type, using `Error` as the `Err` type.
@@ -100,9 +104,43 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub trait DatapathTrait {
     fn get_sock_id(&self) -> u32;
     /// Tell datapath to use a preinstalled program.
+    /// Fails if the datapath has rejected the program before; whether it accepts the switch is
+    /// reported later, through `CongAlg::on_program_error`.
+    /// The new program's `persistent` variables start with the values of the variables of the
+    /// same name and type in the program this flow used before, if it was set with `set_program`
+    /// and the datapath has not rejected it.
+    /// Fails if a value does not suit the type of its parameter (see `lang::Param`), or if a
+    /// required parameter of the program is not given a value.
     fn set_program(&mut self, program_name: String, fields: Option<&[(&str, u32)]>) -> Result<Scope>;
     /// Update the value of a register in an already-installed fold function.
     fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()>;
+    /// Ask the datapath for the current values of `Report` fields, control variables, `Cwnd` or
+    /// `Rate` of the program of `sc`, waiting at most about `timeout` for the answer.
+    /// Fails with `StaleProgramError` if the flow is no longer running that program.
+    /// Datapaths which cannot answer reads fail with the default implementation.
+    fn read_fields(&self, _sc: &Scope, _fields: &[&str], _timeout: Duration) -> Result<Vec<u64>> {
+        Err(Error(String::from("This datapath does not support reading fields")))
+    }
+}
+
+/// The value of a `Report` field which the program never changes, and so is not stored by the
+/// datapath (see `lang::Scope`).
+fn packed_value(reg: &Reg) -> Option<u64> {
+    match *reg {
+        Reg::ImmNum(n) | Reg::ImmFixed(n) => Some(n),
+        Reg::ImmInt(i) => Some(i as u64),
+        Reg::ImmBool(b) => Some(b as u64),
+        _ => None,
+    }
+}
+
+/// The registers of `sc` named in `update`, with their new values, once `Scope::check_updates`
+/// has accepted them.
+fn update_regs(sc: &Scope, update: &[(&str, u32)], install: bool) -> Result<Vec<(Reg, u64)>> {
+    sc.check_updates(update, install)?;
+    Ok(update.iter()
+        .filter_map(|&(reg_name, new_value)| sc.get(reg_name).map(|reg| (reg.clone(), u64::from(new_value))))
+        .collect())
 }
 
 /// A collection of methods to interact with the datapath.
@@ -110,6 +148,15 @@ pub struct Datapath<T: Ipc>{
     sock_id: u32,
     sender: BackendSender<T>,
     programs: Rc<HashMap<String, Scope>>,
+    /// The programs the datapath has rejected, by uid, with its reasons.
+    rejected: Rc<RefCell<HashMap<u32, String>>>,
+    /// The `Scope` of the program this flow runs, as far as we know.
+    current: Option<Scope>,
+    /// The `Scope` of the program most recently set for this flow, until the datapath has had a
+    /// chance to reject it.
+    switching: Option<Scope>,
+    /// Number of timers the datapath reported for this flow when it was created.
+    num_timers: u32,
 }
 
 impl<T: Ipc> DatapathTrait for Datapath<T> {
@@ -121,40 +168,41 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
         // if the program with this key exists, return it; otherwise return nothing
         match self.programs.get(&program_name) {
             Some(sc) => {
+                if let Some(reason) = self.rejected.borrow().get(&sc.program_uid) {
+                    return Err(Error(
+                        format!("Datapath rejected program {:?}: {}", program_name, reason)
+                    ));
+                }
+
+                if u32::from(sc.num_timers) > self.num_timers {
+                    return Err(Error(format!(
+                        "Datapath program {:?} declares {} timers, but the datapath provides {}",
+                        program_name, sc.num_timers, self.num_timers,
+                    )));
+                }
+
                 // apply optional updates to values of registers in this scope
-                let fields : Vec<(Reg, u64)> = fields.unwrap_or_else(|| &[]).iter().map(
-                    |&(reg_name, new_value)| {
-                        if reg_name.starts_with("__") {
-                            return Err(Error(
-                                format!("Cannot update reserved field: {:?}", reg_name)
-                            ));
-                        }
+                let fields = update_regs(sc, fields.unwrap_or_else(|| &[]), true)?;
 
-                        sc.get(reg_name)
-                            .ok_or_else(|| Error(
-                                format!("Unknown field: {:?}", reg_name)
-                            ))
-                            .and_then(|reg| match *reg {
-                                Reg::Control(idx, ref t) => {
-                                    Ok((Reg::Control(idx, t.clone()), u64::from(new_value)))
-                                }
-                                Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                                    Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                                }
-                                _ => Err(Error(
-                                    format!("Cannot update field: {:?}", reg_name),
-                                )),
-                            })
+                // the previous switch took effect unless the datapath rejected it
+                if let Some(prev) = self.switching.take() {
+                    if !self.rejected.borrow().contains_key(&prev.program_uid) {
+                        self.current = Some(prev);
                     }
-                ).collect::<Result<_>>()?;
+                }
+
+                let mappings = self.current.as_ref().map_or_else(Vec::new, |old| sc.register_mapping(old));
                 let msg = serialize::changeprog::Msg {
                     sid: self.sock_id,
                     program_uid: sc.program_uid,
                     num_fields: fields.len() as u32,
-                    fields
+                    num_mappings: mappings.len() as u32,
+                    fields,
+                    mappings,
                 };
                 let buf = serialize::serialize(&msg)?;
                 self.sender.send_msg(&buf[..])?;
+                self.switching = Some(sc.clone());
                 Ok(sc.clone())
             },
             _ => Err(Error(
@@ -165,31 +213,7 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
 
 
     fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()> {
-        let fields : Vec<(Reg, u64)> = update.iter().map(
-            |&(reg_name, new_value)| {
-                if reg_name.starts_with("__") {
-                    return Err(Error(
-                        format!("Cannot update reserved field: {:?}", reg_name)
-                    ));
-                }
-
-                sc.get(reg_name)
-                    .ok_or_else(|| Error(
-                        format!("Unknown field: {:?}", reg_name)
-                    ))
-                    .and_then(|reg| match *reg {
-                        Reg::Control(idx, ref t) => {
-                            Ok((Reg::Control(idx, t.clone()), u64::from(new_value)))
-                        }
-                        Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                            Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                        }
-                        _ => Err(Error(
-                            format!("Cannot update field: {:?}", reg_name),
-                        )),
-                    })
-            }
-        ).collect::<Result<_>>()?;
+        let fields = update_regs(sc, update, false)?;
 
         let msg = serialize::update_field::Msg{
             sid: self.sock_id,
@@ -201,6 +225,121 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
         self.sender.send_msg(&buf[..])?;
         Ok(())
     }
+
+    fn read_fields(&self, sc: &Scope, fields: &[&str], timeout: Duration) -> Result<Vec<u64>> {
+        let regs: Vec<Reg> = fields.iter().map(|&reg_name| {
+            if reg_name.starts_with("__") {
+                return Err(Error(
+                    format!("Cannot read reserved field: {:?}", reg_name)
+                ));
+            }
+
+            sc.get(reg_name)
+                .ok_or_else(|| Error(
+                    format!("Unknown field: {:?}", reg_name)
+                ))
+                .and_then(|reg| match *reg {
+                    Reg::Report(..) => Ok(reg.clone()),
+                    _ if reg.is_updatable() => Ok(reg.clone()),
+                    _ if reg_name.starts_with("Report.") && packed_value(reg).is_some() => Ok(reg.clone()),
+                    _ => Err(Error(
+                        format!("Cannot read field: {:?}", reg_name),
+                    )),
+                })
+        }).collect::<Result<_>>()?;
+
+        // packed fields are not stored by the datapath, so only the others are requested
+        let requested: Vec<Reg> = regs.iter().filter(|r| packed_value(r).is_none()).cloned().collect();
+        let msg = serialize::read_request::Msg {
+            sid: self.sock_id,
+            program_uid: sc.program_uid,
+            num_fields: requested.len() as u32,
+            fields: requested,
+        };
+        let buf = serialize::serialize(&msg)?;
+        let deadline = Instant::now() + timeout;
+        self.sender.send_msg(&buf[..])?;
+
+        let sock_id = self.sock_id;
+        let buf = self.sender.recv_msg(deadline, |m| match *m {
+            Msg::Rd(ref r) => r.sid == sock_id,
+            _ => false,
+        })?;
+        match Msg::from_buf(&buf[..])? {
+            (Msg::Rd(ref r), _) if r.program_uid != sc.program_uid => Err(Error::from(StaleProgramError)),
+            (Msg::Rd(r), _) => {
+                if r.fields.len() != msg.fields.len() {
+                    return Err(Error::from(InvalidReportError));
+                }
+
+                let mut values = r.fields.into_iter();
+                Ok(regs.iter().map(|reg| packed_value(reg).or_else(|| values.next()).unwrap_or(0)).collect())
+            }
+            (m, _) => Err(Error(format!("Expected a read response, got {:?}", m))),
+        }
+    }
+}
+
+impl<T: Ipc> Datapath<T> {
+    /// Start a batch of updates to the fields of many flows, which will be sent to the datapath
+    /// together.
+    pub fn update_batch(&self) -> UpdateBatch<T> {
+        UpdateBatch { sender: self.sender.clone(), updates: vec![] }
+    }
+}
+
+/// Updates to the fields of many flows, sent with as few messages as possible. Each update is
+/// checked like `DatapathTrait::update_field()`.
+pub struct UpdateBatch<T: Ipc> {
+    sender: BackendSender<T>,
+    updates: Vec<serialize::update_field::Msg>,
+}
+
+impl<T: Ipc> UpdateBatch<T> {
+    /// Add an update of the fields of flow `sock_id`, which is running the program of `sc`.
+    pub fn add(&mut self, sock_id: u32, sc: &Scope, update: &[(&str, u32)]) -> Result<()> {
+        let fields = update_regs(sc, update, false)?;
+        self.updates.push(serialize::update_field::Msg {
+            sid: sock_id,
+            num_fields: fields.len() as u8,
+            fields,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Send the updates, splitting them into several messages if they do not fit in one message
+    /// of the IPC mechanism (see `Ipc::max_msg_len`).
+    pub fn send(self) -> Result<()> {
+        use serialize::batch_update_field::{self, update_len};
+        let mut updates = self.updates.into_iter().peekable();
+        while updates.peek().is_some() {
+            let mut len = serialize::HDR_LENGTH + 4;
+            let mut batch = vec![];
+            while let Some(u) = updates.peek().cloned() {
+                if !batch.is_empty() && len + update_len(&u) > T::max_msg_len() {
+                    break;
+                }
+
+                len += update_len(&u);
+                batch.push(u);
+                updates.next();
+            }
+
+            let msg = batch_update_field::Msg { num_updates: batch.len() as u32, updates: batch };
+            let buf = serialize::serialize(&msg)?;
+            self.sender.send_msg(&buf[..])?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Defines a `slog::Logger` to use for (optional) logging 
@@ -254,16 +393,36 @@ pub struct DatapathInfo {
     pub src_port: u32,
     pub dst_ip: u32,
     pub dst_port: u32,
+    /// Number of timers, besides `Micros`, the datapath provides to programs. The flow can only
+    /// switch to programs which declare at most this many timers.
+    pub num_timers: u32,
 }
 
 /// Contains the values of the pre-defined Report struct from the fold function.
 /// Use `get_field` to query its values using the names defined in the fold function.
-pub struct Report {
+/// A report belongs to the program whose `Scope` has the same `program_uid`, a hash of the
+/// compiled program, so it can be read with the `Scope` of any identical program.
+/// A report borrows its fields from the buffer it was received in; use `into_owned` to keep it.
+///
+/// `Report` used to own its fields. Code which stores reports, and so names the type without a
+/// lifetime outside of a function signature, should use `OwnedReport` instead.
+pub struct Report<'a> {
     pub program_uid: u32, 
-        fields: Vec<u64>,
+        fields: serialize::measure::View<'a>,
 }
 
-impl Report {
+/// A `Report` which owns its fields, as returned by `Report::into_owned`.
+pub type OwnedReport = Report<'static>;
+
+impl<'a> Report<'a> {
+    /// Copy the fields out of the receive buffer, to keep the report past `CongAlg::on_report`.
+    pub fn into_owned(self) -> OwnedReport {
+        Report {
+            program_uid: self.program_uid,
+            fields: self.fields.into_owned(),
+        }
+    }
+
     /// Uses the `Scope` returned by `lang::compile` (or `install`) to query 
     /// the `Report` for its values.
     pub fn get_field(&self, field: &str, sc: &Scope) -> Result<u64> {
@@ -275,18 +434,50 @@ impl Report {
             Some(r) => {
                 match *r {
                     Reg::Report(idx, _, _) => {
-                        if idx as usize >= self.fields.len() {
-                            Err(Error::from(InvalidReportError))
-                        } else {
-                            Ok(self.fields[idx as usize])
-                        }
+                        self.fields.get(idx as usize).ok_or_else(|| Error::from(InvalidReportError))
                     },
-                    _ => Err(Error::from(InvalidRegTypeError)),
+                    // a field the program never changes is not sent by the datapath
+                    _ => packed_value(r).ok_or_else(|| Error::from(InvalidRegTypeError)),
                 }
             },
             None => Err(Error::from(FieldNotFoundError)),
         }
     }
+
+    /// Like `get_field`, but converts fixed-point fields (see `lang::FIXED_FRAC_BITS`) to their
+    /// fractional values. `get_field` returns the scaled integer.
+    pub fn get_field_f64(&self, field: &str, sc: &Scope) -> Result<f64> {
+        let v = self.get_field(field, sc)?;
+        match sc.get(field) {
+            Some(&Reg::Report(_, lang::Type::Fixed(_), _)) | Some(&Reg::ImmFixed(_)) => {
+                Ok(v as f64 / f64::from(1u32 << lang::FIXED_FRAC_BITS))
+            }
+            _ => Ok(v as f64),
+        }
+    }
+
+    /// Like `get_field`, for fields of the signed `Int` type.
+    pub fn get_field_i64(&self, field: &str, sc: &Scope) -> Result<i64> {
+        self.get_field(field, sc).map(|v| v as i64)
+    }
+
+    /// The bucket counts of the histogram `field`, declared in the `Report` struct with
+    /// `(field (hist ...))`, read from the report as they are iterated. `Scope::histogram` gives
+    /// the bucket boundaries.
+    pub fn get_histogram<'b>(&'b self, field: &str, sc: &Scope) -> Result<impl Iterator<Item = u64> + 'b> {
+        if sc.program_uid != self.program_uid {
+            return Err(Error::from(StaleProgramError))
+        }
+
+        let num_buckets = sc.histogram(field).ok_or_else(|| Error::from(FieldNotFoundError))?.len() + 1;
+        match sc.get(&lang::bucket_name(field, 0)) {
+            Some(&Reg::Report(idx, _, _)) if idx as usize + num_buckets <= self.fields.len() => {
+                Ok((idx as usize..idx as usize + num_buckets).filter_map(move |i| self.fields.get(i)))
+            }
+            Some(&Reg::Report(_, _, _)) => Err(Error::from(InvalidReportError)),
+            _ => Err(Error::from(InvalidRegTypeError)),
+        }
+    }
 }
 
 /// Implement this trait to define a CCP congestion control algorithm.
@@ -302,6 +493,8 @@ pub trait CongAlg<T: Ipc> {
     /// identifying the program, and the second string is the code for the program itself.
     ///
     /// Portus will panic if any of the datapath programs do not compile.
+    /// Programs kept in `.ccp` files can be loaded with
+    /// [`portus::programs_from_dir`](./fn.programs_from_dir.html).
     ///
     /// For example,
     /// ```
@@ -310,8 +503,24 @@ pub trait CongAlg<T: Ipc> {
     /// ];
     /// ```
     fn init_programs(cfg: Config<T, Self>) -> Vec<(String, String)>;
+    /// Features of the datapath the programs from `init_programs` are compiled for.
+    /// By default, programs only use the original instruction set (version 0) and the standard
+    /// `Ack.*` and `Flow.*` primitives. The number of timers is not taken from here: each flow's
+    /// datapath reports it in `DatapathInfo::num_timers`.
+    fn datapath_capabilities() -> lang::Capabilities {
+        lang::Capabilities::default()
+    }
+    /// The most instructions a program may run on each ack, as estimated by `lang::analysis`.
+    /// It is added to the `datapath_capabilities()` programs are compiled for, so programs which
+    /// may exceed it fail to compile. By default there is no limit.
+    fn instruction_budget() -> Option<u32> {
+        None
+    }
     fn create(control: Datapath<T>, cfg: Config<T, Self>, info: DatapathInfo) -> Self;
     fn on_report(&mut self, sock_id: u32, m: Report);
+    /// Called when the datapath rejects the program this flow switched to with `set_program`,
+    /// with the datapath's reason. The flow keeps running its previous program.
+    fn on_program_error(&mut self, _sock_id: u32, _program_uid: u32, _reason: String) {}
     fn close(&mut self) {} // default implementation does nothing (optional method)
 }
 
@@ -354,10 +563,25 @@ pub fn ip_to_string(mut ip: u32) -> String
         c.to_string(), ip.to_string())
 }
 
+/// Load the datapath programs in the `.ccp` files of the directory `dir`, for
+/// `CongAlg::init_programs`. Each program is named after its file; see `lang::load_dir()`.
+///
+/// For example,
+/// ```no_run
+/// # extern crate portus;
+/// # fn main() {
+/// let programs = portus::programs_from_dir("programs").unwrap();
+/// # }
+/// ```
+pub fn programs_from_dir<P: AsRef<std::path::Path>>(dir: P) -> Result<Vec<(String, String)>> {
+    Ok(lang::load_dir(dir)?)
+}
+
 /// Main execution loop of CCP for the static pipeline use case.
-/// The `run` method blocks 'forever'; it only returns in two cases:
+/// The `run` method blocks 'forever'; it only returns in three cases:
 /// 1. The IPC socket is closed.
 /// 2. An invalid message is received.
+/// 3. The datapath rejects one of the programs from `CongAlg::init_programs()`.
 ///
 /// Callers must construct a `BackendBuilder` and a `Config`.
 /// Algorithm implementations should
@@ -368,12 +592,21 @@ pub fn ip_to_string(mut ip: u32) -> String
 /// which are passed into run_inner to build the backend, so spawn() can create a CCPHandle that references this
 /// boolean to kill the thread.
 pub fn run<I, U>(backend_builder: BackendBuilder<I>, cfg: &Config<I, U>) -> Result<!>
+where
+    I: Ipc,
+    U: CongAlg<I>,
+{
+    run_with_handlers(backend_builder, cfg, MsgHandlers::new())
+}
+
+/// Like `run()`, passing messages of types other than those portus defines to `handlers`.
+pub fn run_with_handlers<I, U>(backend_builder: BackendBuilder<I>, cfg: &Config<I, U>, handlers: MsgHandlers<U>) -> Result<!>
 where
     I: Ipc,
     U: CongAlg<I>,
 {
     // call run_inner
-    match run_inner(backend_builder, cfg, Arc::new(atomic::AtomicBool::new(true))) {
+    match run_inner(backend_builder, cfg, handlers, Arc::new(atomic::AtomicBool::new(true))) {
         Ok(_) => unreachable!(),
         Err(e) => Err(e),
     }
@@ -382,13 +615,23 @@ where
 /// Spawn a thread which will perform the CCP execution loop. Returns
 /// a `CCPHandle`, which the caller can use to cause the execution loop
 /// to stop.
-/// The `run` method blocks 'forever'; it only returns in three cases:
+/// The `run` method blocks 'forever'; it only returns in four cases:
 /// 1. The IPC socket is closed.
 /// 2. An invalid message is received.
-/// 3. The caller calls `CCPHandle::kill()`
+/// 3. The datapath rejects one of the programs from `CongAlg::init_programs()`.
+/// 4. The caller calls `CCPHandle::kill()`
 ///
 /// See [`run`](./fn.run.html) for more information.
 pub fn spawn<I, U>(backend_builder: BackendBuilder<I>, cfg: Config<I, U>) -> CCPHandle
+where
+    I: Ipc,
+    U: CongAlg<I>,
+{
+    spawn_with_handlers(backend_builder, cfg, MsgHandlers::new())
+}
+
+/// Like `spawn()`, passing messages of types other than those portus defines to `handlers`.
+pub fn spawn_with_handlers<I, U>(backend_builder: BackendBuilder<I>, cfg: Config<I, U>, handlers: MsgHandlers<U>) -> CCPHandle
 where
     I: Ipc,
     U: CongAlg<I>,
@@ -397,7 +640,7 @@ where
     CCPHandle {
         continue_listening: stop_signal.clone(),
         join_handle: thread::spawn(move || {
-            run_inner(backend_builder, &cfg, stop_signal.clone())
+            run_inner(backend_builder, &cfg, handlers, stop_signal.clone())
         }),
     }
 }
@@ -418,6 +661,30 @@ where
     Ok(())
 }
 
+// Pass a report to the algorithm of its flow; a report with no fields means the flow has ended.
+fn handle_measure<I, U>(flows: &mut HashMap<u32, U>, m: serialize::measure::View, logger: Option<&slog::Logger>)
+where
+    I: Ipc,
+    U: CongAlg<I>,
+{
+    if flows.contains_key(&m.sid) {
+        if m.num_fields == 0 {
+            let mut alg = flows.remove(&m.sid).unwrap();
+            alg.close();
+        } else {
+            let alg = flows.get_mut(&m.sid).unwrap();
+            alg.on_report(m.sid, Report {
+                program_uid: m.program_uid,
+                fields: m,
+            })
+        }
+    } else {
+        logger.map(|log| {
+            debug!(log, "measurement for unknown flow"; "sid" => m.sid);
+        });
+    }
+}
+
 // Main execution inner loop of ccp.
 // Blocks "forever", or until the iterator stops iterating.
 //
@@ -429,12 +696,12 @@ where
 // It returns any error, either from:
 // 1. the IPC channel failing
 // 2. Receiving an install control message (only the datapath should receive these).
-fn run_inner<I, U>(backend_builder: BackendBuilder<I>, cfg: &Config<I, U>, continue_listening: Arc<atomic::AtomicBool>)  -> Result<()>
+fn run_inner<I, U>(backend_builder: BackendBuilder<I>, cfg: &Config<I, U>, mut handlers: MsgHandlers<U>, continue_listening: Arc<atomic::AtomicBool>)  -> Result<()>
 where
     I: Ipc,
     U: CongAlg<I>,
 {
-    let mut receive_buf = [0u8; 1024];
+    let mut receive_buf = vec![0u8; serialize::MAX_MSG_LEN as usize];
     let mut  b = backend_builder.build(continue_listening.clone(), &mut receive_buf[..]);
     let mut flows = HashMap::<u32, U>::new();
     let backend = b.sender();
@@ -447,17 +714,43 @@ where
     });
 
     let mut scope_map = Rc::new(HashMap::<String, Scope>::new());
+    let rejected = Rc::new(RefCell::new(HashMap::<u32, String>::new()));
+
+    // the number of timers is only known once each flow's datapath reports it, so programs may
+    // declare as many as any datapath could provide; see Datapath::set_program.
+    let mut capabilities = U::datapath_capabilities().with_timers(lang::Capabilities::MAX_TIMERS);
+    if let Some(budget) = U::instruction_budget() {
+        capabilities = capabilities.with_instruction_budget(budget);
+    }
 
+    // programs are identified by their contents, so identical programs are compiled and
+    // installed once, however many names they have.
+    let mut cache = lang::CompileCache::new(capabilities);
+    // the programs installed in the datapath by uid, with their names. Uids are 32-bit hashes, so
+    // two different programs may share one; the programs are compared to tell.
+    let mut installed = HashMap::<u32, (String, Bin)>::new();
     let programs = U::init_programs(cfg.clone());
     for (program_name, program) in programs.iter() {
 
-        match lang::compile(program.as_bytes(), &[]) {
+        match cache.compile(program.as_bytes(), &[]) {
             Ok((bin, sc)) => {
-                match send_and_install(0, backend.clone(), bin, sc.clone()) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        return Err(Error(format!("Failed to install datapath program \"{}\": {:?}", program_name, e)));
-                    },
+                match installed.entry(sc.program_uid) {
+                    Entry::Vacant(e) => {
+                        e.insert((program_name.to_string(), bin.clone()));
+                        match send_and_install(0, backend.clone(), bin, sc.clone()) {
+                            Ok(_) => {},
+                            Err(e) => {
+                                return Err(Error(format!("Failed to install datapath program \"{}\": {:?}", program_name, e)));
+                            },
+                        }
+                    }
+                    Entry::Occupied(ref e) if e.get().1 != bin => {
+                        return Err(Error(format!(
+                            "Datapath programs \"{}\" and \"{}\" differ but have the same uid {:#x}; change either to install both",
+                            e.get().0, program_name, sc.program_uid,
+                        )));
+                    }
+                    Entry::Occupied(_) => {}
                 }
                 Rc::get_mut(&mut scope_map).unwrap().insert(program_name.to_string(), sc.clone());
             }
@@ -493,6 +786,10 @@ where
                         sock_id: c.sid, 
                         sender: backend.clone(),
                         programs: scope_map.clone(),
+                        rejected: rejected.clone(),
+                        current: None,
+                        switching: None,
+                        num_timers: c.num_timers,
                     },
                     cfg.clone(),
                     DatapathInfo {
@@ -503,32 +800,58 @@ where
                         src_port: c.src_port,
                         dst_ip: c.dst_ip,
                         dst_port: c.dst_port,
+                        num_timers: c.num_timers,
                     },
                 );
                 flows.insert(c.sid, alg);
             }
-            Msg::Ms(m) => {
-                if flows.contains_key(&m.sid) {
-                    if m.num_fields == 0 {
-                        let mut alg = flows.remove(&m.sid).unwrap();
-                        alg.close();
-                    } else {
-                        let alg = flows.get_mut(&m.sid).unwrap();
-                        alg.on_report(m.sid, Report {
-                            program_uid: m.program_uid,
-                            fields: m.fields 
-                        })
-                    }
-                } else {
+            Msg::Ms(m) => handle_measure(&mut flows, m, cfg.logger.as_ref()),
+            Msg::Bm(bm) => {
+                for m in bm.iter() {
+                    handle_measure(&mut flows, m, cfg.logger.as_ref());
+                }
+            }
+            Msg::St(st) => {
+                if st.code == serialize::status::OK {
                     cfg.logger.as_ref().map(|log| {
-                        debug!(log, "measurement for unknown flow"; "sid" => m.sid);
+                        debug!(log, "datapath accepted program"; "sid" => st.sid, "program_uid" => st.program_uid);
                     });
+                    continue;
+                }
+
+                let reason = format!("{} (code {})", st.description, st.code);
+                // sid 0 is the install of the programs from init_programs, without which the
+                // algorithm cannot run
+                if st.sid == 0 {
+                    let name = installed.get(&st.program_uid).map_or("<unknown>", |&(ref n, _)| n.as_str());
+                    return Err(Error(format!("Datapath rejected program \"{}\": {}", name, reason)));
+                }
+
+                rejected.borrow_mut().insert(st.program_uid, reason.clone());
+                if let Some(alg) = flows.get_mut(&st.sid) {
+                    alg.on_program_error(st.sid, st.program_uid, reason);
                 }
             }
             Msg::Ins(_) => {
-                unimplemented!()
-                //return Err(Error(String::from("The start() listener should never receive an install \
-                //    message, since it is on the CCP side.")));
+                return Err(Error(String::from("The start() listener should never receive an install \
+                    message, since it is on the CCP side.")));
+            }
+            Msg::Other(raw) => {
+                let (typ, sid) = (raw.typ, raw.sid);
+                match handlers.get_mut(typ) {
+                    Some(handler) => {
+                        if let Err(e) = handler(raw, flows.get_mut(&sid)) {
+                            cfg.logger.as_ref().map(|log| {
+                                warn!(log, "message handler failed"; "type" => typ, "sid" => sid, "err" => ?e);
+                            });
+                        }
+                    }
+                    None => {
+                        cfg.logger.as_ref().map(|log| {
+                            debug!(log, "no handler for message"; "type" => typ, "sid" => sid);
+                        });
+                    }
+                }
             }
             _ => continue,
         }
@@ -0,0 +1,171 @@
+//! The datapath may send the reports of many flows in one message, rather than one `measure::Msg`
+//! per flow, to save a message header and a system call for each.
+//!
+//! serialization format, after the header, whose sid is 0:
+//!
+//! |-------------|------|-------------|------------|--------------------|------|-----
+//! | num reports | sid  | program_uid | num fields | fields             | sid  | ...
+//! | u32         | u32  | u32         | u32        | num fields * u64   | u32  |
+//! |-------------|------|-------------|------------|--------------------|------|-----
+//!
+//! Like a single report, a batch deserializes to a `View`, whose reports are `measure::View`s
+//! over the receive buffer.
+
+use std::io::prelude::*;
+use {Result, Error};
+use super::{AsRawMsg, RawMsg, HDR_LENGTH, measure, u32_to_u8s, u32_from_u8s, u64_to_u8s};
+
+pub(crate) const BATCH_MEASURE: u8 = 8;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Msg {
+    pub num_reports: u32,
+    pub reports: Vec<measure::Msg>,
+}
+
+/// A batch of reports which reads each report from the buffer it was received in.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct View<'a> {
+    pub num_reports: u32,
+    // the reports, whose lengths have been checked
+    reports: &'a [u8],
+}
+
+/// Split the first report off `b`.
+fn split_report<'a>(b: &'a [u8]) -> Result<(measure::View<'a>, &'a [u8])> {
+    if b.len() < 12 {
+        return Err(Error(format!("batched report too short: {:?}", b)));
+    }
+
+    let num_fields = u32_from_u8s(&b[8..12]) as usize;
+    let end = 12 + num_fields * 8;
+    if num_fields > 255 || b.len() < end {
+        return Err(Error(format!("batched report has bad length: {} fields in {} bytes", num_fields, b.len())));
+    }
+
+    let report = measure::View::from_wire(
+        u32_from_u8s(&b[0..4]),
+        u32_from_u8s(&b[4..8]),
+        num_fields as u8,
+        &b[12..end],
+    );
+    Ok((report, &b[end..]))
+}
+
+impl<'a> View<'a> {
+    pub fn from_raw_msg(msg: RawMsg<'a>) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        let num_reports = msg.get_u32(0)?;
+        let mut rest = b;
+        for _ in 0..num_reports {
+            rest = split_report(rest)?.1;
+        }
+
+        Ok(View {
+            num_reports,
+            reports: &b[..b.len() - rest.len()],
+        })
+    }
+
+    /// The reports, in the order the datapath sent them.
+    pub fn iter(&self) -> impl Iterator<Item = measure::View<'a>> + 'a {
+        let mut rest = self.reports;
+        (0..self.num_reports).filter_map(move |_| {
+            let (report, tail) = split_report(rest).ok()?;
+            rest = tail;
+            Some(report)
+        })
+    }
+
+    pub fn to_msg(&self) -> Msg {
+        Msg {
+            num_reports: self.num_reports,
+            reports: self.iter().map(|r| r.to_msg()).collect(),
+        }
+    }
+}
+
+impl<'a, 'b> PartialEq<View<'b>> for View<'a> {
+    fn eq(&self, other: &View<'b>) -> bool {
+        self.num_reports == other.num_reports && self.iter().eq(other.iter())
+    }
+}
+
+impl<'a> PartialEq<Msg> for View<'a> {
+    fn eq(&self, other: &Msg) -> bool {
+        self.num_reports == other.num_reports && self.iter().eq(other.reports.iter().cloned())
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (
+            BATCH_MEASURE,
+            HDR_LENGTH + 4 + self.reports.iter().map(|r| 12 + u32::from(r.num_fields) * 8).sum::<u32>(),
+            0,
+        )
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.num_reports);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let (mut buf32, mut buf64) = ([0u8; 4], [0u8; 8]);
+        for r in &self.reports {
+            for v in &[r.sid, r.program_uid, u32::from(r.num_fields)] {
+                u32_to_u8s(&mut buf32, *v);
+                w.write_all(&buf32[..])?;
+            }
+
+            for f in &r.fields {
+                u64_to_u8s(&mut buf64, *f);
+                w.write_all(&buf64[..])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        View::from_raw_msg(msg).map(|v| v.to_msg())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialize::measure;
+
+    check_msg!(
+        test_batch_measure,
+        super::Msg,
+        super::Msg{
+            num_reports: 3,
+            reports: vec![
+                measure::Msg { sid: 15, program_uid: 72, num_fields: 2, fields: vec![424242, 65535] },
+                measure::Msg { sid: 16, program_uid: 72, num_fields: 0, fields: vec![] },
+                measure::Msg { sid: 256, program_uid: 19, num_fields: 1, fields: vec![42] },
+            ],
+        },
+        ::serialize::Msg::Bm(bm),
+        bm
+    );
+
+    #[test]
+    fn truncated() {
+        let m = super::Msg {
+            num_reports: 1,
+            reports: vec![measure::Msg { sid: 15, program_uid: 72, num_fields: 2, fields: vec![1, 2] }],
+        };
+        let mut buf = ::serialize::serialize(&m).expect("serialize");
+        // claim one more report than there is
+        buf[8] = 2;
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! CCP sends this message to set the values of fields of many flows at once; it is equivalent to
+//! an `update_field::Msg` for each flow.
+//!
+//! serialization format, after the header, whose sid is 0:
+//!
+//! |-------------|------|------------|-------------------------|------|-----
+//! | num updates | sid  | num fields | fields                  | sid  | ...
+//! | u32         | u32  | u32        | num fields * (Reg, u64) | u32  |
+//! |-------------|------|------------|-------------------------|------|-----
+
+use std::io::prelude::*;
+use {Result, Error};
+use super::{AsRawMsg, RawMsg, HDR_LENGTH, update_field, u32_to_u8s, u64_to_u8s};
+
+pub(crate) const BATCH_UPDATE_FIELD: u8 = 9;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Msg {
+    pub num_updates: u32,
+    pub updates: Vec<update_field::Msg>,
+}
+
+/// The length of the serialization of `u` in this message.
+pub(crate) fn update_len(u: &update_field::Msg) -> u32 {
+    8 + u32::from(u.num_fields) * 13 // Reg size = 5, u64 size = 8
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (
+            BATCH_UPDATE_FIELD,
+            HDR_LENGTH + 4 + self.updates.iter().map(update_len).sum::<u32>(),
+            0,
+        )
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.num_updates);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let (mut buf32, mut buf64) = ([0u8; 4], [0u8; 8]);
+        for u in &self.updates {
+            u32_to_u8s(&mut buf32, u.sid);
+            w.write_all(&buf32[..])?;
+            u32_to_u8s(&mut buf32, u32::from(u.num_fields));
+            w.write_all(&buf32[..])?;
+            for f in &u.fields {
+                let reg = f.0.clone().into_iter().map(|e| e.map_err(Error::from)).collect::<Result<Vec<u8>>>()?;
+                w.write_all(&reg[..])?;
+                u64_to_u8s(&mut buf64, f.1);
+                w.write_all(&buf64[..])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
+        Err(Error(String::from("CCP does not receive batch_update_field messages")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::{Reg, Type};
+    use serialize::update_field;
+
+    #[test]
+    fn serialize_batch_update_msg() {
+        let m = super::Msg{
+            num_updates: 2,
+            updates: vec![
+                update_field::Msg { sid: 1, num_fields: 1, fields: vec![(Reg::Implicit(4, Type::Num(None)), 42)] },
+                update_field::Msg { sid: 2, num_fields: 0, fields: vec![] },
+            ],
+        };
+
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                9, 0,                                     // BATCH_UPDATE_FIELD
+                41, 0,                                    // length = 41
+                0, 0, 0, 0,                               // sock_id = 0
+                2, 0, 0, 0,                               // num_updates = 2
+                1, 0, 0, 0,                               // sid = 1
+                1, 0, 0, 0,                               // num_fields = 1
+                2, 4, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0, // Reg::Implicit(4) <- 42
+                2, 0, 0, 0,                               // sid = 2
+                0, 0, 0, 0,                               // num_fields = 0
+            ],
+        );
+    }
+}
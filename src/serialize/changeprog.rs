@@ -1,4 +1,8 @@
 //! CCP sends this message to change the datapath program currently in use.
+//!
+//! The message holds the program's uid and the fields to update. If there are register mappings,
+//! their count and the mappings follow the fields, so datapaths which do not carry registers over
+//! read the message as before.
 
 use std::io::prelude::*;
 use {Result, Error};
@@ -15,14 +19,35 @@ pub struct Msg {
     pub sid: u32,
     pub program_uid: u32,
     pub num_fields: u32,
+    pub num_mappings: u32,
     pub fields: Vec<(Reg, u64)>,
+    /// Registers of the old program whose values the new program starts with, as
+    /// `(old register, new register)` pairs. `fields` are applied afterwards.
+    pub mappings: Vec<(Reg, Reg)>,
+}
+
+fn reg_bytes(r: &Reg) -> Result<Vec<u8>> {
+    r.clone().into_iter().map(|e| e.map_err(Error::from)).collect()
+}
+
+impl Msg {
+    /// The length of the mapping block: nothing if there are no mappings, otherwise their count
+    /// and a pair of registers (5 bytes each) per mapping.
+    fn mappings_len(&self) -> u32 {
+        if self.num_mappings == 0 {
+            0
+        } else {
+            4 + self.num_mappings * 10
+        }
+    }
 }
 
 impl AsRawMsg for Msg {
     fn get_hdr(&self) -> (u8, u32, u32) {
         (
             CHANGEPROG,
-            HDR_LENGTH + 4 + 4 + self.num_fields * 13, // Reg size = 5, u64 size = 8
+            // Reg size = 5, u64 size = 8
+            HDR_LENGTH + 4 + 4 + self.num_fields * 13 + self.mappings_len(),
             self.sid
         )
     }
@@ -39,23 +64,33 @@ impl AsRawMsg for Msg {
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 8];
         for f in &self.fields {
-            let reg = f.0.clone().into_iter().map(|e| e.map_err(Error::from)).collect::<Result<Vec<u8>>>()?;
-            w.write_all(&reg[..])?;
+            w.write_all(&reg_bytes(&f.0)?[..])?;
             u64_to_u8s(&mut buf, f.1);
             w.write_all(&buf[..])?;
         }
+
+        if self.num_mappings == 0 {
+            return Ok(());
+        }
+
+        u32_to_u8s(&mut buf[..4], self.num_mappings);
+        w.write_all(&buf[..4])?;
+        for m in &self.mappings {
+            w.write_all(&reg_bytes(&m.0)?[..])?;
+            w.write_all(&reg_bytes(&m.1)?[..])?;
+        }
         Ok(())
     }
-    
+
     // at least for now, portus does not have to worry about deserializing this message
     fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+        Err(Error(String::from("CCP does not receive changeprog messages")))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use lang::Reg;
+    use lang::{Reg, Type};
 
     #[test]
     fn serialize_changeprog_msg() {
@@ -63,7 +98,9 @@ mod tests {
             sid: 1,
             program_uid: 7,
             num_fields: 1,
-            fields: vec![(Reg::Implicit(4, ::lang::Type::Num(None)), 42)],
+            num_mappings: 1,
+            fields: vec![(Reg::Implicit(4, Type::Num(None)), 42)],
+            mappings: vec![(Reg::Report(2, Type::Num(None), false), Reg::Control(0, Type::Num(None)))],
         };
 
         let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
@@ -71,7 +108,24 @@ mod tests {
             buf,
             vec![
                 4, 0,                                           // CHANGEPROG
-                29, 0,                                          // length = 12
+                43, 0,                                          // length = 43
+                1, 0, 0, 0,                                     // sock_id = 1
+                7, 0, 0, 0,                                     // program_uid = 7
+                1, 0, 0, 0,                                     // num_fields = 1
+                2, 4, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0,        // Reg::Implicit(4) <- 42
+                1, 0, 0, 0,                                     // num_mappings = 1
+                6, 2, 0, 0, 0, 0, 0, 0, 0, 0,                   // Reg::Control(0) <- Reg::Report(2)
+            ],
+        );
+
+        // without mappings, the message is the same as for datapaths which predate them
+        let m = super::Msg { num_mappings: 0, mappings: vec![], ..m };
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                4, 0,                                           // CHANGEPROG
+                29, 0,                                          // length = 29
                 1, 0, 0, 0,                                     // sock_id = 1
                 7, 0, 0, 0,                                     // program_uid = 7
                 1, 0, 0, 0,                                     // num_fields = 1
@@ -80,4 +134,3 @@ mod tests {
         );
     }
 }
-
@@ -17,13 +17,16 @@ pub struct Msg {
     pub src_port: u32,
     pub dst_ip: u32,
     pub dst_port: u32,
+    /// Number of timers, besides `Micros`, the datapath provides to programs. Datapaths which
+    /// predate timers do not send this, and provide none.
+    pub num_timers: u32,
 }
 
 impl AsRawMsg for Msg {
     fn get_hdr(&self) -> (u8, u32, u32) {
         (
             CREATE,
-            HDR_LENGTH + 6 * 4,
+            HDR_LENGTH + 7 * 4,
             self.sid,
         )
     }
@@ -42,6 +45,8 @@ impl AsRawMsg for Msg {
         w.write_all(&buf[..])?;
         u32_to_u8s(&mut buf, self.dst_port as u32);
         w.write_all(&buf[..])?;
+        u32_to_u8s(&mut buf, self.num_timers);
+        w.write_all(&buf[..])?;
         Ok(())
     }
 
@@ -50,15 +55,15 @@ impl AsRawMsg for Msg {
     }
 
     fn from_raw_msg(msg: RawMsg) -> Result<Self> {
-        let u32s = unsafe { msg.get_u32s() }?;
         Ok(Msg {
             sid: msg.sid,
-            init_cwnd: u32s[0],
-            mss: u32s[1],
-            src_ip: u32s[2],
-            src_port: u32s[3],
-            dst_ip: u32s[4],
-            dst_port: u32s[5],
+            init_cwnd: msg.get_u32(0)?,
+            mss: msg.get_u32(1)?,
+            src_ip: msg.get_u32(2)?,
+            src_port: msg.get_u32(3)?,
+            dst_ip: msg.get_u32(4)?,
+            dst_port: msg.get_u32(5)?,
+            num_timers: msg.get_u32(6).unwrap_or(0),
         })
     }
 }
@@ -87,9 +92,24 @@ mod tests {
             src_port: 4242,
             dst_ip: 0,
             dst_port: 4242,
+            num_timers: 2,
         }
     );
 
+    #[test]
+    fn test_create_without_timers() {
+        // datapaths which predate timers send six u32s
+        let m = super::Msg { sid: 15, init_cwnd: 14480, mss: 1448, src_ip: 0, src_port: 4242, dst_ip: 0, dst_port: 4242, num_timers: 3 };
+        let mut buf = ::serialize::serialize(&m).expect("serialize");
+        let len = buf.len() - 4;
+        buf.truncate(len);
+        buf[2] = len as u8;
+        match ::serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+            (::serialize::Msg::Cr(c), _) => assert_eq!(c, super::Msg { num_timers: 0, ..m }),
+            (m, _) => panic!("unexpected message: {:?}", m),
+        }
+    }
+
     extern crate test;
     use self::test::Bencher;
 
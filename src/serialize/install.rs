@@ -1,7 +1,7 @@
 //! CCP sends this message containing a datapath program. 
 
 use std::io::prelude::*;
-use Result;
+use {Result, Error};
 use super::{AsRawMsg, RawMsg, HDR_LENGTH, u32_to_u8s};
 use lang::Bin;
 
@@ -12,6 +12,8 @@ pub(crate) const INSTALL: u8 = 2;
 #[derive(PartialEq)]
 pub struct Msg {
     pub sid: u32,
+    /// `Bin::program_uid()`, a hash of the program. Uids are sparse and may use all 32 bits, so
+    /// the datapath must look programs up by uid rather than use it as an index into its table.
     pub program_uid: u32,
     pub num_events: u32,
     pub num_instrs: u32,
@@ -46,7 +48,7 @@ impl AsRawMsg for Msg {
 
     // at least for now, portus doesn't have to worry about deserializing this
     fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+        Err(Error(String::from("CCP does not receive install messages")))
     }
 }
 
@@ -89,5 +91,10 @@ mod tests {
                 1, 5, 0, 0, 0, 0, 5, 0, 0, 0, 0, 1, 4, 0, 0, 0, //     (bind Report.foo 4))
             ],
         );
+
+        // uids are hashes, which use all 32 bits
+        let m = super::Msg { program_uid: 0xdead_beef, ..m };
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(&buf[8..12], &[0xef, 0xbe, 0xad, 0xde]);
     }
 }
@@ -1,6 +1,10 @@
 //! When the datapath program specifies, the datapath sends a Report message containing
 //! measurements to CCP. Use the `Scope` returned from compiling the program to query the values.
+//!
+//! Deserializing a report gives a `View`, which reads the fields from the receive buffer only
+//! when asked for them, rather than copying them into a `Msg`.
 
+use std::borrow::Cow;
 use std::io::prelude::*;
 use {Result, Error};
 use super::{AsRawMsg, RawMsg, HDR_LENGTH, u32_to_u8s, u64_to_u8s, u64_from_u8s};
@@ -20,14 +24,127 @@ pub struct Msg {
     pub fields: Vec<u64>,
 }
 
-fn deserialize_fields(buf: &[u8]) -> Result<Vec<u64>> {
-    buf.chunks(8)
-        .map(|sl| if sl.len() < 8 {
-            Err(Error(format!("not long enough: {:?}", sl)))
-        } else {
-            Ok(u64_from_u8s(sl))
+/// A report which borrows its fields, either from the buffer it was received in or from a `Msg`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct View<'a> {
+    pub sid: u32,
+    pub program_uid: u32,
+    pub num_fields: u8,
+    fields: Fields<'a>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+enum Fields<'a> {
+    // little-endian u64s, with no alignment
+    Wire(&'a [u8]),
+    Values(Cow<'a, [u64]>),
+}
+
+impl<'a> View<'a> {
+    pub fn from_raw_msg(msg: RawMsg<'a>) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() % 8 != 0 {
+            return Err(Error(format!("not long enough: {:?}", &b[b.len() - b.len() % 8..])));
+        }
+
+        Ok(View {
+            sid: msg.sid,
+            program_uid: msg.get_u32(0)?,
+            num_fields: msg.get_u32(1)? as u8,
+            fields: Fields::Wire(b),
         })
-        .collect()
+    }
+
+    /// A report whose fields are the little-endian u64s in `fields`.
+    pub(crate) fn from_wire(sid: u32, program_uid: u32, num_fields: u8, fields: &'a [u8]) -> Self {
+        View { sid, program_uid, num_fields, fields: Fields::Wire(fields) }
+    }
+
+    /// The number of fields actually sent, which may differ from `num_fields` if the datapath
+    /// misbehaves.
+    pub fn len(&self) -> usize {
+        match self.fields {
+            Fields::Wire(b) => b.len() / 8,
+            Fields::Values(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `i`th field, if there is one.
+    pub fn get(&self, i: usize) -> Option<u64> {
+        match self.fields {
+            Fields::Wire(b) => b.get(i * 8..(i + 1) * 8).map(u64_from_u8s),
+            Fields::Values(ref v) => v.get(i).cloned(),
+        }
+    }
+
+    pub fn iter<'b>(&'b self) -> impl Iterator<Item = u64> + 'b {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+
+    /// Copy the fields out, to keep the report beyond the lifetime of its buffer.
+    pub fn into_owned(self) -> View<'static> {
+        View {
+            sid: self.sid,
+            program_uid: self.program_uid,
+            num_fields: self.num_fields,
+            fields: Fields::Values(Cow::Owned(self.iter().collect())),
+        }
+    }
+
+    pub fn to_msg(&self) -> Msg {
+        Msg {
+            sid: self.sid,
+            program_uid: self.program_uid,
+            num_fields: self.num_fields,
+            fields: self.iter().collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a Msg> for View<'a> {
+    fn from(m: &'a Msg) -> Self {
+        View {
+            sid: m.sid,
+            program_uid: m.program_uid,
+            num_fields: m.num_fields,
+            fields: Fields::Values(Cow::Borrowed(&m.fields[..])),
+        }
+    }
+}
+
+impl From<Msg> for View<'static> {
+    fn from(m: Msg) -> Self {
+        View {
+            sid: m.sid,
+            program_uid: m.program_uid,
+            num_fields: m.num_fields,
+            fields: Fields::Values(Cow::Owned(m.fields)),
+        }
+    }
+}
+
+impl<'a, 'b> PartialEq<View<'b>> for View<'a> {
+    fn eq(&self, other: &View<'b>) -> bool {
+        self.sid == other.sid &&
+            self.program_uid == other.program_uid &&
+            self.num_fields == other.num_fields &&
+            self.iter().eq(other.iter())
+    }
+}
+
+impl<'a> PartialEq<Msg> for View<'a> {
+    fn eq(&self, other: &Msg) -> bool {
+        self.sid == other.sid &&
+            self.program_uid == other.program_uid &&
+            self.num_fields == other.num_fields &&
+            self.iter().eq(other.fields.iter().cloned())
+    }
 }
 
 impl AsRawMsg for Msg {
@@ -59,15 +176,7 @@ impl AsRawMsg for Msg {
     }
 
     fn from_raw_msg(msg: RawMsg) -> Result<Self> {
-        let u32s = unsafe { msg.get_u32s() }?;
-        let b = msg.get_bytes()?;
-        
-        Ok(Msg {
-            sid: msg.sid,
-            program_uid: u32s[0],
-            num_fields: u32s[1] as u8,
-            fields: deserialize_fields(b)?,
-        })
+        View::from_raw_msg(msg).map(|v| v.to_msg())
     }
 }
 
@@ -110,4 +219,55 @@ mod tests {
         42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242,42424242
         ]
     );
+    #[test]
+    fn view_unaligned() {
+        use serialize::{self, Msg};
+        let m = super::Msg { sid: 15, program_uid: 72, num_fields: 3, fields: vec![1, 1 << 40, 3] };
+        let mut buf = vec![0u8];
+        buf.extend(serialize::serialize(&m).expect("serialize"));
+        match Msg::from_buf(&buf[1..]).expect("deserialize").0 {
+            Msg::Ms(v) => {
+                assert_eq!(v.len(), 3);
+                assert_eq!(v.get(1), Some(1 << 40));
+                assert_eq!(v.get(3), None);
+                assert_eq!(v.clone().into_owned(), m);
+                assert_eq!(v.to_msg(), m);
+            }
+            _ => panic!("wrong type for message"),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let m = super::Msg { sid: 15, program_uid: 72, num_fields: 1, fields: vec![1] };
+        let mut buf = ::serialize::serialize(&m).expect("serialize");
+        buf.pop();
+        buf[2] -= 1;
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+    }
+
+    extern crate test;
+    use self::test::Bencher;
+
+    #[bench]
+    fn bench_measure_msg(b: &mut Bencher) {
+        use serialize::{self, AsRawMsg};
+        let m = super::Msg { sid: 15, program_uid: 72, num_fields: 8, fields: vec![42; 8] };
+        let buf = serialize::serialize(&m).expect("serialize");
+        b.iter(|| {
+            let raw = serialize::deserialize(&buf[..]).unwrap();
+            super::Msg::from_raw_msg(raw).unwrap().fields.iter().sum::<u64>()
+        })
+    }
+
+    #[bench]
+    fn bench_measure_view(b: &mut Bencher) {
+        use serialize;
+        let m = super::Msg { sid: 15, program_uid: 72, num_fields: 8, fields: vec![42; 8] };
+        let buf = serialize::serialize(&m).expect("serialize");
+        b.iter(|| {
+            let raw = serialize::deserialize(&buf[..]).unwrap();
+            super::View::from_raw_msg(raw).unwrap().iter().sum::<u64>()
+        })
+    }
 }
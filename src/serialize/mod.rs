@@ -10,7 +10,7 @@
 //! total: 8 Bytes
 //! ```
 //!
-//! Message types 0-3 are reserved for predefined message types. All other types are treated as
+//! Message types below `RESERVED_MSG_TYPES` (0-9) are reserved for predefined message types. All other types are treated as
 //! "unknown" - the header will be parsed, and raw access to the remaining bytes is available
 //! through `RawMsg::get_bytes()`.
 //!
@@ -58,6 +58,10 @@ pub(crate) fn u64_from_u8s(buf: &[u8]) -> u64 {
 }
 
 pub const HDR_LENGTH: u32 = 8;
+/// Message types below this are predefined by portus.
+pub const RESERVED_MSG_TYPES: u8 = 10;
+/// The length of the longest message, which is limited by the 2-byte length in the header.
+pub const MAX_MSG_LEN: u32 = 0xffff;
 fn serialize_header(typ: u8, len: u32, sid: u32) -> Vec<u8> {
     let mut hdr = [0u8; 8];
     u16_to_u8s(&mut hdr[0..2], u16::from(typ));
@@ -88,31 +92,41 @@ pub struct RawMsg<'a> {
 }
 
 impl<'a> RawMsg<'a> {
-    /// For predefined messages, get u32s separately for convenience
-    pub(crate) unsafe fn get_u32s(&self) -> Result<&'a [u32]> {
-        use std::mem;
-        match self.typ {
-            create::CREATE => Ok(mem::transmute(&self.bytes[0..(4 * 6)])),
-            measure::MEASURE => Ok(mem::transmute(&self.bytes[0..8])),
-            update_field::UPDATE_FIELD => Ok(mem::transmute(&self.bytes[0..4])),
-            _ => Ok(&[]),
+    /// For predefined messages, the `i`th of the u32s which start the message.
+    /// These are read from their little-endian bytes, so the buffer need not be aligned.
+    pub(crate) fn get_u32(&self, i: usize) -> Result<u32> {
+        let num_u32s = match self.typ {
+            create::CREATE => 7,
+            measure::MEASURE | read_response::READ_RESPONSE | status::STATUS => 2,
+            update_field::UPDATE_FIELD | batch_measure::BATCH_MEASURE => 1,
+            _ => 0,
+        };
+
+        if i >= num_u32s || self.bytes.len() < 4 * (i + 1) {
+            return Err(super::Error(format!("no u32 {} in message of type {} and length {}", i, self.typ, self.len)));
         }
+
+        Ok(u32_from_u8s(&self.bytes[4 * i..4 * (i + 1)]))
     }
 
     /// For predefined messages, bytes blob is whatever's left (may be nothing)
     /// For other message types, just return the bytes blob
     pub fn get_bytes(&self) -> Result<&'a [u8]> {
-        match self.typ {
-            measure::MEASURE => Ok(&self.bytes[8..(self.len as usize - HDR_LENGTH as usize)]),
-            update_field::UPDATE_FIELD => Ok(&self.bytes[4..(self.len as usize - HDR_LENGTH as usize)]),
-            _ => Ok(self.bytes),
-        }
+        let start = match self.typ {
+            measure::MEASURE | read_response::READ_RESPONSE | status::STATUS => 8,
+            update_field::UPDATE_FIELD | batch_measure::BATCH_MEASURE => 4,
+            _ => 0,
+        };
+
+        self.bytes.get(start..).ok_or_else(|| {
+            super::Error(format!("message of type {} too short: {} bytes", self.typ, self.len))
+        })
     }
 }
 
 /// Types that can be serialized.
-// Message types wanting to become "predefined" (and as such take advantage of `get_u32s()` and
-// `get_u64s()` below) should edit this file accordingly (see `impl RawMsg`)
+// Message types wanting to become "predefined" (and as such take advantage of `get_u32()` and
+// `get_bytes()` above) should edit this file accordingly (see `impl RawMsg`)
 pub trait AsRawMsg {
     fn get_hdr(&self) -> (u8, u32, u32);
     fn get_u32s<W: Write>(&self, _: &mut W) -> Result<()> {
@@ -155,6 +169,11 @@ pub mod measure;
 pub mod install;
 pub mod changeprog;
 pub mod update_field;
+pub mod read_request;
+pub mod read_response;
+pub mod status;
+pub mod batch_measure;
+pub mod batch_update_field;
 mod testmsg;
 
 /// Serialize a serializable message.
@@ -170,7 +189,7 @@ pub fn serialize<T: AsRawMsg>(m: &T) -> Result<Vec<u8>> {
 fn deserialize(buf: &[u8]) -> Result<RawMsg> {
     let mut buf = Cursor::new(buf);
     let (typ, len, sid) = deserialize_header(&mut buf)?;
-    if len < 8 {
+    if len < HDR_LENGTH || len as usize > buf.get_ref().len() {
         return Err(super::Error(format!("nonsensical len in header: ({}, {}, {})", typ, len, sid)));
     }
 
@@ -191,8 +210,11 @@ fn deserialize(buf: &[u8]) -> Result<RawMsg> {
 #[derive(PartialEq)]
 pub enum Msg<'a> {
     Cr(create::Msg),
-    Ms(measure::Msg),
+    Ms(measure::View<'a>),
     Ins(install::Msg),
+    Rd(read_response::Msg),
+    St(status::Msg),
+    Bm(batch_measure::View<'a>),
     Other(RawMsg<'a>),
 }
 
@@ -200,9 +222,12 @@ impl<'a> Msg<'a> {
     fn from_raw_msg(m: RawMsg) -> Result<Msg> {
         match m.typ {
             create::CREATE => Ok(Msg::Cr(create::Msg::from_raw_msg(m)?)),
-            measure::MEASURE => Ok(Msg::Ms(measure::Msg::from_raw_msg(m)?)),
+            measure::MEASURE => Ok(Msg::Ms(measure::View::from_raw_msg(m)?)),
             install::INSTALL => Ok(Msg::Ins(install::Msg::from_raw_msg(m)?)),
-            update_field::UPDATE_FIELD => unimplemented!(),
+            update_field::UPDATE_FIELD => Err(super::Error(String::from("CCP does not receive update_field messages"))),
+            read_response::READ_RESPONSE => Ok(Msg::Rd(read_response::Msg::from_raw_msg(m)?)),
+            status::STATUS => Ok(Msg::St(status::Msg::from_raw_msg(m)?)),
+            batch_measure::BATCH_MEASURE => Ok(Msg::Bm(batch_measure::View::from_raw_msg(m)?)),
             _ => Ok(Msg::Other(m)),
         }
     }
@@ -327,4 +352,16 @@ mod tests {
 
         assert_eq!(buf[len1+len2..].len(), 0);
     }
+    #[test]
+    fn malformed() {
+        for buf in &[
+            &[1, 0, 100, 0, 1, 0, 0, 0, 2, 0, 0, 0][..], // longer than the buffer
+            &[7, 0, 10, 0, 1, 0, 0, 0, 2, 0],           // too short for its type
+            &[1, 0, 13, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],  // part of a field
+            &[3, 0, 12, 0, 1, 0, 0, 0, 0, 0, 0, 0],     // only CCP sends update_field
+            &[2, 0, 16, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0], // or install
+        ] {
+            assert!(Msg::from_buf(buf).is_err(), "{:?}", buf);
+        }
+    }
 }
@@ -0,0 +1,82 @@
+//! CCP sends this message to ask the datapath for the current values of the given registers of
+//! a flow's program. The datapath answers with a `read_response::Msg`.
+
+use std::io::prelude::*;
+use {Result, Error};
+use super::{AsRawMsg, RawMsg, HDR_LENGTH, u32_to_u8s};
+use lang::Reg;
+
+pub(crate) const READ_REQUEST: u8 = 5;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Msg {
+    pub sid: u32,
+    /// The program the registers belong to.
+    pub program_uid: u32,
+    pub num_fields: u32,
+    pub fields: Vec<Reg>,
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (
+            READ_REQUEST,
+            HDR_LENGTH + 4 + 4 + self.num_fields * 5, // Reg size = 5
+            self.sid,
+        )
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.program_uid);
+        w.write_all(&buf[..])?;
+        u32_to_u8s(&mut buf, self.num_fields);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        for f in &self.fields {
+            let reg = f.clone().into_iter().map(|e| e.map_err(Error::from)).collect::<Result<Vec<u8>>>()?;
+            w.write_all(&reg[..])?;
+        }
+
+        Ok(())
+    }
+
+    // at least for now, portus does not have to worry about deserializing this message
+    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
+        Err(Error(String::from("CCP does not receive read_request messages")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::{Reg, Type};
+
+    #[test]
+    fn serialize_read_request_msg() {
+        let m = super::Msg{
+            sid: 1,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![Reg::Implicit(4, Type::Num(None)), Reg::Control(3, Type::Num(None))],
+        };
+
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                5, 0,                                     // READ_REQUEST
+                26, 0,                                    // length = 26
+                1, 0, 0, 0,                               // sock_id = 1
+                7, 0, 0, 0,                               // program_uid = 7
+                2, 0, 0, 0,                               // num_fields = 2
+                2, 4, 0, 0, 0,                            // Reg::Implicit(4)
+                0, 3, 0, 0, 0,                            // Reg::Control(3)
+            ],
+        );
+    }
+}
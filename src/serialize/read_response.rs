@@ -0,0 +1,98 @@
+//! The datapath sends this message in answer to a `read_request::Msg`, with the values of the
+//! requested registers in the order they were requested.
+
+use std::io::prelude::*;
+use {Result, Error};
+use super::{AsRawMsg, RawMsg, HDR_LENGTH, u32_to_u8s, u64_to_u8s, u64_from_u8s};
+
+pub(crate) const READ_RESPONSE: u8 = 6;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Msg {
+    pub sid: u32,
+    /// The program the flow is running, which may differ from the one in the request.
+    pub program_uid: u32,
+    pub num_fields: u32,
+    pub fields: Vec<u64>,
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (
+            READ_RESPONSE,
+            HDR_LENGTH + 4 + 4 + self.num_fields * 8,
+            self.sid,
+        )
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.program_uid);
+        w.write_all(&buf[..])?;
+        u32_to_u8s(&mut buf, self.num_fields);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 8];
+        for f in &self.fields {
+            u64_to_u8s(&mut buf, *f);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        let fields = b.chunks(8)
+            .map(|sl| if sl.len() < 8 {
+                Err(Error(format!("not long enough: {:?}", sl)))
+            } else {
+                Ok(u64_from_u8s(sl))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        let num_fields = msg.get_u32(1)?;
+        if fields.len() != num_fields as usize {
+            return Err(Error(format!(
+                "read response has {} fields, expected {}", fields.len(), num_fields,
+            )));
+        }
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: msg.get_u32(0)?,
+            num_fields,
+            fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_read_response,
+        super::Msg,
+        super::Msg{
+            sid: 15,
+            program_uid: 72,
+            num_fields: 3,
+            fields: vec![42, 0, 1 << 40],
+        },
+        ::serialize::Msg::Rd(rd),
+        rd
+    );
+
+    #[test]
+    fn test_read_response_missing_fields() {
+        let m = super::Msg { sid: 15, program_uid: 72, num_fields: 3, fields: vec![42, 0, 1] };
+        let mut buf = ::serialize::serialize(&m).expect("serialize");
+        // drop the last field, keeping the header's count of 3
+        buf.truncate(buf.len() - 8);
+        buf[2] -= 8;
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+    }
+}
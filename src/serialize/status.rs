@@ -0,0 +1,96 @@
+//! The datapath sends this message to say whether it accepted a program installed with
+//! `install::Msg` or switched to with `changeprog::Msg`, and if not, why.
+
+use std::io::prelude::*;
+use std::str;
+use {Result, Error};
+use super::{AsRawMsg, RawMsg, HDR_LENGTH, u32_to_u8s};
+
+pub(crate) const STATUS: u8 = 7;
+
+/// The datapath accepted the program.
+pub const OK: u32 = 0;
+/// The program has more instructions or events than the datapath supports.
+pub const TOO_MANY_INSTRUCTIONS: u32 = 1;
+/// The program uses an opcode or register type the datapath does not know.
+pub const UNKNOWN_OPCODE: u32 = 2;
+/// A `changeprog::Msg` named a program the datapath does not have.
+pub const UNKNOWN_PROGRAM: u32 = 3;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Msg {
+    pub sid: u32,
+    pub program_uid: u32,
+    /// `OK`, or why the datapath rejected the program.
+    pub code: u32,
+    pub description: String,
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (
+            STATUS,
+            HDR_LENGTH + 4 + 4 + self.description.len() as u32,
+            self.sid,
+        )
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.program_uid);
+        w.write_all(&buf[..])?;
+        u32_to_u8s(&mut buf, self.code);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(self.description.as_bytes())?;
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        let description = str::from_utf8(b)
+            .map_err(|e| Error(format!("status description is not UTF-8: {}", e)))?
+            .trim_end_matches('\0');
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: msg.get_u32(0)?,
+            code: msg.get_u32(1)?,
+            description: String::from(description),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_status_ok,
+        super::Msg,
+        super::Msg{
+            sid: 0,
+            program_uid: 72,
+            code: super::OK,
+            description: String::new(),
+        },
+        ::serialize::Msg::St(st),
+        st
+    );
+
+    check_msg!(
+        test_status_error,
+        super::Msg,
+        super::Msg{
+            sid: 15,
+            program_uid: 72,
+            code: super::UNKNOWN_OPCODE,
+            description: String::from("unknown opcode 27 in instruction 3"),
+        },
+        ::serialize::Msg::St(st),
+        st
+    );
+}
@@ -46,7 +46,7 @@ impl AsRawMsg for Msg {
     }
 
     fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!()
+        Err(Error(String::from("CCP does not receive update_field messages")))
     }
 }
 
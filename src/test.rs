@@ -25,7 +25,7 @@ fn test_ser_over_ipc() {
                 program_uid: 7,
                 num_fields: 1,
                 fields: vec![0],
-            })
+            }.into())
         );
     });
 
@@ -100,7 +100,364 @@ fn bench_ser_over_ipc(b: &mut Bencher) {
                 program_uid: 12,
                 num_fields: 1,
                 fields: vec![0],
-            })
+            }.into())
         );
     });
 }
+
+#[test]
+fn test_read_fields() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use super::{lang, Datapath, DatapathTrait};
+
+    let (ccp_tx, dp_rx) = mpsc::channel();
+    let (dp_tx, ccp_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let mut buf = [0u8; 1024];
+    let mut b = ipc::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let (_, sc) = lang::compile(b"(def (Report (acked 0) (mode 3)) (target 0)) (when true (:= Report.acked target))", &[]).unwrap();
+
+    // a mock datapath, which sends a report before answering each request, and answers the
+    // second as if the flow had switched programs.
+    let uid = sc.program_uid;
+    let dp_tx_late = dp_tx.clone();
+    let dp = thread::spawn(move || {
+        for (i, stale) in [false, true].iter().enumerate() {
+            let req: Vec<u8> = dp_rx.recv().expect("receive read request");
+            assert_eq!(&req[..4], &[5, 0, 26, 0]);
+            assert_eq!(&req[16..], &[2, 4, 0, 0, 0, 0, 0, 0, 0, 0]); // Cwnd, target
+            let report = serialize::measure::Msg { sid: 42, program_uid: uid, num_fields: 1, fields: vec![i as u64] };
+            dp_tx.send(serialize::serialize(&report).expect("serialize")).expect("send report");
+            let resp = serialize::read_response::Msg {
+                sid: 42,
+                program_uid: if *stale { uid + 1 } else { uid },
+                num_fields: 2,
+                fields: vec![14600, 7],
+            };
+            dp_tx.send(serialize::serialize(&resp).expect("serialize")).expect("send response");
+        }
+
+        dp_rx
+    });
+
+    let d = Datapath {
+        sock_id: 42,
+        sender: b.sender(),
+        programs: Rc::new(HashMap::new()),
+        rejected: Rc::new(RefCell::new(HashMap::new())),
+        current: None,
+        switching: None,
+        num_timers: 0,
+    };
+    let timeout = Duration::from_secs(5);
+    // Report.mode is never changed, so the datapath does not store it
+    assert_eq!(d.read_fields(&sc, &["Cwnd", "Report.mode", "target"], timeout).unwrap(), vec![14600, 3, 7]);
+    assert!(d.read_fields(&sc, &["Cwnd", "target"], timeout).is_err());
+    assert!(d.read_fields(&sc, &["Micros"], timeout).is_err());
+    let dp_rx = dp.join().expect("join datapath thread");
+
+    // the reports which arrived while waiting are not lost
+    for i in 0..2 {
+        match b.next().expect("receive report") {
+            serialize::Msg::Ms(m) => assert_eq!(m.iter().collect::<Vec<_>>(), vec![i]),
+            m => panic!("unexpected message: {:?}", m),
+        }
+    }
+
+    // the blocking socket gets no answer, but the timeout holds
+    let start = ::std::time::Instant::now();
+    assert!(d.read_fields(&sc, &["Cwnd"], Duration::from_millis(50)).is_err());
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    // a response which arrived in one read with the report the Backend yielded is found
+    let report = serialize::measure::Msg { sid: 42, program_uid: uid, num_fields: 1, fields: vec![2] };
+    let resp = serialize::read_response::Msg { sid: 42, program_uid: uid, num_fields: 1, fields: vec![9] };
+    let mut both = serialize::serialize(&report).expect("serialize");
+    both.extend(serialize::serialize(&resp).expect("serialize"));
+    dp_tx_late.send(both).expect("send report and response");
+    match b.next().expect("receive report") {
+        serialize::Msg::Ms(m) => assert_eq!(m.iter().collect::<Vec<_>>(), vec![2]),
+        m => panic!("unexpected message: {:?}", m),
+    }
+
+    assert_eq!(d.read_fields(&sc, &["target"], Duration::from_millis(10)).unwrap(), vec![9]);
+    assert_eq!(dp_rx.try_iter().count(), 2);
+}
+
+#[test]
+fn test_set_program_timers() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::{lang, Datapath, DatapathTrait};
+
+    let (ccp_tx, dp_rx) = mpsc::channel();
+    let (_dp_tx, ccp_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let mut buf = [0u8; 1024];
+    let b = ipc::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let capabilities = lang::Capabilities::default().with_timers(2);
+    let (_, sc) = lang::compile_with_capabilities(b"(def (timer probe)) (when (> probe 10) (:= probe 0))", &[], capabilities).unwrap();
+    let mut programs = HashMap::new();
+    programs.insert(String::from("probe"), sc);
+
+    let mut d = Datapath {
+        sock_id: 42,
+        sender: b.sender(),
+        programs: Rc::new(programs),
+        rejected: Rc::new(RefCell::new(HashMap::new())),
+        current: None,
+        switching: None,
+        num_timers: 0,
+    };
+    assert!(d.set_program(String::from("probe"), None).is_err());
+    assert!(dp_rx.try_recv().is_err());
+
+    d.num_timers = 1;
+    assert!(d.set_program(String::from("probe"), None).is_ok());
+    assert_eq!(dp_rx.recv().expect("receive changeprog")[0], 4);
+}
+
+#[test]
+fn test_set_program_after_rejection() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::{lang, Datapath, DatapathTrait};
+
+    let (ccp_tx, dp_rx) = mpsc::channel();
+    let (_dp_tx, ccp_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let mut buf = [0u8; 1024];
+    let b = ipc::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let (_, ss) = lang::compile(b"(def (persistent rounds 0)) (when true (:= rounds (+ rounds 1)))", &[]).unwrap();
+    let (_, ca) = lang::compile(b"(def (persistent rounds 0)) (when true (:= rounds (+ rounds 2)))", &[]).unwrap();
+    let ss_uid = ss.program_uid;
+    let mut programs = HashMap::new();
+    programs.insert(String::from("ss"), ss);
+    programs.insert(String::from("ca"), ca);
+    let programs = Rc::new(programs);
+
+    // each switch carries rounds over from the program before, unless the datapath rejected it
+    for &reject in &[false, true] {
+        let rejected = Rc::new(RefCell::new(HashMap::new()));
+        let mut d = Datapath {
+            sock_id: 42,
+            sender: b.sender(),
+            programs: programs.clone(),
+            rejected: rejected.clone(),
+            current: None,
+            switching: None,
+            num_timers: 0,
+        };
+
+        d.set_program(String::from("ss"), None).unwrap();
+        let changeprog: Vec<u8> = dp_rx.recv().expect("receive changeprog");
+        assert_eq!(changeprog[2], 16);
+        if reject {
+            rejected.borrow_mut().insert(ss_uid, String::from("no"));
+        }
+
+        d.set_program(String::from("ca"), None).unwrap();
+        let changeprog: Vec<u8> = dp_rx.recv().expect("receive changeprog");
+        assert_eq!(changeprog[2], if reject { 16 } else { 30 });
+    }
+}
+
+/// An algorithm which switches each new flow to `prog`, and forwards the datapath's complaints.
+struct SwitchOnCreate(mpsc::Sender<String>);
+
+impl super::CongAlg<ipc::chan::Socket<Blocking>> for SwitchOnCreate {
+    type Config = mpsc::Sender<String>;
+    fn name() -> String {
+        String::from("switch-on-create")
+    }
+
+    fn init_programs(_cfg: super::Config<ipc::chan::Socket<Blocking>, Self>) -> Vec<(String, String)> {
+        vec![(String::from("prog"), String::from("(def (Report.acked 0)) (when true (:= Report.acked Ack.bytes_acked))"))]
+    }
+
+    fn create(mut control: super::Datapath<ipc::chan::Socket<Blocking>>, cfg: super::Config<ipc::chan::Socket<Blocking>, Self>, _info: super::DatapathInfo) -> Self {
+        use super::DatapathTrait;
+        control.set_program(String::from("prog"), None).expect("set program");
+        SwitchOnCreate(cfg.config)
+    }
+
+    fn on_report(&mut self, _sock_id: u32, _m: super::Report) {}
+
+    fn on_program_error(&mut self, sock_id: u32, _program_uid: u32, reason: String) {
+        self.0.send(format!("{}: {}", sock_id, reason)).expect("send program error");
+    }
+}
+
+#[test]
+fn test_program_status() {
+    use serialize::status;
+
+    // start CCP, with a mock datapath which accepts the install, then rejects the switch of a flow
+    let (ccp_tx, dp_rx) = mpsc::channel();
+    let (dp_tx, ccp_rx) = mpsc::channel();
+    let (errs_tx, errs_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let h = super::spawn::<_, SwitchOnCreate>(ipc::BackendBuilder { sock: sk }, super::Config { logger: None, config: errs_tx });
+
+    let install: Vec<u8> = dp_rx.recv().expect("receive install");
+    assert_eq!(install[0], 2);
+    let uid = serialize::u32_from_u8s(&install[8..12]);
+    let ok = status::Msg { sid: 0, program_uid: uid, code: status::OK, description: String::new() };
+    dp_tx.send(serialize::serialize(&ok).unwrap()).unwrap();
+    let create = serialize::create::Msg { sid: 15, init_cwnd: 14600, mss: 1460, src_ip: 0, src_port: 0, dst_ip: 0, dst_port: 0, num_timers: 0 };
+    dp_tx.send(serialize::serialize(&create).unwrap()).unwrap();
+    let changeprog: Vec<u8> = dp_rx.recv().expect("receive changeprog");
+    assert_eq!(changeprog[0], 4);
+    let rejected = status::Msg { sid: 15, program_uid: uid, code: status::UNKNOWN_PROGRAM, description: String::from("no such program") };
+    dp_tx.send(serialize::serialize(&rejected).unwrap()).unwrap();
+    assert_eq!(
+        errs_rx.recv_timeout(::std::time::Duration::from_secs(5)).expect("program error"),
+        "15: no such program (code 3)",
+    );
+
+    // programs rejected at install stop CCP
+    let rejected = status::Msg { sid: 0, program_uid: uid, code: status::TOO_MANY_INSTRUCTIONS, description: String::from("too long") };
+    dp_tx.send(serialize::serialize(&rejected).unwrap()).unwrap();
+    let err = h.wait().expect_err("run should stop");
+    assert_eq!(err.0, "Datapath rejected program \"prog\": too long (code 1)");
+}
+
+/// An algorithm which forwards the first field of each report of each flow.
+struct ForwardReports(mpsc::Sender<String>);
+
+impl super::CongAlg<ipc::chan::Socket<Blocking>> for ForwardReports {
+    type Config = mpsc::Sender<String>;
+    fn name() -> String {
+        String::from("forward-reports")
+    }
+
+    fn init_programs(_cfg: super::Config<ipc::chan::Socket<Blocking>, Self>) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    fn create(_control: super::Datapath<ipc::chan::Socket<Blocking>>, cfg: super::Config<ipc::chan::Socket<Blocking>, Self>, _info: super::DatapathInfo) -> Self {
+        ForwardReports(cfg.config)
+    }
+
+    fn on_report(&mut self, sock_id: u32, m: super::Report) {
+        self.0.send(format!("{}: {}", sock_id, m.fields.get(0).unwrap())).expect("send report");
+    }
+
+    fn close(&mut self) {
+        self.0.send(String::from("closed")).expect("send close");
+    }
+}
+
+#[test]
+fn test_batch_measure() {
+    let (ccp_tx, _dp_rx) = mpsc::channel();
+    let (dp_tx, ccp_rx) = mpsc::channel();
+    let (reports_tx, reports_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let h = super::spawn::<_, ForwardReports>(ipc::BackendBuilder { sock: sk }, super::Config { logger: None, config: reports_tx });
+
+    for sid in 1..4 {
+        let create = serialize::create::Msg { sid, init_cwnd: 14600, mss: 1460, src_ip: 0, src_port: 0, dst_ip: 0, dst_port: 0, num_timers: 0 };
+        dp_tx.send(serialize::serialize(&create).unwrap()).unwrap();
+    }
+
+    // reports for many flows, including one which has ended and one which never started
+    let report = |sid, fields: Vec<u64>| serialize::measure::Msg { sid, program_uid: 1, num_fields: fields.len() as u8, fields };
+    let batch = serialize::batch_measure::Msg {
+        num_reports: 4,
+        reports: vec![report(1, vec![10, 0]), report(3, vec![30]), report(2, vec![]), report(9, vec![90])],
+    };
+    dp_tx.send(serialize::serialize(&batch).unwrap()).unwrap();
+
+    let got: Vec<String> = (0..3).map(|_| reports_rx.recv_timeout(::std::time::Duration::from_secs(5)).expect("report")).collect();
+    assert_eq!(got, vec!["1: 10", "3: 30", "closed"]);
+    h.kill();
+    h.wait().ok();
+}
+
+#[test]
+fn test_update_batch() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::{lang, Datapath};
+
+    let (ccp_tx, dp_rx) = mpsc::channel();
+    let (_dp_tx, ccp_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let mut buf = [0u8; 1024];
+    let b = ipc::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let (_, sc) = lang::compile(b"(def (Report (acked 0) (mode 3)) (target 0)) (when true (:= Report.acked target))", &[]).unwrap();
+    let d = Datapath {
+        sock_id: 1,
+        sender: b.sender(),
+        programs: Rc::new(HashMap::new()),
+        rejected: Rc::new(RefCell::new(HashMap::new())),
+        current: None,
+        switching: None,
+        num_timers: 0,
+    };
+
+    // 8 + 2 * 13 bytes for each flow: more than fit in one message
+    let mut batch = d.update_batch();
+    for sid in 0..3000 {
+        batch.add(sid, &sc, &[("Cwnd", 14600), ("target", sid)]).unwrap();
+    }
+    assert!(batch.add(1, &sc, &[("Report.acked", 0)]).is_err());
+    assert_eq!(batch.len(), 3000);
+    batch.send().unwrap();
+
+    let msgs: Vec<Vec<u8>> = dp_rx.try_iter().collect();
+    assert_eq!(msgs.len(), 2);
+    let mut total = 0;
+    for m in &msgs {
+        assert_eq!(m[0], 9);
+        assert_eq!(usize::from(m[2]) | usize::from(m[3]) << 8, m.len());
+        assert!(m.len() <= <ipc::chan::Socket<Blocking> as ipc::Ipc>::max_msg_len() as usize);
+        total += serialize::u32_from_u8s(&m[8..12]);
+    }
+    assert_eq!(total, 3000);
+}
+
+#[test]
+fn test_msg_handlers() {
+    use serialize::AsRawMsg;
+    use test_helper::TestMsg;
+
+    let mut handlers = super::MsgHandlers::<ForwardReports>::new();
+    assert!(handlers.register(3, |_, _| Ok(())).is_err());
+    let (got_tx, got_rx) = mpsc::channel();
+    handlers.register(0xff, move |raw, alg| {
+        let m = TestMsg::from_raw_msg(raw)?;
+        got_tx.send((m.0, alg.is_some())).expect("send message");
+        Ok(())
+    }).expect("register handler");
+    assert!(handlers.register(0xff, |_, _| Ok(())).is_err());
+
+    let (ccp_tx, _dp_rx) = mpsc::channel();
+    let (dp_tx, ccp_rx) = mpsc::channel();
+    let (reports_tx, _reports_rx) = mpsc::channel();
+    let sk = ipc::chan::Socket::<Blocking>::new(ccp_tx, ccp_rx).expect("initialize ipc");
+    let h = super::spawn_with_handlers::<_, ForwardReports>(
+        ipc::BackendBuilder { sock: sk },
+        super::Config { logger: None, config: reports_tx },
+        handlers,
+    );
+
+    // a message of a type with no handler is skipped
+    let mut unhandled = serialize::serialize(&TestMsg(String::from("ignored"))).unwrap();
+    unhandled[0] = 0xfe;
+    dp_tx.send(unhandled).unwrap();
+    dp_tx.send(serialize::serialize(&TestMsg(String::from("hello, ccp"))).unwrap()).unwrap();
+    assert_eq!(
+        got_rx.recv_timeout(::std::time::Duration::from_secs(5)).expect("handled message"),
+        (String::from("hello, ccp"), false),
+    );
+    h.kill();
+    h.wait().ok();
+}